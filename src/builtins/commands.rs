@@ -0,0 +1,603 @@
+use crate::builtins::{self, shell_quote, Definition, Invocation};
+use crate::env::Aliases;
+use crate::lang::parser::KEYWORDS;
+use crate::lang::{ExecutionContext, Result};
+use std::path::PathBuf;
+
+/// Builtins that find, describe, and rename commands
+pub const BUILTINS: &[Definition] = &[
+    Definition {
+        name: "alias",
+        run: alias,
+        usage: "alias [-p] [NAME[=VALUE] ...]",
+        summary: "Define or print aliases",
+    },
+    Definition {
+        name: "builtin",
+        run: builtin,
+        usage: "builtin NAME [ARG ...]",
+        summary: "Run a builtin, even if a function has the same name",
+    },
+    Definition {
+        name: "command",
+        run: command,
+        usage: "command [-vV] NAME [ARG ...]",
+        summary: "Run a command without looking up functions, or describe it",
+    },
+    Definition {
+        name: "hash",
+        run: hash,
+        usage: "hash [-r] [-d] [NAME ...]",
+        summary: "Remember or forget the locations of programs",
+    },
+    Definition {
+        name: "help",
+        run: help,
+        usage: "help [-ds] [NAME ...]",
+        summary: "Print help for builtins",
+    },
+    Definition {
+        name: "type",
+        run: type_,
+        usage: "type [-at] NAME ...",
+        summary: "Describe how names would be interpreted as commands",
+    },
+    Definition {
+        name: "unalias",
+        run: unalias,
+        usage: "unalias -a | NAME ...",
+        summary: "Remove aliases",
+    },
+];
+
+/// The ways a command name can be interpreted, in the order they're tried
+enum Kind {
+    Alias(String),
+    Keyword,
+    /// A function and its definition
+    Function(String),
+    Builtin,
+    File(PathBuf),
+}
+
+/// type [-at] NAME ...
+///
+/// Describe how each name would be interpreted when used as a command
+pub fn type_(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut all = false;
+    let mut terse = false;
+    let mut first_name = 1;
+    for arg in &args[1..] {
+        if arg.len() < 2 || !arg.starts_with('-') {
+            break;
+        }
+        first_name += 1;
+        if arg == "--" {
+            break;
+        }
+        for c in arg[1..].chars() {
+            match c {
+                'a' => all = true,
+                't' => terse = true,
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+
+    let mut code = 0;
+    for name in &args[first_name..] {
+        let kinds = interpretations(inv.ec, name, all);
+        if kinds.is_empty() {
+            if !terse {
+                inv.error(format!("{}: not found", name));
+            }
+            code = 1;
+        }
+
+        let mut out = String::new();
+        for kind in kinds {
+            out.push_str(&describe(name, kind, terse));
+            out.push('\n');
+        }
+        inv.print(out)?;
+    }
+    Ok(code)
+}
+
+/// command [-vV] NAME [ARG ...]
+///
+/// Run a builtin or program even if a function has the same name,
+/// or with -v or -V describe how the name would be run
+pub fn command(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut describe_only = false;
+    let mut verbose = false;
+    let mut first_name = 1;
+    for arg in &args[1..] {
+        if arg.len() < 2 || !arg.starts_with('-') {
+            break;
+        }
+        first_name += 1;
+        if arg == "--" {
+            break;
+        }
+        for c in arg[1..].chars() {
+            match c {
+                'v' => describe_only = true,
+                'V' => {
+                    describe_only = true;
+                    verbose = true;
+                }
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+
+    if first_name >= args.len() {
+        return Ok(0);
+    }
+
+    if !describe_only {
+        let words = inv.args[first_name..].to_vec();
+        return Ok(inv.jobs.run_words(inv.ec, &words, [inv.stdin, inv.stdout, inv.stderr], false)?.exit_code);
+    }
+
+    let mut code = 0;
+    for name in &args[first_name..] {
+        match interpretations(inv.ec, name, false).pop() {
+            Some(kind) => {
+                let line = match kind {
+                    Kind::Alias(value) if !verbose => format!("alias {}={}", name, shell_quote(value)),
+                    Kind::File(path) if !verbose => path.display().to_string(),
+                    _ if !verbose => name.clone(),
+                    kind => describe(name, kind, false),
+                };
+                inv.print(format!("{}\n", line))?;
+            }
+            None => {
+                if verbose {
+                    inv.error(format!("{}: not found", name));
+                }
+                code = 1;
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// builtin NAME [ARG ...]
+///
+/// Run the builtin NAME directly, so functions wrapping a builtin can call it without calling themselves
+pub fn builtin(inv: &mut Invocation) -> Result<i32> {
+    let name = match inv.args.get(1) {
+        Some(name) => name.to_string_lossy(),
+        None => return Ok(0),
+    };
+    match builtins::lookup(&name) {
+        Some(run) => run(&mut Invocation {
+            jobs: inv.jobs,
+            ec: inv.ec,
+            args: &inv.args[1..],
+            stdin: inv.stdin,
+            stdout: inv.stdout,
+            stderr: inv.stderr,
+            redirections: inv.redirections,
+        }),
+        None => {
+            inv.error(format!("{}: not a shell builtin", name));
+            Ok(1)
+        }
+    }
+}
+
+/// alias [-p] [NAME[=VALUE] ...]
+///
+/// Define aliases, or print them in a form that can be read back in.
+/// A reserved word like `if` can be given an alias, but it's never expanded, commands are parsed before
+/// aliases are looked up so a reserved word at the start of a command is always the reserved word.
+pub fn alias(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut args = &args[1..];
+    while let Some(first) = args.first() {
+        match first.as_str() {
+            "-p" => args = &args[1..],
+            "--" => {
+                args = &args[1..];
+                break;
+            }
+            _ => break,
+        }
+    }
+
+    if args.is_empty() {
+        let mut out = String::new();
+        for (name, value) in inv.ec.aliases().iter() {
+            out.push_str(&format!("alias {}={}\n", name, shell_quote(value)));
+        }
+        inv.print(out)?;
+        return Ok(0);
+    }
+
+    let mut code = 0;
+    for arg in args {
+        match arg.find('=') {
+            Some(split) => {
+                let name = &arg[..split];
+                if !Aliases::is_valid_name(name) {
+                    inv.error(format!("`{}': invalid alias name", name));
+                    code = 1;
+                    continue;
+                }
+                inv.ec.aliases_mut().define(name, &arg[split + 1..]);
+            }
+            None => match inv.ec.aliases().value(arg) {
+                Some(value) => inv.print(format!("alias {}={}\n", arg, shell_quote(value)))?,
+                None => {
+                    inv.error(format!("{}: not found", arg));
+                    code = 1;
+                }
+            },
+        }
+    }
+    Ok(code)
+}
+
+/// unalias -a | NAME ...
+///
+/// Remove aliases, -a removes all of them
+pub fn unalias(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("-a") => {
+            inv.ec.aliases_mut().clear();
+            return Ok(0);
+        }
+        None => {
+            inv.error("usage: unalias [-a] name [name ...]");
+            return Ok(2);
+        }
+        _ => (),
+    }
+
+    let mut code = 0;
+    for name in &args[1..] {
+        if inv.ec.aliases_mut().remove(name).is_none() {
+            inv.error(format!("{}: not found", name));
+            code = 1;
+        }
+    }
+    Ok(code)
+}
+
+/// hash [-r] [-d] [NAME ...]
+///
+/// Find and remember where programs are, with no names the remembered programs are listed.
+/// -r forgets everything and -d forgets the named programs.
+pub fn hash(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut clear = false;
+    let mut delete = false;
+    let mut first_name = 1;
+    for arg in &args[1..] {
+        if arg.len() < 2 || !arg.starts_with('-') {
+            break;
+        }
+        first_name += 1;
+        if arg == "--" {
+            break;
+        }
+        for c in arg[1..].chars() {
+            match c {
+                'r' => clear = true,
+                'd' => delete = true,
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+
+    if clear {
+        inv.ec.path_cache_mut().clear();
+    }
+
+    let names = &args[first_name..];
+    if names.is_empty() {
+        if clear {
+            return Ok(0);
+        }
+        if inv.ec.path_cache_mut().is_empty() {
+            inv.error("hash table empty");
+            return Ok(0);
+        }
+
+        let mut out = "hits\tcommand\n".to_string();
+        for (_, hashed) in inv.ec.path_cache_mut().iter() {
+            out.push_str(&format!("{:>4}\t{}\n", hashed.hits, hashed.path.display()));
+        }
+        inv.print(out)?;
+        return Ok(0);
+    }
+
+    let mut code = 0;
+    for name in names {
+        if delete {
+            if inv.ec.path_cache_mut().remove(name).is_none() {
+                inv.error(format!("{}: not found", name));
+                code = 1;
+            }
+            continue;
+        }
+
+        // names with a slash are never searched for, and functions and builtins don't need to be
+        if name.contains('/') || inv.ec.functions().exists(name) || builtins::is_builtin(name) {
+            continue;
+        }
+        match inv.ec.find_executable(name) {
+            Ok(path) => inv.ec.path_cache_mut().insert(name.as_str(), path, 0),
+            Err(_) => {
+                inv.error(format!("{}: not found", name));
+                code = 1;
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// help [-ds] [NAME ...]
+///
+/// Print the usage and a description of builtins, or list every builtin without names.
+/// -d prints only the descriptions and -s only the usage.
+pub fn help(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut descriptions = false;
+    let mut synopses = false;
+    let mut first_name = 1;
+    for arg in &args[1..] {
+        if arg.len() < 2 || !arg.starts_with('-') {
+            break;
+        }
+        first_name += 1;
+        if arg == "--" {
+            break;
+        }
+        for c in arg[1..].chars() {
+            match c {
+                'd' => descriptions = true,
+                's' => synopses = true,
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+
+    let format = |d: &Definition| {
+        if descriptions {
+            format!("{} - {}\n", d.name, d.summary)
+        } else if synopses {
+            format!("{}: {}\n", d.name, d.usage)
+        } else {
+            format!("{}: {}\n    {}\n", d.name, d.usage, d.summary)
+        }
+    };
+
+    let mut text = String::new();
+    if args.len() <= first_name {
+        for d in builtins::definitions() {
+            if descriptions || synopses {
+                text.push_str(&format(d));
+            } else {
+                text.push_str(&format!("{}\n", d.usage));
+            }
+        }
+        inv.print(text)?;
+        return Ok(0);
+    }
+
+    let mut code = 0;
+    for name in &args[first_name..] {
+        match builtins::definition(name) {
+            Some(d) => text.push_str(&format(d)),
+            None => {
+                inv.error(format!("no help topics match `{}'", name));
+                code = 1;
+            }
+        }
+    }
+    inv.print(text)?;
+    Ok(code)
+}
+
+/// Describe one interpretation of a name, either with a sentence or, when terse, a single word
+fn describe(name: &str, kind: Kind, terse: bool) -> String {
+    match (kind, terse) {
+        (Kind::Alias(_), true) => "alias".to_string(),
+        (Kind::Keyword, true) => "keyword".to_string(),
+        (Kind::Function(_), true) => "function".to_string(),
+        (Kind::Builtin, true) => "builtin".to_string(),
+        (Kind::File(_), true) => "file".to_string(),
+        (Kind::Alias(value), false) => format!("{} is aliased to `{}'", name, value),
+        (Kind::Keyword, false) => format!("{} is a shell keyword", name),
+        (Kind::Function(definition), false) => format!("{} is a function\n{}", name, definition),
+        (Kind::Builtin, false) => format!("{} is a shell builtin", name),
+        (Kind::File(path), false) => format!("{} is {}", name, path.display()),
+    }
+}
+
+/// Find what a command name refers to, with `all` every interpretation is given instead of just the first
+fn interpretations(ec: &ExecutionContext, name: &str, all: bool) -> Vec<Kind> {
+    let mut kinds = Vec::new();
+    if let Some(value) = ec.aliases().value(name) {
+        kinds.push(Kind::Alias(value.clone()));
+    }
+    if KEYWORDS.contains(&name) {
+        kinds.push(Kind::Keyword);
+    }
+    // special builtins are found before functions, the same as when they're run
+    let special = builtins::is_special(name);
+    if special {
+        kinds.push(Kind::Builtin);
+    }
+    if let Some(definition) = ec.functions().definition(name) {
+        kinds.push(Kind::Function(definition));
+    }
+    if builtins::is_builtin(name) && !special {
+        kinds.push(Kind::Builtin);
+    }
+
+    if name.contains('/') {
+        let path = ec.cwd.join(name);
+        if path.is_file() {
+            kinds.push(Kind::File(PathBuf::from(name)));
+        }
+    } else {
+        kinds.extend(ec.find_executables(name).into_iter().map(Kind::File));
+    }
+
+    if !all {
+        kinds.truncate(1);
+    }
+    kinds
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::testing::run;
+
+    #[test]
+    fn type_describes_each_kind_of_command() {
+        let out = run("alias ll='ls -l'\nfunction f { echo hi; }\ntype ll f cd sh");
+        let sh = run("command -v sh").stdout;
+        assert_eq!(
+            out.stdout,
+            format!(
+                "ll is aliased to `ls -l'\nf is a function\nfunction f {{\n    echo hi\n}}\ncd is a shell builtin\nsh is {}",
+                sh
+            )
+        );
+        let out = run("alias ll='ls -l'\nfunction f { echo hi; }\ntype -t ll f cd sh for");
+        assert_eq!(out.stdout, "alias\nfunction\nbuiltin\nfile\nkeyword\n");
+    }
+
+    #[test]
+    fn type_fails_for_unknown_names() {
+        let out = run("type rush-no-such-command; echo $?");
+        assert_eq!(out.stdout, "1\n");
+        assert!(out.stderr.contains("rush-no-such-command: not found"));
+    }
+
+    #[test]
+    fn type_a_lists_every_interpretation() {
+        let out = run("function echo { builtin echo fn; }\ntype -ta echo");
+        let lines: Vec<&str> = out.stdout.lines().collect();
+        assert_eq!(&lines[..2], &["function", "builtin"]);
+        assert!(lines[2..].iter().all(|&kind| kind == "file"));
+    }
+
+    #[test]
+    fn type_agrees_with_what_runs() {
+        // functions override regular builtins and programs
+        let out = run("function true { builtin echo function; }\ntype -t true; true");
+        assert_eq!(out.stdout, "function\nfunction\n");
+        let out = run("function sh { echo function; }\ntype -t sh; sh");
+        assert_eq!(out.stdout, "function\nfunction\n");
+
+        // but not special builtins
+        let out = run("function export { echo function; }\ntype -t export; export X=1; echo $X");
+        assert_eq!(out.stdout, "builtin\n1\n");
+        let out = run("function export { echo function; }\ntype -a export");
+        assert_eq!(out.stdout, "export is a shell builtin\nexport is a function\nfunction export {\n    echo function\n}\n");
+    }
+
+    #[test]
+    fn command_skips_functions() {
+        let out = run("function echo { command echo wrapped \"$@\"; }\necho hi");
+        assert_eq!(out.stdout, "wrapped hi\n");
+        let out = run("function sh { echo function; }\ncommand sh -c 'echo program'");
+        assert_eq!(out.stdout, "program\n");
+    }
+
+    #[test]
+    fn command_v_prints_how_names_resolve() {
+        let out = run("function f { :; }\ncommand -v f cd sh; echo $?; command -v rush-no-such-command; echo $?");
+        let lines: Vec<&str> = out.stdout.lines().collect();
+        assert_eq!(&lines[..2], &["f", "cd"]);
+        assert!(lines[2].ends_with("/sh"));
+        assert_eq!(&lines[3..], &["0", "1"]);
+        assert_eq!(run("command -V cd").stdout, "cd is a shell builtin\n");
+    }
+
+    #[test]
+    fn hash_remembers_programs_until_path_changes() {
+        let out = run("hash sh; hash");
+        assert!(out.stdout.starts_with("hits\tcommand\n   0\t/"), "{}", out.stdout);
+        assert!(out.stdout.ends_with("/sh\n"), "{}", out.stdout);
+        let out = run("sh -c :; sh -c :; hash");
+        assert!(out.stdout.contains("   2\t"), "{}", out.stdout);
+
+        for forget in &["hash -d sh", "hash -r", "PATH=$PATH:/opt/bin"] {
+            let out = run(&format!("hash sh; {}; hash", forget));
+            assert_eq!(out.stdout, "", "{}", forget);
+            assert_eq!(out.stderr, "rush: hash: hash table empty\n", "{}", forget);
+        }
+    }
+
+    #[test]
+    fn aliases_expand_at_the_start_of_commands() {
+        let out = run("alias say='echo said'\nsay hi; echo say");
+        assert_eq!(out.stdout, "said hi\nsay\n");
+    }
+
+    #[test]
+    fn alias_prints_definitions_that_read_back_in() {
+        let out = run("alias ll='ls -la'\nalias ll");
+        assert_eq!(out.stdout, "alias ll='ls -la'\n");
+
+        let script = "alias q='echo \"$HOME\" '\\''single'\\'' `x`' ll='ls -la'\n\
+                      alias >saved; unalias -a; alias\n\
+                      . ./saved; alias >again; cmp saved again && cat again";
+        let out = run(script);
+        assert_eq!(out.stdout, "alias ll='ls -la'\nalias q='echo \"$HOME\" '\\''single'\\'' `x`'\n");
+    }
+
+    #[test]
+    fn unalias_removes_aliases() {
+        let out = run("alias a=b c=d\nunalias a; alias a; echo $?; alias; unalias -a; alias");
+        assert_eq!(out.stdout, "1\nalias c=d\n");
+        assert!(out.stderr.contains("alias: a: not found"));
+        assert_eq!(run("unalias nothing").status, 1);
+    }
+
+    #[test]
+    fn reserved_words_can_be_aliased_but_are_not_expanded() {
+        let out = run("alias do=echo\nalias do; for ((i = 0; i < 1; i++)) do echo loop; done");
+        assert_eq!(out.stdout, "alias do=echo\nloop\n");
+    }
+
+    #[test]
+    fn builtin_skips_functions_with_the_same_name() {
+        let out = run("mkdir sub\nfunction cd { builtin cd \"$@\" && echo moved; }\ncd sub; pwd");
+        assert!(out.stdout.starts_with("moved\n") && out.stdout.ends_with("/sub\n"), "{}", out.stdout);
+        assert_eq!(out.status, 0);
+        let out = run("function echo { builtin echo \"[$*]\"; }\necho a b");
+        assert_eq!(out.stdout, "[a b]\n");
+    }
+
+    #[test]
+    fn builtin_fails_for_other_commands() {
+        let out = run("function f { echo called; }\nbuiltin f; echo $?; builtin sh -c 'echo ran'; echo $?");
+        assert_eq!(out.stdout, "1\n1\n");
+        assert!(out.stderr.contains("f: not a shell builtin"), "{}", out.stderr);
+        assert!(out.stderr.contains("sh: not a shell builtin"), "{}", out.stderr);
+    }
+}
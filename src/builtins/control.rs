@@ -0,0 +1,782 @@
+use crate::builtins::{describe_io_error, shell_quote, Definition, Invocation};
+use crate::env::traps::{self, Action};
+use crate::env::Options;
+use crate::expr::condition;
+use crate::lang::ast::Command;
+use crate::lang::exec::shell_fd;
+use crate::lang::{ErrorKind, Flow, Result};
+use failure::ResultExt;
+use nix::fcntl;
+use nix::unistd;
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+/// Builtins that change what the shell runs next
+pub const BUILTINS: &[Definition] = &[
+    Definition {
+        name: ".",
+        run: source,
+        usage: ". FILE [ARG ...]",
+        summary: "Run the commands in a file in the current shell",
+    },
+    Definition {
+        name: ":",
+        run: true_,
+        usage: ": [ARG ...]",
+        summary: "Do nothing successfully",
+    },
+    Definition {
+        name: "[",
+        run: test,
+        usage: "[ EXPRESSION ]",
+        summary: "Evaluate a conditional expression",
+    },
+    Definition {
+        name: "break",
+        run: break_,
+        usage: "break [N]",
+        summary: "Leave enclosing loops",
+    },
+    Definition {
+        name: "continue",
+        run: continue_,
+        usage: "continue [N]",
+        summary: "Start the next iteration of an enclosing loop",
+    },
+    Definition {
+        name: "eval",
+        run: eval,
+        usage: "eval [ARG ...]",
+        summary: "Run the arguments as shell input",
+    },
+    Definition {
+        name: "exec",
+        run: exec,
+        usage: "exec [COMMAND [ARG ...]]",
+        summary: "Replace the shell with a command",
+    },
+    Definition {
+        name: "exit",
+        run: exit,
+        usage: "exit [N]",
+        summary: "Leave the shell",
+    },
+    Definition {
+        name: "false",
+        run: false_,
+        usage: "false",
+        summary: "Do nothing unsuccessfully",
+    },
+    Definition {
+        name: "return",
+        run: return_,
+        usage: "return [N]",
+        summary: "Leave the current function or sourced file",
+    },
+    Definition {
+        name: "set",
+        run: set,
+        usage: "set [-+bn] [-+o OPTION] [--] [ARG ...]",
+        summary: "Change shell options and the positional parameters",
+    },
+    Definition {
+        name: "source",
+        run: source,
+        usage: "source FILE [ARG ...]",
+        summary: "Run the commands in a file in the current shell",
+    },
+    Definition {
+        name: "test",
+        run: test,
+        usage: "test EXPRESSION",
+        summary: "Evaluate a conditional expression",
+    },
+    Definition {
+        name: "trap",
+        run: trap,
+        usage: "trap [-p] [[ACTION] CONDITION ...]",
+        summary: "Run commands when the shell receives signals, exits, or runs a command",
+    },
+    Definition {
+        name: "true",
+        run: true_,
+        usage: "true",
+        summary: "Do nothing successfully",
+    },
+];
+
+/// true, : [ARG ...]
+///
+/// Do nothing successfully, the arguments are still expanded for their side effects
+pub fn true_(_: &mut Invocation) -> Result<i32> {
+    Ok(0)
+}
+
+/// false
+///
+/// Do nothing unsuccessfully
+pub fn false_(_: &mut Invocation) -> Result<i32> {
+    Ok(1)
+}
+
+/// test EXPRESSION, [ EXPRESSION ]
+///
+/// Succeed if the conditional expression is true. Errors in the expression give status 2.
+pub fn test(inv: &mut Invocation) -> Result<i32> {
+    let mut args = &inv.args[1..];
+    if inv.name() == "[" {
+        match args.split_last() {
+            Some((last, rest)) if last == "]" => args = rest,
+            _ => {
+                inv.error("missing `]'");
+                return Ok(2);
+            }
+        }
+    }
+
+    match condition::evaluate(args, &inv.ec.cwd) {
+        Ok(true) => Ok(0),
+        Ok(false) => Ok(1),
+        Err(e) => {
+            inv.error(e);
+            Ok(2)
+        }
+    }
+}
+
+/// exit [N]
+///
+/// Leave the shell with status N, or the status of the last command
+pub fn exit(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    if args.len() > 2 {
+        inv.error("too many arguments");
+        return Ok(1);
+    }
+
+    let code = match args.get(1) {
+        Some(arg) => match arg.trim().parse::<i64>() {
+            Ok(n) => (n & 0xff) as i32,
+            Err(_) => {
+                inv.error(format!("{}: numeric argument required", arg));
+                2
+            }
+        },
+        None => last_exit_code(inv),
+    };
+
+    inv.jobs.request_flow(Flow::Exit(code));
+    Ok(code)
+}
+
+/// return [N]
+///
+/// Leave the current function or sourced file with status N, or the status of the last command
+pub fn return_(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    if !inv.jobs.can_return() {
+        inv.error("can only `return' from a function or sourced script");
+        return Ok(1);
+    }
+
+    let code = match args.get(1) {
+        Some(arg) => match arg.trim().parse::<i64>() {
+            Ok(n) => (n & 0xff) as i32,
+            Err(_) => {
+                inv.error(format!("{}: numeric argument required", arg));
+                2
+            }
+        },
+        None => last_exit_code(inv),
+    };
+
+    inv.jobs.request_flow(Flow::Return(code));
+    Ok(code)
+}
+
+/// break [N]
+///
+/// Leave N enclosing loops, one by default
+pub fn break_(inv: &mut Invocation) -> Result<i32> {
+    loop_control(inv, Flow::Break)
+}
+
+/// continue [N]
+///
+/// Start the next iteration of the N'th enclosing loop, the innermost by default
+pub fn continue_(inv: &mut Invocation) -> Result<i32> {
+    loop_control(inv, Flow::Continue)
+}
+
+fn loop_control(inv: &mut Invocation, flow: fn(usize) -> Flow) -> Result<i32> {
+    let args = inv.text_args();
+    let n = match args.get(1) {
+        Some(arg) => match arg.parse::<i64>() {
+            Ok(n) if n >= 1 => n as usize,
+            Ok(_) => {
+                inv.error(format!("{}: loop count out of range", arg));
+                return Ok(1);
+            }
+            Err(_) => {
+                inv.error(format!("{}: numeric argument required", arg));
+                return Ok(128);
+            }
+        },
+        None => 1,
+    };
+
+    let depth = inv.jobs.loop_depth();
+    if depth == 0 {
+        inv.error("only meaningful in a `for', `while', or `until' loop");
+        return Ok(0);
+    }
+    // asking to leave more loops than there are leaves all of them
+    inv.jobs.request_flow(flow(n.min(depth)));
+    Ok(0)
+}
+
+/// source FILE [ARG ...]
+///
+/// Run the commands in FILE inside the current shell, with ARGs as the positional parameters while it runs.
+/// Without a slash FILE is searched for in PATH, then in the current directory.
+pub fn source(inv: &mut Invocation) -> Result<i32> {
+    let file = match inv.args.get(1) {
+        Some(f) => f.clone(),
+        None => {
+            inv.error("filename argument required");
+            return Ok(2);
+        }
+    };
+
+    let data = match fs::read_to_string(find_sourced_file(inv, &file)) {
+        Ok(data) => data,
+        Err(e) => {
+            inv.error(format!("{}: {}", file.to_string_lossy(), describe_io_error(&e)));
+            return Ok(1);
+        }
+    };
+
+    let command = match Command::parse_script(&data) {
+        Ok(c) => c,
+        Err(e) => {
+            inv.error(format!("{}: {}", file.to_string_lossy(), e));
+            return Ok(2);
+        }
+    };
+
+    let saved = if inv.args.len() > 2 {
+        let params = inv.args[2..].to_vec();
+        Some(inv.ec.variables_mut().set_positional(params))
+    } else {
+        None
+    };
+
+    let status = inv.jobs.run_returnable(inv.ec, command, [inv.stdin, inv.stdout, inv.stderr]);
+
+    if let Some(params) = saved {
+        inv.ec.variables_mut().set_positional(params);
+    }
+    Ok(status?.exit_code)
+}
+
+/// The value of `$?`
+fn last_exit_code(inv: &Invocation) -> i32 {
+    inv.ec.variables().status()
+}
+
+fn find_sourced_file(inv: &Invocation, file: &OsStr) -> PathBuf {
+    if !file.as_bytes().contains(&b'/') {
+        let path = inv.ec.variables().value(&OsString::from("PATH"));
+        for dir in env::split_paths(&path) {
+            let candidate = inv.ec.cwd.join(dir).join(file);
+            if candidate.is_file() {
+                return candidate;
+            }
+        }
+    }
+    inv.ec.cwd.join(Path::new(file))
+}
+
+/// eval [ARG ...]
+///
+/// Join the arguments with spaces and run the result as shell input in the current shell
+pub fn eval(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let source = args[1..].join(" ");
+    let command = match Command::parse(&source) {
+        Ok(c) => c,
+        Err(e) => {
+            inv.error(e);
+            return Ok(2);
+        }
+    };
+    Ok(inv.jobs.run_with_fds(inv.ec, command, [inv.stdin, inv.stdout, inv.stderr])?.exit_code)
+}
+
+/// exec [COMMAND [ARG ...]]
+///
+/// Replace the shell with COMMAND. Without a command, the redirections are applied to the shell itself,
+/// fds above 2 stay open for every command after it until they're closed with `exec N>&-`.
+pub fn exec(inv: &mut Invocation) -> Result<i32> {
+    if inv.args.len() < 2 {
+        // 0 to 2 are the shell's own standard fds, anything higher is kept for the script and given to each command
+        let standard: Vec<(RawFd, RawFd)> = inv.redirections.iter().cloned().filter(|&(n, _)| n <= 2).collect();
+        redirect_shell(&standard)?;
+        // like the standard fds, everything is copied before any are closed, so `exec 4>&3 3>&-` moves fd 3
+        let mut script = Vec::new();
+        for &(n, fd) in inv.redirections.iter().filter(|&&(n, _)| n > 2) {
+            if fd < 0 {
+                script.push((n, -1));
+            } else if inv.ec.script_fd(n) != Some(fd) {
+                match copy_fd(fd) {
+                    Ok(copy) => script.push((n, copy)),
+                    Err(e) => {
+                        close_copies(&script.iter().map(|&(_, copy)| copy).collect::<Vec<_>>());
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        for (n, copy) in script {
+            if copy < 0 {
+                inv.ec.close_script_fd(n);
+            } else {
+                inv.ec.keep_script_fd(n, copy);
+            }
+        }
+        return Ok(0);
+    }
+
+    let code = match inv.ec.command_path(&inv.args[1]) {
+        Ok(path) => {
+            let mut args = Vec::with_capacity(inv.args.len() - 1);
+            for arg in &inv.args[1..] {
+                args.push(CString::new(arg.as_bytes()).context(ErrorKind::IllegalNullByte)?);
+            }
+            let exe = CString::new(path.as_os_str().as_bytes()).context(ErrorKind::IllegalNullByte)?;
+            let environ = inv.ec.environment(&[]);
+
+            let saved = save_fds(inv.redirections)?;
+            if let Err(e) = redirect_shell(inv.redirections) {
+                restore_fds(&saved)?;
+                return Err(e);
+            }
+            let err = unistd::chdir(&inv.ec.cwd).and_then(|_| unistd::execve(&exe, &args, &environ));
+
+            // still here, so put the shell's own fds back
+            restore_fds(&saved)?;
+
+            match err {
+                Err(nix::Error::Sys(errno)) => {
+                    inv.error(format!("{}: {}", inv.args[1].to_string_lossy(), errno.desc()));
+                    if errno == nix::errno::Errno::ENOENT {
+                        127
+                    } else {
+                        126
+                    }
+                }
+                _ => 126,
+            }
+        }
+        Err(_) => {
+            inv.error(format!("{}: not found", inv.args[1].to_string_lossy()));
+            127
+        }
+    };
+
+    if !inv.ec.is_interactive() {
+        inv.jobs.request_flow(Flow::Exit(code));
+    }
+    Ok(code)
+}
+
+/// Do redirections to the shell itself, each fd becomes a copy of the fd it's redirected to, or is closed.
+/// Every fd is copied before any are replaced, so redirections like `3>&1 1>&2` see the fds as they were.
+fn redirect_shell(redirections: &[(RawFd, RawFd)]) -> Result<()> {
+    let mut copies = Vec::with_capacity(redirections.len());
+    for &(_, fd) in redirections {
+        let copy = if fd < 0 { Ok(-1) } else { copy_fd(fd) };
+        match copy {
+            Ok(copy) => copies.push(copy),
+            Err(e) => {
+                close_copies(&copies);
+                return Err(e);
+            }
+        }
+    }
+
+    let mut result = Ok(());
+    for (&(target, _), &copy) in redirections.iter().zip(&copies) {
+        if copy < 0 {
+            unistd::close(target).ok();
+        } else if result.is_ok() {
+            result = unistd::dup2(copy, target).map(|_| ()).context(ErrorKind::SysError);
+        }
+    }
+    close_copies(&copies);
+    Ok(result?)
+}
+
+fn copy_fd(fd: RawFd) -> Result<RawFd> {
+    shell_fd(unistd::dup(fd).context(ErrorKind::SysError)?)
+}
+
+fn close_copies(copies: &[RawFd]) {
+    for &fd in copies.iter().filter(|&&fd| fd >= 0) {
+        unistd::close(fd).ok();
+    }
+}
+
+/// Copies of the fds the redirections would change, -1 for the ones that aren't open
+fn save_fds(redirections: &[(RawFd, RawFd)]) -> Result<Vec<(RawFd, RawFd)>> {
+    let mut saved = Vec::with_capacity(redirections.len());
+    for &(target, _) in redirections {
+        // an fd that isn't open has nothing to copy, it's closed again afterwards
+        let open = fcntl::fcntl(target, fcntl::FcntlArg::F_GETFD).is_ok();
+        saved.push((target, if open { copy_fd(target)? } else { -1 }));
+    }
+    Ok(saved)
+}
+
+/// Put back the fds `save_fds` copied
+fn restore_fds(saved: &[(RawFd, RawFd)]) -> Result<()> {
+    let result = redirect_shell(saved);
+    close_copies(&saved.iter().map(|&(_, copy)| copy).collect::<Vec<_>>());
+    result
+}
+
+/// trap [-p] [[ACTION] CONDITION ...]
+///
+/// Run ACTION when the shell receives one of the signals, exits with the EXIT condition,
+/// or is about to run a simple command with the DEBUG condition, which sets RUSH_COMMAND to the command.
+/// An empty ACTION ignores the signals and `-`, or no ACTION with one condition, gives them their default
+/// behavior back. Without conditions, or with -p, the traps are printed in a form that can be read back in.
+pub fn trap(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut print = false;
+    let mut first_operand = 1;
+    for arg in &args[1..] {
+        if arg.len() < 2 || !arg.starts_with('-') {
+            break;
+        }
+        first_operand += 1;
+        match arg.as_str() {
+            "--" => break,
+            "-p" => print = true,
+            _ => {
+                inv.error(format!("{}: invalid option", arg));
+                return Ok(2);
+            }
+        }
+    }
+    let operands = &args[first_operand..];
+
+    if operands.is_empty() || print {
+        let mut listing = String::new();
+        for (cond, action) in traps::traps() {
+            let shown = operands.is_empty() || operands.iter().any(|o| traps::parse_condition(o) == Some(cond));
+            if !shown {
+                continue;
+            }
+            let source = match action {
+                Action::Ignore => "",
+                Action::Eval(ref s) => s.as_str(),
+            };
+            listing.push_str(&format!(
+                "trap -- '{}' {}\n",
+                source.replace('\'', "'\\''"),
+                traps::condition_name(cond)
+            ));
+        }
+        inv.print(listing)?;
+        return Ok(0);
+    }
+
+    let (action, conditions) = if operands.len() == 1 {
+        (None, operands)
+    } else {
+        match operands[0].as_str() {
+            "-" => (None, &operands[1..]),
+            "" => (Some(Action::Ignore), &operands[1..]),
+            source => (Some(Action::Eval(source.to_string())), &operands[1..]),
+        }
+    };
+
+    let mut code = 0;
+    for name in conditions {
+        let cond = match traps::parse_condition(name) {
+            Some(cond) => cond,
+            None => {
+                inv.error(format!("{}: invalid signal specification", name));
+                code = 1;
+                continue;
+            }
+        };
+        let result = match action {
+            Some(ref a) => traps::trap(cond, a.clone()),
+            None => traps::release(cond),
+        };
+        if let Err(e) = result {
+            let reason = match e {
+                nix::Error::Sys(errno) => errno.desc().to_string(),
+                e => e.to_string(),
+            };
+            inv.error(format!("{}: {}", name, reason));
+            code = 1;
+        }
+    }
+    Ok(code)
+}
+
+/// set [-+bn] [-+o OPTION] [--] [ARG ...]
+///
+/// Turn shell options on with `-` and off with `+`, then make any ARGs the positional parameters.
+/// `-o` without an OPTION lists the options, `+o` lists them as `set` commands, and no arguments lists the variables.
+pub fn set(inv: &mut Invocation) -> Result<i32> {
+    if inv.args.len() == 1 {
+        let mut listing = String::new();
+        let vars = inv.ec.variables();
+        for (name, _) in vars.iter() {
+            listing.push_str(&format!(
+                "{}={}\n",
+                name.to_string_lossy(),
+                shell_quote(vars.value(name).to_string_lossy())
+            ));
+        }
+        inv.print(listing)?;
+        return Ok(0);
+    }
+
+    let mut args = inv.args[1..].iter();
+    let mut positional = None;
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            positional = Some(args.cloned().collect());
+            break;
+        }
+        let flags = arg.to_string_lossy();
+        if flags.len() < 2 || !(flags.starts_with('-') || flags.starts_with('+')) {
+            let mut params = vec![arg.clone()];
+            params.extend(args.cloned());
+            positional = Some(params);
+            break;
+        }
+
+        let on = flags.starts_with('-');
+        for c in flags[1..].chars() {
+            let name = if c == 'o' {
+                match args.next() {
+                    Some(name) => name.to_string_lossy().into_owned(),
+                    None => {
+                        list_options(inv, on)?;
+                        continue;
+                    }
+                }
+            } else {
+                match Options::flag_name(c) {
+                    Some(name) => name.to_string(),
+                    None => {
+                        inv.error(format!("{}{}: invalid option", if on { '-' } else { '+' }, c));
+                        return Ok(2);
+                    }
+                }
+            };
+
+            if name == "noexec" && on && inv.ec.is_interactive() {
+                inv.error("-n: an interactive shell can't stop running commands");
+                return Ok(1);
+            }
+            if !inv.ec.options_mut().set(&name, on) {
+                inv.error(format!("{}: invalid option name", name));
+                return Ok(2);
+            }
+        }
+    }
+
+    if let Some(params) = positional {
+        inv.ec.variables_mut().set_positional(params);
+    }
+    Ok(0)
+}
+
+/// Print every option for `set -o`, or as the commands that would restore them for `set +o`
+fn list_options(inv: &Invocation, readable: bool) -> Result<()> {
+    let mut listing = String::new();
+    for (name, on) in inv.ec.options().all() {
+        if readable {
+            listing.push_str(&format!("{:<15}\t{}\n", name, if on { "on" } else { "off" }));
+        } else {
+            listing.push_str(&format!("set {}o {}\n", if on { '-' } else { '+' }, name));
+        }
+    }
+    inv.print(listing)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::ast::Command;
+    use crate::lang::testing::{run, run_with_bytes, scratch_dir, with_shell};
+
+    #[test]
+    fn true_false_and_colon() {
+        assert_eq!(run("true").status, 0);
+        assert_eq!(run("false").status, 1);
+        assert_eq!(run(": ignored arguments").status, 0);
+        assert_eq!(run("PATH=; true && : && false || echo found").stdout, "found\n");
+    }
+
+    #[test]
+    fn colon_still_expands_its_arguments() {
+        let out = run(": ${VAR:=default} $((n = 2)); echo $VAR $n");
+        assert_eq!(out.stdout, "default 2\n");
+    }
+
+    #[test]
+    fn break_and_continue_the_innermost_loop() {
+        let out = run("for ((i = 0; i < 5; i++)); do [ $i -eq 1 ] && continue; [ $i -eq 3 ] && break; echo $i; done");
+        assert_eq!(out.stdout, "0\n2\n");
+        // inside a compound command inside the loop
+        let out = run("for ((i = 0; i < 5; i++)); do { echo $i; [ $i -eq 1 ] && { break; echo unreachable; }; }; done");
+        assert_eq!(out.stdout, "0\n1\n");
+    }
+
+    #[test]
+    fn break_and_continue_enclosing_loops() {
+        let script = "for ((i = 0; i < 3; i++)); do\n\
+                      for ((j = 0; j < 3; j++)); do\n\
+                      [ $j -eq 1 ] && continue 2\n\
+                      echo $i$j\n\
+                      done\n\
+                      echo unreachable\n\
+                      done";
+        assert_eq!(run(script).stdout, "00\n10\n20\n");
+        let out = run("for ((i = 0; i < 3; i++)); do for ((j = 0; j < 3; j++)); do echo $i$j; break 2; done; done");
+        assert_eq!(out.stdout, "00\n");
+        // more levels than there are loops leaves all of them
+        let out = run("for ((i = 0; i < 3; i++)); do for ((;;)); do break 10; done; echo unreachable; done; echo $i");
+        assert_eq!(out.stdout, "0\n");
+    }
+
+    #[test]
+    fn loop_control_outside_a_loop() {
+        let out = run("break; echo $?; continue; echo $?");
+        assert_eq!(out.stdout, "0\n0\n");
+        assert!(out.stderr.contains("break: only meaningful in a `for', `while', or `until' loop"));
+        let out = run("for ((i = 0; i < 1; i++)); do break 0; done; echo $?");
+        assert_eq!(out.stdout, "1\n");
+        assert!(out.stderr.contains("break: 0: loop count out of range"));
+    }
+
+    #[test]
+    fn test_and_bracket() {
+        let out = run("touch file; [ -f file ] && test -n \"$HOME\" && [ ! -d file ]; echo $?");
+        assert_eq!(out.stdout, "0\n");
+        assert_eq!(run("test 1 -gt 2").status, 1);
+        assert_eq!(run("[ ]").status, 1);
+    }
+
+    #[test]
+    fn test_compares_and_looks_up_the_bytes_it_was_given() {
+        let script = "touch \"$BYTES\"; test \"$BYTES\" = \"$BYTES\" && [ \"$BYTES\" != caf ] && test -f \"$BYTES\"; echo $?";
+        assert_eq!(run_with_bytes(b"caf\xe9", script), b"0\n");
+        let script = "set -- \"$BYTES\" two; echo \"$1\"; . /dev/null \"$BYTES\"; echo \"$1\"";
+        assert_eq!(run_with_bytes(b"\xff", script), b"\xff\n\xff\n");
+    }
+
+    #[test]
+    fn test_errors_are_status_2() {
+        let out = run("[ 1 -eq 1; echo $?; test x -lt 1; echo $?");
+        assert_eq!(out.stdout, "2\n2\n");
+        assert!(out.stderr.contains("rush: [: missing `]'"), "{}", out.stderr);
+        assert!(out.stderr.contains("rush: test: x: integer expression expected"), "{}", out.stderr);
+    }
+
+    #[test]
+    fn source_uses_its_redirections() {
+        let out = run("echo 'echo sourced; echo oops >&2; X=1' >lib.sh\n. ./lib.sh >out 2>/dev/null; echo $X; cat out");
+        assert_eq!(out.stdout, "1\nsourced\n");
+        assert_eq!(out.stderr, "");
+    }
+
+    #[test]
+    fn source_runs_nothing_from_a_file_with_a_syntax_error() {
+        let out = run("printf 'FIRST=1\\nif true; then echo no; fi\\n' >bad.sh\nsource ./bad.sh; echo $? ${FIRST-unset}");
+        assert_eq!(out.stdout, "2 unset\n");
+        assert_eq!(out.stderr, "rush: source: ./bad.sh: line 2: syntax error near `if'\n");
+    }
+
+    #[test]
+    fn exec_redirects_the_shell() {
+        let out = run("( exec 2>log; echo to-log >&2; ls /nonexistent-rush-test 2>/dev/null; echo still-log >&2 )\ncat log");
+        assert_eq!(out.stdout, "to-log\nstill-log\n");
+        let out = run("( exec 3>three 1>&3 3>&-; echo moved; echo closed >&3 )\ncat three");
+        assert_eq!(out.stdout, "moved\n");
+        let out = run("echo input >in\n( exec <in; read line; echo \"[$line]\" )");
+        assert_eq!(out.stdout, "[input]\n");
+    }
+
+    #[test]
+    fn exec_keeps_higher_fds_for_the_script() {
+        let out = run("exec 3>f; echo x >&3; sh -c 'echo y >&3'; { echo z >&3; }; exec 3>&-; cat f");
+        assert_eq!(out.stdout, "x\ny\nz\n");
+        let out = run("exec 4>g; exec 5>&4 4>&-; echo copied >&5; echo inner 5>h >&5; cat g h");
+        assert_eq!(out.stdout, "copied\ninner\n");
+    }
+
+    #[test]
+    fn script_fds_are_the_shells_own() {
+        let dir = scratch_dir();
+        let kept = with_shell(|ec, jobs| {
+            ec.set_cwd(dir.to_path_buf()).unwrap();
+            jobs.run(ec, Command::from("exec 3>f")).unwrap();
+            let opened = ec.script_fd(3);
+            jobs.run(ec, Command::from("exec 3>&-")).unwrap();
+            (opened, ec.script_fd(3))
+        });
+        assert!(kept.0.unwrap() >= 10, "{:?}", kept);
+        assert_eq!(kept.1, None);
+    }
+
+    #[test]
+    fn exec_passes_exported_variables() {
+        let out = run("( export FOO=exported; BAR=local; exec sh -c 'echo \"$FOO ${BAR-unset}\"' )");
+        assert_eq!(out.stdout, "exported unset\n");
+        let out = run("( exec sh -c 'echo error >&2' 2>log ); cat log");
+        assert_eq!(out.stdout, "error\n");
+    }
+
+    #[test]
+    fn exec_failures_exit_a_script() {
+        let out = run("( exec /nonexistent/program >out; echo unreachable ); echo $?; cat out");
+        assert_eq!(out.stdout, "127\n");
+        assert!(out.stderr.contains("/nonexistent/program"));
+        assert_eq!(run("exec /nonexistent/program; echo unreachable").status, 127);
+    }
+
+    #[test]
+    fn eval_runs_its_arguments_as_shell_input() {
+        let out = run("eval 'echo a;' echo b");
+        assert_eq!(out.stdout, "a\nb\n");
+        assert_eq!(run("eval false").status, 1);
+        assert_eq!(run("eval").status, 0);
+    }
+
+    #[test]
+    fn eval_syntax_errors_are_status_2() {
+        for source in &["if", "foo |", "do", "fi"] {
+            let out = run(&format!("eval '{}'; echo $?", source));
+            assert_eq!(out.stdout, "2\n", "eval '{}'", source);
+            assert!(out.stderr.starts_with("rush: eval: syntax error"), "eval '{}': {}", source, out.stderr);
+        }
+    }
+
+    #[test]
+    fn noexec_stops_running_commands_but_still_reads_them() {
+        let out = run("echo a; set -n; echo b; { echo c; } | cat\nfor ((i = 0; i < 2; i++)); do echo $i; done\nset +n; echo d");
+        assert_eq!((out.status, out.stdout.as_str()), (0, "a\n"));
+    }
+}
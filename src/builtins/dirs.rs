@@ -0,0 +1,440 @@
+use crate::builtins::{describe_io_error, Definition, Invocation};
+use crate::lang::{ExecutionContext, Result};
+use nix::libc;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+
+/// Builtins for the working directory and the directory stack
+pub const BUILTINS: &[Definition] = &[
+    Definition {
+        name: "cd",
+        run: cd,
+        usage: "cd [DIR]",
+        summary: "Change the working directory",
+    },
+    Definition {
+        name: "dirs",
+        run: dirs,
+        usage: "dirs [-clpv] [+N | -N]",
+        summary: "Print the directory stack",
+    },
+    Definition {
+        name: "popd",
+        run: popd,
+        usage: "popd [+N | -N]",
+        summary: "Remove a directory from the directory stack",
+    },
+    Definition {
+        name: "pushd",
+        run: pushd,
+        usage: "pushd [DIR | +N | -N]",
+        summary: "Add a directory to the directory stack",
+    },
+    Definition {
+        name: "pwd",
+        run: pwd,
+        usage: "pwd [-LP]",
+        summary: "Print the working directory",
+    },
+];
+
+/// cd [DIR]
+///
+/// Change the shell's working directory, `cd -` goes back to $OLDPWD
+pub fn cd(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let (target, announce) = match args.get(1).map(|s| s.as_str()) {
+        None => match non_empty_var(inv.ec, "HOME") {
+            Some(home) => (PathBuf::from(home), false),
+            None => {
+                inv.error("HOME not set");
+                return Ok(1);
+            }
+        },
+        Some("-") => match non_empty_var(inv.ec, "OLDPWD") {
+            Some(old) => (PathBuf::from(old), true),
+            None => {
+                inv.error("OLDPWD not set");
+                return Ok(1);
+            }
+        },
+        Some(dir) => (PathBuf::from(dir), false),
+    };
+
+    let searched = match args.get(1) {
+        Some(dir) if dir != "-" => search_cdpath(inv.ec, &target),
+        _ => None,
+    };
+    let (resolved, announce) = match searched {
+        Some((found, from_cdpath)) => (found, from_cdpath),
+        None => (logical_join(&inv.ec.cwd, &target), announce),
+    };
+    if let Err(e) = enterable(&resolved) {
+        inv.error(format!("{}: {}", target.display(), describe_io_error(&e)));
+        return Ok(1);
+    }
+
+    if !change_dir(inv, resolved) {
+        return Ok(1);
+    }
+    if announce {
+        inv.print(format!("{}\n", inv.ec.cwd.display()))?;
+    }
+    Ok(0)
+}
+
+/// pwd [-LP]
+///
+/// Print the working directory, with -P any symlinks in it are resolved
+pub fn pwd(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut physical = false;
+    for arg in &args[1..] {
+        match arg.as_str() {
+            "-L" => physical = false,
+            "-P" => physical = true,
+            _ => {
+                inv.error(format!("{}: invalid option", arg));
+                return Ok(2);
+            }
+        }
+    }
+
+    let dir = if physical {
+        match fs::canonicalize(&inv.ec.cwd) {
+            Ok(dir) => dir,
+            Err(e) => {
+                inv.error(format!("{}: {}", inv.ec.cwd.display(), describe_io_error(&e)));
+                return Ok(1);
+            }
+        }
+    } else {
+        inv.ec.cwd.clone()
+    };
+
+    let mut line = dir.into_os_string().into_vec();
+    line.push(b'\n');
+    inv.print(line)?;
+    Ok(0)
+}
+
+/// pushd [DIR | +N | -N]
+///
+/// Save the working directory on the directory stack and change to DIR. Without arguments the top two
+/// directories are swapped, and +N or -N rotates the stack so the N'th directory, counted from the
+/// left or right of the `dirs` listing, is on top.
+pub fn pushd(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut full = vec![inv.ec.cwd.clone()];
+    full.extend(inv.ec.dir_stack.iter().cloned());
+
+    let rotated = match args.get(1) {
+        None => {
+            if full.len() < 2 {
+                inv.error("no other directory");
+                return Ok(1);
+            }
+            full.swap(0, 1);
+            full
+        }
+        Some(arg) if is_stack_index(arg) => match stack_index(arg, full.len()) {
+            Some(n) => {
+                full.rotate_left(n);
+                full
+            }
+            None => {
+                inv.error(format!("{}: directory stack index out of range", arg));
+                return Ok(1);
+            }
+        },
+        Some(dir) => {
+            let resolved = logical_join(&inv.ec.cwd, Path::new(dir));
+            if let Err(e) = enterable(&resolved) {
+                inv.error(format!("{}: {}", dir, describe_io_error(&e)));
+                return Ok(1);
+            }
+            full.insert(0, resolved);
+            full
+        }
+    };
+
+    if !enter_stack_top(inv, rotated) {
+        return Ok(1);
+    }
+    print_stack(inv, false, false, false)?;
+    Ok(0)
+}
+
+/// popd [+N | -N]
+///
+/// Remove the top directory from the directory stack and change to the new top,
+/// or with +N or -N remove the N'th directory, counted from the left or right of the `dirs` listing
+pub fn popd(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    if inv.ec.dir_stack.is_empty() {
+        inv.error("directory stack empty");
+        return Ok(1);
+    }
+
+    let mut full = vec![inv.ec.cwd.clone()];
+    full.extend(inv.ec.dir_stack.iter().cloned());
+
+    let n = match args.get(1) {
+        None => 0,
+        Some(arg) if is_stack_index(arg) => match stack_index(arg, full.len()) {
+            Some(n) => n,
+            None => {
+                inv.error(format!("{}: directory stack index out of range", arg));
+                return Ok(1);
+            }
+        },
+        Some(arg) => {
+            inv.error(format!("{}: invalid argument", arg));
+            return Ok(2);
+        }
+    };
+
+    full.remove(n);
+    if n == 0 {
+        if !enter_stack_top(inv, full) {
+            return Ok(1);
+        }
+    } else {
+        inv.ec.dir_stack = full.split_off(1);
+    }
+    print_stack(inv, false, false, false)?;
+    Ok(0)
+}
+
+/// dirs [-clpv] [+N | -N]
+///
+/// Print the directory stack, starting with the working directory. -c clears it, -l doesn't shorten
+/// $HOME to `~`, -p prints one directory per line, and -v numbers them. +N or -N prints just the N'th one.
+pub fn dirs(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut long = false;
+    let mut per_line = false;
+    let mut numbered = false;
+    let mut index = None;
+    for arg in &args[1..] {
+        if is_stack_index(arg) {
+            index = Some(arg.clone());
+            continue;
+        }
+        if arg.len() < 2 || !arg.starts_with('-') {
+            inv.error(format!("{}: invalid argument", arg));
+            return Ok(2);
+        }
+        for c in arg[1..].chars() {
+            match c {
+                'c' => {
+                    inv.ec.dir_stack.clear();
+                    return Ok(0);
+                }
+                'l' => long = true,
+                'p' => per_line = true,
+                'v' => {
+                    per_line = true;
+                    numbered = true;
+                }
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+
+    match index {
+        Some(arg) => {
+            let len = inv.ec.dir_stack.len() + 1;
+            match stack_index(&arg, len) {
+                Some(0) => inv.print(format!("{}\n", display_dir(inv.ec, &inv.ec.cwd, long)))?,
+                Some(n) => inv.print(format!("{}\n", display_dir(inv.ec, &inv.ec.dir_stack[n - 1], long)))?,
+                None => {
+                    inv.error(format!("{}: directory stack index out of range", arg));
+                    return Ok(1);
+                }
+            }
+        }
+        None => print_stack(inv, long, per_line, numbered)?,
+    }
+    Ok(0)
+}
+
+/// Whether an argument is a `+N` or `-N` directory stack index
+fn is_stack_index(arg: &str) -> bool {
+    arg.len() > 1 && (arg.starts_with('+') || arg.starts_with('-')) && arg[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Turn `+N` or `-N` into an index from the left of a stack with `len` entries
+fn stack_index(arg: &str, len: usize) -> Option<usize> {
+    let n = arg[1..].parse::<usize>().ok()?;
+    if n >= len {
+        None
+    } else if arg.starts_with('+') {
+        Some(n)
+    } else {
+        Some(len - 1 - n)
+    }
+}
+
+/// Change to the first directory in a full stack and keep the rest as the saved directories.
+/// Nothing changes if the directory can't be entered.
+fn enter_stack_top(inv: &mut Invocation, mut full: Vec<PathBuf>) -> bool {
+    let saved = full.split_off(1);
+    let top = full.pop().unwrap();
+    if let Err(e) = enterable(&top) {
+        inv.error(format!("{}: {}", top.display(), describe_io_error(&e)));
+        return false;
+    }
+    let changed = change_dir(inv, top);
+    inv.ec.dir_stack = saved;
+    changed
+}
+
+fn print_stack(inv: &Invocation, long: bool, per_line: bool, numbered: bool) -> Result<()> {
+    let mut out = String::new();
+    let entries = Some(&inv.ec.cwd).into_iter().chain(inv.ec.dir_stack.iter());
+    for (i, dir) in entries.enumerate() {
+        let shown = display_dir(inv.ec, dir, long);
+        if numbered {
+            out.push_str(&format!("{:2}  {}\n", i, shown));
+        } else if per_line {
+            out.push_str(&format!("{}\n", shown));
+        } else {
+            if i > 0 {
+                out.push(' ');
+            }
+            out.push_str(&shown);
+        }
+    }
+    if !per_line {
+        out.push('\n');
+    }
+    inv.print(out)
+}
+
+/// Show a directory the way `dirs` does, with $HOME shortened to `~` unless `long` is set
+fn display_dir(ec: &ExecutionContext, dir: &Path, long: bool) -> String {
+    if !long {
+        if let Some(home) = non_empty_var(ec, "HOME") {
+            if let Ok(rest) = dir.strip_prefix(&home) {
+                if rest.as_os_str().is_empty() {
+                    return "~".to_string();
+                }
+                return format!("~/{}", rest.display());
+            }
+        }
+    }
+    dir.display().to_string()
+}
+
+/// Move the execution context to a new directory, keeping $PWD and $OLDPWD up to date.
+/// The directory changes even if one of them is readonly, but then an error is printed and `false` is returned.
+fn change_dir(inv: &mut Invocation, dir: PathBuf) -> bool {
+    match inv.ec.set_cwd(dir) {
+        Ok(()) => true,
+        Err(e) => {
+            inv.error(e);
+            false
+        }
+    }
+}
+
+fn non_empty_var(ec: &ExecutionContext, name: &str) -> Option<OsString> {
+    let value = ec.variables().value(&OsString::from(name));
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// Look for a relative directory in each of the directories in $CDPATH, an empty entry is the working directory.
+/// Targets that are absolute or start with `.` or `..` aren't searched for.
+/// Gives the directory found, and whether it should be printed because it wasn't found through `.`
+fn search_cdpath(ec: &ExecutionContext, target: &Path) -> Option<(PathBuf, bool)> {
+    let cdpath = non_empty_var(ec, "CDPATH")?;
+    match target.components().next() {
+        Some(Component::Normal(_)) => (),
+        _ => return None,
+    }
+
+    for entry in cdpath.as_bytes().split(|b| *b == b':') {
+        let entry = Path::new(OsStr::from_bytes(entry));
+        let relative_to_cwd = entry.as_os_str().is_empty() || entry == Path::new(".");
+        let candidate = logical_join(&ec.cwd, &entry.join(target));
+        if enterable(&candidate).is_ok() {
+            return Some((candidate, !relative_to_cwd));
+        }
+    }
+    None
+}
+
+/// Join `target` onto `cwd`, resolving "." and ".." lexically so symlinks in the path are preserved
+fn logical_join(cwd: &Path, target: &Path) -> PathBuf {
+    let mut joined = PathBuf::new();
+    for component in cwd.join(target).components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                joined.pop();
+            }
+            c => joined.push(c.as_os_str()),
+        }
+    }
+    joined
+}
+
+/// Check that `dir` is a directory the shell has permission to search
+fn enterable(dir: &Path) -> io::Result<()> {
+    if !fs::metadata(dir)?.is_dir() {
+        return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+    }
+
+    let c_dir = CString::new(dir.as_os_str().as_bytes())?;
+    if unsafe { libc::access(c_dir.as_ptr(), libc::X_OK) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::testing::{run_in, scratch_dir};
+
+    #[test]
+    fn cdpath_is_searched_and_the_match_printed() {
+        let dir = scratch_dir();
+        let out = run_in(&dir, "mkdir -p projects/repo; CDPATH=/nowhere:projects; cd repo; pwd");
+        let expected = format!("{}/projects/repo\n", dir.display());
+        assert_eq!(out.stdout, format!("{}{}", expected, expected));
+    }
+
+    #[test]
+    fn empty_cdpath_entries_are_the_working_directory() {
+        let dir = scratch_dir();
+        let out = run_in(&dir, "mkdir -p here projects/here; CDPATH=:projects; cd here; pwd");
+        assert_eq!(out.stdout, format!("{}/here\n", dir.display()));
+        let out = run_in(&dir, "CDPATH=.:projects; cd here; pwd");
+        assert_eq!(out.stdout, format!("{}/here\n", dir.display()));
+    }
+
+    #[test]
+    fn dot_relative_and_absolute_targets_skip_cdpath() {
+        let dir = scratch_dir();
+        let out = run_in(&dir, "mkdir -p projects/repo; CDPATH=projects; cd ./repo; echo $?; cd /; pwd");
+        assert_eq!(out.stdout, "1\n/\n");
+    }
+
+    #[test]
+    fn cd_through_cdpath_sets_oldpwd() {
+        let dir = scratch_dir();
+        let out = run_in(&dir, "mkdir -p projects/repo; CDPATH=projects; cd repo >/dev/null; echo $OLDPWD; cd -");
+        assert_eq!(out.stdout, format!("{}\n{}\n", dir.display(), dir.display()));
+    }
+}
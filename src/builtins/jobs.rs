@@ -0,0 +1,562 @@
+use crate::builtins::{Definition, Invocation};
+use crate::env::traps::{self, parse_signal, signal_description, signal_name, Signal};
+use crate::lang::{ErrorKind, ExitStatus, JobManager, JobStatus, Result};
+use nix::sys::signal;
+use nix::unistd::Pid;
+
+/// Job control builtins
+pub const BUILTINS: &[Definition] = &[
+    Definition {
+        name: "bg",
+        run: bg,
+        usage: "bg [JOB]",
+        summary: "Continue a stopped job in the background",
+    },
+    Definition {
+        name: "disown",
+        run: disown,
+        usage: "disown [-ah] [JOB ...]",
+        summary: "Remove jobs from the job table",
+    },
+    Definition {
+        name: "fg",
+        run: fg,
+        usage: "fg [JOB]",
+        summary: "Move a job to the foreground",
+    },
+    Definition {
+        name: "jobs",
+        run: jobs,
+        usage: "jobs [-lp] [JOB ...]",
+        summary: "List the background jobs",
+    },
+    Definition {
+        name: "kill",
+        run: kill,
+        usage: "kill [-s SIGNAL | -n NUMBER | -SIGNAL] PID | JOB ...",
+        summary: "Send a signal to processes or jobs",
+    },
+    Definition {
+        name: "wait",
+        run: wait,
+        usage: "wait [-n | JOB | PID ...]",
+        summary: "Wait for jobs or processes to finish",
+    },
+];
+
+/// jobs [-lp] [JOB ...]
+///
+/// List the background jobs, finished jobs are forgotten once they've been listed
+pub fn jobs(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut long = false;
+    let mut pids_only = false;
+    let mut selected = Vec::new();
+    for arg in &args[1..] {
+        if arg.len() < 2 || !arg.starts_with('-') {
+            match parse_job_spec(inv, arg) {
+                Ok(id) => selected.push(id),
+                Err(e) => {
+                    inv.error(e);
+                    return Ok(1);
+                }
+            }
+            continue;
+        }
+        for c in arg[1..].chars() {
+            match c {
+                'l' => long = true,
+                'p' => pids_only = true,
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+
+    inv.jobs.reap()?;
+
+    let current = inv.jobs.current_job();
+    let previous = inv.jobs.previous_job();
+    let mut listing = String::new();
+    let mut finished = Vec::new();
+    for job in inv.jobs.jobs() {
+        if !selected.is_empty() && !selected.contains(&job.id) {
+            continue;
+        }
+
+        let status = inv.jobs.job_status(job);
+        if let JobStatus::Complete(_) = status {
+            finished.push(job.id);
+        }
+
+        if pids_only {
+            if let Some(pid) = job.pids.first() {
+                listing.push_str(&format!("{}\n", pid));
+            }
+            continue;
+        }
+
+        let marker = job_marker(job.id, current, previous);
+        let (state, suffix) = describe_status(&status);
+
+        if long {
+            let pids: Vec<String> = job.pids.iter().map(|p| p.to_string()).collect();
+            listing.push_str(&format!(
+                "[{}]{} {} {:<22}{}{}\n",
+                job.id,
+                marker,
+                pids.join(" "),
+                state,
+                job.text,
+                suffix
+            ));
+        } else {
+            listing.push_str(&format!("[{}]{}  {:<24}{}{}\n", job.id, marker, state, job.text, suffix));
+        }
+    }
+
+    inv.print(listing)?;
+    for id in finished {
+        inv.jobs.remove_job(id);
+    }
+    Ok(0)
+}
+
+/// Collect the background jobs that finished since they were last checked and stop tracking them,
+/// giving the lines that announce them, like `[1]+  Done                    sleep 5`.
+/// It's only meant to be printed between commands, when it can't land in the middle of a line being typed.
+pub fn notify_completed(jobs: &mut JobManager) -> Result<String> {
+    jobs.reap()?;
+
+    let current = jobs.current_job();
+    let previous = jobs.previous_job();
+    let mut notices = String::new();
+    let mut finished = Vec::new();
+    for job in jobs.jobs() {
+        let status = jobs.job_status(job);
+        if let JobStatus::Complete(_) = status {
+            let (state, _) = describe_status(&status);
+            notices.push_str(&format!(
+                "[{}]{}  {:<24}{}\n",
+                job.id,
+                job_marker(job.id, current, previous),
+                state,
+                job.text
+            ));
+            finished.push(job.id);
+        }
+    }
+
+    for id in finished {
+        jobs.remove_job(id);
+    }
+    Ok(notices)
+}
+
+/// How `jobs` marks the current job and the previous one
+fn job_marker(id: usize, current: Option<usize>, previous: Option<usize>) -> char {
+    if Some(id) == current {
+        '+'
+    } else if Some(id) == previous {
+        '-'
+    } else {
+        ' '
+    }
+}
+
+/// The state `jobs` shows for a job, and what to put after its command
+fn describe_status(status: &JobStatus) -> (String, &'static str) {
+    match *status {
+        JobStatus::Running => ("Running".to_string(), " &"),
+        JobStatus::Stopped => ("Stopped".to_string(), ""),
+        JobStatus::Complete(ref s) if s.exit_code == 0 => ("Done".to_string(), ""),
+        JobStatus::Complete(ExitStatus {
+            signal: Some(sig),
+            core_dumped,
+            ..
+        }) => (
+            if core_dumped {
+                format!("{} (core dumped)", signal_description(sig))
+            } else {
+                signal_description(sig).to_string()
+            },
+            "",
+        ),
+        JobStatus::Complete(ref s) => (format!("Exit {}", s.exit_code), ""),
+    }
+}
+
+/// fg [JOB]
+///
+/// Move a job to the foreground and wait for it, the status is the job's status
+pub fn fg(inv: &mut Invocation) -> Result<i32> {
+    let id = match job_argument(inv) {
+        Some(id) => id,
+        None => return Ok(1),
+    };
+
+    let text = inv.jobs.job(id).map(|job| format!("{}\n", job.text));
+    if let Some(text) = text {
+        inv.print(text)?;
+    }
+    Ok(inv.jobs.foreground(id)?.exit_code)
+}
+
+/// bg [JOB]
+///
+/// Continue a stopped job in the background
+pub fn bg(inv: &mut Invocation) -> Result<i32> {
+    let id = match job_argument(inv) {
+        Some(id) => id,
+        None => return Ok(1),
+    };
+
+    inv.jobs.reap()?;
+    let text = match inv.jobs.job(id) {
+        Some(job) => match inv.jobs.job_status(job) {
+            JobStatus::Stopped => job.text.clone(),
+            _ => {
+                inv.error(format!("job {} already in background", id));
+                return Ok(1);
+            }
+        },
+        None => return Ok(1),
+    };
+
+    inv.jobs.resume_background(id)?;
+    inv.print(format!("[{}]+ {} &\n", id, text))?;
+    Ok(0)
+}
+
+/// wait [-n | JOB | PID ...]
+///
+/// Wait for the given jobs or processes, or every background job, to finish.
+/// The status is that of the last one waited for, or 127 if it wasn't one of this shell's.
+/// With -n it's whichever job finishes next, and 127 straight away if there are no jobs.
+/// A signal with a trap stops the wait straight away, the status is then 128 plus the signal's number.
+pub fn wait(inv: &mut Invocation) -> Result<i32> {
+    traps::interrupt_waits(true);
+    let result = wait_for_operands(inv);
+    traps::interrupt_waits(false);
+    match result {
+        Err(e) => match *e.kind() {
+            ErrorKind::WaitInterrupted(sig) => Ok(128 + sig as i32),
+            _ => Err(e),
+        },
+        waited => waited,
+    }
+}
+
+fn wait_for_operands(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    if args.get(1).map(|arg| arg == "-n").unwrap_or(false) {
+        if args.len() > 2 {
+            inv.error("-n: too many arguments");
+            return Ok(2);
+        }
+        return match inv.jobs.wait_any_job()? {
+            Some((_, status)) => Ok(status.exit_code),
+            None => Ok(127),
+        };
+    }
+
+    if args.len() < 2 {
+        let ids: Vec<usize> = inv.jobs.jobs().iter().map(|job| job.id).collect();
+        for id in ids {
+            inv.jobs.wait_job(id)?;
+        }
+        return Ok(0);
+    }
+
+    let mut code = 0;
+    for arg in &args[1..] {
+        code = if arg.starts_with('%') {
+            match parse_job_spec(inv, arg) {
+                Ok(id) => inv.jobs.wait_job(id)?.exit_code,
+                Err(e) => {
+                    inv.error(e);
+                    127
+                }
+            }
+        } else {
+            match arg.parse::<i32>() {
+                Ok(pid) => match inv.jobs.wait_pid(Pid::from_raw(pid)) {
+                    Some(status) => status?.exit_code,
+                    None => {
+                        inv.error(format!("pid {} is not a child of this shell", pid));
+                        127
+                    }
+                },
+                Err(_) => {
+                    inv.error(format!("{}: not a pid or valid job spec", arg));
+                    2
+                }
+            }
+        };
+    }
+    Ok(code)
+}
+
+/// kill [-s SIGNAL | -n NUMBER | -SIGNAL] PID | JOB ...
+/// kill -l [SIGNAL | STATUS ...]
+///
+/// Send a signal, SIGTERM by default, to processes or jobs. Stopped jobs are continued after SIGTERM and SIGHUP
+/// so they can act on it.
+pub fn kill(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut sig = Signal::SIGTERM;
+    let mut args = args[1..].iter().peekable();
+
+    match args.peek().map(|s| s.as_str()) {
+        Some("-l") | Some("-L") => {
+            args.next();
+            return list_signals(inv, args.cloned().collect());
+        }
+        Some("-s") | Some("-n") => {
+            args.next();
+            let name = match args.next() {
+                Some(name) => name,
+                None => {
+                    inv.error("option requires an argument");
+                    return Ok(2);
+                }
+            };
+            sig = match parse_signal(name) {
+                Some(sig) => sig,
+                None => {
+                    inv.error(format!("{}: invalid signal specification", name));
+                    return Ok(1);
+                }
+            };
+        }
+        Some("--") => {
+            args.next();
+        }
+        Some(opt) if opt.len() > 1 && opt.starts_with('-') => {
+            args.next();
+            sig = match parse_signal(&opt[1..]) {
+                Some(sig) => sig,
+                None => {
+                    inv.error(format!("{}: invalid signal specification", &opt[1..]));
+                    return Ok(1);
+                }
+            };
+            if args.peek().map(|s| s.as_str()) == Some("--") {
+                args.next();
+            }
+        }
+        _ => (),
+    }
+
+    let targets: Vec<String> = args.cloned().collect();
+    if targets.is_empty() {
+        inv.error("usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ... or kill -l [sigspec]");
+        return Ok(2);
+    }
+
+    inv.jobs.reap()?;
+    let mut code = 0;
+    for target in &targets {
+        let (pids, stopped) = if target.starts_with('%') {
+            match parse_job_spec(inv, target) {
+                Ok(id) => {
+                    let job = inv.jobs.job(id).unwrap();
+                    (job.signal_targets(), inv.jobs.job_status(job) == JobStatus::Stopped)
+                }
+                Err(e) => {
+                    inv.error(e);
+                    code = 1;
+                    continue;
+                }
+            }
+        } else {
+            match target.parse::<i32>() {
+                Ok(pid) => (vec![Pid::from_raw(pid)], false),
+                Err(_) => {
+                    inv.error(format!("{}: arguments must be process or job IDs", target));
+                    code = 1;
+                    continue;
+                }
+            }
+        };
+
+        for pid in pids {
+            let mut result = signal::kill(pid, sig);
+            if result.is_ok() && stopped && (sig == Signal::SIGTERM || sig == Signal::SIGHUP) {
+                result = signal::kill(pid, Signal::SIGCONT);
+            }
+
+            if let Err(e) = result {
+                let reason = match e {
+                    nix::Error::Sys(errno) => errno.desc().to_string(),
+                    e => e.to_string(),
+                };
+                inv.error(format!("({}) - {}", pid, reason));
+                code = 1;
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// disown [-ah] [JOB ...]
+///
+/// Forget jobs, the current one by default, so they aren't waited for or sent SIGHUP when the shell exits.
+/// -a forgets every job and -h keeps the jobs but stops them from being sent SIGHUP.
+pub fn disown(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut all = false;
+    let mut keep = false;
+    let mut specs = Vec::new();
+    for arg in &args[1..] {
+        if arg.len() < 2 || !arg.starts_with('-') || !specs.is_empty() {
+            specs.push(arg.clone());
+            continue;
+        }
+        for c in arg[1..].chars() {
+            match c {
+                'a' => all = true,
+                'h' => keep = true,
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+
+    let mut code = 0;
+    let ids: Vec<usize> = if all {
+        inv.jobs.jobs().iter().map(|job| job.id).collect()
+    } else if specs.is_empty() {
+        match inv.jobs.current_job() {
+            Some(id) => vec![id],
+            None => {
+                inv.error("current: no such job");
+                return Ok(1);
+            }
+        }
+    } else {
+        let mut ids = Vec::new();
+        for spec in &specs {
+            match parse_job_spec(inv, spec) {
+                Ok(id) => ids.push(id),
+                Err(e) => {
+                    inv.error(e);
+                    code = 1;
+                }
+            }
+        }
+        ids
+    };
+
+    for id in ids {
+        if keep {
+            if let Some(job) = inv.jobs.job_mut(id) {
+                job.hangup = false;
+            }
+        } else {
+            inv.jobs.remove_job(id);
+        }
+    }
+    Ok(code)
+}
+
+/// Print the names of all the signals, or the names of some signal numbers or exit statuses
+fn list_signals(inv: &mut Invocation, args: Vec<String>) -> Result<i32> {
+    if args.is_empty() {
+        let names: Vec<String> = Signal::iterator().map(signal_name).collect();
+        inv.print(format!("{}\n", names.join(" ")))?;
+        return Ok(0);
+    }
+
+    let mut code = 0;
+    for arg in args {
+        // exit statuses of signaled processes are 128 + the signal number
+        let described = match arg.parse::<i32>() {
+            Ok(n) => Signal::from_c_int(if n > 128 { n - 128 } else { n })
+                .ok()
+                .map(signal_name),
+            Err(_) => parse_signal(&arg).map(|sig| (sig as i32).to_string()),
+        };
+        match described {
+            Some(d) => inv.print(format!("{}\n", d))?,
+            None => {
+                inv.error(format!("{}: invalid signal specification", arg));
+                code = 1;
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// Get the job named by a builtin's only argument, or the current job without one.
+/// Errors are reported before returning `None`.
+fn job_argument(inv: &Invocation) -> Option<usize> {
+    let args = inv.text_args();
+    let result = match args.get(1) {
+        Some(spec) => parse_job_spec(inv, spec),
+        None => inv
+            .jobs
+            .current_job()
+            .ok_or_else(|| "current: no such job".to_string()),
+    };
+
+    match result {
+        Ok(id) => Some(id),
+        Err(e) => {
+            inv.error(e);
+            None
+        }
+    }
+}
+
+/// Find the job a job spec refers to:
+/// `%N` is job number N, `%%`, `%+`, and `%` are the current job, `%-` is the previous job,
+/// `%string` is the job whose command starts with string, and `%?string` is one containing string.
+pub fn parse_job_spec(inv: &Invocation, spec: &str) -> ::std::result::Result<usize, String> {
+    let no_such_job = || format!("{}: no such job", spec);
+    if !spec.starts_with('%') {
+        return Err(no_such_job());
+    }
+
+    let name = &spec[1..];
+    let found = match name {
+        "" | "%" | "+" => inv.jobs.current_job(),
+        "-" => inv.jobs.previous_job(),
+        _ => if let Ok(n) = name.parse::<usize>() {
+            inv.jobs.job(n).map(|job| job.id)
+        } else {
+            let matches: Vec<usize> = inv
+                .jobs
+                .jobs()
+                .iter()
+                .filter(|job| match name.strip_prefix('?') {
+                    Some(contained) => job.text.contains(contained),
+                    None => job.text.starts_with(name),
+                }).map(|job| job.id)
+                .collect();
+
+            if matches.len() > 1 {
+                return Err(format!("{}: ambiguous job spec", spec));
+            }
+            matches.first().cloned()
+        },
+    };
+    found.ok_or_else(no_such_job)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::testing::run;
+
+    #[test]
+    fn jobs_lists_background_jobs_until_they_finish() {
+        let out = run("sleep 1 & jobs; kill %1; wait; jobs");
+        assert_eq!(out.stdout, "[1]+  Running                 sleep 1 &\n");
+        let out = run("true & sleep 1 & jobs -p %2 >pids; kill %2; wait; wc -l <pids");
+        assert_eq!(out.stdout.trim(), "1");
+    }
+}
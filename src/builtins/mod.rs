@@ -0,0 +1,229 @@
+//! Commands that are run inside the shell process instead of being forked and exec'd
+
+mod commands;
+mod control;
+mod dirs;
+mod jobs;
+mod printf;
+mod process;
+mod stdio;
+mod vars;
+
+pub use self::jobs::notify_completed;
+
+use crate::env::Variables;
+use crate::lang::{ErrorKind, ExecutionContext, JobManager, Result};
+use failure::ResultExt;
+use nix::errno::Errno;
+use nix::unistd;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+
+/// A builtin takes the invocation and returns the command's exit code
+pub type Builtin = fn(&mut Invocation) -> Result<i32>;
+
+/// Everything a builtin has access to while it runs
+pub struct Invocation<'a> {
+    pub jobs: &'a mut JobManager,
+    pub ec: &'a mut ExecutionContext,
+    /// The words the builtin was invoked with, as bytes, see `text_args` for builtins that need text
+    pub args: &'a [OsString],
+    pub stdin: RawFd,
+    pub stdout: RawFd,
+    pub stderr: RawFd,
+    /// Every fd the command's redirections change, with the shell's fd it's redirected to, or -1 if it's closed.
+    /// The standard fds are included, so this is only needed for the others, or to redirect the shell itself.
+    pub redirections: &'a [(RawFd, RawFd)],
+}
+
+/// A builtin's handler along with the help text shown for it
+pub struct Definition {
+    pub name: &'static str,
+    pub run: Builtin,
+    /// A synopsis of the builtin's arguments, like `cd [DIR]`
+    pub usage: &'static str,
+    /// A one-line description
+    pub summary: &'static str,
+}
+
+/// Each module lists the builtins it implements
+const TABLES: &[&[Definition]] = &[
+    commands::BUILTINS,
+    control::BUILTINS,
+    dirs::BUILTINS,
+    jobs::BUILTINS,
+    printf::BUILTINS,
+    process::BUILTINS,
+    stdio::BUILTINS,
+    vars::BUILTINS,
+];
+
+/// Every builtin, sorted by name
+pub fn definitions() -> Vec<&'static Definition> {
+    let mut all: Vec<&'static Definition> = TABLES.iter().flat_map(|table| table.iter()).collect();
+    all.sort_by_key(|d| d.name);
+    all
+}
+
+pub fn definition<T: AsRef<str>>(name: T) -> Option<&'static Definition> {
+    TABLES
+        .iter()
+        .flat_map(|table| table.iter())
+        .find(|d| d.name == name.as_ref())
+}
+
+/// Find the builtin with the given name
+pub fn lookup<T: AsRef<str>>(name: T) -> Option<Builtin> {
+    definition(name).map(|d| d.run)
+}
+
+pub fn is_builtin<T: AsRef<str>>(name: T) -> bool {
+    lookup(name).is_some()
+}
+
+/// The builtins POSIX calls special, they're found before functions with the same name
+const SPECIAL: &[&str] = &[
+    ".", ":", "break", "continue", "eval", "exec", "exit", "export", "readonly", "return", "set", "shift",
+    "times", "trap", "unset",
+];
+
+pub fn is_special<T: AsRef<str>>(name: T) -> bool {
+    SPECIAL.contains(&name.as_ref())
+}
+
+impl<'a> Invocation<'a> {
+    /// The name the builtin was invoked with
+    pub fn name(&self) -> &str {
+        self.args[0].to_str().unwrap_or_default()
+    }
+
+    /// The arguments as text, for builtins that deal in names, numbers and options.
+    /// Bytes that aren't UTF-8 are replaced, so builtins that pass arguments along use `args` instead.
+    pub fn text_args(&self) -> Vec<String> {
+        self.args.iter().map(|arg| arg.to_string_lossy().into_owned()).collect()
+    }
+
+    /// Write some bytes to the builtin's standard output
+    pub fn print<T: AsRef<[u8]>>(&self, s: T) -> Result<()> {
+        write_all(self.stdout, s.as_ref())
+    }
+
+    /// Write some bytes to the builtin's standard error, a diagnostic that can't be written is dropped
+    pub fn eprint<T: AsRef<[u8]>>(&self, s: T) {
+        write_all(self.stderr, s.as_ref()).ok();
+    }
+
+    /// Print a diagnostic prefixed with the builtin's name
+    pub fn error<T: fmt::Display>(&self, msg: T) {
+        self.eprint(format!("rush: {}: {}\n", self.name(), msg));
+    }
+
+    /// Check a name given for a variable, printing an error and returning `false` if it isn't a valid identifier
+    pub fn check_name(&self, name: &str) -> bool {
+        if Variables::is_valid_name(name) {
+            return true;
+        }
+        self.error(ErrorKind::InvalidIdentifier(name.to_string()));
+        false
+    }
+
+    /// Set a variable, unless it's readonly, in which case an error is printed and `false` is returned
+    pub fn assign<T: Into<OsString>>(&mut self, name: &str, value: T) -> bool {
+        match self.ec.variables_mut().define(name, value) {
+            Ok(()) => true,
+            Err(e) => {
+                self.error(e);
+                false
+            }
+        }
+    }
+
+    /// Append to a variable like `NAME+=VALUE`, unless it's readonly, in which case an error is printed and `false` is returned
+    pub fn append<T: Into<OsString>>(&mut self, name: &str, value: T) -> bool {
+        match self.ec.variables_mut().append(name, value) {
+            Ok(()) => true,
+            Err(e) => {
+                self.error(e);
+                false
+            }
+        }
+    }
+
+    /// Assign or append to a variable, depending on whether the argument it came from used `=` or `+=`
+    pub fn assign_or_append<T: Into<OsString>>(&mut self, name: &str, value: T, append: bool) -> bool {
+        if append {
+            self.append(name, value)
+        } else {
+            self.assign(name, value)
+        }
+    }
+
+    /// Unset a variable, unless it's readonly, in which case an error is printed and `false` is returned
+    pub fn unassign(&mut self, name: &str) -> bool {
+        match self.ec.variables_mut().remove(&OsString::from(name)) {
+            Ok(_) => true,
+            Err(e) => {
+                self.error(e);
+                false
+            }
+        }
+    }
+}
+
+/// Split an argument like `NAME=VALUE` into the name and the value, which is missing without an `=`.
+/// With `NAME+=VALUE` the value is appended, which is what the flag says. The value keeps its bytes.
+pub fn split_assignment(arg: &OsStr) -> (Cow<'_, str>, Option<&OsStr>, bool) {
+    let bytes = arg.as_bytes();
+    let (name, value, append) = match bytes.iter().position(|&b| b == b'=') {
+        Some(split) if bytes[..split].ends_with(b"+") => (&bytes[..split - 1], Some(&bytes[split + 1..]), true),
+        Some(split) => (&bytes[..split], Some(&bytes[split + 1..]), false),
+        None => (bytes, None, false),
+    };
+    (String::from_utf8_lossy(name), value.map(OsStr::from_bytes), append)
+}
+
+fn write_all(fd: RawFd, mut bytes: &[u8]) -> Result<()> {
+    while !bytes.is_empty() {
+        match unistd::write(fd, bytes) {
+            Ok(n) => bytes = &bytes[n..],
+            Err(nix::Error::Sys(Errno::EINTR)) => (),
+            Err(e) => Err(e).context(ErrorKind::SysError)?,
+        }
+    }
+    Ok(())
+}
+
+/// Describe an I/O error the way other shells do, without Rust's "(os error N)" suffix
+pub fn describe_io_error(e: &io::Error) -> String {
+    match e.raw_os_error() {
+        Some(errno) => Errno::from_i32(errno).desc().to_string(),
+        None => e.to_string(),
+    }
+}
+
+/// Wrap a string in double quotes so the shell would read it back as the same word
+pub fn double_quote<T: AsRef<str>>(s: T) -> String {
+    let mut quoted = String::with_capacity(s.as_ref().len() + 2);
+    quoted.push('"');
+    for c in s.as_ref().chars() {
+        if c == '"' || c == '\\' || c == '$' || c == '`' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Quote a string for the shell, leaving it bare when that's already safe
+pub fn shell_quote<T: AsRef<str>>(s: T) -> String {
+    let s = s.as_ref();
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "_-./,:+=@%^".contains(c)) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
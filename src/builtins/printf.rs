@@ -0,0 +1,486 @@
+use crate::builtins::stdio::{interpret_escapes, Escaped};
+use crate::builtins::{shell_quote, Definition, Invocation};
+use crate::lang::Result;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::iter::{Cloned, Peekable};
+use std::os::unix::ffi::OsStrExt;
+use std::slice;
+use std::str;
+
+/// The format string is bytes, only the escapes and conversions in it need to be text
+type Bytes<'a> = Peekable<Cloned<slice::Iter<'a, u8>>>;
+
+/// printf is big enough to get a module of its own
+pub const BUILTINS: &[Definition] = &[
+    Definition {
+        name: "printf",
+        run: printf,
+        usage: "printf FORMAT [ARGUMENT ...]",
+        summary: "Print formatted arguments",
+    },
+];
+
+/// printf FORMAT [ARGUMENT ...]
+///
+/// Print the arguments according to FORMAT, the format is reused until every argument has been consumed
+pub fn printf(inv: &mut Invocation) -> Result<i32> {
+    if inv.args.len() < 2 {
+        inv.error("usage: printf format [arguments]");
+        return Ok(2);
+    }
+
+    let mut f = Formatter {
+        args: &inv.args[2..],
+        next: 0,
+        out: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    loop {
+        let consumed = f.next;
+        if !f.format(inv.args[1].as_bytes()) || f.next >= f.args.len() || f.next == consumed {
+            break;
+        }
+    }
+
+    for e in &f.errors {
+        inv.error(e);
+    }
+    inv.print(&f.out)?;
+    Ok(if f.errors.is_empty() { 0 } else { 1 })
+}
+
+/// A single conversion specification, like `%-08.3f`
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    zero: bool,
+    alternate: bool,
+    width: usize,
+    precision: Option<usize>,
+    conversion: char,
+}
+
+struct Formatter<'a> {
+    args: &'a [OsString],
+    next: usize,
+    out: Vec<u8>,
+    errors: Vec<String>,
+}
+
+impl<'a> Formatter<'a> {
+    /// Write `fmt` to the output once, returns false if no more output should be written
+    fn format(&mut self, fmt: &[u8]) -> bool {
+        let mut chars = fmt.iter().cloned().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                b'\\' => if format_escape(&mut chars, &mut self.out) == Escaped::Stop {
+                    return false;
+                },
+                b'%' => {
+                    let spec = self.spec(&mut chars);
+                    if !self.convert(&spec) {
+                        return false;
+                    }
+                }
+                c => self.out.push(c),
+            }
+        }
+        true
+    }
+
+    /// Parse the flags, width, precision, and conversion character following a '%'
+    fn spec(&mut self, chars: &mut Bytes) -> Spec {
+        let mut spec = Spec::default();
+        while let Some(&c) = chars.peek() {
+            match c {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'0' => spec.zero = true,
+                b'#' => spec.alternate = true,
+                _ => break,
+            }
+            chars.next();
+        }
+
+        if chars.peek() == Some(&b'*') {
+            chars.next();
+            let width = self.next_integer();
+            spec.left |= width < 0;
+            spec.width = width.unsigned_abs() as usize;
+        } else {
+            spec.width = take_number(chars).unwrap_or(0);
+        }
+
+        if chars.peek() == Some(&b'.') {
+            chars.next();
+            spec.precision = if chars.peek() == Some(&b'*') {
+                chars.next();
+                Some(self.next_integer().max(0) as usize)
+            } else {
+                Some(take_number(chars).unwrap_or(0))
+            };
+        }
+
+        spec.conversion = chars.next().map(char::from).unwrap_or('\0');
+        spec
+    }
+
+    /// Write a single conversion, returns false if no more output should be written
+    fn convert(&mut self, spec: &Spec) -> bool {
+        match spec.conversion {
+            '%' => self.out.push(b'%'),
+            's' => {
+                let arg = self.next_arg().map(OsStr::as_bytes).unwrap_or(b"");
+                let s = match spec.precision {
+                    Some(p) => take_chars(arg, p),
+                    None => arg,
+                };
+                self.pad(spec, "", s, false);
+            }
+            'b' => {
+                let mut expanded = Vec::new();
+                let escaped = interpret_escapes(self.next_arg().map(OsStr::as_bytes).unwrap_or(b""), &mut expanded);
+                self.pad(spec, "", &expanded, false);
+                if escaped == Escaped::Stop {
+                    return false;
+                }
+            }
+            'q' => {
+                let quoted = shell_quote(self.next_text().unwrap_or_default());
+                self.pad(spec, "", quoted.as_bytes(), false);
+            }
+            'c' => {
+                let c = take_chars(self.next_arg().map(OsStr::as_bytes).unwrap_or(b""), 1);
+                self.pad(spec, "", c, false);
+            }
+            'd' | 'i' => {
+                let value = self.next_integer();
+                let sign = if value < 0 {
+                    "-"
+                } else if spec.plus {
+                    "+"
+                } else if spec.space {
+                    " "
+                } else {
+                    ""
+                };
+                let digits = with_precision((value as i128).abs().to_string(), spec.precision);
+                self.pad(spec, sign, digits.as_bytes(), spec.precision.is_none());
+            }
+            'u' | 'o' | 'x' | 'X' => {
+                let value = self.next_integer() as u64;
+                let digits = with_precision(
+                    match spec.conversion {
+                        'u' => value.to_string(),
+                        'o' => format!("{:o}", value),
+                        'x' => format!("{:x}", value),
+                        _ => format!("{:X}", value),
+                    },
+                    spec.precision,
+                );
+                let prefix = match spec.conversion {
+                    'o' if spec.alternate && !digits.starts_with('0') => "0",
+                    'x' if spec.alternate && value != 0 => "0x",
+                    'X' if spec.alternate && value != 0 => "0X",
+                    _ => "",
+                };
+                self.pad(spec, prefix, digits.as_bytes(), spec.precision.is_none());
+            }
+            'f' | 'F' | 'e' | 'E' | 'g' | 'G' => {
+                let value = self.next_float();
+                let sign = if value.is_sign_negative() && value != 0.0 {
+                    "-"
+                } else if spec.plus {
+                    "+"
+                } else if spec.space {
+                    " "
+                } else {
+                    ""
+                };
+                let digits = format_float(value.abs(), spec);
+                let finite = value.is_finite();
+                self.pad(spec, sign, digits.as_bytes(), finite);
+            }
+            '\0' => {
+                self.errors.push("missing format character".to_string());
+                return false;
+            }
+            c => {
+                self.errors.push(format!("%{}: invalid format character", c));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Pad `body` out to the spec's width, zeros go between the prefix and the body
+    fn pad(&mut self, spec: &Spec, prefix: &str, body: &[u8], allow_zeros: bool) {
+        let len = prefix.len() + body.len();
+        let fill = spec.width.saturating_sub(len);
+        if spec.left {
+            self.out.extend(prefix.as_bytes());
+            self.out.extend(body);
+            self.out.extend((0..fill).map(|_| b' '));
+        } else if spec.zero && allow_zeros {
+            self.out.extend(prefix.as_bytes());
+            self.out.extend((0..fill).map(|_| b'0'));
+            self.out.extend(body);
+        } else {
+            self.out.extend((0..fill).map(|_| b' '));
+            self.out.extend(prefix.as_bytes());
+            self.out.extend(body);
+        }
+    }
+
+    fn next_arg(&mut self) -> Option<&'a OsStr> {
+        let arg = self.args.get(self.next).map(|s| s.as_os_str());
+        if arg.is_some() {
+            self.next += 1;
+        }
+        arg
+    }
+
+    /// The next argument for a conversion that needs text, like a number
+    fn next_text(&mut self) -> Option<Cow<'a, str>> {
+        self.next_arg().map(OsStr::to_string_lossy)
+    }
+
+    fn next_integer(&mut self) -> i64 {
+        let arg = match self.next_text() {
+            Some(v) => v,
+            None => return 0,
+        };
+
+        let (value, complete) = parse_integer(&arg);
+        if !complete {
+            self.errors.push(format!("{}: invalid number", arg));
+        }
+        value
+    }
+
+    fn next_float(&mut self) -> f64 {
+        let arg = match self.next_text() {
+            Some(v) => v,
+            None => return 0.0,
+        };
+
+        let trimmed = arg.trim_start();
+        if let Ok(v) = trimmed.parse::<f64>() {
+            return v;
+        }
+
+        let (value, complete) = parse_integer(&arg);
+        if complete {
+            return value as f64;
+        }
+
+        // use the longest prefix that makes sense as a number
+        let value = (1..trimmed.len())
+            .rev()
+            .filter(|i| trimmed.is_char_boundary(*i))
+            .filter_map(|i| trimmed[..i].parse::<f64>().ok())
+            .next()
+            .unwrap_or(0.0);
+        self.errors.push(format!("{}: invalid number", arg));
+        value
+    }
+}
+
+/// Handle a backslash escape in the format string itself, which allows octal escapes without a leading 0
+fn format_escape(chars: &mut Bytes, out: &mut Vec<u8>) -> Escaped {
+    match chars.peek().cloned() {
+        Some(c) if char::from(c).is_digit(8) => {
+            out.push(take_radix(chars, 8, 3) as u8);
+            Escaped::Continue
+        }
+        Some(b'x') => {
+            chars.next();
+            if chars.peek().map(|c| c.is_ascii_hexdigit()).unwrap_or(false) {
+                out.push(take_radix(chars, 16, 2) as u8);
+            } else {
+                out.extend(b"\\x");
+            }
+            Escaped::Continue
+        }
+        Some(b'"') => {
+            chars.next();
+            out.push(b'"');
+            Escaped::Continue
+        }
+        Some(c) => {
+            chars.next();
+            interpret_escapes(&[b'\\', c], out)
+        }
+        None => {
+            out.push(b'\\');
+            Escaped::Continue
+        }
+    }
+}
+
+fn take_radix(chars: &mut Bytes, radix: u32, max_digits: usize) -> u32 {
+    let mut value = 0;
+    for _ in 0..max_digits {
+        match chars.peek().and_then(|&c| char::from(c).to_digit(radix)) {
+            Some(d) => {
+                value = value * radix + d;
+                chars.next();
+            }
+            None => break,
+        }
+    }
+    value
+}
+
+fn take_number(chars: &mut Bytes) -> Option<usize> {
+    let mut value = None;
+    while let Some(d) = chars.peek().and_then(|&c| char::from(c).to_digit(10)) {
+        value = Some(value.unwrap_or(0) * 10 + d as usize);
+        chars.next();
+    }
+    value
+}
+
+/// The first `n` characters of an argument, or its first `n` bytes when it isn't UTF-8
+fn take_chars(arg: &[u8], n: usize) -> &[u8] {
+    match str::from_utf8(arg) {
+        Ok(text) => text.char_indices().nth(n).map(|(i, _)| &arg[..i]).unwrap_or(arg),
+        Err(_) => &arg[..n.min(arg.len())],
+    }
+}
+
+/// Parse an integer argument the way C's strtol would, including 0x and 0 prefixes.
+/// A leading quote gives the value of the character after it.
+/// Returns the value and whether the whole argument was used.
+fn parse_integer(arg: &str) -> (i64, bool) {
+    let s = arg.trim_start();
+    if s.is_empty() {
+        return (0, true);
+    }
+
+    if s.starts_with('\'') || s.starts_with('"') {
+        return (s[1..].chars().next().map(|c| c as i64).unwrap_or(0), true);
+    }
+
+    let (negative, unsigned) = match s.chars().next() {
+        Some('-') => (true, &s[1..]),
+        Some('+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+
+    let (radix, digits) = if unsigned.starts_with("0x") || unsigned.starts_with("0X") {
+        (16, &unsigned[2..])
+    } else if unsigned.len() > 1 && unsigned.starts_with('0') {
+        (8, &unsigned[1..])
+    } else {
+        (10, unsigned)
+    };
+
+    let end = digits
+        .find(|c: char| !c.is_digit(radix))
+        .unwrap_or(digits.len());
+    let value = i64::from_str_radix(&digits[..end], radix).unwrap_or(0);
+    (
+        if negative { -value } else { value },
+        end == digits.len() && (end > 0 || radix == 8),
+    )
+}
+
+fn with_precision(digits: String, precision: Option<usize>) -> String {
+    match precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(p) if p > digits.len() => format!("{}{}", "0".repeat(p - digits.len()), digits),
+        _ => digits,
+    }
+}
+
+/// Format a non-negative float for one of the %f, %e, or %g conversions
+fn format_float(value: f64, spec: &Spec) -> String {
+    let upper = spec.conversion.is_uppercase();
+    if value.is_infinite() {
+        return if upper { "INF" } else { "inf" }.to_string();
+    }
+    if value.is_nan() {
+        return if upper { "NAN" } else { "nan" }.to_string();
+    }
+
+    let precision = spec.precision.unwrap_or(6);
+    match spec.conversion {
+        'f' | 'F' => format!("{:.*}", precision, value),
+        'e' | 'E' => exponential(value, precision, upper),
+        _ => {
+            let precision = precision.max(1);
+            let exponent = exponential(value, precision - 1, false)
+                .rsplit('e')
+                .next()
+                .and_then(|e| e.parse::<i32>().ok())
+                .unwrap_or(0);
+
+            let formatted = if exponent < -4 || exponent >= precision as i32 {
+                exponential(value, precision - 1, upper)
+            } else {
+                format!("{:.*}", (precision as i32 - 1 - exponent) as usize, value)
+            };
+
+            if spec.alternate {
+                formatted
+            } else {
+                strip_fraction_zeros(formatted)
+            }
+        }
+    }
+}
+
+/// Format a number like C's %e, which always has a sign and at least two exponent digits
+fn exponential(value: f64, precision: usize, upper: bool) -> String {
+    let formatted = format!("{:.*e}", precision, value);
+    let (mantissa, exponent) = match formatted.find('e') {
+        Some(i) => (&formatted[..i], formatted[i + 1..].parse::<i32>().unwrap_or(0)),
+        None => (formatted.as_str(), 0),
+    };
+
+    format!(
+        "{}{}{}{:02}",
+        mantissa,
+        if upper { 'E' } else { 'e' },
+        if exponent < 0 { '-' } else { '+' },
+        exponent.abs()
+    )
+}
+
+fn strip_fraction_zeros(s: String) -> String {
+    let (number, exponent) = match s.find(['e', 'E']) {
+        Some(i) => (&s[..i], &s[i..]),
+        None => (s.as_str(), ""),
+    };
+
+    if !number.contains('.') {
+        return s.clone();
+    }
+    format!(
+        "{}{}",
+        number.trim_end_matches('0').trim_end_matches('.'),
+        exponent
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::testing::{run, run_with_bytes};
+
+    #[test]
+    fn precision_counts_characters_of_text() {
+        assert_eq!(run("printf '%.2s|%c|%3s\\n' h\u{e9}llo \u{e9}t\u{e9} ab").stdout, "h\u{e9}|\u{e9}| ab\n");
+    }
+
+    #[test]
+    fn arguments_and_formats_keep_their_bytes() {
+        let script = "printf \"$BYTES|%s|%.1s|%b\\n\" \"$BYTES\" \"$BYTES\" \"$BYTES\"";
+        assert_eq!(run_with_bytes(b"\xff\xfe", script), b"\xff\xfe|\xff\xfe|\xff|\xff\xfe\n");
+    }
+}
@@ -0,0 +1,446 @@
+use crate::builtins::{Definition, Invocation};
+use crate::lang::exec::cpu_time;
+use crate::lang::Result;
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::stat::{self, Mode};
+use std::mem;
+use std::time::Duration;
+
+/// Builtins for the shell process' own limits and resource usage
+pub const BUILTINS: &[Definition] = &[
+    Definition {
+        name: "times",
+        run: times,
+        usage: "times",
+        summary: "Print the CPU time used by the shell and its children",
+    },
+    Definition {
+        name: "ulimit",
+        run: ulimit,
+        usage: "ulimit [-SHa] [-cdflmnstuv] [LIMIT]",
+        summary: "Print or set resource limits",
+    },
+    Definition {
+        name: "umask",
+        run: umask,
+        usage: "umask [-pS] [MODE]",
+        summary: "Print or set the file creation mask",
+    },
+];
+
+/// umask [-pS] [MODE]
+///
+/// Print or set the file creation mask, MODE is either octal or symbolic like `u=rwx,g=rx,o=rx`
+pub fn umask(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut symbolic = false;
+    let mut reusable = false;
+    let mut first_operand = 1;
+    for arg in &args[1..] {
+        if arg.len() < 2 || !arg.starts_with('-') {
+            break;
+        }
+        first_operand += 1;
+        if arg == "--" {
+            break;
+        }
+        for c in arg[1..].chars() {
+            match c {
+                'S' => symbolic = true,
+                'p' => reusable = true,
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+
+    let mask = current_umask();
+    match args.get(first_operand) {
+        Some(mode) => {
+            let new_mask = if mode.chars().all(|c| c.is_digit(8)) {
+                u32::from_str_radix(mode, 8).ok().filter(|&m| m <= 0o777)
+            } else {
+                // symbolic modes describe the permissions that are allowed, which is the inverse of the mask
+                parse_symbolic(mode, !mask & 0o777).map(|allowed| !allowed & 0o777)
+            };
+
+            match new_mask {
+                Some(m) => {
+                    stat::umask(Mode::from_bits_truncate(m));
+                    if symbolic {
+                        inv.print(format!("{}\n", format_symbolic(m)))?;
+                    }
+                    Ok(0)
+                }
+                None => {
+                    inv.error(format!("{}: invalid mode", mode));
+                    Ok(1)
+                }
+            }
+        }
+        None => {
+            let shown = if symbolic {
+                format_symbolic(mask)
+            } else {
+                format!("{:04o}", mask)
+            };
+            if reusable {
+                inv.print(format!(
+                    "umask {}{}\n",
+                    if symbolic { "-S " } else { "" },
+                    shown
+                ))?;
+            } else {
+                inv.print(format!("{}\n", shown))?;
+            }
+            Ok(0)
+        }
+    }
+}
+
+/// The process's file creation mask, it can only be read by setting it so it's put back straight after
+fn current_umask() -> u32 {
+    let mask = stat::umask(Mode::empty());
+    stat::umask(mask);
+    mask.bits() as u32
+}
+
+/// Show the permissions a mask allows, like `u=rwx,g=rx,o=rx`
+fn format_symbolic(mask: u32) -> String {
+    let allowed = !mask & 0o777;
+    let classes: Vec<String> = [('u', 6), ('g', 3), ('o', 0)]
+        .iter()
+        .map(|&(who, shift)| {
+            let bits = (allowed >> shift) & 0o7;
+            let mut class = format!("{}=", who);
+            for &(bit, c) in &[(0o4, 'r'), (0o2, 'w'), (0o1, 'x')] {
+                if bits & bit != 0 {
+                    class.push(c);
+                }
+            }
+            class
+        })
+        .collect();
+    classes.join(",")
+}
+
+/// Apply comma separated clauses like `u+w` or `go=rx` to a set of permission bits
+fn parse_symbolic(mode: &str, mut allowed: u32) -> Option<u32> {
+    for clause in mode.split(',') {
+        let mut chars = clause.chars().peekable();
+        let mut who = 0;
+        while let Some(&c) = chars.peek() {
+            who |= match c {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => break,
+            };
+            chars.next();
+        }
+        if who == 0 {
+            who = 0o777;
+        }
+
+        // every clause needs at least one operator, each followed by any number of permissions
+        let mut op = chars.next()?;
+        loop {
+            let mut perms = 0;
+            while let Some(&c) = chars.peek() {
+                perms |= match c {
+                    'r' => 0o444,
+                    'w' => 0o222,
+                    'x' => 0o111,
+                    _ => break,
+                };
+                chars.next();
+            }
+
+            match op {
+                '+' => allowed |= perms & who,
+                '-' => allowed &= !(perms & who),
+                '=' => allowed = (allowed & !who) | (perms & who),
+                _ => return None,
+            }
+
+            match chars.next() {
+                Some(next) => op = next,
+                None => break,
+            }
+        }
+    }
+    Some(allowed)
+}
+
+/// A resource `ulimit` can change: its option, the kernel's resource number,
+/// a description with the unit used for values, and how many bytes or items make up one unit
+struct Limit {
+    option: char,
+    resource: libc::c_int,
+    description: &'static str,
+    unit: Option<&'static str>,
+    scale: libc::rlim_t,
+}
+
+const LIMITS: &[Limit] = &[
+    Limit {
+        option: 'c',
+        resource: libc::RLIMIT_CORE,
+        description: "core file size",
+        unit: Some("blocks"),
+        scale: 512,
+    },
+    Limit {
+        option: 'd',
+        resource: libc::RLIMIT_DATA,
+        description: "data seg size",
+        unit: Some("kbytes"),
+        scale: 1024,
+    },
+    Limit {
+        option: 'f',
+        resource: libc::RLIMIT_FSIZE,
+        description: "file size",
+        unit: Some("blocks"),
+        scale: 512,
+    },
+    Limit {
+        option: 'l',
+        resource: libc::RLIMIT_MEMLOCK,
+        description: "max locked memory",
+        unit: Some("kbytes"),
+        scale: 1024,
+    },
+    Limit {
+        option: 'm',
+        resource: libc::RLIMIT_RSS,
+        description: "max memory size",
+        unit: Some("kbytes"),
+        scale: 1024,
+    },
+    Limit {
+        option: 'n',
+        resource: libc::RLIMIT_NOFILE,
+        description: "open files",
+        unit: None,
+        scale: 1,
+    },
+    Limit {
+        option: 's',
+        resource: libc::RLIMIT_STACK,
+        description: "stack size",
+        unit: Some("kbytes"),
+        scale: 1024,
+    },
+    Limit {
+        option: 't',
+        resource: libc::RLIMIT_CPU,
+        description: "cpu time",
+        unit: Some("seconds"),
+        scale: 1,
+    },
+    Limit {
+        option: 'u',
+        resource: libc::RLIMIT_NPROC,
+        description: "max user processes",
+        unit: None,
+        scale: 1,
+    },
+    Limit {
+        option: 'v',
+        resource: libc::RLIMIT_AS,
+        description: "virtual memory",
+        unit: Some("kbytes"),
+        scale: 1024,
+    },
+];
+
+/// ulimit [-SHa] [-cdflmnstuv] [LIMIT]
+///
+/// Print or set resource limits, the file size limit is used when no resource is given.
+/// Soft limits are printed by default and both limits are set unless -S or -H is used.
+pub fn ulimit(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut soft = false;
+    let mut hard = false;
+    let mut all = false;
+    let mut limit = None;
+    let mut first_operand = 1;
+    for arg in &args[1..] {
+        if arg.len() < 2 || !arg.starts_with('-') {
+            break;
+        }
+        first_operand += 1;
+        if arg == "--" {
+            break;
+        }
+        for c in arg[1..].chars() {
+            match c {
+                'S' => soft = true,
+                'H' => hard = true,
+                'a' => all = true,
+                _ => match LIMITS.iter().find(|l| l.option == c) {
+                    Some(l) => limit = Some(l),
+                    None => {
+                        inv.error(format!("-{}: invalid option", c));
+                        return Ok(2);
+                    }
+                },
+            }
+        }
+    }
+
+    if all {
+        let mut out = String::new();
+        for l in LIMITS {
+            let value = match get_rlimit(l.resource) {
+                Ok(r) => format_limit(l, if hard { r.rlim_max } else { r.rlim_cur }),
+                Err(e) => e.desc().to_string(),
+            };
+            let unit = match l.unit {
+                Some(unit) => format!("({}, -{})", unit, l.option),
+                None => format!("(-{})", l.option),
+            };
+            out.push_str(&format!("{:<24}{:<14}{}\n", l.description, unit, value));
+        }
+        inv.print(out)?;
+        return Ok(0);
+    }
+
+    let limit = limit.unwrap_or_else(|| LIMITS.iter().find(|l| l.option == 'f').unwrap());
+    let mut current = match get_rlimit(limit.resource) {
+        Ok(r) => r,
+        Err(e) => {
+            inv.error(format!(
+                "{}: cannot get limit: {}",
+                limit.description,
+                e.desc()
+            ));
+            return Ok(1);
+        }
+    };
+
+    let operand = match args.get(first_operand) {
+        Some(operand) => operand,
+        None => {
+            let value = if hard && !soft {
+                current.rlim_max
+            } else {
+                current.rlim_cur
+            };
+            inv.print(format!("{}\n", format_limit(limit, value)))?;
+            return Ok(0);
+        }
+    };
+
+    let value = match operand.as_str() {
+        "unlimited" => libc::RLIM_INFINITY,
+        "hard" => current.rlim_max,
+        "soft" => current.rlim_cur,
+        n => match n
+            .parse::<libc::rlim_t>()
+            .ok()
+            .and_then(|n| n.checked_mul(limit.scale))
+        {
+            Some(n) => n,
+            None => {
+                inv.error(format!("{}: invalid number", n));
+                return Ok(1);
+            }
+        },
+    };
+
+    if hard || !soft {
+        current.rlim_max = value;
+    }
+    if soft || !hard {
+        current.rlim_cur = value;
+    }
+    if let Err(e) = set_rlimit(limit.resource, &current) {
+        inv.error(format!(
+            "{}: cannot modify limit: {}",
+            limit.description,
+            e.desc()
+        ));
+        return Ok(1);
+    }
+    Ok(0)
+}
+
+/// Show a limit in the units ulimit uses for the resource
+fn format_limit(limit: &Limit, value: libc::rlim_t) -> String {
+    if value == libc::RLIM_INFINITY {
+        "unlimited".to_string()
+    } else {
+        (value / limit.scale).to_string()
+    }
+}
+
+fn get_rlimit(resource: libc::c_int) -> ::std::result::Result<libc::rlimit, Errno> {
+    let mut rlim: libc::rlimit = unsafe { mem::zeroed() };
+    if unsafe { libc::getrlimit(resource as _, &mut rlim) } != 0 {
+        return Err(Errno::last());
+    }
+    Ok(rlim)
+}
+
+fn set_rlimit(resource: libc::c_int, rlim: &libc::rlimit) -> ::std::result::Result<(), Errno> {
+    if unsafe { libc::setrlimit(resource as _, rlim) } != 0 {
+        return Err(Errno::last());
+    }
+    Ok(())
+}
+
+/// times
+///
+/// Print the user and system CPU time used by the shell, then by its finished children
+pub fn times(inv: &mut Invocation) -> Result<i32> {
+    let (shell_user, shell_sys) = cpu_time(libc::RUSAGE_SELF);
+    let (children_user, children_sys) = cpu_time(libc::RUSAGE_CHILDREN);
+    inv.print(format!(
+        "{} {}\n{} {}\n",
+        format_minutes(shell_user),
+        format_minutes(shell_sys),
+        format_minutes(children_user),
+        format_minutes(children_sys)
+    ))?;
+    Ok(0)
+}
+
+/// Show a duration like `1m2.345s`, rounded to the millisecond
+fn format_minutes(d: Duration) -> String {
+    let millis = (d.as_secs() * 1000) + u64::from((d.subsec_nanos() + 500_000) / 1_000_000);
+    format!("{}m{}.{:03}s", millis / 60_000, (millis / 1000) % 60, millis % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::testing::run;
+
+    #[test]
+    fn durations_are_minutes_and_rounded_seconds() {
+        assert_eq!(format_minutes(Duration::new(0, 0)), "0m0.000s");
+        assert_eq!(format_minutes(Duration::new(62, 345_000_000)), "1m2.345s");
+        assert_eq!(format_minutes(Duration::new(59, 999_600_000)), "1m0.000s");
+        assert_eq!(format_minutes(Duration::new(1, 499_999)), "0m1.000s");
+        assert_eq!(format_minutes(Duration::new(1, 500_000)), "0m1.001s");
+        assert_eq!(format_minutes(Duration::new(3600, 0)), "60m0.000s");
+    }
+
+    #[test]
+    fn times_prints_the_shell_then_its_children() {
+        let out = run("times");
+        let lines: Vec<&str> = out.stdout.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(fields.len(), 2);
+            assert!(fields.iter().all(|f| f.contains('m') && f.ends_with('s')), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use crate::builtins::{Definition, Invocation};
+use crate::lang::{ErrorKind, Result};
+use failure::ResultExt;
+use nix::errno::Errno;
+use nix::unistd;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::RawFd;
+
+/// Builtins that read from and write to the standard streams
+pub const BUILTINS: &[Definition] = &[
+    Definition {
+        name: "echo",
+        run: echo,
+        usage: "echo [-neE] [ARG ...]",
+        summary: "Print the arguments",
+    },
+    Definition {
+        name: "read",
+        run: read,
+        usage: "read [-r] [-p PROMPT] [-n COUNT] [NAME ...]",
+        summary: "Read a line into variables",
+    },
+];
+
+/// echo [-neE] [ARG ...]
+///
+/// Print the arguments separated by spaces, followed by a newline
+pub fn echo(inv: &mut Invocation) -> Result<i32> {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut first_arg = 1;
+    for arg in &inv.args[1..] {
+        let arg = arg.as_bytes();
+        if arg.len() < 2 || arg[0] != b'-' || !arg[1..].iter().all(|c| b"neE".contains(c)) {
+            break;
+        }
+        for c in &arg[1..] {
+            match c {
+                b'n' => newline = false,
+                b'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        first_arg += 1;
+    }
+
+    let mut out = Vec::new();
+    for (i, arg) in inv.args[first_arg..].iter().enumerate() {
+        if i > 0 {
+            out.push(b' ');
+        }
+        if escapes {
+            if interpret_escapes(arg.as_bytes(), &mut out) == Escaped::Stop {
+                return inv.print(out).map(|_| 0);
+            }
+        } else {
+            out.extend(arg.as_bytes());
+        }
+    }
+
+    if newline {
+        out.push(b'\n');
+    }
+    inv.print(out).map(|_| 0)
+}
+
+/// read [-r] [-p PROMPT] [-n COUNT] [NAME ...]
+///
+/// Read a line from standard input and split it into the named variables using IFS.
+/// The last variable gets the rest of the line, and REPLY is used when no names are given.
+pub fn read(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut raw = false;
+    let mut prompt = None;
+    let mut limit = None;
+    let mut args = args[1..].iter();
+    let mut names = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            names.extend(args.cloned());
+            break;
+        }
+        if arg.len() < 2 || !arg.starts_with('-') {
+            names.push(arg.clone());
+            names.extend(args.cloned());
+            break;
+        }
+
+        for (i, c) in arg.char_indices().skip(1) {
+            match c {
+                'r' => raw = true,
+                'p' | 'n' => {
+                    let value = if arg.len() > i + 1 {
+                        arg[i + 1..].to_string()
+                    } else if let Some(next) = args.next() {
+                        next.clone()
+                    } else {
+                        inv.error(format!("-{}: option requires an argument", c));
+                        return Ok(2);
+                    };
+
+                    if c == 'p' {
+                        prompt = Some(value);
+                    } else {
+                        match value.parse::<usize>() {
+                            Ok(n) => limit = Some(n),
+                            Err(_) => {
+                                inv.error(format!("{}: invalid number", value));
+                                return Ok(2);
+                            }
+                        }
+                    }
+                    break;
+                }
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+
+    if !names.iter().all(|name| inv.check_name(name)) {
+        return Ok(1);
+    }
+
+    if let Some(p) = prompt {
+        if unistd::isatty(inv.stdin).unwrap_or(false) {
+            inv.eprint(p);
+        }
+    }
+
+    // each byte is paired with whether it was escaped, escaped bytes never split fields
+    let mut line: Vec<(u8, bool)> = Vec::new();
+    let mut chars_read = 0;
+    let mut eof = false;
+    let mut escape_next = false;
+    while limit.map(|n| chars_read < n).unwrap_or(true) {
+        let b = match read_byte(inv.stdin)? {
+            Some(b) => b,
+            None => {
+                eof = true;
+                break;
+            }
+        };
+
+        if escape_next {
+            escape_next = false;
+            if b != b'\n' {
+                line.push((b, true));
+            }
+        } else if b == b'\\' && !raw {
+            escape_next = true;
+            continue;
+        } else if b == b'\n' {
+            break;
+        } else {
+            line.push((b, false));
+        }
+
+        // continuation bytes of multi-byte characters don't count towards the limit
+        if b & 0xc0 != 0x80 {
+            chars_read += 1;
+        }
+    }
+
+    if names.is_empty() {
+        let reply: Vec<u8> = line.iter().map(|&(b, _)| b).collect();
+        if !inv.assign("REPLY", OsString::from_vec(reply)) {
+            return Ok(1);
+        }
+        return Ok(if eof { 1 } else { 0 });
+    }
+
+    let ifs_name = OsString::from("IFS");
+    let ifs = if inv.ec.variables().exists(&ifs_name) {
+        inv.ec.variables().value(&ifs_name).as_bytes().to_vec()
+    } else {
+        b" \t\n".to_vec()
+    };
+    let is_ifs = |&(b, escaped): &(u8, bool)| !escaped && ifs.contains(&b);
+    let is_ifs_space = |&(b, escaped): &(u8, bool)| is_ifs(&(b, escaped)) && (b == b' ' || b == b'\t' || b == b'\n');
+
+    let mut rest = &line[..];
+    while rest.first().map(&is_ifs_space).unwrap_or(false) {
+        rest = &rest[1..];
+    }
+
+    for (i, name) in names.iter().enumerate() {
+        let field = if i + 1 == names.len() {
+            let mut end = rest.len();
+            while end > 0 && is_ifs_space(&rest[end - 1]) {
+                end -= 1;
+            }
+            let field = &rest[..end];
+            rest = &rest[rest.len()..];
+            field
+        } else {
+            let end = rest.iter().position(&is_ifs).unwrap_or(rest.len());
+            let field = &rest[..end];
+            rest = &rest[end..];
+
+            // a field ends at IFS whitespace and at most one other IFS character
+            while rest.first().map(&is_ifs_space).unwrap_or(false) {
+                rest = &rest[1..];
+            }
+            if rest.first().map(&is_ifs).unwrap_or(false) {
+                rest = &rest[1..];
+                while rest.first().map(&is_ifs_space).unwrap_or(false) {
+                    rest = &rest[1..];
+                }
+            }
+            field
+        };
+
+        let value: Vec<u8> = field.iter().map(|&(b, _)| b).collect();
+        if !inv.assign(name, OsString::from_vec(value)) {
+            return Ok(1);
+        }
+    }
+
+    Ok(if eof { 1 } else { 0 })
+}
+
+/// Read a single byte, so nothing past the end of the line is taken away from the next command
+fn read_byte(fd: RawFd) -> Result<Option<u8>> {
+    let mut buf = [0; 1];
+    loop {
+        match unistd::read(fd, &mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(nix::Error::Sys(Errno::EINTR)) => (),
+            Err(e) => Err(e).context(ErrorKind::SysError)?,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Escaped {
+    Continue,
+    /// A `\c` was found, nothing more should be printed
+    Stop,
+}
+
+/// Expand backslash escapes the way `echo -e` and `printf %b` do, appending the result to `out`
+pub fn interpret_escapes(s: &[u8], out: &mut Vec<u8>) -> Escaped {
+    let mut bytes = s.iter().cloned().peekable();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'a') => out.push(0x07),
+            Some(b'b') => out.push(0x08),
+            Some(b'c') => return Escaped::Stop,
+            Some(b'e') | Some(b'E') => out.push(0x1b),
+            Some(b'f') => out.push(0x0c),
+            Some(b'n') => out.push(b'\n'),
+            Some(b'r') => out.push(b'\r'),
+            Some(b't') => out.push(b'\t'),
+            Some(b'v') => out.push(0x0b),
+            Some(b'\\') => out.push(b'\\'),
+            Some(b'0') => {
+                let mut value: u32 = 0;
+                for _ in 0..3 {
+                    match bytes.peek().and_then(|&b| char::from(b).to_digit(8)) {
+                        Some(d) => {
+                            value = value * 8 + d;
+                            bytes.next();
+                        }
+                        None => break,
+                    }
+                }
+                out.push(value as u8);
+            }
+            Some(other) => {
+                out.push(b'\\');
+                out.push(other);
+            }
+            None => out.push(b'\\'),
+        }
+    }
+    Escaped::Continue
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::testing::{run, run_with_bytes};
+
+    #[test]
+    fn echo_options_and_escapes() {
+        assert_eq!(run("echo -n a; echo -e 'b\\tc'; echo -nE 'd\\n'; echo -x").stdout, "ab\tc\nd\\n-x\n");
+        assert_eq!(run("echo -e 'one\\ctwo'; echo three").stdout, "onethree\n");
+    }
+
+    #[test]
+    fn echo_prints_the_bytes_it_was_given() {
+        let script = "echo \"$BYTES\"; echo -e \"$BYTES\\\\0101\"; builtin echo \"$BYTES\"; command echo \"$BYTES\"";
+        assert_eq!(run_with_bytes(b"\xe9", script), b"\xe9\n\xe9A\n\xe9\n\xe9\n");
+    }
+
+    #[test]
+    fn read_checks_its_names_before_taking_input() {
+        let out = run("echo one | { read ok foo-bar; echo $? ${ok-unset}; read line; echo $line; }");
+        assert_eq!(out.stdout, "1 unset\none\n");
+        assert_eq!(out.stderr, "rush: read: 'foo-bar': not a valid identifier\n");
+    }
+}
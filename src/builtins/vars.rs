@@ -0,0 +1,974 @@
+use crate::builtins::{double_quote, split_assignment, Definition, Invocation};
+use crate::expr::{self, Expr};
+use crate::lang::word;
+use crate::lang::Result;
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+
+/// Builtins for variables and their attributes
+pub const BUILTINS: &[Definition] = &[
+    Definition {
+        name: "declare",
+        run: declare,
+        usage: "declare [-fFginprxaA] [+inrx] [NAME[=VALUE] ...]",
+        summary: "Set variable attributes and values",
+    },
+    Definition {
+        name: "export",
+        run: export,
+        usage: "export [-n] [-p] [NAME[=VALUE] ...]",
+        summary: "Mark variables to be passed to child processes",
+    },
+    Definition {
+        name: "getopts",
+        run: getopts,
+        usage: "getopts OPTSTRING NAME [ARG ...]",
+        summary: "Parse options from the positional parameters",
+    },
+    Definition {
+        name: "let",
+        run: let_,
+        usage: "let EXPRESSION ...",
+        summary: "Evaluate arithmetic expressions",
+    },
+    Definition {
+        name: "local",
+        run: local,
+        usage: "local [NAME[=VALUE] ...]",
+        summary: "Declare variables local to a function",
+    },
+    Definition {
+        name: "readonly",
+        run: readonly,
+        usage: "readonly [-p] [NAME[=VALUE] ...]",
+        summary: "Stop variables from being changed",
+    },
+    Definition {
+        name: "shift",
+        run: shift,
+        usage: "shift [N]",
+        summary: "Drop positional parameters",
+    },
+    Definition {
+        name: "typeset",
+        run: declare,
+        usage: "typeset [-fFginprxaA] [+inrx] [NAME[=VALUE] ...]",
+        summary: "Set variable attributes and values",
+    },
+    Definition {
+        name: "unset",
+        run: unset,
+        usage: "unset [-f] [-v] [-n] NAME[[KEY]] ...",
+        summary: "Remove variables or functions",
+    },
+];
+
+/// export [-n] [-p] [NAME[=VALUE] ...]
+///
+/// Mark variables to be passed to child processes, optionally assigning them at the same time
+pub fn export(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut unexport = false;
+    let mut names = Vec::new();
+    for (arg, raw) in args[1..].iter().zip(&inv.args[1..]) {
+        match arg.as_str() {
+            "-n" if names.is_empty() => unexport = true,
+            "-p" if names.is_empty() => (),
+            _ => names.push(raw.clone()),
+        }
+    }
+
+    if names.is_empty() {
+        let mut listing = String::new();
+        for (name, value) in inv.ec.variables().exported() {
+            match value {
+                Some(v) => listing.push_str(&format!(
+                    "export {}={}\n",
+                    name.to_string_lossy(),
+                    double_quote(v.to_string_lossy())
+                )),
+                None => listing.push_str(&format!("export {}\n", name.to_string_lossy())),
+            }
+        }
+        inv.print(listing)?;
+        return Ok(0);
+    }
+
+    let mut code = 0;
+    for arg in names {
+        let (name, value, append) = split_assignment(&arg);
+        if !inv.check_name(&name) {
+            code = 1;
+            continue;
+        }
+
+        if let Some(v) = value {
+            if !inv.assign_or_append(&name, v, append) {
+                return Ok(1);
+            }
+        }
+        let name = OsString::from(name.as_ref());
+
+        if unexport {
+            inv.ec.variables_mut().unexport(&name);
+        } else {
+            inv.ec.variables_mut().export(&name);
+        }
+    }
+    Ok(code)
+}
+
+/// unset [-f] [-v] [-n] NAME[[KEY]] ...
+///
+/// Remove variables or functions, without a flag functions are only removed when there's no variable by that name.
+/// `NAME[KEY]` removes one element of an array. Unsetting a nameref unsets the variable it refers to,
+/// with -n it's the nameref itself.
+pub fn unset(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut functions = false;
+    let mut variables = false;
+    let mut references = false;
+    let mut first_name = 1;
+    for arg in &args[1..] {
+        match arg.as_str() {
+            "-f" => functions = true,
+            "-v" => variables = true,
+            "-n" => references = true,
+            "--" => {
+                first_name += 1;
+                break;
+            }
+            _ => break,
+        }
+        first_name += 1;
+    }
+
+    let mut code = 0;
+    for name in &args[first_name..] {
+        if let Some((array, key)) = split_subscript(name).filter(|_| variables || !functions) {
+            if let Err(e) = inv.ec.variables_mut().remove_element(&OsString::from(array), key) {
+                inv.error(e);
+                code = 1;
+            }
+            continue;
+        }
+        let var_name = OsString::from(name.as_str());
+        let removed_variable = if variables || !functions {
+            let removed = if references {
+                inv.ec.variables_mut().remove_nameref(&var_name)
+            } else {
+                inv.ec.variables_mut().remove(&var_name)
+            };
+            match removed {
+                Ok(removed) => removed.is_some(),
+                Err(_) => {
+                    inv.error(format!("{}: cannot unset: readonly variable", name));
+                    code = 1;
+                    continue;
+                }
+            }
+        } else {
+            false
+        };
+        if !removed_variable && !variables {
+            inv.ec.functions_mut().remove(name);
+        }
+    }
+    Ok(code)
+}
+
+/// Split `NAME[KEY]` into the name and the key
+fn split_subscript(s: &str) -> Option<(&str, &str)> {
+    let open = s.find('[')?;
+    if open == 0 || !s.ends_with(']') {
+        return None;
+    }
+    Some((&s[..open], &s[open + 1..s.len() - 1]))
+}
+
+/// shift [N]
+///
+/// Drop the first N positional parameters, one by default, and renumber the rest
+pub fn shift(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let n = match args.get(1) {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => {
+                inv.error(format!("{}: numeric argument required", arg));
+                return Ok(1);
+            }
+        },
+        None => 1,
+    };
+
+    let mut params = inv.ec.variables().positional().to_vec();
+    if n > params.len() {
+        inv.error(format!("{}: shift count out of range", n));
+        return Ok(1);
+    }
+    params.drain(..n);
+    inv.ec.variables_mut().set_positional(params);
+    Ok(0)
+}
+
+/// getopts OPTSTRING NAME [ARG ...]
+///
+/// Put the next option from the positional parameters, or the ARGs, in NAME.
+/// Letters followed by `:` in OPTSTRING take an argument, which is put in OPTARG, and OPTIND is the next
+/// argument to look at. A leading `:` reports bad options through NAME and OPTARG instead of printing errors.
+pub fn getopts(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    if args.len() < 3 {
+        inv.error("usage: getopts optstring name [arg ...]");
+        return Ok(2);
+    }
+
+    let (silent, optstring) = match args[1].strip_prefix(':') {
+        Some(rest) => (true, rest.to_string()),
+        None => (false, args[1].clone()),
+    };
+    let name = args[2].clone();
+    if !inv.check_name(&name) {
+        return Ok(1);
+    }
+    let args: Vec<String> = if args.len() > 3 {
+        args[3..].to_vec()
+    } else {
+        inv.ec
+            .variables()
+            .positional()
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect()
+    };
+
+    let vars = inv.ec.variables();
+    let mut optind = vars
+        .value(&OsString::from("OPTIND"))
+        .to_str()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let report_errors = !silent && vars.value(&OsString::from("OPTERR")) != "0";
+    let script = vars.value(&OsString::from("0")).to_string_lossy().to_string();
+
+    // the position inside a word is only kept while OPTIND is left alone, so `OPTIND=1` starts over
+    let word: Vec<char> = match args.get(optind - 1) {
+        Some(arg) => arg.chars().collect(),
+        None => Vec::new(),
+    };
+    let mut position = match inv.ec.option_cursor() {
+        (last, position) if last == optind && position < word.len() => position,
+        _ => 1,
+    };
+    if word.len() < 2 || word[0] != '-' || (position == 1 && word == ['-', '-']) {
+        if word == ['-', '-'] {
+            optind += 1;
+        }
+        finish_options(inv, &name, optind);
+        return Ok(1);
+    }
+
+    let option = word[position];
+    position += 1;
+    if position >= word.len() {
+        optind += 1;
+        position = 1;
+    }
+
+    let takes_argument = match optstring.find(option) {
+        Some(i) if option != ':' => optstring[i + option.len_utf8()..].starts_with(':'),
+        _ => {
+            if report_errors {
+                inv.eprint(format!("{}: illegal option -- {}\n", script, option));
+            }
+            return Ok(set_option(inv, &name, '?', if silent { Some(option.to_string()) } else { None }, optind, position));
+        }
+    };
+
+    let optarg = if !takes_argument {
+        None
+    } else if position > 1 {
+        // the rest of the word is the argument, like `-ovalue`
+        let rest: String = word[position..].iter().collect();
+        optind += 1;
+        position = 1;
+        Some(rest)
+    } else if let Some(arg) = args.get(optind - 1) {
+        optind += 1;
+        Some(arg.clone())
+    } else {
+        if report_errors {
+            inv.eprint(format!("{}: option requires an argument -- {}\n", script, option));
+        }
+        let (value, optarg) = if silent {
+            (':', Some(option.to_string()))
+        } else {
+            ('?', None)
+        };
+        return Ok(set_option(inv, &name, value, optarg, optind, position));
+    };
+
+    Ok(set_option(inv, &name, option, optarg, optind, position))
+}
+
+/// Record the option `getopts` found, and where to look for the next one.
+/// Returns getopts' status, which is 1 if one of the variables couldn't be set.
+fn set_option(inv: &mut Invocation, name: &str, option: char, optarg: Option<String>, optind: usize, position: usize) -> i32 {
+    let mut set = inv.assign(name, option.to_string());
+    set &= match optarg {
+        Some(arg) => inv.assign("OPTARG", arg),
+        None => inv.unassign("OPTARG"),
+    };
+    set &= inv.assign("OPTIND", optind.to_string());
+    inv.ec.set_option_cursor((optind, position));
+    if set {
+        0
+    } else {
+        1
+    }
+}
+
+/// Record that there are no more options
+fn finish_options(inv: &mut Invocation, name: &str, optind: usize) {
+    inv.assign(name, "?");
+    inv.unassign("OPTARG");
+    inv.assign("OPTIND", optind.to_string());
+    inv.ec.set_option_cursor((optind, 1));
+}
+
+/// local [NAME[=VALUE] ...]
+///
+/// Declare variables that only exist until the current function returns, without names the locals are listed
+pub fn local(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    if !inv.ec.variables().in_scope() {
+        inv.error("can only be used in a function");
+        return Ok(1);
+    }
+
+    if args.len() < 2 {
+        let mut listing = String::new();
+        for name in inv.ec.variables().locals() {
+            if inv.ec.variables().exists(name) {
+                listing.push_str(&format!(
+                    "{}={}\n",
+                    name.to_string_lossy(),
+                    double_quote(inv.ec.variables().value(name).to_string_lossy())
+                ));
+            }
+        }
+        inv.print(listing)?;
+        return Ok(0);
+    }
+
+    let mut code = 0;
+    for arg in &inv.args[1..] {
+        let (name, value, append) = split_assignment(arg);
+        if !inv.check_name(&name) {
+            code = 1;
+            continue;
+        }
+        if inv.ec.variables().is_readonly(&OsString::from(name.as_ref())) {
+            inv.error(format!("{}: readonly variable", name));
+            return Ok(1);
+        }
+        inv.ec.variables_mut().make_local(&OsString::from(name.as_ref()));
+        if let Some(v) = value {
+            if !inv.assign_or_append(&name, v, append) {
+                return Ok(1);
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// readonly [-p] [NAME[=VALUE] ...]
+///
+/// Stop variables from being assigned or unset, optionally assigning them first. Without names the readonly
+/// variables are listed in a form that can be read back by the shell.
+pub fn readonly(inv: &mut Invocation) -> Result<i32> {
+    let names: Vec<OsString> = inv.args[1..]
+        .iter()
+        .skip_while(|arg| *arg == "-p")
+        .skip_while(|arg| *arg == "--")
+        .cloned()
+        .collect();
+
+    if names.is_empty() {
+        let mut listing = String::new();
+        for (name, value) in inv.ec.variables().readonly() {
+            match value {
+                Some(v) => listing.push_str(&format!(
+                    "readonly {}={}\n",
+                    name.to_string_lossy(),
+                    double_quote(v.to_string_lossy())
+                )),
+                None => listing.push_str(&format!("readonly {}\n", name.to_string_lossy())),
+            }
+        }
+        inv.print(listing)?;
+        return Ok(0);
+    }
+
+    let mut code = 0;
+    for arg in names {
+        let (name, value, append) = split_assignment(&arg);
+        if !inv.check_name(&name) {
+            code = 1;
+            continue;
+        }
+        if let Some(v) = value {
+            if !inv.assign_or_append(&name, v, append) {
+                code = 1;
+                continue;
+            }
+        }
+        inv.ec.variables_mut().set_readonly(&OsString::from(name.as_ref()));
+    }
+    Ok(code)
+}
+
+/// let EXPRESSION ...
+///
+/// Evaluate each argument as an arithmetic expression, failing if the last one is 0
+pub fn let_(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    if args.len() < 2 {
+        inv.error("expression expected");
+        return Ok(1);
+    }
+
+    let mut last = false;
+    for arg in &args[1..] {
+        let value = match expr::parse(arg).map(|e| e.evaluate(inv.ec.variables_mut())) {
+            Ok(Ok(value)) => value,
+            Ok(Err(e)) => {
+                inv.error(e);
+                return Ok(1);
+            }
+            Err(e) => {
+                inv.error(format!("{}: {}", arg, e.kind()));
+                return Ok(1);
+            }
+        };
+
+        if let Expr::Number(n) = value {
+            // the evaluator works with floats, so dividing by zero shows up as an infinite or NaN result
+            if !n.is_finite() {
+                inv.error(format!("{}: division by 0", arg));
+                return Ok(1);
+            }
+        }
+        last = value.as_boolean();
+    }
+    Ok(if last { 0 } else { 1 })
+}
+
+/// declare [-fFginprxaA] [+inrx] [NAME[=VALUE] ...]
+///
+/// Set or remove the attributes of variables, optionally assigning them at the same time:
+/// -i evaluates assignments as arithmetic, -r makes the variable readonly, and -x exports it, a `+` removes them.
+/// -n makes the variable a nameref, VALUE is the name of the variable it stands for from then on.
+/// -a makes the variable an array indexed by number and -A an associative array, neither can be undone.
+/// An array's VALUE can be a list of elements like `(a b)` or `([KEY]=a)`.
+/// Without names, or with -p, the variables are printed as `declare` commands that would recreate them.
+/// Inside a function the variables are made local unless -g is given.
+/// -f prints the definitions of functions instead, and -F only their names.
+pub fn declare(inv: &mut Invocation) -> Result<i32> {
+    let args = inv.text_args();
+    let mut adding = String::new();
+    let mut removing = String::new();
+    let mut print = false;
+    let mut functions = false;
+    let mut definitions = false;
+    let mut global = false;
+    let mut first_name = 1;
+    for arg in &args[1..] {
+        if arg == "--" {
+            first_name += 1;
+            break;
+        }
+        if arg.len() < 2 || !(arg.starts_with('-') || arg.starts_with('+')) {
+            break;
+        }
+        first_name += 1;
+        for c in arg[1..].chars() {
+            match c {
+                'f' => {
+                    functions = true;
+                    definitions = true;
+                }
+                'F' => functions = true,
+                'g' => global = true,
+                'p' => print = true,
+                'i' | 'n' | 'r' | 'x' | 'a' | 'A' if arg.starts_with('-') => adding.push(c),
+                'i' | 'n' | 'r' | 'x' | 'a' | 'A' => removing.push(c),
+                _ => {
+                    inv.error(format!("-{}: invalid option", c));
+                    return Ok(2);
+                }
+            }
+        }
+    }
+    let names = &args[first_name..];
+
+    if functions {
+        return list_functions(inv, names, definitions);
+    }
+
+    if names.is_empty() {
+        let vars = inv.ec.variables();
+        let all: BTreeSet<&OsString> = vars.iter().map(|(name, _)| name).chain(vars.arrays().map(|(name, _)| name)).collect();
+        let mut listing = String::new();
+        for name in all {
+            if adding.chars().all(|c| attributes(inv, name).contains(c)) {
+                listing.push_str(&declaration(inv, name));
+            }
+        }
+        inv.print(listing)?;
+        return Ok(0);
+    }
+
+    let mut code = 0;
+    if print {
+        let mut listing = String::new();
+        for name in names {
+            let key = OsString::from(name.as_str());
+            if inv.ec.variables().exists(&key) || !attributes(inv, &key).is_empty() {
+                listing.push_str(&declaration(inv, &key));
+            } else {
+                inv.error(format!("{}: not found", name));
+                code = 1;
+            }
+        }
+        inv.print(listing)?;
+        return Ok(code);
+    }
+
+    let make_local = !global && inv.ec.variables().in_scope();
+    for arg in &inv.args[first_name..] {
+        let (name, value, append) = split_assignment(arg);
+        if !inv.check_name(&name) {
+            code = 1;
+            continue;
+        }
+        let key = OsString::from(name.as_ref());
+        if inv.ec.variables().is_readonly(&key) && (value.is_some() || make_local || removing.contains('r')) {
+            inv.error(format!("{}: readonly variable", name));
+            code = 1;
+            continue;
+        }
+
+        let vars = inv.ec.variables();
+        if (removing.contains('a') || removing.contains('A')) && vars.is_array(&key) {
+            inv.error(format!("{}: cannot destroy array variables in this way", name));
+            code = 1;
+            continue;
+        }
+        if adding.contains('a') && vars.is_array(&key) && !vars.is_indexed(&key) {
+            inv.error(format!("{}: cannot convert associative to indexed array", name));
+            code = 1;
+            continue;
+        }
+        if adding.contains('A') && vars.is_indexed(&key) {
+            inv.error(format!("{}: cannot convert indexed to associative array", name));
+            code = 1;
+            continue;
+        }
+
+        if make_local {
+            inv.ec.variables_mut().make_local(&key);
+        }
+        if adding.contains('n') {
+            if let Err(e) = inv.ec.variables_mut().make_reference(&key, value.map(OsString::from)) {
+                inv.error(e);
+                code = 1;
+                continue;
+            }
+        } else if removing.contains('n') {
+            inv.ec.variables_mut().unset_nameref(&key);
+        }
+        let made_array = if adding.contains('A') {
+            inv.ec.variables_mut().make_array(&key)
+        } else if adding.contains('a') {
+            inv.ec.variables_mut().make_indexed_array(&key)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = made_array {
+            inv.error(e);
+            code = 1;
+            continue;
+        }
+        for c in adding.chars() {
+            match c {
+                'i' => inv.ec.variables_mut().set_integer(&key),
+                'x' => inv.ec.variables_mut().export(&key),
+                _ => (),
+            }
+        }
+        for c in removing.chars() {
+            match c {
+                'i' => inv.ec.variables_mut().unset_integer(&key),
+                'x' => inv.ec.variables_mut().unexport(&key),
+                _ => (),
+            }
+        }
+
+        // a nameref's value is the name it was given, not an assignment to what it refers to
+        let value = value.filter(|_| !adding.contains('n'));
+        let elements = value
+            .filter(|_| inv.ec.variables().is_array(&key))
+            .and_then(|v| word::parse_compound(&v.to_string_lossy()));
+        if let Some(elements) = elements {
+            let assigned = elements
+                .compound_elements(inv.ec.variables_mut())
+                .and_then(|elements| inv.ec.variables_mut().define_array(&key, elements, append));
+            if let Err(e) = assigned {
+                inv.error(e);
+                code = 1;
+                continue;
+            }
+        } else if let Some(v) = value {
+            if !inv.assign_or_append(&name, v, append) {
+                code = 1;
+                continue;
+            }
+        }
+        if adding.contains('r') {
+            inv.ec.variables_mut().set_readonly(&key);
+        }
+    }
+    Ok(code)
+}
+
+/// List functions for `declare -f` and `declare -F`, all of them or just the ones asked for,
+/// with their definitions or only their names
+fn list_functions(inv: &mut Invocation, names: &[String], definitions: bool) -> Result<i32> {
+    let functions = inv.ec.functions();
+    let describe = |name: &str| match functions.definition(name) {
+        Some(definition) if definitions => Some(format!("{}\n", definition)),
+        Some(_) => Some(format!("declare -f {}\n", name)),
+        None => None,
+    };
+    let mut listing = String::new();
+    let mut code = 0;
+    if names.is_empty() {
+        for name in functions.names() {
+            listing.extend(describe(name));
+        }
+    }
+    for name in names {
+        if let Some(text) = describe(name) {
+            listing.push_str(&text);
+        } else {
+            code = 1;
+        }
+    }
+    inv.print(listing)?;
+    Ok(code)
+}
+
+/// The `declare` flags describing a variable's attributes, in the order they're printed
+fn attributes(inv: &Invocation, name: &OsString) -> String {
+    let vars = inv.ec.variables();
+    let mut flags = String::new();
+    if vars.is_indexed(name) && !vars.is_nameref(name) {
+        flags.push('a');
+    } else if vars.is_array(name) && !vars.is_nameref(name) {
+        flags.push('A');
+    }
+    if vars.is_integer(name) {
+        flags.push('i');
+    }
+    if vars.is_nameref(name) {
+        flags.push('n');
+    }
+    if vars.is_readonly(name) {
+        flags.push('r');
+    }
+    if vars.is_exported(name) {
+        flags.push('x');
+    }
+    flags
+}
+
+/// A `declare` command that recreates a variable with its attributes and value
+fn declaration(inv: &Invocation, name: &OsString) -> String {
+    let mut flags = attributes(inv, name);
+    if flags.is_empty() {
+        flags.push('-');
+    }
+    let vars = inv.ec.variables();
+    if let Some(target) = vars.reference(name) {
+        format!(
+            "declare -{} {}={}\n",
+            flags,
+            name.to_string_lossy(),
+            double_quote(target.to_string_lossy())
+        )
+    } else if let Some(elements) = vars.elements(name) {
+        let elements = word::compound_text(elements.into_iter().map(|(key, v)| (Some(key.as_str()), v.as_os_str())));
+        format!("declare -{} {}={}\n", flags, name.to_string_lossy(), elements)
+    } else if vars.exists(name) {
+        format!(
+            "declare -{} {}={}\n",
+            flags,
+            name.to_string_lossy(),
+            double_quote(vars.value(name).to_string_lossy())
+        )
+    } else {
+        format!("declare -{} {}\n", flags, name.to_string_lossy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::testing::{run, run_with_bytes};
+
+    #[test]
+    fn export_passes_variables_to_children() {
+        let out = run("FOO=1; sh -c 'echo ${FOO-unset}'; export FOO; sh -c 'echo ${FOO-unset}'");
+        assert_eq!(out.stdout, "unset\n1\n");
+        let out = run("export PATH=$PATH:/opt/bin BAR=two; sh -c 'echo \"${PATH##*:} $BAR\"'");
+        assert_eq!(out.stdout, "/opt/bin two\n");
+    }
+
+    #[test]
+    fn assigned_values_keep_their_bytes() {
+        let script = "export E=\"$BYTES\"; sh -c 'printf %s \"$E\"'; declare D=\"$BYTES\"; readonly R+=\"$BYTES\"\n\
+                      function f { local L=\"$BYTES\"; echo \"$D$R$L\"; }; f";
+        assert_eq!(run_with_bytes(b"\xe9", script), b"\xe9\xe9\xe9\xe9\n");
+    }
+
+    #[test]
+    fn export_n_stops_passing_a_variable() {
+        let out = run("export FOO=1; export -n FOO; sh -c 'echo ${FOO-unset}'; echo $FOO");
+        assert_eq!(out.stdout, "unset\n1\n");
+    }
+
+    #[test]
+    fn export_p_lists_reusable_assignments() {
+        let out = run("export RUSH_TEST_EXPORT='a \"b\" $c'; export -p");
+        assert!(out.stdout.contains("export RUSH_TEST_EXPORT=\"a \\\"b\\\" \\$c\"\n"));
+    }
+
+    #[test]
+    fn unset_variables_are_unset_not_empty() {
+        let out = run("FOO=1; BAR=2; unset FOO BAR; echo \"${FOO-unset} ${BAR-unset}\"");
+        assert_eq!(out.stdout, "unset unset\n");
+        assert_eq!(run("unset NOT_DEFINED_ANYWHERE").status, 0);
+    }
+
+    #[test]
+    fn unset_f_removes_functions() {
+        let out = run("function f { echo called; }\nf; unset -f f; f");
+        assert_eq!(out.stdout, "called\n");
+        assert_eq!(out.status, 127);
+    }
+
+    #[test]
+    fn unset_prefers_variables_unless_told_otherwise() {
+        let out = run("function f { echo called; }\nf=1; unset f; echo ${f-unset}; f; unset f; f");
+        assert_eq!(out.stdout, "unset\ncalled\n");
+        let out = run("function f { echo called; }\nunset -v f; f");
+        assert_eq!(out.stdout, "called\n");
+    }
+
+    #[test]
+    fn unset_readonly_fails() {
+        let out = run("readonly FOO=1; unset FOO; echo $? $FOO");
+        assert_eq!(out.stdout, "1 1\n");
+        assert!(out.stderr.contains("FOO: cannot unset: readonly variable"));
+    }
+
+    #[test]
+    fn readonly_variables_keep_their_value() {
+        let script = "readonly R=1\n\
+                      R=2; echo \"$? $R\"\n\
+                      export R=3; echo \"$? $R\"\n\
+                      echo 4 >in; read R <in; echo \"$? $R\"\n\
+                      declare R=5; echo \"$? $R\"";
+        let out = run(script);
+        assert_eq!(out.stdout, "1 1\n1 1\n1 1\n1 1\n");
+        assert_eq!(out.stderr.matches("R: readonly variable").count(), 4, "{}", out.stderr);
+    }
+
+    #[test]
+    fn readonly_marks_existing_variables() {
+        let out = run("PATH_COPY=$PATH; readonly PATH_COPY; PATH_COPY=/; echo $?; [ \"$PATH_COPY\" = \"$PATH\" ]");
+        assert_eq!(out.stdout, "1\n");
+        assert_eq!(out.status, 0);
+    }
+
+    #[test]
+    fn readonly_p_lists_reusable_declarations() {
+        let out = run("readonly A='x \"y\"' B; readonly -p");
+        assert!(out.stdout.contains("readonly A=\"x \\\"y\\\"\"\n"), "{}", out.stdout);
+        assert!(out.stdout.contains("readonly B\n"), "{}", out.stdout);
+    }
+
+    #[test]
+    fn declare_sets_and_removes_attributes() {
+        let out = run("declare -x X=1; sh -c 'echo ${X-unset}'; declare +x X; sh -c 'echo ${X-unset}'; echo $X");
+        assert_eq!(out.stdout, "1\nunset\n1\n");
+        let out = run("declare -i n=2*3; echo $n; n=n+1; echo $n; declare +i n; n=n+1; echo $n");
+        assert_eq!(out.stdout, "6\n7\nn+1\n");
+        let out = run("declare -r R=1; R=2; echo \"$? $R\"; declare +r R; echo $?");
+        assert_eq!(out.stdout, "1 1\n1\n");
+        assert!(out.stderr.contains("R: readonly variable"), "{}", out.stderr);
+    }
+
+    #[test]
+    fn declare_combines_attributes() {
+        let out = run("declare -ix N=1+1; sh -c 'echo $N'; declare -p N; declare -ir M=5; declare -p M");
+        assert_eq!(out.stdout, "2\ndeclare -ix N=\"2\"\ndeclare -ir M=\"5\"\n");
+        let out = run("declare -x A=1 B=2; declare +x A; declare -p A B");
+        assert_eq!(out.stdout, "declare -- A=\"1\"\ndeclare -x B=\"2\"\n");
+    }
+
+    #[test]
+    fn declare_p_output_can_be_read_back() {
+        let out = run("declare -ix N=3; S='a \"b\" $c'; declare -p N S >saved; unset N S; . ./saved; declare -p N S");
+        assert_eq!(out.stdout, "declare -ix N=\"3\"\ndeclare -- S=\"a \\\"b\\\" \\$c\"\n");
+        let out = run("declare -p NOT_DEFINED_ANYWHERE; echo $?");
+        assert_eq!(out.stdout, "1\n");
+    }
+
+    #[test]
+    fn declare_f_prints_functions() {
+        let out = run("function f { echo called; }\ndeclare -F; declare -f f >saved; unset -f f; . ./saved; f");
+        assert_eq!(out.stdout, "declare -f f\ncalled\n");
+        assert_eq!(run("declare -f missing").status, 1);
+    }
+
+    #[test]
+    fn declare_in_a_function_makes_locals() {
+        let out = run("V=outer; function f { declare V=inner; declare -g G=global; echo $V; }\nf; echo $V $G");
+        assert_eq!(out.stdout, "inner\nouter global\n");
+    }
+
+    #[test]
+    fn declare_a_makes_indexed_arrays() {
+        let out = run("declare -a E; declare -p E; declare -a A=(a \"b c\" [5]=d e); declare -p A; echo ${!A[@]}");
+        assert_eq!(out.stdout, "declare -a E=()\ndeclare -a A=([0]=\"a\" [1]=\"b c\" [5]=\"d\" [6]=\"e\" )\n0 1 5 6\n");
+        let out = run("declare -a Q='(x y)'; echo ${Q[1]}; declare -a | grep Q=");
+        assert_eq!(out.stdout, "y\ndeclare -a Q=([0]=\"x\" [1]=\"y\" )\n");
+    }
+
+    #[test]
+    fn associative_arrays_are_keyed_by_strings() {
+        let out = run("declare -A colors; colors[red]=#f00; k=green; colors[$k]=#0f0; colors[1+1]=two; echo ${colors[red]} ${colors[green]} ${colors[1+1]}");
+        assert_eq!(out.stdout, "#f00 #0f0 two\n");
+        let out = run("declare -A M=([a b]=1 [c]=\"2 3\"); printf '<%s>' \"${!M[@]}\" \"${M[@]}\"; echo");
+        assert_eq!(out.stdout, "<a b><c><1><2 3>\n");
+        let out = run("declare -A M; M=scalar; echo ${M[0]}; declare -p M");
+        assert_eq!(out.stdout, "scalar\ndeclare -A M=([0]=\"scalar\" )\n");
+    }
+
+    #[test]
+    fn integer_variables_evaluate_what_they_are_assigned() {
+        let out = run("declare -i n; n=2+3; echo $n; n=garbage; echo $n; n=7; n+=4; echo $n; declare -p n; declare -i m=4*2; echo $m");
+        assert_eq!(out.stdout, "5\n0\n11\ndeclare -i n=\"11\"\n8\n");
+        let out = run("declare -i n=1+1; export n; sh -c 'echo $n'; unset n; n=2+3; echo $n; declare -p n");
+        assert_eq!(out.stdout, "2\n2+3\ndeclare -- n=\"2+3\"\n");
+    }
+
+    #[test]
+    fn indexed_arrays_order_by_number() {
+        let out = run("A=(zero); A[10]=ten; A[2]=two; A[1+2]=three; echo ${A[@]}; echo ${A[-1]} ${A[2*1]}");
+        assert_eq!(out.stdout, "zero two three ten\nten two\n");
+        let out = run("A=(a b); A+=(c d); A+=([1]=B); echo ${A[@]}; unset A[0]; echo ${!A[@]}");
+        assert_eq!(out.stdout, "a B c d\n1 2 3\n");
+        let out = run("S=first; S[1]=second; declare -p S");
+        assert_eq!(out.stdout, "declare -a S=([0]=\"first\" [1]=\"second\" )\n");
+    }
+
+    #[test]
+    fn declare_p_recreates_arrays() {
+        let out = run("declare -a A=(a [7]='b \"c\"'); declare -A M=([k]=v [\"x y\"]=z); declare -p A M >saved; unset A M; . ./saved; declare -p A M");
+        assert_eq!(
+            out.stdout,
+            "declare -a A=([0]=\"a\" [7]=\"b \\\"c\\\"\" )\ndeclare -A M=([k]=\"v\" [\"x y\"]=\"z\" )\n"
+        );
+    }
+
+    #[test]
+    fn array_kinds_cannot_be_changed_or_removed() {
+        let out = run("declare -a A=(1); declare +a A; echo $?; declare -A A; echo $?; declare -A M; declare -a M; echo $?");
+        assert_eq!(out.stdout, "1\n1\n1\n");
+        assert!(out.stderr.contains("A: cannot destroy array variables in this way"), "{}", out.stderr);
+        assert!(out.stderr.contains("A: cannot convert indexed to associative array"), "{}", out.stderr);
+        assert!(out.stderr.contains("M: cannot convert associative to indexed array"), "{}", out.stderr);
+    }
+
+    #[test]
+    fn invalid_names_are_refused_and_the_valid_ones_still_set() {
+        let out = run("export 9x=1 ok=2; echo $? $ok");
+        assert_eq!(out.stdout, "1 2\n");
+        assert_eq!(out.stderr, "rush: export: '9x': not a valid identifier\n");
+        let out = run("readonly foo-bar=1; echo $?; declare bad.name=1; echo $?");
+        assert_eq!(out.stdout, "1\n1\n");
+        assert_eq!(
+            out.stderr,
+            "rush: readonly: 'foo-bar': not a valid identifier\nrush: declare: 'bad.name': not a valid identifier\n"
+        );
+        let out = run("function f { local a-b=1 c=2; echo $? $c; }; f; getopts a 1x; echo $?");
+        assert_eq!(out.stdout, "1 2\n1\n");
+        assert_eq!(
+            out.stderr,
+            "rush: local: 'a-b': not a valid identifier\nrush: getopts: '1x': not a valid identifier\n"
+        );
+    }
+
+    #[test]
+    fn namerefs_read_and_write_the_variable_they_name() {
+        assert_eq!(run("x=1; declare -n r=x; echo $r; r=2; echo $x ${!r}").stdout, "1\n2 x\n");
+        let out = run("function set_it { declare -n out=$1; out=done; }; set_it res; echo $res ${out-unset}");
+        assert_eq!(out.stdout, "done unset\n");
+        let out = run("declare -n r=arr; r[1]=b; echo ${arr[1]}; declare -p r; declare +n r; echo $r");
+        assert_eq!(out.stdout, "b\ndeclare -n r=\"arr\"\narr\n");
+        assert_eq!(run("v=w; w=deep; echo ${!v}").stdout, "deep\n");
+    }
+
+    #[test]
+    fn unset_goes_through_a_nameref_unless_given_n() {
+        let out = run("x=1; declare -n r=x; unset r; echo ${x-unset} ${!r}; unset -n r; echo ${r-gone}");
+        assert_eq!(out.stdout, "unset x\ngone\n");
+    }
+
+    #[test]
+    fn circular_and_invalid_references_are_refused() {
+        let out = run("declare -n c=c; echo $?; declare -n a=b; declare -n b=a; echo $?; declare -n p=q q=s s=p; echo $?");
+        assert_eq!(out.stdout, "1\n1\n1\n");
+        assert_eq!(
+            out.stderr,
+            "rush: declare: c: circular name reference\n\
+             rush: declare: b: circular name reference\n\
+             rush: declare: s: circular name reference\n"
+        );
+        let out = run("declare -n r=1x; echo $?");
+        assert_eq!(out.stdout, "1\n");
+        assert_eq!(out.stderr, "rush: declare: '1x': not a valid identifier\n");
+    }
+
+    #[test]
+    fn appending_assignments_add_to_what_was_there() {
+        let out = run("msg=hello; msg+=\" world\"; unset u; u+=x; PATH+=\":/opt/bin\"; echo $msg $u; printenv PATH");
+        assert_eq!(out.stdout, format!("hello world x\n{}:/opt/bin\n", std::env::var("PATH").unwrap()));
+        let out = run("X=1; X+=2 sh -c 'echo $X'; echo $X; a=(x y); a[1]+=Y; a+=(z); echo ${a[@]}");
+        assert_eq!(out.stdout, "12\n1\nx yY z\n");
+    }
+
+    #[test]
+    fn builtins_take_appending_arguments() {
+        let script = "function f { local l=a; local l+=b; echo $l; }; f\n\
+                      export E=1; export E+=2; sh -c 'echo $E'; declare d=1; declare d+=2; echo $d\n\
+                      declare -i n=5; declare n+=2+3; echo $n; r=a; readonly r+=b; echo $r; readonly r+=c; echo $?";
+        let out = run(script);
+        assert_eq!(out.stdout, "ab\n12\n12\n10\nab\n1\n");
+        assert_eq!(out.stderr, "rush: readonly: r: readonly variable\n");
+    }
+}
@@ -0,0 +1,47 @@
+//! Aliases replace the first word of a simple command with some other shell input
+use std::collections::btree_map;
+use std::collections::BTreeMap;
+
+pub type Name = String;
+pub type Value = String;
+pub type Iter<'a> = btree_map::Iter<'a, Name, Value>;
+
+#[derive(Debug, Clone, Default)]
+pub struct Aliases {
+    map: BTreeMap<Name, Value>,
+}
+
+impl Aliases {
+    pub fn new() -> Aliases {
+        Aliases::default()
+    }
+
+    /// Whether a name can be used for an alias, it can't contain anything that would end or quote a word
+    pub fn is_valid_name(name: &str) -> bool {
+        !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || "/$`=\"'\\<>|&;()".contains(c))
+    }
+
+    pub fn define<T: Into<Name>, U: Into<Value>>(&mut self, k: T, v: U) {
+        self.map.insert(k.into(), v.into());
+    }
+
+    pub fn remove(&mut self, k: &str) -> Option<Value> {
+        self.map.remove(k)
+    }
+
+    pub fn value(&self, k: &str) -> Option<&Value> {
+        self.map.get(k)
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        self.map.iter()
+    }
+}
@@ -1,9 +1,11 @@
-use lang::ast;
+use crate::lang::ast;
 use std::collections::btree_map;
 use std::collections::BTreeMap;
+use std::rc::Rc;
 
 pub type Name = String;
-pub type Value = ast::Command;
+/// A function's body is shared, so calling it doesn't copy the whole tree
+pub type Value = Rc<ast::Command>;
 pub type Iter<'b> = btree_map::Iter<'b, Name, Value>;
 pub type IterMut<'b> = btree_map::IterMut<'b, Name, Value>;
 pub type IntoIter = btree_map::IntoIter<Name, Value>;
@@ -34,18 +36,34 @@ impl Functions {
         }
     }
 
-    pub fn remove(&mut self, k: &Name) {
-        self.map.remove(k);
+    pub fn remove(&mut self, k: &str) -> Option<Value> {
+        self.map.remove(k)
     }
 
-    pub fn value(&self, k: &Name) -> Option<Value> {
-        self.map.get(k).map(|v| v.clone())
+    /// A function's body, for calling it
+    pub fn value(&self, k: &str) -> Option<Value> {
+        self.map.get(k).cloned()
     }
 
-    pub fn exists(&self, k: &Name) -> bool {
+    /// A function's body, for printing it
+    pub fn body(&self, k: &str) -> Option<&ast::Command> {
+        self.map.get(k).map(|v| &**v)
+    }
+
+    /// The function written out as shell text, `function NAME { ... }`, which defines it again when it's run
+    pub fn definition(&self, k: &str) -> Option<String> {
+        self.body(k).map(|body| format!("function {} {}", k, body))
+    }
+
+    pub fn exists(&self, k: &str) -> bool {
         self.map.contains_key(k)
     }
 
+    /// Iterate over the names of every function, sorted
+    pub fn names<'b>(&'b self) -> impl Iterator<Item = &'b Name> + 'b {
+        self.map.keys()
+    }
+
     pub fn entry<'a, T: Into<Name>>(&'a mut self, key: T) -> Entry {
         match self.map.entry(key.into()) {
             btree_map::Entry::Occupied(v) => Entry::Occupied(OccupiedEntry { entry: v }),
@@ -176,3 +194,46 @@ impl IntoIterator for Functions {
         self.map.into_iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_functions_no_longer_exist() {
+        let mut funcs = Functions::new();
+        funcs.insert("f".to_string(), Rc::new(ast::Command::from("true")));
+        assert!(funcs.remove("f").is_some());
+        assert!(funcs.value("f").is_none());
+        assert!(funcs.remove("f").is_none());
+    }
+
+    #[test]
+    fn names_are_listed_in_order() {
+        let mut funcs = Functions::new();
+        for name in &["b", "a", "c"] {
+            funcs.insert(name.to_string(), Rc::new(ast::Command::from("true")));
+        }
+        assert_eq!(funcs.names().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+        assert!(funcs.exists("a"));
+        assert!(!funcs.exists("d"));
+    }
+
+    #[test]
+    fn calls_share_the_body() {
+        let mut funcs = Functions::new();
+        funcs.insert("f".to_string(), Rc::new(ast::Command::from("echo a; echo b")));
+        let (first, second) = (funcs.value("f").unwrap(), funcs.value("f").unwrap());
+        assert!(Rc::ptr_eq(&first, &second));
+        assert_eq!(funcs.body("f").unwrap().to_string(), first.to_string());
+    }
+
+    #[test]
+    fn definitions_define_the_function_again() {
+        let mut funcs = Functions::new();
+        funcs.insert("f".to_string(), Rc::new(ast::Command::from("{ echo a; }")));
+        let definition = funcs.definition("f").unwrap();
+        assert!(definition.starts_with("function f {"), "{}", definition);
+        assert!(funcs.definition("g").is_none());
+    }
+}
@@ -0,0 +1,107 @@
+//! The locations of programs that have already been found in $PATH, so the search doesn't need to be repeated
+use std::collections::btree_map;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+pub type Name = String;
+pub type Iter<'a> = btree_map::Iter<'a, Name, Hashed>;
+
+#[derive(Debug, Clone)]
+pub struct Hashed {
+    pub path: PathBuf,
+    /// The number of times the command has been run through this entry
+    pub hits: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PathCache {
+    map: BTreeMap<Name, Hashed>,
+    /// The value of $PATH the entries were found with
+    search_path: OsString,
+}
+
+impl PathCache {
+    pub fn new() -> PathCache {
+        PathCache::default()
+    }
+
+    /// Forget every entry if $PATH has changed since they were found
+    pub fn sync(&mut self, search_path: &OsString) {
+        if &self.search_path != search_path {
+            self.map.clear();
+            self.search_path = search_path.clone();
+        }
+    }
+
+    /// Find the cached location of a command and count the use,
+    /// entries that no longer exist on the filesystem are dropped
+    pub fn hit(&mut self, k: &str) -> Option<PathBuf> {
+        let exists = match self.map.get(k) {
+            Some(hashed) => hashed.path.exists(),
+            None => return None,
+        };
+        if !exists {
+            self.map.remove(k);
+            return None;
+        }
+
+        let hashed = self.map.get_mut(k).unwrap();
+        hashed.hits += 1;
+        Some(hashed.path.clone())
+    }
+
+    pub fn insert<T: Into<Name>>(&mut self, k: T, path: PathBuf, hits: usize) {
+        self.map.insert(k.into(), Hashed { path, hits });
+    }
+
+    pub fn remove(&mut self, k: &str) -> Option<Hashed> {
+        self.map.remove(k)
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        self.map.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_count_hits() {
+        let mut cache = PathCache::new();
+        cache.insert("sh", PathBuf::from("/"), 0);
+        assert_eq!(cache.hit("sh"), Some(PathBuf::from("/")));
+        assert_eq!(cache.hit("sh"), Some(PathBuf::from("/")));
+        assert_eq!(cache.iter().next().map(|(_, hashed)| hashed.hits), Some(2));
+        assert_eq!(cache.hit("ls"), None);
+    }
+
+    #[test]
+    fn missing_files_are_dropped() {
+        let mut cache = PathCache::new();
+        cache.insert("gone", PathBuf::from("/nonexistent/rush-test"), 0);
+        assert_eq!(cache.hit("gone"), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn changing_the_search_path_clears_the_cache() {
+        let mut cache = PathCache::new();
+        cache.sync(&OsString::from("/bin"));
+        cache.insert("sh", PathBuf::from("/"), 0);
+        cache.sync(&OsString::from("/bin"));
+        assert!(!cache.is_empty());
+        cache.sync(&OsString::from("/usr/bin:/bin"));
+        assert!(cache.is_empty());
+    }
+}
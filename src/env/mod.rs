@@ -1,6 +1,12 @@
+pub mod aliases;
 pub mod functions;
+pub mod hash;
+pub mod options;
 pub mod traps;
 pub mod variables;
 
+pub use self::aliases::Aliases;
 pub use self::functions::Functions;
+pub use self::hash::PathCache;
+pub use self::options::Options;
 pub use self::variables::Variables;
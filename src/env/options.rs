@@ -0,0 +1,52 @@
+//! The settings `set` turns on and off, like `set -n` or `set -o noexec`
+
+/// Each setting's long name and its single letter flag
+const FLAGS: &[(&str, char)] = &[("noexec", 'n'), ("notify", 'b')];
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Read commands without running them
+    pub noexec: bool,
+    /// Announce background jobs finishing straight away, instead of waiting for the next prompt
+    pub notify: bool,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    /// The long name of the setting a flag like `n` stands for
+    pub fn flag_name(flag: char) -> Option<&'static str> {
+        FLAGS.iter().find(|&&(_, f)| f == flag).map(|&(name, _)| name)
+    }
+
+    /// Whether a setting is on, `None` if there's no setting with that name
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "noexec" => Some(self.noexec),
+            "notify" => Some(self.notify),
+            _ => None,
+        }
+    }
+
+    /// Turn a setting on or off, `false` if there's no setting with that name
+    pub fn set(&mut self, name: &str, on: bool) -> bool {
+        match name {
+            "noexec" => self.noexec = on,
+            "notify" => self.notify = on,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Every setting with whether it's on, sorted by name
+    pub fn all(&self) -> Vec<(&'static str, bool)> {
+        let mut all: Vec<(&'static str, bool)> = FLAGS
+            .iter()
+            .map(|&(name, _)| (name, self.get(name).unwrap_or(false)))
+            .collect();
+        all.sort();
+        all
+    }
+}
@@ -1,90 +1,239 @@
-use nix;
+use lazy_static::lazy_static;
 use nix::sys::signal;
 pub use nix::sys::signal::Signal;
 use std::collections::HashMap;
 use std::os::raw::c_int;
-use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::RwLock;
 
 lazy_static! {
     static ref GLOBAL_TRAPS: RwLock<Traps> = { RwLock::new(Traps::with_capacity(31)) };
 }
 
-pub type LineFn = Box<FnMut() + Send + Sync + 'static>;
-pub type Traps = HashMap<Signal, Vec<Action>>;
-pub type TrapIter<'a> = slice::Iter<'a, Action>;
+/// The signals that arrived since the last call to `take_pending`, one bit per signal number
+static PENDING: AtomicUsize = AtomicUsize::new(0);
 
+/// Whether SIGINT is caught so it can't kill the shell, even without a trap for it
+static CATCHING_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+/// Whether trapped signals cut short a wait for children, instead of it being restarted
+static INTERRUPTING_WAITS: AtomicBool = AtomicBool::new(false);
+
+/// Whether there's a DEBUG trap with commands to run, so simple commands can check without taking the lock
+static DEBUGGING: AtomicBool = AtomicBool::new(false);
+
+pub type Traps = HashMap<Condition, Action>;
+
+/// What a trap waits for, a signal, the shell exiting, or a simple command about to run
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Condition {
+    Exit,
+    Debug,
+    Signal(Signal),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Action {
-    NoOp,
+    /// The signal is ignored, by the shell and the commands it runs
+    Ignore,
+    /// Shell input to run
     Eval(String),
-    Func(LineFn),
 }
 
-pub fn trap(sig: Signal, a: Action) -> nix::Result<()> {
-    let mut mut_traps = GLOBAL_TRAPS.write().unwrap();
-    match mut_traps.get_mut(&sig) {
-        Some(v) => {
-            v.push(a);
-            return Ok(());
+/// Set the action for a condition, replacing any that was there before.
+/// Signals only ever set a flag in their handler, the action is run later by `take_pending`'s caller.
+pub fn trap(cond: Condition, a: Action) -> nix::Result<()> {
+    match cond {
+        Condition::Signal(sig) => {
+            let handler = match a {
+                Action::Ignore => signal::SigHandler::SigIgn,
+                Action::Eval(_) => signal::SigHandler::Handler(__rush_global_signal_handler),
+            };
+            set_handler(sig, handler)?;
         }
-        None => unsafe {
-            signal::sigaction(
-                sig,
-                &signal::SigAction::new(
-                    signal::SigHandler::Handler(__rush_global_signal_handler),
-                    signal::SaFlags::empty(),
-                    signal::SigSet::empty(),
-                ),
-            )
-        }.map(|_| ())?,
-    };
-    mut_traps.insert(sig, vec![a]);
+        Condition::Debug => DEBUGGING.store(a != Action::Ignore, Ordering::SeqCst),
+        Condition::Exit => (),
+    }
+    GLOBAL_TRAPS.write().unwrap().insert(cond, a);
     Ok(())
 }
 
-pub fn trap_s<T: AsRef<str>>(sig: T, a: Action) -> nix::Result<()> {
-    trap(
-        match parse_signal(sig) {
-            Some(v) => v,
-            None => return Err(nix::Error::UnsupportedOperation),
-        },
-        a,
+/// Remove a condition's trap, giving the signal its default behavior back
+pub fn release(cond: Condition) -> nix::Result<()> {
+    GLOBAL_TRAPS.write().unwrap().remove(&cond);
+    match cond {
+        Condition::Signal(Signal::SIGINT) if catching_interrupts() => set_handler(
+            Signal::SIGINT,
+            signal::SigHandler::Handler(__rush_global_signal_handler),
+        ),
+        Condition::Signal(sig) => set_handler(sig, signal::SigHandler::SigDfl),
+        Condition::Debug => {
+            DEBUGGING.store(false, Ordering::SeqCst);
+            Ok(())
+        }
+        Condition::Exit => Ok(()),
+    }
+}
+
+/// Keep the shell alive through SIGINT, the way interactive shells are.
+/// Without a trap it's only recorded, and `take_pending`'s caller decides what an interrupt means.
+pub fn catch_interrupts() -> nix::Result<()> {
+    CATCHING_INTERRUPTS.store(true, Ordering::SeqCst);
+    if is_trapped(Condition::Signal(Signal::SIGINT)) {
+        return Ok(());
+    }
+    set_handler(
+        Signal::SIGINT,
+        signal::SigHandler::Handler(__rush_global_signal_handler),
     )
 }
 
-pub fn release(sig: Signal) -> nix::Result<()> {
-    let mut mut_traps = GLOBAL_TRAPS.write().unwrap();
-    mut_traps.remove(&sig);
-    unsafe {
-        signal::sigaction(
-            sig,
-            &signal::SigAction::new(
-                signal::SigHandler::SigDfl,
-                signal::SaFlags::empty(),
-                signal::SigSet::empty(),
-            ),
-        )
-    }.map(|_| ())
+/// Let SIGINT kill the process again, unless it's trapped, for the children of a shell that caught it
+pub fn restore_interrupts() {
+    if !CATCHING_INTERRUPTS.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    if !is_trapped(Condition::Signal(Signal::SIGINT)) {
+        set_handler(Signal::SIGINT, signal::SigHandler::SigDfl).ok();
+    }
+}
+
+/// Have SIGCHLD interrupt whatever the shell is blocked in, so `set -b` can announce jobs as they finish.
+/// A trap for it does that already, otherwise it's only recorded.
+pub fn watch_children() -> nix::Result<()> {
+    if is_trapped(Condition::Signal(Signal::SIGCHLD)) {
+        return Ok(());
+    }
+    set_handler(
+        Signal::SIGCHLD,
+        signal::SigHandler::Handler(__rush_global_signal_handler),
+    )
+}
+
+/// Undo `watch_children`, SIGCHLD gets its default behavior back unless it's trapped
+pub fn unwatch_children() {
+    if !is_trapped(Condition::Signal(Signal::SIGCHLD)) {
+        set_handler(Signal::SIGCHLD, signal::SigHandler::SigDfl).ok();
+    }
+}
+
+pub fn catching_interrupts() -> bool {
+    CATCHING_INTERRUPTS.load(Ordering::SeqCst)
+}
+
+/// Have the signals with a trap that runs commands interrupt whatever the shell is blocked in,
+/// so the `wait` builtin can return as soon as one arrives and its trap can run
+pub fn interrupt_waits(on: bool) {
+    INTERRUPTING_WAITS.store(on, Ordering::SeqCst);
+    let flags = if on {
+        signal::SaFlags::empty()
+    } else {
+        signal::SaFlags::SA_RESTART
+    };
+    for (cond, a) in traps() {
+        if let (Condition::Signal(sig), Action::Eval(_)) = (cond, a) {
+            set_handler_with_flags(sig, signal::SigHandler::Handler(__rush_global_signal_handler), flags).ok();
+        }
+    }
+}
+
+pub fn interrupting_waits() -> bool {
+    INTERRUPTING_WAITS.load(Ordering::SeqCst)
+}
+
+/// Whether the DEBUG trap has commands to run before each simple command
+pub fn debugging() -> bool {
+    DEBUGGING.load(Ordering::SeqCst)
 }
 
-pub fn is_trapped(sig: Signal) -> bool {
-    return GLOBAL_TRAPS.read().unwrap().contains_key(&sig);
+/// Forget a SIGINT that arrived since the last call to `take_pending`, returning whether there was one
+pub fn discard_interrupt() -> bool {
+    let bit = 1 << (Signal::SIGINT as usize);
+    PENDING.fetch_and(!bit, Ordering::SeqCst) & bit != 0
 }
 
+fn set_handler(sig: Signal, handler: signal::SigHandler) -> nix::Result<()> {
+    set_handler_with_flags(sig, handler, signal::SaFlags::SA_RESTART)
+}
+
+fn set_handler_with_flags(sig: Signal, handler: signal::SigHandler, flags: signal::SaFlags) -> nix::Result<()> {
+    unsafe { signal::sigaction(sig, &signal::SigAction::new(handler, flags, signal::SigSet::empty())) }.map(|_| ())
+}
+
+pub fn action(cond: Condition) -> Option<Action> {
+    GLOBAL_TRAPS.read().unwrap().get(&cond).cloned()
+}
+
+pub fn is_trapped(cond: Condition) -> bool {
+    GLOBAL_TRAPS.read().unwrap().contains_key(&cond)
+}
+
+/// Every trap that is set, the exit trap first, then by signal number, and the DEBUG trap last
+pub fn traps() -> Vec<(Condition, Action)> {
+    let mut all: Vec<(Condition, Action)> = GLOBAL_TRAPS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(cond, a)| (*cond, a.clone()))
+        .collect();
+    all.sort_by_key(|&(cond, _)| match cond {
+        Condition::Exit => (0, 0),
+        Condition::Signal(sig) => (1, sig as i32),
+        Condition::Debug => (2, 0),
+    });
+    all
+}
+
+/// The signals trapped with an empty action, the programs the shell starts keep ignoring them
+pub fn ignored() -> Vec<Signal> {
+    traps()
+        .into_iter()
+        .filter_map(|(cond, a)| match (cond, a) {
+            (Condition::Signal(sig), Action::Ignore) => Some(sig),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A signal with a trap that runs commands that arrived since the last call to `take_pending`, it stays pending
+pub fn pending_trap() -> Option<Signal> {
+    let pending = PENDING.load(Ordering::SeqCst);
+    Signal::iterator().find(|sig| {
+        pending & (1 << (*sig as usize)) != 0
+            && action(Condition::Signal(*sig))
+                .map(|a| a != Action::Ignore)
+                .unwrap_or(false)
+    })
+}
+
+/// The trapped signals that arrived since the last call, each is only returned once
+pub fn take_pending() -> Vec<Signal> {
+    let pending = PENDING.swap(0, Ordering::SeqCst);
+    Signal::iterator()
+        .filter(|sig| pending & (1 << (*sig as usize)) != 0)
+        .collect()
+}
+
+// only async-signal-safe work is allowed here, so the signal is just recorded
 extern "C" fn __rush_global_signal_handler(sig: c_int) {
-    let mut traps = GLOBAL_TRAPS.write().unwrap();
-    match traps.get_mut(&(Signal::from_c_int(sig).unwrap())) {
-        Some(actions) => for action in actions {
-            match action {
-                Action::Eval(ref s) => {
-                    println!("\n==> Signal handler for \"{}\"", s);
-                    unimplemented!();
-                }
-                Action::Func(ref mut f) => f(),
-                Action::NoOp => (),
-            }
-        },
-        None => (),
+    PENDING.fetch_or(1 << sig, Ordering::SeqCst);
+}
+
+/// Parse the name of a trap condition, either a signal, `EXIT` (also known as 0), or `DEBUG`
+pub fn parse_condition<T: AsRef<str>>(s: T) -> Option<Condition> {
+    match s.as_ref().to_ascii_uppercase().trim() {
+        "0" | "EXIT" => Some(Condition::Exit),
+        "DEBUG" => Some(Condition::Debug),
+        _ => parse_signal(s).map(Condition::Signal),
+    }
+}
+
+/// The name `trap -p` shows for a condition, like `EXIT` or `SIGINT`
+pub fn condition_name(cond: Condition) -> String {
+    match cond {
+        Condition::Exit => "EXIT".to_string(),
+        Condition::Debug => "DEBUG".to_string(),
+        Condition::Signal(sig) => format!("{:?}", sig),
     }
 }
 
@@ -129,3 +278,66 @@ pub fn parse_signal<T: AsRef<str>>(s: T) -> Option<Signal> {
         _ => return None,
     })
 }
+
+/// The name of a signal without its SIG prefix, the way `kill -l` shows it
+pub fn signal_name(sig: Signal) -> String {
+    format!("{:?}", sig).trim_start_matches("SIG").to_string()
+}
+
+/// What a signal means, like "Terminated" for SIGTERM, the way a job killed by it is reported
+pub fn signal_description(sig: Signal) -> &'static str {
+    match sig {
+        Signal::SIGHUP => "Hangup",
+        Signal::SIGINT => "Interrupt",
+        Signal::SIGQUIT => "Quit",
+        Signal::SIGILL => "Illegal instruction",
+        Signal::SIGTRAP => "Trace/breakpoint trap",
+        Signal::SIGABRT => "Aborted",
+        Signal::SIGBUS => "Bus error",
+        Signal::SIGFPE => "Floating point exception",
+        Signal::SIGKILL => "Killed",
+        Signal::SIGUSR1 => "User defined signal 1",
+        Signal::SIGSEGV => "Segmentation fault",
+        Signal::SIGUSR2 => "User defined signal 2",
+        Signal::SIGPIPE => "Broken pipe",
+        Signal::SIGALRM => "Alarm clock",
+        Signal::SIGTERM => "Terminated",
+        Signal::SIGSTKFLT => "Stack fault",
+        Signal::SIGCHLD => "Child exited",
+        Signal::SIGCONT => "Continued",
+        Signal::SIGSTOP => "Stopped (signal)",
+        Signal::SIGTSTP => "Stopped",
+        Signal::SIGTTIN => "Stopped (tty input)",
+        Signal::SIGTTOU => "Stopped (tty output)",
+        Signal::SIGURG => "Urgent I/O condition",
+        Signal::SIGXCPU => "CPU time limit exceeded",
+        Signal::SIGXFSZ => "File size limit exceeded",
+        Signal::SIGVTALRM => "Virtual timer expired",
+        Signal::SIGPROF => "Profiling timer expired",
+        Signal::SIGWINCH => "Window changed",
+        Signal::SIGIO => "I/O possible",
+        Signal::SIGPWR => "Power failure",
+        Signal::SIGSYS => "Bad system call",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn signals_are_described_the_conventional_way() {
+        assert_eq!(signal_description(Signal::SIGSEGV), "Segmentation fault");
+        assert_eq!(signal_description(Signal::SIGTERM), "Terminated");
+        assert_eq!(signal_description(Signal::SIGKILL), "Killed");
+        assert_eq!(signal_description(Signal::SIGABRT), "Aborted");
+        assert_eq!(signal_description(Signal::SIGHUP), "Hangup");
+    }
+
+    #[test]
+    fn every_signal_has_its_own_description() {
+        let descriptions: HashSet<&str> = Signal::iterator().map(signal_description).collect();
+        assert_eq!(descriptions.len(), Signal::iterator().count());
+    }
+}
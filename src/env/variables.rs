@@ -1,9 +1,15 @@
 ///! Variables is a wrapper around a `BTreeMap<OsString, OsString>`.
 ///! It provides specialized methods for working with shell variables.
+use crate::expr::{self, Expr};
+use crate::lang::{ErrorKind, Result};
+use std::cell::Cell;
 use std::collections::btree_map;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::ffi::OsString;
+use std::mem;
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 pub type Name = OsString;
 pub type Value = OsString;
@@ -12,9 +18,56 @@ pub type Iter<'a> = btree_map::Iter<'a, Name, Value>;
 pub type IterMut<'a> = btree_map::IterMut<'a, Name, Value>;
 pub type IntoIter = btree_map::IntoIter<Name, Value>;
 
+/// The elements of an array, by key. An indexed array's keys are its indices written out in decimal.
+pub type Array = BTreeMap<String, Value>;
+
+/// The key a plain `$NAME` or `NAME=value` uses when NAME is an array
+const SCALAR_KEY: &str = "0";
+
 #[derive(Debug, Clone)]
 pub struct Variables {
     map: BTreeMap<Name, Value>,
+    /// The variables declared with `declare -A` or `declare -a`, a name is never in both this and `map`
+    arrays: BTreeMap<Name, Array>,
+    /// The arrays in `arrays` that are indexed by number rather than associative
+    indexed: BTreeSet<Name>,
+    exported: BTreeSet<Name>,
+    readonly: BTreeSet<Name>,
+    integer: BTreeSet<Name>,
+    /// The variables declared with `declare -n`, their value is the name of the variable they stand for
+    nameref: BTreeSet<Name>,
+    positional: Vec<Value>,
+    /// The exit status of the last command, `$?`
+    status: i32,
+    /// The variables hidden by locals in each function call, innermost last
+    scopes: Vec<BTreeMap<Name, Hidden>>,
+    /// The line of the command being run, `$LINENO`
+    line: usize,
+    /// The state of the generator behind `$RANDOM`, it moves on every time it's expanded
+    random: Cell<u32>,
+    /// `$SECONDS` counts up from `seconds` starting at `seconds_since`
+    seconds: i64,
+    seconds_since: Instant,
+}
+
+/// A variable's value and whether it was exported, kept while a local variable or a command's
+/// temporary assignment with the same name hides it
+#[derive(Debug, Clone)]
+pub struct Hidden {
+    value: Option<Value>,
+    array: Option<Array>,
+    indexed: bool,
+    exported: bool,
+    integer: bool,
+    nameref: bool,
+}
+
+/// A scope started by `push_scope`, it has to be handed back to `pop_scope` to end it
+#[must_use = "a scope has to be given back to pop_scope, or its locals outlive the function"]
+#[derive(Debug)]
+pub struct Scope {
+    /// How many scopes there are with this one on top
+    depth: usize,
 }
 
 pub enum Entry<'a> {
@@ -36,40 +89,542 @@ impl Variables {
     pub fn new() -> Variables {
         Variables {
             map: BTreeMap::new(),
+            arrays: BTreeMap::new(),
+            indexed: BTreeSet::new(),
+            exported: BTreeSet::new(),
+            readonly: BTreeSet::new(),
+            integer: BTreeSet::new(),
+            nameref: BTreeSet::new(),
+            positional: Vec::new(),
+            status: 0,
+            scopes: Vec::new(),
+            line: 0,
+            random: Cell::new(random_seed()),
+            seconds: 0,
+            seconds_since: Instant::now(),
+        }
+    }
+
+    /// Whether a name can be used for a variable or function: letters, digits and underscores, not starting with a digit
+    pub fn is_valid_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            _ => false,
         }
     }
 
+    /// Create a set of variables from the process environment, all of them are marked as exported
     pub fn from_env() -> Variables {
+        let map: BTreeMap<Name, Value> = env::vars_os().collect();
         Variables {
-            map: env::vars_os().collect(),
+            exported: map.keys().cloned().collect(),
+            map,
+            arrays: BTreeMap::new(),
+            indexed: BTreeSet::new(),
+            readonly: BTreeSet::new(),
+            integer: BTreeSet::new(),
+            nameref: BTreeSet::new(),
+            positional: Vec::new(),
+            status: 0,
+            scopes: Vec::new(),
+            line: 0,
+            random: Cell::new(random_seed()),
+            seconds: 0,
+            seconds_since: Instant::now(),
         }
     }
 
     pub fn import_env(&mut self) {
-        self.map.append(&mut env::vars_os().collect());
+        for (k, v) in env::vars_os() {
+            self.exported.insert(k.clone());
+            self.map.insert(k, v);
+        }
+    }
+
+    /// Assign a variable, it keeps its attributes. Fails if the variable is readonly.
+    /// Assigning to an associative array sets its element with the key `0`, and assigning to a nameref
+    /// assigns the variable it refers to.
+    pub fn define<T: Into<OsString>, U: Into<OsString>>(&mut self, k: T, v: U) -> Result<()> {
+        let k = k.into();
+        let k = self.resolve(&k).clone();
+        self.check_writable(&k)?;
+        let v = self.assigned_value(&k, v.into())?;
+        if self.assign_special(&k, &v) {
+            return Ok(());
+        }
+        if let Some(array) = self.arrays.get_mut(&k) {
+            array.insert(SCALAR_KEY.to_string(), v);
+            return Ok(());
+        }
+        if self.exported.contains(&k) {
+            env::set_var(&k, &v);
+        }
+        self.map.insert(k, v);
+        Ok(())
     }
 
-    pub fn define<T: Into<OsString>, U: Into<OsString>>(&mut self, k: T, v: U) {
-        self.map.insert(k.into(), v.into());
+    /// Append to a variable for `NAME+=value`, an integer variable has the value's arithmetic result added to it instead.
+    /// A variable that isn't set is assigned the value like it would be with `=`.
+    pub fn append<T: Into<OsString>, U: Into<OsString>>(&mut self, k: T, v: U) -> Result<()> {
+        let k = k.into();
+        let k = self.resolve(&k).clone();
+        self.check_writable(&k)?;
+        let old = if self.exists(&k) { Some(self.value(&k)) } else { None };
+        let v = self.appended_value(&k, old, v.into())?;
+        self.define(k, v)
     }
 
-    pub fn remove(&mut self, k: &OsString) {
-        self.map.remove(k);
+    /// Append to one element of an array for `NAME[KEY]+=value`, the same way `append` does
+    pub fn append_element<T: Into<OsString>>(&mut self, k: &Name, key: &str, v: T) -> Result<()> {
+        let k = &self.resolve(k).clone();
+        self.check_writable(k)?;
+        let key = &self.subscript(k, key)?;
+        let old = self.element(k, key).cloned();
+        let v = self.appended_value(k, old, v.into())?;
+        self.define_element(k, key, v)
+    }
+
+    fn appended_value(&mut self, k: &Name, old: Option<Value>, v: Value) -> Result<Value> {
+        let mut old = match old {
+            Some(old) => old,
+            None => return Ok(v),
+        };
+        if self.integer.contains(k) {
+            let number = |v: &Value| v.to_str().and_then(|v| v.trim().parse::<i64>().ok()).unwrap_or(0);
+            let added = self.assigned_value(k, v)?;
+            return Ok(OsString::from(number(&old).wrapping_add(number(&added)).to_string()));
+        }
+        old.push(v);
+        Ok(old)
+    }
+
+    /// Unset a variable, along with all its attributes. Fails if the variable is readonly.
+    /// For a nameref it's the variable it refers to that's unset.
+    pub fn remove(&mut self, k: &OsString) -> Result<Option<Value>> {
+        let k = self.resolve(k).clone();
+        self.check_writable(&k)?;
+        Ok(self.forget(&k))
+    }
+
+    /// Unset a nameref itself, instead of the variable it refers to. Any other variable is unset as usual.
+    pub fn remove_nameref(&mut self, k: &OsString) -> Result<Option<Value>> {
+        self.check_writable(k)?;
+        Ok(self.forget(k))
+    }
+
+    fn forget(&mut self, k: &OsString) -> Option<Value> {
+        self.unexport(k);
+        self.integer.remove(k);
+        self.nameref.remove(k);
+        self.indexed.remove(k);
+        // an array that's removed gives back its value the same as any other variable, even if it has none
+        match self.arrays.remove(k) {
+            Some(mut array) => array.remove(SCALAR_KEY).or(Some(OsString::new())),
+            None => self.map.remove(k),
+        }
+    }
+
+    /// What's stored when a value is assigned to a variable, an integer variable gets the value's arithmetic result.
+    /// Anything that isn't a valid expression becomes 0.
+    fn assigned_value(&mut self, k: &Name, v: Value) -> Result<Value> {
+        if !self.integer.contains(k) {
+            return Ok(v);
+        }
+        let parsed = match v.to_str().map(expr::parse) {
+            Some(Ok(e)) => e,
+            _ => return Ok(OsString::from("0")),
+        };
+        match parsed.evaluate(self)? {
+            Expr::Number(n) if n.is_finite() => Ok(OsString::from((n as i64).to_string())),
+            _ => Ok(OsString::from("0")),
+        }
+    }
+
+    /// Assign one of the variables that's worked out when it's expanded, giving `false` if `k` isn't one of them.
+    /// `RANDOM=n` seeds the generator and `SECONDS=n` starts counting again from n.
+    fn assign_special(&mut self, k: &Name, v: &Value) -> bool {
+        let number = v.to_str().and_then(|v| v.trim().parse::<i64>().ok()).unwrap_or(0);
+        match k.to_str() {
+            Some("RANDOM") => self.random.set(number as u32),
+            Some("SECONDS") => {
+                self.seconds = number;
+                self.seconds_since = Instant::now();
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn check_writable(&self, k: &OsString) -> Result<()> {
+        if self.readonly.contains(k) {
+            Err(ErrorKind::ReadonlyVariable(k.to_string_lossy().to_string()))?;
+        }
+        Ok(())
     }
 
     pub fn value(&self, k: &OsString) -> OsString {
-        self.map
+        if let Some(v) = self.special(k) {
+            return v.unwrap_or(OsString::new());
+        }
+
+        self.scalar(k).cloned().unwrap_or(OsString::new())
+    }
+
+    pub fn exists(&self, k: &OsString) -> bool {
+        match self.special(k) {
+            Some(v) => v.is_some(),
+            None => self.scalar(k).is_some(),
+        }
+    }
+
+    /// A variable's value, for an associative array that's its element with the key `0`
+    fn scalar(&self, k: &OsString) -> Option<&Value> {
+        let k = self.resolve(k);
+        match self.arrays.get(k) {
+            Some(array) => array.get(SCALAR_KEY),
+            None => self.map.get(k),
+        }
+    }
+
+    /// Make a variable an associative array, an existing value becomes the element with the key `0`
+    pub fn make_array(&mut self, k: &Name) -> Result<()> {
+        self.convert_to_array(k, false)
+    }
+
+    /// Make a variable an array indexed by number, an existing value becomes the element at index 0
+    pub fn make_indexed_array(&mut self, k: &Name) -> Result<()> {
+        self.convert_to_array(k, true)
+    }
+
+    /// A variable that's already an array is left the kind it is
+    fn convert_to_array(&mut self, k: &Name, indexed: bool) -> Result<()> {
+        let k = &self.resolve(k).clone();
+        if self.arrays.contains_key(k) {
+            return Ok(());
+        }
+        self.check_writable(k)?;
+        let mut array = Array::new();
+        if let Some(v) = self.map.remove(k) {
+            if self.exported.contains(k) {
+                env::remove_var(k);
+            }
+            array.insert(SCALAR_KEY.to_string(), v);
+        }
+        self.arrays.insert(k.clone(), array);
+        if indexed {
+            self.indexed.insert(k.clone());
+        }
+        Ok(())
+    }
+
+    pub fn is_array(&self, k: &Name) -> bool {
+        self.arrays.contains_key(self.resolve(k))
+    }
+
+    /// Whether a variable is an array indexed by number, as opposed to an associative one or no array at all
+    pub fn is_indexed(&self, k: &Name) -> bool {
+        self.indexed.contains(self.resolve(k))
+    }
+
+    /// The elements of an array with their keys, an indexed array's in order of their index
+    /// and an associative array's sorted by key
+    pub fn elements(&self, k: &Name) -> Option<Vec<(&String, &Value)>> {
+        let k = self.resolve(k);
+        let mut elements: Vec<(&String, &Value)> = self.arrays.get(k)?.iter().collect();
+        if self.indexed.contains(k) {
+            elements.sort_by_key(|(key, _)| key.parse::<i64>().unwrap_or(0));
+        }
+        Some(elements)
+    }
+
+    /// The key an element is stored under. An indexed array's subscript is an arithmetic expression,
+    /// a negative one counts back from the end. Any other variable's key is used as it is.
+    pub fn subscript(&mut self, k: &Name, key: &str) -> Result<String> {
+        let k = self.resolve(k).clone();
+        if !self.indexed.contains(&k) {
+            return Ok(key.to_string());
+        }
+        let bad = || ErrorKind::BadSubscript(format!("{}[{}]", k.to_string_lossy(), key));
+        let mut index = match expr::parse(key).map(|e| e.evaluate(self)) {
+            Ok(Ok(Expr::Number(n))) if n.is_finite() => n as i64,
+            Ok(Err(e)) => return Err(e),
+            _ => Err(bad())?,
+        };
+        if index < 0 {
+            index += self.next_index(&k);
+        }
+        if index < 0 {
+            Err(bad())?;
+        }
+        Ok(index.to_string())
+    }
+
+    /// One past the highest index of an indexed array, where an element without a key goes in `NAME+=(...)`
+    fn next_index(&self, k: &Name) -> i64 {
+        self.arrays
             .get(k)
-            .map(|v| v.clone())
-            .unwrap_or(OsString::new())
+            .and_then(|array| array.keys().filter_map(|key| key.parse::<i64>().ok()).max())
+            .map(|last| last + 1)
+            .unwrap_or(0)
+    }
+
+    /// Assign a whole array for `NAME=(...)`, replacing its elements, or for `NAME+=(...)`, adding to them.
+    /// An element without a key goes after the one before it. A variable that isn't an array becomes an indexed one.
+    pub fn define_array(&mut self, k: &Name, elements: Vec<(Option<String>, Value)>, append: bool) -> Result<()> {
+        let k = &self.resolve(k).clone();
+        self.check_writable(k)?;
+        self.make_indexed_array(k)?;
+        if !append {
+            self.arrays.insert(k.clone(), Array::new());
+        }
+        let mut next = self.next_index(k);
+        for (key, v) in elements {
+            let key = match key {
+                Some(key) => self.subscript(k, &key)?,
+                None => next.to_string(),
+            };
+            next = key.parse::<i64>().map(|index| index + 1).unwrap_or(next + 1);
+            let v = self.assigned_value(k, v)?;
+            if let Some(array) = self.arrays.get_mut(k) {
+                array.insert(key, v);
+            }
+        }
+        Ok(())
+    }
+
+    /// Iterate over the arrays, sorted by name
+    pub fn arrays<'a>(&'a self) -> impl Iterator<Item = (&'a Name, &'a Array)> + 'a {
+        self.arrays.iter()
+    }
+
+    /// Look up one element of an array by the key `subscript` gives. Any other variable only has the element `0`, its value.
+    pub fn element(&self, k: &Name, key: &str) -> Option<&Value> {
+        let k = self.resolve(k);
+        match self.arrays.get(k) {
+            Some(array) => array.get(key),
+            None if key == SCALAR_KEY => self.map.get(k),
+            None => None,
+        }
+    }
+
+    /// Set one element of an array. Fails if the variable is readonly.
+    /// A variable that isn't an array becomes an indexed one, its value the element at index 0.
+    pub fn define_element<T: Into<OsString>>(&mut self, k: &Name, key: &str, v: T) -> Result<()> {
+        let k = &self.resolve(k).clone();
+        self.check_writable(k)?;
+        self.make_indexed_array(k)?;
+        let key = self.subscript(k, key)?;
+        let v = self.assigned_value(k, v.into())?;
+        match self.arrays.get_mut(k) {
+            Some(array) => {
+                array.insert(key, v);
+                Ok(())
+            }
+            None => Err(ErrorKind::NotAnArray(k.to_string_lossy().to_string()))?,
+        }
+    }
+
+    /// Unset one element of an array, the array itself stays even when it's left empty
+    pub fn remove_element(&mut self, k: &Name, key: &str) -> Result<Option<Value>> {
+        let k = &self.resolve(k).clone();
+        self.check_writable(k)?;
+        let key = self.subscript(k, key)?;
+        match self.arrays.get_mut(k) {
+            Some(array) => Ok(array.remove(&key)),
+            None => Err(ErrorKind::NotAnArray(k.to_string_lossy().to_string()))?,
+        }
+    }
+
+    /// The positional parameters, $1 and onward
+    pub fn positional(&self) -> &[Value] {
+        &self.positional
+    }
+
+    /// The exit status of the last command
+    pub fn status(&self) -> i32 {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: i32) {
+        self.status = status;
+    }
+
+    /// The line of the command being run, counted from 1
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
+    /// Replace the positional parameters, returning the old ones so they can be restored later
+    pub fn set_positional(&mut self, params: Vec<Value>) -> Vec<Value> {
+        mem::replace(&mut self.positional, params)
+    }
+
+    /// Start a scope for local variables, each function call gets one
+    pub fn push_scope(&mut self) -> Scope {
+        self.scopes.push(BTreeMap::new());
+        Scope {
+            depth: self.scopes.len(),
+        }
+    }
+
+    /// End a scope, giving back every variable its locals hid the value and attributes it had before.
+    /// Any scopes started inside it that are still open end with it.
+    pub fn pop_scope(&mut self, scope: Scope) {
+        while self.scopes.len() >= scope.depth {
+            let locals = match self.scopes.pop() {
+                Some(locals) => locals,
+                None => break,
+            };
+            for (k, hidden) in locals {
+                self.forget(&k);
+                if let Some(v) = hidden.value {
+                    self.map.insert(k.clone(), v);
+                }
+                if let Some(array) = hidden.array {
+                    self.arrays.insert(k.clone(), array);
+                }
+                if hidden.indexed {
+                    self.indexed.insert(k.clone());
+                }
+                if hidden.exported {
+                    self.export(&k);
+                }
+                if hidden.integer {
+                    self.integer.insert(k.clone());
+                }
+                if hidden.nameref {
+                    self.nameref.insert(k);
+                }
+            }
+        }
+    }
+
+    /// Whether there's a scope `local` can declare variables in
+    pub fn in_scope(&self) -> bool {
+        !self.scopes.is_empty()
     }
 
-    pub fn exists<T: Into<OsString>>(&self, k: &OsString) -> bool {
-        self.map.contains_key(k)
+    /// Make a variable local to the innermost scope, it starts out unset but stays exported if it was.
+    /// Outer scopes, and anything called from this one, see the local until the scope ends.
+    /// Lookups don't need to search the scopes, the local is the variable until then.
+    pub fn make_local(&mut self, k: &Name) {
+        let hidden_exported = self.exported.contains(k);
+        let hidden = self.hide(k);
+        let newly_local = match self.scopes.last_mut() {
+            Some(scope) if !scope.contains_key(k) => {
+                scope.insert(k.clone(), hidden);
+                true
+            }
+            _ => false,
+        };
+        if newly_local {
+            self.arrays.remove(k);
+            self.indexed.remove(k);
+            self.integer.remove(k);
+            self.nameref.remove(k);
+            if self.map.remove(k).is_some() && hidden_exported {
+                env::remove_var(k);
+            }
+        }
     }
 
-    pub fn has_value<T: Into<OsString>>(&self, k: &OsString) -> bool {
-        self.map.get(k).map(|v| v.len() > 0).unwrap_or(false)
+    /// Save a variable's value and attributes, so `unhide` or the end of a scope can put them back later
+    pub fn hide(&self, k: &Name) -> Hidden {
+        Hidden {
+            value: self.map.get(k).cloned(),
+            array: self.arrays.get(k).cloned(),
+            indexed: self.indexed.contains(k),
+            exported: self.exported.contains(k),
+            integer: self.integer.contains(k),
+            nameref: self.nameref.contains(k),
+        }
+    }
+
+    /// Give a variable back the value and export it had when it was hidden, it's unset if it had no value.
+    /// Its other attributes are left alone.
+    pub fn unhide(&mut self, k: &Name, hidden: Hidden) {
+        match hidden.value {
+            Some(v) => self.map.insert(k.clone(), v),
+            None => self.map.remove(k),
+        };
+        match hidden.array {
+            Some(array) => self.arrays.insert(k.clone(), array),
+            None => self.arrays.remove(k),
+        };
+        if hidden.indexed {
+            self.indexed.insert(k.clone());
+        } else {
+            self.indexed.remove(k);
+        }
+        if hidden.exported {
+            self.exported.insert(k.clone());
+        } else {
+            self.exported.remove(k);
+        }
+        match self.map.get(k) {
+            Some(v) if hidden.exported => env::set_var(k, v),
+            _ => env::remove_var(k),
+        }
+    }
+
+    /// The names of the variables local to the innermost scope
+    pub fn locals<'a>(&'a self) -> impl Iterator<Item = &'a Name> + 'a {
+        self.scopes.last().into_iter().flat_map(|scope| scope.keys())
+    }
+
+    /// Look up one of the special parameters, `$?` or one derived from the positional parameters ($1, $#, $@, and $*),
+    /// or a variable that's worked out every time it's expanded, like `$RANDOM`.
+    /// Returns `None` if `k` isn't one of them, and `Some(None)` if it is but isn't set.
+    fn special(&self, k: &OsString) -> Option<Option<Value>> {
+        let name = k.to_str()?;
+        match name {
+            "?" => Some(Some(OsString::from(self.status.to_string()))),
+            "LINENO" => Some(Some(OsString::from(self.line.to_string()))),
+            "RANDOM" => Some(Some(OsString::from(self.next_random().to_string()))),
+            "SECONDS" => {
+                let elapsed = self.seconds_since.elapsed().as_secs() as i64;
+                Some(Some(OsString::from((self.seconds + elapsed).to_string())))
+            }
+            "#" => Some(Some(OsString::from(self.positional.len().to_string()))),
+            "@" | "*" => {
+                let mut joined = OsString::new();
+                for (i, v) in self.positional.iter().enumerate() {
+                    if i > 0 {
+                        joined.push(" ");
+                    }
+                    joined.push(v);
+                }
+                Some(Some(joined))
+            }
+            _ if name != "0" && !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) => Some(
+                name.parse::<usize>()
+                    .ok()
+                    .and_then(|n| self.positional.get(n - 1))
+                    .cloned(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Move the `$RANDOM` generator on and give its next number, from 0 to 32767.
+    /// It's a Park-Miller generator like bash's, which would never move on from 0, so that seed is swapped for another.
+    fn next_random(&self) -> u32 {
+        let seed = match u64::from(self.random.get()) % 2_147_483_647 {
+            0 => 123_459_876,
+            seed => seed,
+        };
+        let next = (seed * 16807 % 2_147_483_647) as u32;
+        self.random.set(next);
+        ((next >> 16) ^ (next & 0xffff)) & 0x7fff
+    }
+
+    pub fn has_value(&self, k: &OsString) -> bool {
+        self.scalar(k).map(|v| !v.is_empty()).unwrap_or(false)
     }
 
     pub fn entry<'a, T: Into<Name>>(&'a mut self, key: T) -> Entry<'a> {
@@ -79,8 +634,105 @@ impl Variables {
         }
     }
 
-    pub fn export(&self, k: &OsString) {
-        env::set_var(k, self.value(k));
+    /// Mark a variable to be inherited by child processes.
+    /// The variable doesn't have to be set yet, it will be exported once it is.
+    pub fn export(&mut self, k: &OsString) {
+        if let Some(v) = self.map.get(k) {
+            env::set_var(k, v);
+        }
+        self.exported.insert(k.clone());
+    }
+
+    /// Stop a variable from being inherited by child processes, it keeps its value in the shell
+    pub fn unexport(&mut self, k: &OsString) {
+        if self.exported.remove(k) {
+            env::remove_var(k);
+        }
+    }
+
+    pub fn is_exported(&self, k: &OsString) -> bool {
+        self.exported.contains(k)
+    }
+
+    /// Iterate over the names of every exported variable, along with their values if they are set
+    pub fn exported<'a>(&'a self) -> impl Iterator<Item = (&'a Name, Option<&'a Value>)> + 'a {
+        self.exported.iter().map(move |k| (k, self.map.get(k)))
+    }
+
+    /// Stop a variable from being assigned or unset for the rest of the shell's life
+    pub fn set_readonly(&mut self, k: &OsString) {
+        self.readonly.insert(k.clone());
+    }
+
+    pub fn is_readonly(&self, k: &OsString) -> bool {
+        self.readonly.contains(k)
+    }
+
+    /// Mark a variable as holding an integer, everything assigned to it from then on is evaluated as arithmetic
+    pub fn set_integer(&mut self, k: &OsString) {
+        self.integer.insert(k.clone());
+    }
+
+    pub fn unset_integer(&mut self, k: &OsString) {
+        self.integer.remove(k);
+    }
+
+    pub fn is_integer(&self, k: &OsString) -> bool {
+        self.integer.contains(k)
+    }
+
+    /// Make a variable a nameref for the variable named by `target`, or by the value it already has without one.
+    /// Fails if the variable is readonly, the target isn't a valid name, or the reference would lead back to itself.
+    pub fn make_reference(&mut self, k: &Name, target: Option<Value>) -> Result<()> {
+        self.check_writable(k)?;
+        let target = target.or_else(|| self.map.get(k).cloned()).unwrap_or_default();
+        if !target.is_empty() && !target.to_str().map(Variables::is_valid_name).unwrap_or(false) {
+            Err(ErrorKind::InvalidIdentifier(target.to_string_lossy().to_string()))?;
+        }
+        if self.chain(&target).any(|name| name == k) {
+            Err(ErrorKind::CircularReference(k.to_string_lossy().to_string()))?;
+        }
+        self.arrays.remove(k);
+        self.indexed.remove(k);
+        self.map.insert(k.clone(), target);
+        self.nameref.insert(k.clone());
+        Ok(())
+    }
+
+    /// Stop a variable from being a nameref, it keeps the name it referred to as its value
+    pub fn unset_nameref(&mut self, k: &Name) {
+        self.nameref.remove(k);
+    }
+
+    pub fn is_nameref(&self, k: &Name) -> bool {
+        self.nameref.contains(k)
+    }
+
+    /// The name of the variable a nameref refers to, which is empty until it's given one
+    pub fn reference(&self, k: &Name) -> Option<&Value> {
+        self.map.get(k).filter(|_| self.nameref.contains(k))
+    }
+
+    /// The variable a name stands for: the one at the end of a chain of namerefs, or the name itself for any
+    /// other variable. A nameref that doesn't refer to anything yet stands for itself.
+    fn resolve<'a>(&'a self, k: &'a Name) -> &'a Name {
+        self.chain(k).last().unwrap_or(k)
+    }
+
+    /// A name followed by each name the namerefs along the way refer to.
+    /// Cycles are refused when a reference is made, the limit only stops one that got through some other way.
+    fn chain<'a>(&'a self, k: &'a Name) -> impl Iterator<Item = &'a Name> + 'a {
+        let mut next = Some(k);
+        (0..self.nameref.len() + 1).map_while(move |_| {
+            let name = next?;
+            next = self.reference(name).filter(|target| !target.is_empty());
+            Some(name)
+        })
+    }
+
+    /// Iterate over the names of every readonly variable, along with their values if they are set
+    pub fn readonly<'a>(&'a self) -> impl Iterator<Item = (&'a Name, Option<&'a Value>)> + 'a {
+        self.readonly.iter().map(move |k| (k, self.map.get(k)))
     }
 
     pub fn iter<'a>(&'a self) -> Iter<'a> {
@@ -92,6 +744,15 @@ impl Variables {
     }
 }
 
+/// A different `$RANDOM` sequence for every shell, from the time it started and its process id
+fn random_seed() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ d.as_secs() as u32)
+        .unwrap_or(0);
+    nanos ^ process::id()
+}
+
 impl<'a> OccupiedEntry<'a> {
     pub fn key(&self) -> &Name {
         self.entry.key()
@@ -216,3 +877,172 @@ impl IntoIterator for Variables {
         self.map.into_iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_variables_no_longer_exist() {
+        let mut vars = Variables::new();
+        let name = OsString::from("FOO");
+        vars.define("FOO", "").unwrap();
+        assert!(vars.exists(&name));
+        assert_eq!(vars.remove(&name).unwrap(), Some(OsString::new()));
+        assert!(!vars.exists(&name));
+        assert_eq!(vars.remove(&name).unwrap(), None);
+    }
+
+    #[test]
+    fn readonly_variables_cannot_be_removed() {
+        let mut vars = Variables::new();
+        let name = OsString::from("FOO");
+        vars.define("FOO", "1").unwrap();
+        vars.set_readonly(&name);
+        assert!(vars.remove(&name).is_err());
+        assert_eq!(vars.value(&name), "1");
+    }
+
+    #[test]
+    fn inherited_variables_are_exported_and_new_ones_are_not() {
+        let mut vars = Variables::from_env();
+        let path = OsString::from("PATH");
+        assert!(vars.is_exported(&path));
+        vars.define("RUSH_TEST_LOCAL", "1").unwrap();
+        assert!(!vars.is_exported(&OsString::from("RUSH_TEST_LOCAL")));
+        assert!(env::var_os("RUSH_TEST_LOCAL").is_none());
+    }
+
+    #[test]
+    fn exports_survive_new_values() {
+        let mut vars = Variables::new();
+        let name = OsString::from("RUSH_TEST_REEXPORTED");
+        vars.export(&name);
+        assert_eq!(vars.exported().collect::<Vec<_>>(), vec![(&name, None)]);
+        vars.define(name.clone(), "1").unwrap();
+        vars.define(name.clone(), "2").unwrap();
+        assert!(vars.is_exported(&name));
+        assert_eq!(env::var_os(&name), Some(OsString::from("2")));
+        vars.unexport(&name);
+        assert!(env::var_os(&name).is_none());
+        assert_eq!(vars.value(&name), "2");
+    }
+
+    #[test]
+    fn only_exported_variables_are_listed_as_exported() {
+        let mut vars = Variables::new();
+        vars.define("RUSH_TEST_A", "a").unwrap();
+        vars.define("RUSH_TEST_B", "b").unwrap();
+        vars.export(&OsString::from("RUSH_TEST_B"));
+        let exported: Vec<_> = vars.exported().map(|(k, v)| (k.clone(), v.cloned())).collect();
+        assert_eq!(exported, vec![(OsString::from("RUSH_TEST_B"), Some(OsString::from("b")))]);
+        vars.unexport(&OsString::from("RUSH_TEST_B"));
+    }
+
+    #[test]
+    fn locals_shadow_and_are_put_back_when_their_scope_ends() {
+        let mut vars = Variables::new();
+        let name = OsString::from("V");
+        vars.define("V", "global").unwrap();
+        let scope = vars.push_scope();
+        vars.make_local(&name);
+        assert!(!vars.exists(&name));
+        vars.define("V", "local").unwrap();
+        assert_eq!(vars.value(&name), "local");
+        vars.pop_scope(scope);
+        assert_eq!(vars.value(&name), "global");
+    }
+
+    #[test]
+    fn nested_scopes_see_the_innermost_local() {
+        let mut vars = Variables::new();
+        let name = OsString::from("V");
+        vars.define("V", "global").unwrap();
+        let outer = vars.push_scope();
+        vars.make_local(&name);
+        vars.define("V", "outer").unwrap();
+        let inner = vars.push_scope();
+        assert_eq!(vars.value(&name), "outer");
+        vars.make_local(&name);
+        vars.define("V", "inner").unwrap();
+        vars.pop_scope(inner);
+        assert_eq!(vars.value(&name), "outer");
+        vars.pop_scope(outer);
+        assert_eq!(vars.value(&name), "global");
+    }
+
+    #[test]
+    fn unset_locals_stay_local() {
+        let mut vars = Variables::new();
+        let name = OsString::from("V");
+        vars.define("V", "global").unwrap();
+        let scope = vars.push_scope();
+        vars.make_local(&name);
+        vars.define("V", "local").unwrap();
+        vars.remove(&name).unwrap();
+        assert!(!vars.exists(&name));
+        vars.pop_scope(scope);
+        assert_eq!(vars.value(&name), "global");
+    }
+
+    #[test]
+    fn scopes_keep_what_isnt_local() {
+        let mut vars = Variables::new();
+        let scope = vars.push_scope();
+        vars.define("G", "from the function").unwrap();
+        vars.pop_scope(scope);
+        assert_eq!(vars.value(&OsString::from("G")), "from the function");
+    }
+
+    #[test]
+    fn ending_a_scope_ends_the_ones_inside_it() {
+        let mut vars = Variables::new();
+        let name = OsString::from("V");
+        let outer = vars.push_scope();
+        vars.make_local(&name);
+        vars.define("V", "outer").unwrap();
+        // an error unwinding past the inner call never gives its scope back
+        let _inner = vars.push_scope();
+        vars.make_local(&name);
+        vars.define("V", "inner").unwrap();
+        vars.pop_scope(outer);
+        assert!(!vars.exists(&name));
+        assert!(!vars.in_scope());
+    }
+
+    #[test]
+    fn appending_adds_to_strings_numbers_and_elements() {
+        let mut vars = Variables::new();
+        let name = OsString::from("S");
+        vars.append("S", "new").unwrap();
+        assert_eq!(vars.value(&name), "new");
+        vars.append("S", " more").unwrap();
+        assert_eq!(vars.value(&name), "new more");
+
+        let n = OsString::from("N");
+        vars.set_integer(&n);
+        vars.define("N", "5").unwrap();
+        vars.append("N", "2*3").unwrap();
+        assert_eq!(vars.value(&n), "11");
+
+        let a = OsString::from("A");
+        vars.make_indexed_array(&a).unwrap();
+        vars.append_element(&a, "1", "x").unwrap();
+        vars.append_element(&a, "1", "y").unwrap();
+        assert_eq!(vars.element(&a, "1").unwrap(), "xy");
+
+        vars.set_readonly(&name);
+        assert!(vars.append("S", "!").is_err());
+        assert_eq!(vars.value(&name), "new more");
+    }
+
+    #[test]
+    fn names_are_letters_digits_and_underscores_not_starting_with_a_digit() {
+        for name in &["a", "_", "_x9", "FOO_BAR", "a1b2"] {
+            assert!(Variables::is_valid_name(name), "{}", name);
+        }
+        for name in &["", "9x", "foo-bar", "bad.name", "a b", "A=1", "é"] {
+            assert!(!Variables::is_valid_name(name), "{}", name);
+        }
+    }
+}
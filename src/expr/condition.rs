@@ -0,0 +1,330 @@
+//! Conditional expressions, like `-f file` and `"$a" = b`, as used by `test` and `[`
+use failure::Fail;
+use nix::libc;
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::result;
+
+pub type Result<T> = result::Result<T, ErrorKind>;
+
+#[derive(Eq, PartialEq, Debug)]
+pub enum ErrorKind {
+    IntegerExpected(String),
+    UnaryOperatorExpected(String),
+    BinaryOperatorExpected(String),
+    ArgumentExpected,
+    ExpectingRightParentheses,
+    TooManyArguments,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::IntegerExpected(arg) => write!(f, "{}: integer expression expected", arg),
+            ErrorKind::UnaryOperatorExpected(arg) => write!(f, "{}: unary operator expected", arg),
+            ErrorKind::BinaryOperatorExpected(arg) => write!(f, "{}: binary operator expected", arg),
+            ErrorKind::ArgumentExpected => write!(f, "argument expected"),
+            ErrorKind::ExpectingRightParentheses => write!(f, "`)' expected"),
+            ErrorKind::TooManyArguments => write!(f, "too many arguments"),
+        }
+    }
+}
+
+impl Fail for ErrorKind {}
+
+/// Evaluate a conditional expression, relative paths in file tests are relative to `cwd`.
+/// With four or fewer arguments POSIX's rules, based on the number of arguments, decide what each one is.
+/// The arguments are bytes, so strings and paths are compared and looked up as they are.
+pub fn evaluate<T: AsRef<OsStr>>(args: &[T], cwd: &Path) -> Result<bool> {
+    let args: Vec<&OsStr> = args.iter().map(|a| a.as_ref()).collect();
+    by_count(&args, cwd)
+}
+
+fn by_count(args: &[&OsStr], cwd: &Path) -> Result<bool> {
+    match args.len() {
+        0 => Ok(false),
+        1 => Ok(!args[0].is_empty()),
+        2 => if args[0] == "!" {
+            Ok(args[1].is_empty())
+        } else if is_unary(args[0]) {
+            unary(args[0], args[1], cwd)
+        } else {
+            Err(ErrorKind::UnaryOperatorExpected(text(args[0])))
+        },
+        3 => if is_binary(args[1]) {
+            binary(args[0], args[1], args[2])
+        } else if args[1] == "-a" {
+            Ok(!args[0].is_empty() && !args[2].is_empty())
+        } else if args[1] == "-o" {
+            Ok(!args[0].is_empty() || !args[2].is_empty())
+        } else if args[0] == "!" {
+            Ok(!by_count(&args[1..], cwd)?)
+        } else if args[0] == "(" && args[2] == ")" {
+            Ok(!args[1].is_empty())
+        } else {
+            Err(ErrorKind::BinaryOperatorExpected(text(args[1])))
+        },
+        4 if args[0] == "!" => Ok(!by_count(&args[1..], cwd)?),
+        4 if args[0] == "(" && args[3] == ")" => by_count(&args[1..3], cwd),
+        _ => {
+            let mut parser = Parser {
+                args,
+                pos: 0,
+                cwd,
+            };
+            let result = parser.or()?;
+            if parser.pos < args.len() {
+                return Err(ErrorKind::TooManyArguments);
+            }
+            Ok(result)
+        }
+    }
+}
+
+/// Parses longer expressions, `-o` binds looser than `-a`, which binds looser than `!`
+struct Parser<'a> {
+    args: &'a [&'a OsStr],
+    pos: usize,
+    cwd: &'a Path,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a OsStr> {
+        self.args.get(self.pos).cloned()
+    }
+
+    /// Whether the next argument is `s`
+    fn peek_is(&self, s: &str) -> bool {
+        self.peek().map(|arg| arg == s).unwrap_or(false)
+    }
+
+    fn next(&mut self) -> Result<&'a OsStr> {
+        let arg = self.peek().ok_or(ErrorKind::ArgumentExpected)?;
+        self.pos += 1;
+        Ok(arg)
+    }
+
+    fn or(&mut self) -> Result<bool> {
+        let mut value = self.and()?;
+        while self.peek_is("-o") {
+            self.pos += 1;
+            let right = self.and()?;
+            value = value || right;
+        }
+        Ok(value)
+    }
+
+    fn and(&mut self) -> Result<bool> {
+        let mut value = self.not()?;
+        while self.peek_is("-a") {
+            self.pos += 1;
+            let right = self.not()?;
+            value = value && right;
+        }
+        Ok(value)
+    }
+
+    fn not(&mut self) -> Result<bool> {
+        if self.peek_is("!") {
+            self.pos += 1;
+            return Ok(!self.not()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool> {
+        let arg = self.next()?;
+        if arg == "(" {
+            let value = self.or()?;
+            if !self.peek_is(")") {
+                return Err(ErrorKind::ExpectingRightParentheses);
+            }
+            self.pos += 1;
+            return Ok(value);
+        }
+
+        if let Some(op) = self.peek() {
+            if is_binary(op) {
+                self.pos += 1;
+                let right = self.next()?;
+                return binary(arg, op, right);
+            }
+        }
+        if is_unary(arg) && self.peek().is_some() {
+            let operand = self.next()?;
+            return unary(arg, operand, self.cwd);
+        }
+        Ok(!arg.is_empty())
+    }
+}
+
+fn is_unary(op: &OsStr) -> bool {
+    ["-z", "-n", "-e", "-f", "-d", "-r", "-w", "-x", "-s", "-L", "-h"]
+        .iter()
+        .any(|u| op == *u)
+}
+
+/// Whether `op` compares two arguments, `-a` and `-o` combine expressions so they aren't included
+fn is_binary(op: &OsStr) -> bool {
+    ["=", "==", "!=", "-eq", "-ne", "-lt", "-le", "-gt", "-ge"]
+        .iter()
+        .any(|b| op == *b)
+}
+
+fn unary(op: &OsStr, operand: &OsStr, cwd: &Path) -> Result<bool> {
+    let path = cwd.join(operand);
+    Ok(match op.to_str().unwrap_or("") {
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        "-e" => fs::metadata(&path).is_ok(),
+        "-f" => fs::metadata(&path).map(|m| m.is_file()).unwrap_or(false),
+        "-d" => fs::metadata(&path).map(|m| m.is_dir()).unwrap_or(false),
+        "-s" => fs::metadata(&path).map(|m| m.len() > 0).unwrap_or(false),
+        "-L" | "-h" => fs::symlink_metadata(&path)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false),
+        "-r" => accessible(&path, libc::R_OK),
+        "-w" => accessible(&path, libc::W_OK),
+        "-x" => accessible(&path, libc::X_OK),
+        _ => return Err(ErrorKind::UnaryOperatorExpected(text(op))),
+    })
+}
+
+fn binary(left: &OsStr, op: &OsStr, right: &OsStr) -> Result<bool> {
+    let op = op.to_str().unwrap_or("");
+    match op {
+        "=" | "==" => return Ok(left == right),
+        "!=" => return Ok(left != right),
+        _ => (),
+    }
+
+    let (left, right) = (integer(left)?, integer(right)?);
+    Ok(match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        "-ge" => left >= right,
+        _ => return Err(ErrorKind::BinaryOperatorExpected(op.to_string())),
+    })
+}
+
+/// Parse an operand of an integer comparison, surrounding whitespace is allowed
+fn integer(s: &OsStr) -> Result<i64> {
+    s.to_str()
+        .and_then(|s| s.trim().parse::<i64>().ok())
+        .ok_or_else(|| ErrorKind::IntegerExpected(text(s)))
+}
+
+/// An argument as it's shown in an error message
+fn text(arg: &OsStr) -> String {
+    arg.to_string_lossy().into_owned()
+}
+
+/// Whether the shell's user has the given permission on a file
+fn accessible(path: &Path, mode: libc::c_int) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => unsafe { libc::access(c_path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn test(args: &[&str]) -> Result<bool> {
+        evaluate(args, &env::temp_dir())
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(test(&[]), Ok(false));
+        assert_eq!(test(&[""]), Ok(false));
+        assert_eq!(test(&["-z"]), Ok(true));
+        assert_eq!(test(&["-z", ""]), Ok(true));
+        assert_eq!(test(&["-n", ""]), Ok(false));
+        assert_eq!(test(&["a", "=", "a"]), Ok(true));
+        assert_eq!(test(&["a", "==", "b"]), Ok(false));
+        assert_eq!(test(&["a", "!=", "b"]), Ok(true));
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(test(&["10", "-gt", "9"]), Ok(true));
+        assert_eq!(test(&[" 3 ", "-eq", "3"]), Ok(true));
+        assert_eq!(test(&["-1", "-lt", "0"]), Ok(true));
+        assert_eq!(test(&["2", "-le", "1"]), Ok(false));
+        assert_eq!(test(&["1", "-ne", "1"]), Ok(false));
+        assert_eq!(test(&["1", "-ge", "1"]), Ok(true));
+        assert_eq!(test(&["1.5", "-eq", "1"]), Err(ErrorKind::IntegerExpected("1.5".to_string())));
+        assert_eq!(test(&["1", "-eq", "x"]), Err(ErrorKind::IntegerExpected("x".to_string())));
+    }
+
+    #[test]
+    fn files() {
+        let dir = env::temp_dir();
+        let file = dir.join(format!("rush-condition-{}", std::process::id()));
+        fs::write(&file, "x").unwrap();
+        let file = file.to_str().unwrap();
+        assert_eq!(test(&["-e", file]), Ok(true));
+        assert_eq!(test(&["-f", file]), Ok(true));
+        assert_eq!(test(&["-s", file]), Ok(true));
+        assert_eq!(test(&["-d", file]), Ok(false));
+        assert_eq!(test(&["-L", file]), Ok(false));
+        assert_eq!(test(&["-d", dir.to_str().unwrap()]), Ok(true));
+        assert_eq!(test(&["-e", "/nonexistent/rush-test"]), Ok(false));
+        fs::remove_file(file).unwrap();
+        assert_eq!(test(&["-r", "/"]), Ok(true));
+        assert_eq!(test(&["-x", "/"]), Ok(true));
+    }
+
+    #[test]
+    fn relative_paths_are_relative_to_the_working_directory() {
+        assert_eq!(evaluate(&["-d", "bin"], Path::new("/usr")), Ok(true));
+        assert_eq!(evaluate(&["-d", "usr"], Path::new("/")), Ok(true));
+    }
+
+    #[test]
+    fn negation_and_combination() {
+        assert_eq!(test(&["!", ""]), Ok(true));
+        assert_eq!(test(&["!", "a", "=", "a"]), Ok(false));
+        assert_eq!(test(&["a", "-a", ""]), Ok(false));
+        assert_eq!(test(&["a", "-o", ""]), Ok(true));
+        // -a binds tighter than -o
+        assert_eq!(test(&["a", "-o", "", "-a", ""]), Ok(true));
+        assert_eq!(test(&["(", "a", "-o", "", ")", "-a", ""]), Ok(false));
+        assert_eq!(test(&["!", "1", "-eq", "2", "-a", "-n", "x"]), Ok(true));
+    }
+
+    #[test]
+    fn arguments_are_disambiguated_by_count() {
+        // operators are plain strings when there aren't enough arguments for them to be operators
+        assert_eq!(test(&["-n"]), Ok(true));
+        assert_eq!(test(&["!"]), Ok(true));
+        assert_eq!(test(&["=", "=", "="]), Ok(true));
+        assert_eq!(test(&["(", "-o", ")"]), Ok(true));
+        assert_eq!(test(&["!", "-z", ""]), Ok(false));
+        assert_eq!(test(&["(", "!", "", ")"]), Ok(true));
+    }
+
+    #[test]
+    fn malformed_expressions() {
+        assert_eq!(test(&["a", "b"]), Err(ErrorKind::UnaryOperatorExpected("a".to_string())));
+        assert_eq!(test(&["a", "b", "c"]), Err(ErrorKind::BinaryOperatorExpected("b".to_string())));
+        assert_eq!(test(&["(", "a", "-a", "b"]), Err(ErrorKind::ExpectingRightParentheses));
+        assert_eq!(test(&["a", "=", "a", "b", "c"]), Err(ErrorKind::TooManyArguments));
+        assert_eq!(test(&["a", "-a", "b", "-a"]), Err(ErrorKind::ArgumentExpected));
+    }
+
+    #[test]
+    fn error_messages() {
+        assert_eq!(ErrorKind::IntegerExpected("x".to_string()).to_string(), "x: integer expression expected");
+        assert_eq!(ErrorKind::ExpectingRightParentheses.to_string(), "`)' expected");
+    }
+}
@@ -1,4 +1,4 @@
-use failure;
+use failure::Fail;
 use std::{fmt, result};
 
 pub type Result<T> = result::Result<T, Error>;
@@ -60,7 +60,7 @@ impl Error {
 }
 
 impl failure::Fail for Error {
-    fn cause(&self) -> Option<&failure::Fail> {
+    fn cause(&self) -> Option<&dyn failure::Fail> {
         self.inner.cause()
     }
 
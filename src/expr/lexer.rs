@@ -1,8 +1,10 @@
-use expr::errors::*;
-use expr::types::*;
+use crate::expr::errors::*;
+use crate::expr::types::*;
 
-use nom;
-use nom::types::CompleteStr;
+use nom::{
+    alt, call, char, delimited, do_parse, flat_map, map, named, opt, parse_to, preceded, recognize,
+    tag, take_while1, tuple, types::CompleteStr, ws,
+};
 
 #[derive(Debug, Clone)]
 pub struct TokenStream<'a> {
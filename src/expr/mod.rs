@@ -1,5 +1,6 @@
-//! Types & parser for shell expressions (everything inside "$(())" )
+//! Types & parser for shell expressions (everything inside "$(())" ), and the conditions `test` evaluates
 
+pub mod condition;
 mod errors;
 pub mod lexer;
 pub mod parser;
@@ -9,13 +10,18 @@ pub use self::errors::*;
 pub use self::parser::parse;
 pub use self::types::Expr;
 use self::types::Operator;
-use env::Variables;
+use crate::env::Variables;
+use crate::lang;
+use failure::ResultExt;
 use nom::types::CompleteStr;
 use std::ffi::OsString;
 use std::str::FromStr;
 
-pub fn eval<T: AsRef<str>>(s: T, vars: &mut Variables) -> Result<String> {
-    Ok(parse(s.as_ref())?.evaluate(vars).to_string())
+pub fn eval<T: AsRef<str>>(s: T, vars: &mut Variables) -> lang::Result<String> {
+    Ok(parse(s.as_ref())
+        .context(lang::ErrorKind::ExpressionError)?
+        .evaluate(vars)?
+        .to_string())
 }
 
 impl Expr {
@@ -26,7 +32,7 @@ impl Expr {
         }
     }
 
-    pub fn modify_variable<F: Fn(f64) -> f64>(self, vars: &mut Variables, f: F) -> Self {
+    pub fn modify_variable<F: Fn(f64) -> f64>(self, vars: &mut Variables, f: F) -> lang::Result<Self> {
         match self {
             Expr::Variable(n) => {
                 let name: OsString = n.to_string().into();
@@ -35,15 +41,15 @@ impl Expr {
                 )).map(|(_, y)| y as f64)
                     .unwrap_or(0.0_f64));
 
-                vars.define(&name, new_value.clone().to_string());
-                return Expr::Number(new_value);
+                vars.define(&name, new_value.clone().to_string())?;
+                return Ok(Expr::Number(new_value));
             }
             _ => (),
         };
 
-        let me = self.evaluate(vars);
+        let me = self.evaluate(vars)?;
 
-        match me {
+        Ok(match me {
             Expr::Variable(n) => {
                 let name = n.to_string().into();
                 let new_value = f(lexer::float(CompleteStr(
@@ -51,7 +57,7 @@ impl Expr {
                 )).map(|(_, y)| y as f64)
                     .unwrap_or(0.0_f64));
 
-                vars.define(n.clone().to_string(), new_value.clone().to_string());
+                vars.define(n.clone().to_string(), new_value.clone().to_string())?;
                 Expr::Number(new_value)
             }
             Expr::Number(n) => Expr::Number(n),
@@ -59,10 +65,10 @@ impl Expr {
             Expr::Infix(n) => Expr::Infix(n),
             Expr::Prefix(n) => Expr::Prefix(n),
             Expr::Suffix(n) => Expr::Suffix(n),
-        }
+        })
     }
 
-    pub fn assign_variable<F: Fn(f64) -> f64>(mut self, vars: &mut Variables, f: F) -> Self {
+    pub fn assign_variable<F: Fn(f64) -> f64>(mut self, vars: &mut Variables, f: F) -> lang::Result<Self> {
         for _ in 0..2 {
             match self {
                 Expr::Variable(n) => {
@@ -71,99 +77,100 @@ impl Expr {
                         vars.value(&name).to_str().unwrap_or("0"),
                     )).map(|(_, y)| y as f64)
                         .unwrap_or(0.0_f64));
-                    vars.define(name, new_value.to_string());
-                    return Expr::Number(new_value);
+                    vars.define(name, new_value.to_string())?;
+                    return Ok(Expr::Number(new_value));
                 }
-                _ => self = self.evaluate(vars),
+                _ => self = self.evaluate(vars)?,
             }
         }
-        self
+        Ok(self)
     }
 
-    pub fn modify_number<F: Fn(f64) -> f64>(self, vars: &mut Variables, f: F) -> Self {
-        let me = self.evaluate(vars);
-        match me {
+    pub fn modify_number<F: Fn(f64) -> f64>(self, vars: &mut Variables, f: F) -> lang::Result<Self> {
+        let me = self.evaluate(vars)?;
+        Ok(match me {
             Expr::Number(n) => Expr::Number(f(n)),
             _ => me,
-        }
+        })
     }
 
-    pub fn modify_number_i<F: Fn(isize) -> isize>(self, vars: &mut Variables, f: F) -> Self {
-        let me = self.evaluate(vars);
-        match me {
+    pub fn modify_number_i<F: Fn(isize) -> isize>(self, vars: &mut Variables, f: F) -> lang::Result<Self> {
+        let me = self.evaluate(vars)?;
+        Ok(match me {
             Expr::Number(n) => Expr::Number(f(n as isize) as f64),
             _ => me,
-        }
+        })
     }
 
-    pub fn evaluate(self, vars: &mut Variables) -> Self {
-        match self {
+    /// Work out an expression's value, which fails if it assigns to a readonly variable
+    pub fn evaluate(self, vars: &mut Variables) -> lang::Result<Self> {
+        Ok(match self {
             Expr::Number(n) => Expr::Number(n),
             Expr::Variable(n) => Expr::Number(
-                lexer::float(CompleteStr(&vars.value(&n.into()).into_string().unwrap()))
+                lexer::float(CompleteStr(vars.value(&n.into()).to_str().unwrap_or("0")))
                     .map(|(_, y)| y as f64)
                     .unwrap_or(0.0_f64),
             ),
             Expr::Condition(cond) => {
-                if cond.condition.clone().evaluate(vars).as_boolean() {
-                    cond.on_true.evaluate(vars)
+                if cond.condition.clone().evaluate(vars)?.as_boolean() {
+                    cond.on_true.evaluate(vars)?
                 } else {
-                    cond.on_false.evaluate(vars)
+                    cond.on_false.evaluate(vars)?
                 }
             }
             Expr::Prefix(pre) => match pre.operator {
-                Operator::Increment => pre.right.modify_variable(vars, |v| v + 1.0),
-                Operator::Decrement => pre.right.modify_variable(vars, |v| v - 1.0),
+                Operator::Increment => pre.right.modify_variable(vars, |v| v + 1.0)?,
+                Operator::Decrement => pre.right.modify_variable(vars, |v| v - 1.0)?,
                 Operator::Not => if pre.right.as_boolean() {
                     Expr::Number(0.0_f64)
                 } else {
                     Expr::Number(1.0_f64)
                 },
-                Operator::Negate => pre.right.modify_number(vars, |x| !(x as isize) as f64),
+                Operator::Negate => pre.right.modify_number(vars, |x| !(x as isize) as f64)?,
                 Operator::Add => pre.right,
-                Operator::Subtract => pre.right.modify_number(vars, |x| -x),
+                Operator::Subtract => pre.right.modify_number(vars, |x| -x)?,
                 _ => unreachable!(),
             },
             Expr::Suffix(suf) => {
-                let copy = suf.left.clone().evaluate(vars);
+                let copy = suf.left.clone().evaluate(vars)?;
                 match suf.operator {
-                    Operator::Increment => suf.left.modify_variable(vars, |v| v + 1.0),
-                    Operator::Decrement => suf.left.modify_variable(vars, |v| v + 1.0),
+                    Operator::Increment => suf.left.modify_variable(vars, |v| v + 1.0)?,
+                    Operator::Decrement => suf.left.modify_variable(vars, |v| v + 1.0)?,
                     _ => unreachable!(),
                 };
                 copy
             }
             Expr::Infix(inf) => {
-                let right = match inf.right.clone().evaluate(vars) {
+                let right = match inf.right.clone().evaluate(vars)? {
                     Expr::Number(v) => v,
                     _ => unreachable!(),
                 };
 
                 match inf.operator {
-                    Operator::Add => inf.left.modify_number(vars, |v| v + right),
-                    Operator::Subtract => inf.left.modify_number(vars, |v| v - right),
-                    Operator::Multiply => inf.left.modify_number(vars, |v| v * right),
-                    Operator::Divide => inf.left.modify_number(vars, |v| v / right),
-                    Operator::Modulo => inf.left.modify_number(vars, |v| v % right),
-                    Operator::LeftShift => inf.left.modify_number_i(vars, |v| v << right as isize),
-                    Operator::RightShift => inf.left.modify_number_i(vars, |v| v >> right as isize),
+                    Operator::Add => inf.left.modify_number(vars, |v| v + right)?,
+                    Operator::Subtract => inf.left.modify_number(vars, |v| v - right)?,
+                    Operator::Multiply => inf.left.modify_number(vars, |v| v * right)?,
+                    Operator::Divide => inf.left.modify_number(vars, |v| v / right)?,
+                    Operator::Modulo => inf.left.modify_number(vars, |v| v % right)?,
+                    Operator::LeftShift => inf.left.modify_number_i(vars, |v| v << right as isize)?,
+                    Operator::RightShift => inf.left.modify_number_i(vars, |v| v >> right as isize)?,
                     Operator::LessThan => inf.left
-                        .modify_number(vars, |v| (v < right) as isize as f64),
+                        .modify_number(vars, |v| (v < right) as isize as f64)?,
                     Operator::LessThanOrEqual => inf.left
-                        .modify_number(vars, |v| (v <= right) as isize as f64),
+                        .modify_number(vars, |v| (v <= right) as isize as f64)?,
                     Operator::GreaterThan => inf.left
-                        .modify_number(vars, |v| (v > right) as isize as f64),
+                        .modify_number(vars, |v| (v > right) as isize as f64)?,
                     Operator::GreaterThanOrEqual => inf.left
-                        .modify_number(vars, |v| (v >= right) as isize as f64),
+                        .modify_number(vars, |v| (v >= right) as isize as f64)?,
                     Operator::Equal => inf.left
-                        .modify_number(vars, |v| (v == right) as isize as f64),
+                        .modify_number(vars, |v| (v == right) as isize as f64)?,
                     Operator::NotEqual => inf.left
-                        .modify_number(vars, |v| (v != right) as isize as f64),
-                    Operator::BitAnd => inf.left.modify_number_i(vars, |v| v & right as isize),
+                        .modify_number(vars, |v| (v != right) as isize as f64)?,
+                    Operator::BitAnd => inf.left.modify_number_i(vars, |v| v & right as isize)?,
                     Operator::BitExclusiveOr => {
-                        inf.left.modify_number_i(vars, |v| v ^ right as isize)
+                        inf.left.modify_number_i(vars, |v| v ^ right as isize)?
                     }
-                    Operator::BitOr => inf.left.modify_number_i(vars, |v| v | right as isize),
+                    Operator::BitOr => inf.left.modify_number_i(vars, |v| v | right as isize)?,
                     Operator::And => if inf.left.as_boolean() && inf.right.as_boolean() {
                         Expr::Number(1.0_f64)
                     } else {
@@ -174,26 +181,26 @@ impl Expr {
                     } else {
                         Expr::Number(0.0_f64)
                     },
-                    Operator::Assign => inf.left.assign_variable(vars, |_| right),
-                    Operator::AssignAdd => inf.left.assign_variable(vars, |v| v + right),
-                    Operator::AssignSubtract => inf.left.assign_variable(vars, |v| v - right),
-                    Operator::AssignMultiply => inf.left.assign_variable(vars, |v| v * right),
-                    Operator::AssignDivide => inf.left.assign_variable(vars, |v| v / right),
-                    Operator::AssignModulo => inf.left.assign_variable(vars, |v| v % right),
+                    Operator::Assign => inf.left.assign_variable(vars, |_| right)?,
+                    Operator::AssignAdd => inf.left.assign_variable(vars, |v| v + right)?,
+                    Operator::AssignSubtract => inf.left.assign_variable(vars, |v| v - right)?,
+                    Operator::AssignMultiply => inf.left.assign_variable(vars, |v| v * right)?,
+                    Operator::AssignDivide => inf.left.assign_variable(vars, |v| v / right)?,
+                    Operator::AssignModulo => inf.left.assign_variable(vars, |v| v % right)?,
                     Operator::AssignBitAnd => inf.left
-                        .assign_variable(vars, |v| (v as isize & right as isize) as f64),
+                        .assign_variable(vars, |v| (v as isize & right as isize) as f64)?,
                     Operator::AssignBitExclusiveOr => inf.left
-                        .assign_variable(vars, |v| (v as isize ^ right as isize) as f64),
+                        .assign_variable(vars, |v| (v as isize ^ right as isize) as f64)?,
                     Operator::AssignBitOr => inf.left
-                        .assign_variable(vars, |v| (v as isize | right as isize) as f64),
+                        .assign_variable(vars, |v| (v as isize | right as isize) as f64)?,
                     Operator::AssignLeftShift => inf.left
-                        .assign_variable(vars, |v| ((v as isize) << right as isize) as f64),
+                        .assign_variable(vars, |v| ((v as isize) << right as isize) as f64)?,
                     Operator::AssignRightShift => inf.left
-                        .assign_variable(vars, |v| (v as isize >> right as isize) as f64),
+                        .assign_variable(vars, |v| (v as isize >> right as isize) as f64)?,
                     _ => unreachable!(),
                 }
             }
-        }
+        })
     }
 }
 
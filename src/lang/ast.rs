@@ -1,4 +1,6 @@
-use lang::word::Word;
+use crate::lang::word::Word;
+use crate::lang::{ErrorKind, Result};
+use std::fmt;
 use std::os::unix::io::RawFd;
 use std::vec::Vec;
 
@@ -17,8 +19,11 @@ pub enum Command {
     Case(Box<Case>),
     While(Box<While>),
     For(Box<For>),
+    ArithmeticFor(Box<ArithmeticFor>),
     Until(Box<Until>),
     Function(Box<Function>),
+    Time(Box<Command>),
+    Background(Box<Background>),
     Comment(String),
 }
 
@@ -44,13 +49,17 @@ pub enum IoOperation {
     HereDocument,      // <<
     HereDocumentStrip, // <<-
     InputDupFd,        // <&
-    OutputDupFd,       // &>
+    OutputDupFd,       // >&
     ReadWrite,         // <>
 }
 
 #[derive(Debug, Clone)]
 pub struct SimpleCommand {
     pub arguments: Vec<Word>,
+    /// The line the command starts on, counted from 1, or 0 for a command that wasn't read from anywhere.
+    /// The parser puts the length of the input left after the start of the command here,
+    /// which is turned into the line once the whole input is parsed.
+    pub line: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -83,6 +92,21 @@ pub struct For {
     pub body: Command,
 }
 
+#[derive(Debug, Clone)]
+pub struct ArithmeticFor {
+    pub init: Word,
+    pub condition: Word,
+    pub step: Word,
+    pub body: Command,
+}
+
+/// A list run asynchronously with `&`, the text is what the `jobs` builtin shows
+#[derive(Debug, Clone)]
+pub struct Background {
+    pub command: Command,
+    pub text: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct Function {
     pub name: Word,
@@ -98,6 +122,8 @@ pub struct Case {
 #[derive(Debug, Clone)]
 pub struct Pipeline {
     pub bang: bool,
+    /// Whether the standard error of the command right before the pipe goes into it too, with `|&`
+    pub stderr: bool,
     pub from: Command,
     pub to: Command,
 }
@@ -141,20 +167,124 @@ where
     T: AsRef<str>,
 {
     fn from(s: T) -> Command {
-        use lang::parser::commandline;
+        use crate::lang::parser::commandline;
         use nom::types::CompleteStr;
-        commandline(CompleteStr(s.as_ref())).unwrap().1
+        let mut command = commandline(CompleteStr(s.as_ref())).unwrap().1;
+        command.number_lines(s.as_ref());
+        command
     }
 }
 
 impl Command {
+    /// Parse a complete piece of shell input, unlike `Command::from` it fails if any of the input is left over
+    pub fn parse<T: AsRef<str>>(s: T) -> Result<Command> {
+        use crate::lang::parser::commandline;
+        use nom::types::CompleteStr;
+        match commandline(CompleteStr(s.as_ref())) {
+            Ok((rest, mut command)) => if rest.0.trim().is_empty() {
+                command.number_lines(s.as_ref());
+                Ok(command)
+            } else {
+                let near = rest.0.split_whitespace().next().unwrap_or("");
+                Err(ErrorKind::SyntaxError(near.to_string()).into())
+            },
+            Err(_) => Err(ErrorKind::SyntaxError(s.as_ref().trim().to_string()).into()),
+        }
+    }
+
+    /// Parse a whole script like `parse` does, a syntax error also says which line it's on
+    pub fn parse_script<T: AsRef<str>>(s: T) -> Result<Command> {
+        use crate::lang::parser::commandline;
+        use nom::types::CompleteStr;
+        let s = s.as_ref();
+        let (unparsed, command) = match commandline(CompleteStr(s)) {
+            Ok((rest, command)) => (rest.0.trim_start(), Some(command)),
+            Err(_) => (s.trim_start(), None),
+        };
+        match command {
+            Some(mut command) if unparsed.is_empty() => {
+                command.number_lines(s);
+                Ok(command)
+            }
+            _ => {
+                let line = s[..s.len() - unparsed.len()].matches('\n').count() + 1;
+                let near = unparsed.split_whitespace().next().unwrap_or("");
+                Err(ErrorKind::ScriptSyntaxError(line, near.to_string()).into())
+            }
+        }
+    }
+
     pub fn simple(args: Vec<Word>) -> Command {
-        Command::SimpleCommand(SimpleCommand { arguments: args })
+        Command::SimpleCommand(SimpleCommand {
+            arguments: args,
+            line: 0,
+        })
+    }
+
+    /// Turn the input lengths the parser left in each simple command into the lines they start on
+    fn number_lines(&mut self, source: &str) {
+        let newlines: Vec<usize> = source.match_indices('\n').map(|(i, _)| i).collect();
+        self.renumber(&|rest| {
+            let offset = source.len().saturating_sub(rest);
+            match newlines.binary_search(&offset) {
+                Ok(i) | Err(i) => i + 1,
+            }
+        });
+    }
+
+    /// Change the line of every simple command, like moving the lines of an interactive command
+    /// after the ones read before it
+    pub fn renumber<F: Fn(usize) -> usize>(&mut self, f: &F) {
+        match self {
+            Command::SimpleCommand(cmd) => cmd.line = f(cmd.line),
+            Command::Pipeline(pipe) => {
+                pipe.from.renumber(f);
+                pipe.to.renumber(f);
+            }
+            Command::FileRedirect(redirect) => redirect.left.renumber(f),
+            Command::ConditionalPair(cond) => {
+                cond.left.renumber(f);
+                cond.right.renumber(f);
+            }
+            Command::Group(group) | Command::BraceGroup(group) | Command::SubShell(group) => {
+                for command in &mut group.commands {
+                    command.renumber(f);
+                }
+            }
+            Command::If(cond) => {
+                cond.condition.renumber(f);
+                cond.success.renumber(f);
+                cond.failure.renumber(f);
+            }
+            Command::Case(case) => {
+                for (_, command) in &mut case.cases {
+                    command.renumber(f);
+                }
+            }
+            Command::While(lp) => {
+                lp.condition.renumber(f);
+                lp.body.renumber(f);
+            }
+            Command::Until(lp) => {
+                lp.condition.renumber(f);
+                lp.body.renumber(f);
+            }
+            Command::For(lp) => {
+                lp.condition.renumber(f);
+                lp.body.renumber(f);
+            }
+            Command::ArithmeticFor(lp) => lp.body.renumber(f),
+            Command::Function(func) => func.body.renumber(f),
+            Command::Time(timed) => timed.renumber(f),
+            Command::Background(bg) => bg.command.renumber(f),
+            Command::Comment(_) => (),
+        }
     }
 
-    pub fn pipeline(bang: bool, source: Command, dest: Command) -> Command {
+    pub fn pipeline(bang: bool, stderr: bool, source: Command, dest: Command) -> Command {
         Command::Pipeline(Box::new(Pipeline {
-            bang: bang,
+            bang,
+            stderr,
             from: source,
             to: dest,
         }))
@@ -175,7 +305,325 @@ impl Command {
         }))
     }
 
+    pub fn background<T: Into<String>>(command: Command, text: T) -> Command {
+        Command::Background(Box::new(Background {
+            command,
+            text: text.into(),
+        }))
+    }
+
     pub fn group(source: Vec<Command>) -> Command {
         Command::Group(Box::new(CommandGroup { commands: source }))
     }
+
+    /// Whether there's nothing but comments to run, which leaves `$?` alone
+    pub fn is_comment(&self) -> bool {
+        match self {
+            Command::Comment(_) => true,
+            Command::Group(group) | Command::BraceGroup(group) => group.commands.iter().all(|c| c.is_comment()),
+            _ => false,
+        }
+    }
+
+    /// Commands to run in the current environment, like `{ a; b; }`
+    pub fn brace_group(body: Command) -> Command {
+        Command::BraceGroup(Command::into_group(body))
+    }
+
+    /// Commands to run in a copy of the shell, like `(a; b)`
+    pub fn subshell(body: Command) -> Command {
+        Command::SubShell(Command::into_group(body))
+    }
+
+    fn into_group(body: Command) -> Box<CommandGroup> {
+        match body {
+            Command::Group(group) => group,
+            command => Box::new(CommandGroup {
+                commands: vec![command],
+            }),
+        }
+    }
+
+    /// Add words to the end of the last simple command, which is where the words after an alias go
+    pub fn append_arguments(&mut self, args: &[Word]) {
+        if args.is_empty() {
+            return;
+        }
+        match self {
+            Command::SimpleCommand(cmd) => cmd.arguments.extend_from_slice(args),
+            Command::Group(group) | Command::BraceGroup(group) => match group.commands.last_mut() {
+                Some(last) => last.append_arguments(args),
+                None => group.commands.push(Command::simple(args.to_vec())),
+            },
+            Command::Pipeline(pipe) => pipe.to.append_arguments(args),
+            Command::ConditionalPair(cond) => cond.right.append_arguments(args),
+            Command::FileRedirect(redirect) => redirect.left.append_arguments(args),
+            _ => (),
+        }
+    }
+}
+
+/// How many spaces each level of a compound command's body is indented by when it's printed
+const INDENT: usize = 4;
+
+/// A command is written back out as shell text that parses to the same command.
+/// Lists are written one command to a line, and the bodies of groups, loops and functions are indented.
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl Command {
+    /// Write the command at some depth of indentation, which the lines after its first one start at
+    fn write(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        match self {
+            Command::SimpleCommand(cmd) => {
+                for (i, arg) in cmd.arguments.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(" ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                Ok(())
+            }
+            Command::Pipeline(pipe) => {
+                if pipe.bang {
+                    f.write_str("! ")?;
+                }
+                Command::write_pipeline(pipe, f, depth)
+            }
+            Command::FileRedirect(redirect) => {
+                redirect.left.write(f, depth)?;
+                let mut bare = match &redirect.left {
+                    Command::SimpleCommand(cmd) => cmd.arguments.is_empty(),
+                    _ => false,
+                };
+                for destination in &redirect.redirects {
+                    if !bare {
+                        f.write_str(" ")?;
+                    }
+                    bare = false;
+                    write!(f, "{}", destination)?;
+                }
+                Ok(())
+            }
+            Command::ConditionalPair(cond) => {
+                cond.left.write(f, depth)?;
+                f.write_str(match cond.operator {
+                    ConditionOperator::AndIf => " && ",
+                    ConditionOperator::OrIf => " || ",
+                })?;
+                match &cond.right {
+                    // lists group from the left, one on the right has to be braced to stay together
+                    Command::ConditionalPair(_) => {
+                        f.write_str("{ ")?;
+                        cond.right.write(f, depth)?;
+                        f.write_str("; }")
+                    }
+                    right => right.write(f, depth),
+                }
+            }
+            Command::Group(group) => {
+                for (i, command) in group.commands.iter().enumerate() {
+                    if i > 0 {
+                        Command::write_newline(f, depth)?;
+                    }
+                    command.write(f, depth)?;
+                }
+                Ok(())
+            }
+            Command::BraceGroup(group) => {
+                f.write_str("{")?;
+                Command::write_body(f, &group.commands, depth + 1)?;
+                Command::write_newline(f, depth)?;
+                f.write_str("}")
+            }
+            Command::SubShell(group) => {
+                f.write_str("(")?;
+                Command::write_body(f, &group.commands, depth + 1)?;
+                Command::write_newline(f, depth)?;
+                f.write_str(")")
+            }
+            Command::If(cond) => {
+                f.write_str("if ")?;
+                cond.condition.write(f, depth)?;
+                f.write_str("; then")?;
+                Command::write_body(f, cond.success.lines(), depth + 1)?;
+                if !cond.failure.lines().is_empty() {
+                    Command::write_newline(f, depth)?;
+                    f.write_str("else")?;
+                    Command::write_body(f, cond.failure.lines(), depth + 1)?;
+                }
+                Command::write_newline(f, depth)?;
+                f.write_str("fi")
+            }
+            Command::Case(case) => {
+                write!(f, "case {} in", case.input)?;
+                for (pattern, command) in &case.cases {
+                    Command::write_newline(f, depth + 1)?;
+                    write!(f, "{})", pattern)?;
+                    Command::write_body(f, command.lines(), depth + 2)?;
+                    Command::write_newline(f, depth + 2)?;
+                    f.write_str(";;")?;
+                }
+                Command::write_newline(f, depth)?;
+                f.write_str("esac")
+            }
+            Command::While(lp) => Command::write_loop(f, "while", &lp.condition, &lp.body, depth),
+            Command::Until(lp) => Command::write_loop(f, "until", &lp.condition, &lp.body, depth),
+            Command::For(lp) => Command::write_loop(f, "for", &lp.condition, &lp.body, depth),
+            Command::ArithmeticFor(lp) => {
+                write!(f, "for (({}; {}; {})); do", lp.init, lp.condition, lp.step)?;
+                Command::write_body(f, lp.body.lines(), depth + 1)?;
+                Command::write_newline(f, depth)?;
+                f.write_str("done")
+            }
+            Command::Function(func) => {
+                write!(f, "function {} ", func.name)?;
+                func.body.write(f, depth)
+            }
+            Command::Time(timed) => {
+                f.write_str("time ")?;
+                timed.write(f, depth)
+            }
+            Command::Background(bg) => {
+                bg.command.write(f, depth)?;
+                f.write_str(" &")
+            }
+            Command::Comment(text) => write!(f, "#{}", text),
+        }
+    }
+
+    /// Write the commands of a pipeline without its `!`, which the parser puts on every pipe of it
+    fn write_pipeline(pipe: &Pipeline, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        match &pipe.from {
+            Command::Pipeline(from) => Command::write_pipeline(from, f, depth)?,
+            from => from.write(f, depth)?,
+        }
+        f.write_str(if pipe.stderr { " |& " } else { " | " })?;
+        pipe.to.write(f, depth)
+    }
+
+    fn write_loop(f: &mut fmt::Formatter, keyword: &str, condition: &Command, body: &Command, depth: usize) -> fmt::Result {
+        write!(f, "{} ", keyword)?;
+        condition.write(f, depth)?;
+        f.write_str("; do")?;
+        Command::write_body(f, body.lines(), depth + 1)?;
+        Command::write_newline(f, depth)?;
+        f.write_str("done")
+    }
+
+    /// Write each command on a line of its own
+    fn write_body(f: &mut fmt::Formatter, commands: &[Command], depth: usize) -> fmt::Result {
+        for command in commands {
+            Command::write_newline(f, depth)?;
+            command.write(f, depth)?;
+        }
+        Ok(())
+    }
+
+    fn write_newline(f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(f, "\n{:width$}", "", width = depth * INDENT)
+    }
+
+    /// The commands of a list, or just this one command when it isn't a list
+    fn lines(&self) -> &[Command] {
+        match self {
+            Command::Group(group) => &group.commands,
+            command => std::slice::from_ref(command),
+        }
+    }
+}
+
+impl IoOperation {
+    /// The operator as it's written in the source
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            IoOperation::Input => "<",
+            IoOperation::OutputCreate => ">",
+            IoOperation::Output => ">|",
+            IoOperation::OutputAppend => ">>",
+            IoOperation::HereDocument => "<<",
+            IoOperation::HereDocumentStrip => "<<-",
+            IoOperation::InputDupFd => "<&",
+            IoOperation::OutputDupFd => ">&",
+            IoOperation::ReadWrite => "<>",
+        }
+    }
+}
+
+impl fmt::Display for RedirectDestination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(fd) = self.fd {
+            write!(f, "{}", fd)?;
+        }
+        write!(f, "{}{}", self.operation.symbol(), self.file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a command parsed to, leaving out the lines it was on and the text shown for background jobs,
+    /// which change when it's printed on different lines
+    fn shape(command: &Command) -> String {
+        let mut command = command.clone();
+        command.renumber(&|_| 0);
+        let debug = format!("{:?}", command);
+        let mut shape = String::new();
+        let mut rest = debug.as_str();
+        while let Some(start) = rest.find("text: \"") {
+            shape.push_str(&rest[..start]);
+            let mut escaped = false;
+            let end = rest[start + 7..]
+                .char_indices()
+                .find(|&(_, c)| {
+                    let closes = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    closes
+                })
+                .map(|(i, _)| start + 7 + i + 1)
+                .unwrap();
+            rest = &rest[end..];
+        }
+        shape + rest
+    }
+
+    #[test]
+    fn printed_commands_parse_back_to_the_same_command() {
+        let corpus = [
+            "echo hello world",
+            "echo 'single $quoted' \"double $HOME \\\"q\\\"\" \\$x",
+            "echo ${x:-default} ${#} ${x:=set} ${u^^} ${a[1]} ${a[@]} ${!r} $((1 + 2 * $n)) ~/dir *.rs file?",
+            "A=1 B='two words' env",
+            "echo \"it's $x\" 'a \"b\" `c`' \"\"",
+            "a | b |& c",
+            "! a | b",
+            "a && b || c && d",
+            "a && { b; c; } || (d; e)",
+            "sleep 1 & echo started; wait",
+            "cat <in >out 2>&1 >>log 3<>rw 4>|clobber",
+            "{ echo a; echo b; } >out",
+            "time sleep 0",
+            "for ((i = 0; i < 3; i++)); do echo $i; done",
+            "function f { local x=$1; g() { echo $x; }; g | cat; }",
+            "f() { for ((;;)); do break; done; }",
+            "echo one\n# a comment\necho two",
+        ];
+        for source in corpus.iter() {
+            let parsed = Command::parse(source).unwrap_or_else(|e| panic!("{}\n{}", source, e));
+            let printed = parsed.to_string();
+            let reparsed = Command::parse(&printed).unwrap_or_else(|e| panic!("{}\n{}\n{}", source, printed, e));
+            assert_eq!(shape(&parsed), shape(&reparsed), "{}\nprinted as\n{}", source, printed);
+            assert_eq!(printed, reparsed.to_string(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn bodies_are_indented_one_level_each() {
+        let printed = Command::parse("function f { a; { b; c; }; }").unwrap().to_string();
+        assert_eq!(printed.trim_end(), "function f {\n    a\n    {\n        b\n        c\n    }\n}");
+    }
 }
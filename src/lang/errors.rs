@@ -1,5 +1,6 @@
-use failure;
-use lang::exec;
+use crate::lang::exec;
+use failure::Fail;
+use nix::sys::signal::Signal;
 use std::os::unix::io::RawFd;
 use std::{fmt, result};
 
@@ -44,6 +45,9 @@ pub enum ErrorKind {
     #[fail(display = "invalid job {:?}", _0)]
     InvalidJobId(exec::Jid),
 
+    #[fail(display = "%{}: no such job", _0)]
+    NoSuchJob(usize),
+
     #[fail(
         display = "failed to close a pipe file descriptor in the parent process (action: {:?})",
         _0
@@ -52,6 +56,45 @@ pub enum ErrorKind {
 
     #[fail(display = "failed to wait for signal")]
     SigWaitFailed,
+
+    #[fail(display = "syntax error near `{}'", _0)]
+    SyntaxError(String),
+
+    #[fail(display = "line {}: syntax error near `{}'", _0, _1)]
+    ScriptSyntaxError(usize, String),
+
+    #[fail(display = "{}: {}", _0, _1)]
+    RedirectFailed(String, String),
+
+    #[fail(display = "redirecting file descriptor {} is not supported", _0)]
+    UnsupportedRedirect(RawFd),
+
+    #[fail(display = "{}: {}", _0, _1)]
+    UnsetParameter(String, String),
+
+    #[fail(display = "{}: readonly variable", _0)]
+    ReadonlyVariable(String),
+
+    #[fail(display = "{}: not an array", _0)]
+    NotAnArray(String),
+
+    #[fail(display = "{}: bad array subscript", _0)]
+    BadSubscript(String),
+
+    #[fail(display = "'{}': not a valid identifier", _0)]
+    InvalidIdentifier(String),
+
+    #[fail(display = "{}: circular name reference", _0)]
+    CircularReference(String),
+
+    #[fail(display = "{}: maximum function nesting level exceeded ({})", _0, _1)]
+    FunctionNesting(String, usize),
+
+    #[fail(display = "interrupted")]
+    Interrupted,
+
+    #[fail(display = "wait interrupted by {:?}", _0)]
+    WaitInterrupted(Signal),
 }
 
 impl Error {
@@ -61,7 +104,7 @@ impl Error {
 }
 
 impl failure::Fail for Error {
-    fn cause(&self) -> Option<&failure::Fail> {
+    fn cause(&self) -> Option<&dyn failure::Fail> {
         self.inner.cause()
     }
 
@@ -1,55 +1,345 @@
-use env::functions::Functions;
-use env::traps;
-use env::variables::Variables;
+use crate::builtins;
+use crate::env::aliases::Aliases;
+use crate::env::functions::Functions;
+use crate::env::hash::PathCache;
+use crate::env::options::Options;
+use crate::env::traps;
+use crate::env::variables::{Hidden, Variables};
+use crate::expr;
+use crate::lang::ast::ArithmeticFor;
+use crate::lang::ast::Command;
+use crate::lang::ast::ConditionOperator;
+use crate::lang::ast::Pipeline;
+use crate::lang::ast::SimpleCommand;
+use crate::lang::ast::{IoOperation, RedirectDestination};
+use crate::lang::word::{self, Word};
+use crate::lang::{Error, ErrorKind, Result};
 use failure::ResultExt;
-use lang::ast::Command;
-use lang::ast::ConditionOperator;
-use lang::word::Word;
-use lang::{Error, ErrorKind, Result};
+use nix::errno::Errno;
+use nix::fcntl::{self, FdFlag, OFlag};
 use nix::libc;
 use nix::sys::signal;
-use nix::sys::wait::{wait, WaitStatus};
+use nix::sys::stat::Mode;
+use nix::sys::wait::{wait, waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd;
 use std::collections::{BTreeMap, BTreeSet};
 use std::env;
+use std::fs;
 use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::RawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Copy, Clone, Eq, Ord, PartialEq, PartialOrd)]
 pub struct Jid(u32);
 
 #[derive(Debug, Clone)]
 pub struct ExecutionContext {
+    /// The logical working directory, the one $PWD shows and children start in. Change it with `set_cwd`.
     pub cwd: PathBuf,
+    /// The directories saved by `pushd`, the most recently pushed first
+    pub dir_stack: Vec<PathBuf>,
     vars: Variables,
     funcs: Functions,
+    aliases: Aliases,
+    hashed: PathCache,
+    options: Options,
+    interactive: bool,
+    /// Where `getopts` is inside a word of clustered options, and the OPTIND it was at
+    option_cursor: (usize, usize),
+    /// The fds from 3 up that `exec` opened for the script, by number. Each is kept as one of the shell's own fds
+    /// and put in place for every command, so the script's fd numbers never clash with the shell's.
+    script_fds: BTreeMap<RawFd, RawFd>,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct ExitStatus {
     pub pid: unistd::Pid,
+    /// A process killed by a signal gets 128 plus the signal's number, the same as in other shells
     pub exit_code: i32,
     pub core_dumped: bool,
     pub signal: Option<signal::Signal>,
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum JobStatus {
     Running,
+    Stopped,
     Complete(ExitStatus),
 }
 
+/// A job started in the background, the user can refer to it with a job spec like `%1`
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: usize,
+    /// The command as it was typed
+    pub text: String,
+    pub pids: Vec<unistd::Pid>,
+    /// The process group all of the job's processes share, jobs only get their own with job control
+    pub pgid: Option<unistd::Pid>,
+    /// Whether the job is sent SIGHUP when the shell exits, `disown -h` clears it
+    pub hangup: bool,
+    jids: Vec<Jid>,
+}
+
+impl Job {
+    /// Where to send a signal so every process in the job gets it, the whole process group at once if there is one
+    pub fn signal_targets(&self) -> Vec<unistd::Pid> {
+        match self.pgid {
+            Some(pgid) => vec![unistd::Pid::from_raw(-libc::pid_t::from(pgid))],
+            None => self.pids.clone(),
+        }
+    }
+}
+
+/// Non-local control flow requested by a builtin.
+/// While a flow is pending, command lists stop executing and unwind until something handles it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Flow {
+    /// Leave the shell with the given exit code
+    Exit(i32),
+    /// Leave the innermost function or sourced file with the given exit code
+    Return(i32),
+    /// Leave the given number of enclosing loops
+    Break(usize),
+    /// Leave the given number of enclosing loops, then start the next iteration of the last one
+    Continue(usize),
+}
+
 pub struct JobManager {
     next_jid: u32,
     running_jobs: BTreeMap<libc::pid_t, Jid>,
     completed_jobs: BTreeMap<Jid, ExitStatus>,
+    stopped_jobs: BTreeSet<Jid>,
+    flow: Option<Flow>,
+    /// How many functions and sourced files are running, `return` is only allowed inside one
+    returnable_depth: usize,
+    /// How many function calls are running
+    function_depth: usize,
+    /// How many loops are running in the current function, `break` and `continue` are only allowed inside one
+    loop_depth: usize,
+    /// The aliases being expanded, an alias isn't expanded again inside itself so `alias ls='ls -F'` works
+    expanding_aliases: Vec<String>,
+    jobs: Vec<Job>,
+    /// Job ids from least to most recently started, the last one is the current job
+    job_order: Vec<usize>,
+    /// Whether jobs get their own process groups and the terminal, only interactive shells do job control
+    job_control: bool,
+    shell_pgid: unistd::Pid,
+    /// The process group each process was put in
+    process_groups: BTreeMap<Jid, unistd::Pid>,
+    /// The process group of the job being started, set once its first process is spawned
+    job_pgid: Option<unistd::Pid>,
+    /// The commands of the job being started, used to describe it if it's stopped
+    job_text: Vec<String>,
+    /// Whether the job being started runs in the background, so it doesn't get the terminal
+    starting_background: bool,
+    /// Whether the command being started is the last thing the shell will do, so a program can take over
+    /// the shell's process instead of being forked
+    exec_last: bool,
+    /// Whether the DEBUG trap is running, its own commands don't set it off again
+    in_debug_trap: bool,
+}
+
+/// What running a command left behind, either processes that may still be running
+/// or the status of a command that already finished
+#[derive(Debug, Clone)]
+enum Execution {
+    /// The command's status will be the last one's once they're all done
+    Started(Vec<Jid>),
+    Finished(ExitStatus),
+}
+
+impl Execution {
+    /// A command that ran inside the shell process, like a builtin
+    fn finished(exit_code: i32) -> Execution {
+        Execution::Finished(ExitStatus {
+            pid: unistd::getpid(),
+            exit_code,
+            core_dumped: false,
+            signal: None,
+        })
+    }
+
+    /// The processes that were started, if any
+    fn started(self) -> Vec<Jid> {
+        match self {
+            Execution::Started(jids) => jids,
+            Execution::Finished(_) => Vec::new(),
+        }
+    }
 }
 
+/// How to set up the children a command starts.
+/// The fds the actions mention belong to whichever arm opened them, which closes them once everything
+/// under it has been spawned. They're all close-on-exec, so a program only gets the copies dup'd to their places,
+/// and a forked subshell closes them itself, so nothing needs to list them to be closed in the child.
 struct ProcOptions<'a> {
-    close_fds: &'a Vec<RawFd>,
+    /// What to do to a child's fds before it runs, in order
+    fds: Vec<FdAction>,
     env: &'a [CString],
-    stdin: Option<RawFd>,
-    stdout: Option<RawFd>,
+}
+
+/// A change to a child's file descriptors, made in the child between fork and exec
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum FdAction {
+    /// Make the second fd a copy of the first, after the actions before it have been done
+    Dup(RawFd, RawFd),
+    Close(RawFd),
+}
+
+impl<'a> ProcOptions<'a> {
+    /// The same options with more fd actions done after the existing ones
+    fn with(&self, actions: &[FdAction]) -> ProcOptions<'a> {
+        let mut fds = self.fds.clone();
+        fds.extend_from_slice(actions);
+        ProcOptions { fds, env: self.env }
+    }
+
+    /// The same options with the fds `exec` opened for the script put in place first,
+    /// so the command's own redirections can still replace them
+    fn with_script_fds(&self, ec: &ExecutionContext) -> ProcOptions<'a> {
+        let mut fds: Vec<FdAction> = ec.script_fds.iter().map(|(&n, &fd)| FdAction::Dup(fd, n)).collect();
+        fds.extend_from_slice(&self.fds);
+        ProcOptions { fds, env: self.env }
+    }
+
+    /// Which of the shell's fds a child's fd `n` would end up being, or -1 if it would be closed.
+    /// Commands that run inside the shell use this instead of changing the shell's own fds.
+    fn fd(&self, n: RawFd) -> RawFd {
+        let mut map = BTreeMap::new();
+        for action in &self.fds {
+            match *action {
+                FdAction::Dup(from, to) => {
+                    let source = map.get(&from).cloned().unwrap_or(from);
+                    map.insert(to, source);
+                }
+                FdAction::Close(fd) => {
+                    map.insert(fd, -1);
+                }
+            }
+        }
+        map.get(&n).cloned().unwrap_or(n)
+    }
+
+    /// Print a diagnostic for a command on the standard error it would have, one that can't be written is dropped
+    fn error<T: fmt::Display>(&self, e: T) {
+        unistd::write(self.fd(2), format!("rush: {}\n", e).as_bytes()).ok();
+    }
+
+    /// Every fd the actions change, along with the shell's fd it would end up as, or -1 if it would be closed
+    fn redirections(&self) -> Vec<(RawFd, RawFd)> {
+        let mut targets: Vec<RawFd> = self
+            .fds
+            .iter()
+            .map(|action| match *action {
+                FdAction::Dup(_, to) => to,
+                FdAction::Close(fd) => fd,
+            })
+            .collect();
+        targets.sort();
+        targets.dedup();
+        targets
+            .into_iter()
+            .map(|n| (n, self.fd(n)))
+            .filter(|&(n, fd)| n != fd)
+            .collect()
+    }
+
+    /// Do the fd actions to the current process, stopping at the first fd that can't be copied.
+    /// Closing an fd that isn't open is fine, it ends up closed either way.
+    fn apply(&self) -> ::std::result::Result<(), SetupFailure> {
+        for action in &self.fds {
+            match *action {
+                // the copy dup2 makes isn't close-on-exec, an fd dup'd onto itself needs the flag cleared instead
+                FdAction::Dup(from, to) => {
+                    let copied = if from != to {
+                        unistd::dup2(from, to).map(|_| ())
+                    } else {
+                        fcntl::fcntl(from, fcntl::FcntlArg::F_SETFD(FdFlag::empty())).map(|_| ())
+                    };
+                    if let Err(e) = copied {
+                        return Err(SetupFailure::Redirect(from, errno_of(e)));
+                    }
+                }
+                FdAction::Close(fd) => {
+                    unistd::close(fd).ok();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a child couldn't do to get ready to run its program, sent back to the shell to report
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum SetupFailure {
+    /// An fd couldn't be copied into place for a redirection or pipe
+    Redirect(RawFd, Errno),
+    /// The working directory couldn't be entered
+    Chdir(Errno),
+    Exec(Errno),
+}
+
+impl SetupFailure {
+    /// A missing program is 127, anything else that stops it from running is 126
+    fn exit_code(self) -> i32 {
+        match self {
+            SetupFailure::Exec(Errno::ENOENT) => 127,
+            _ => 126,
+        }
+    }
+
+    /// The failure as (step, errno, fd), always the same size so the shell can read it in one go
+    fn to_bytes(self) -> [u8; 12] {
+        let (step, errno, fd): (i32, Errno, RawFd) = match self {
+            SetupFailure::Redirect(fd, errno) => (0, errno, fd),
+            SetupFailure::Chdir(errno) => (1, errno, -1),
+            SetupFailure::Exec(errno) => (2, errno, -1),
+        };
+        let mut bytes = [0; 12];
+        bytes[..4].copy_from_slice(&step.to_ne_bytes());
+        bytes[4..8].copy_from_slice(&(errno as i32).to_ne_bytes());
+        bytes[8..].copy_from_slice(&fd.to_ne_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; 12]) -> Option<SetupFailure> {
+        let word = |i: usize| {
+            let mut w = [0; 4];
+            w.copy_from_slice(&bytes[i * 4..(i + 1) * 4]);
+            i32::from_ne_bytes(w)
+        };
+        let errno = Errno::from_i32(word(1));
+        match word(0) {
+            0 => Some(SetupFailure::Redirect(word(2), errno)),
+            1 => Some(SetupFailure::Chdir(errno)),
+            2 => Some(SetupFailure::Exec(errno)),
+            _ => None,
+        }
+    }
+
+    /// Describe the failure for a command running `exe` in `dir`
+    fn message(self, exe: &str, dir: &Path) -> String {
+        match self {
+            SetupFailure::Redirect(fd, errno) => format!("rush: {}: {}\n", fd, errno.desc()),
+            SetupFailure::Chdir(errno) => format!("rush: {}: {}\n", dir.display(), errno.desc()),
+            SetupFailure::Exec(errno) => format!("rush: {}: {}\n", exe, errno.desc()),
+        }
+    }
+}
+
+fn errno_of(e: nix::Error) -> Errno {
+    match e {
+        nix::Error::Sys(errno) => errno,
+        _ => Errno::UnknownErrno,
+    }
 }
 
 impl JobManager {
@@ -58,312 +348,2555 @@ impl JobManager {
             next_jid: 0,
             running_jobs: BTreeMap::new(),
             completed_jobs: BTreeMap::new(),
+            stopped_jobs: BTreeSet::new(),
+            flow: None,
+            returnable_depth: 0,
+            function_depth: 0,
+            loop_depth: 0,
+            expanding_aliases: Vec::new(),
+            jobs: Vec::new(),
+            job_order: Vec::new(),
+            job_control: false,
+            shell_pgid: unistd::getpgrp(),
+            process_groups: BTreeMap::new(),
+            job_pgid: None,
+            job_text: Vec::new(),
+            starting_background: false,
+            exec_last: false,
+            in_debug_trap: false,
+        }
+    }
+
+    /// Put the shell in its own process group and take the terminal, after this every job gets a process group.
+    /// The shell ignores the signals used to stop it, so only the job in the foreground is affected by them.
+    pub fn enable_job_control(&mut self) -> Result<()> {
+        for sig in JOB_CONTROL_SIGNALS {
+            set_disposition(*sig, signal::SigHandler::SigIgn);
         }
+
+        let pid = unistd::getpid();
+        // a session leader already leads its group, and can't move to another
+        unistd::setpgid(pid, pid).ok();
+        self.shell_pgid = unistd::getpgrp();
+        unistd::tcsetpgrp(TERMINAL, self.shell_pgid).context(ErrorKind::SysError)?;
+        self.job_control = true;
+        Ok(())
+    }
+
+    /// Ask the enclosing command lists to unwind
+    pub fn request_flow(&mut self, flow: Flow) {
+        self.flow = Some(flow);
+    }
+
+    /// Forget the pending control flow, like an exit the shell decided not to go through with
+    pub fn clear_flow(&mut self) {
+        self.flow = None;
+    }
+
+    /// Get the control flow that is currently unwinding, if any
+    pub fn pending_flow(&self) -> Option<Flow> {
+        self.flow
     }
 
     pub fn run(&mut self, ec: &mut ExecutionContext, command: Command) -> Result<ExitStatus> {
-        let close_fds = Vec::new();
+        self.run_with_fds(ec, command, [0, 1, 2])
+    }
+
+    /// Run a command with the given standard input, output, and error, like the commands a builtin runs
+    pub fn run_with_fds(
+        &mut self,
+        ec: &mut ExecutionContext,
+        command: Command,
+        standard_fds: [RawFd; 3],
+    ) -> Result<ExitStatus> {
+        let (fds, copies) = standard_actions(standard_fds)?;
         let env = Vec::new();
-        let opts = ProcOptions {
-            stdin: None,
-            stdout: None,
-            close_fds: &close_fds,
-            env: &env,
-        };
+        let opts = ProcOptions { fds, env: &env };
 
-        let jids = self.spawn_procs_from_ast(&opts, ec, &command)?;
-        self.await_all(&jids);
-        Ok(jids
-            .last()
-            .map(|id| self.completed_jobs.get(id).unwrap().clone())
-            .unwrap_or(ExitStatus {
-                exit_code: 0,
-                core_dumped: false,
-                pid: unistd::getpid(),
-                signal: None,
-            }))
-    }
-
-    fn next(&mut self) -> Result<(Jid, ExitStatus)> {
-        let mut status = None;
-        while status.is_none() {
-            match wait().context(ErrorKind::WaitFailed)? {
-                WaitStatus::Exited(pid, code) => {
-                    status = self.running_jobs.get(&pid.into()).map(|jid| {
-                        (
-                            jid.clone(),
-                            ExitStatus {
-                                pid: pid,
-                                exit_code: code,
-                                core_dumped: false,
-                                signal: None,
-                            },
-                        )
-                    });
-                }
-                WaitStatus::Signaled(pid, sig, core_dump) => {
-                    status = self.running_jobs.get(&pid.into()).map(|jid| {
-                        (
-                            jid.clone(),
-                            ExitStatus {
-                                pid: pid,
-                                exit_code: -1,
-                                core_dumped: core_dump,
-                                signal: Some(sig),
-                            },
-                        )
-                    });
-                }
+        let execution = self.spawn_procs_from_ast(&opts, ec, &command);
+        for fd in copies {
+            unistd::close(fd).ok();
+        }
+        let status = self.finish(execution?)?;
+        if !command.is_comment() {
+            ec.vars.set_status(status.exit_code);
+        }
+        self.run_traps(ec)?;
+        Ok(status)
+    }
+
+    /// Run the last commands the shell has to run, like a whole script.
+    /// If they end with a program and there's nothing left for the shell to do afterwards,
+    /// the program is exec'd in the shell's place instead of forked and waited for.
+    pub fn run_last(&mut self, ec: &mut ExecutionContext, command: Command) -> Result<ExitStatus> {
+        self.exec_last = true;
+        let result = self.run(ec, command);
+        self.exec_last = false;
+        result
+    }
+
+    /// Run the traps of any trapped signals that arrived since the last time this was called
+    pub fn run_traps(&mut self, ec: &mut ExecutionContext) -> Result<()> {
+        let mut interrupted = false;
+        for sig in traps::take_pending() {
+            match traps::action(traps::Condition::Signal(sig)) {
+                Some(traps::Action::Eval(source)) => self.run_trap(ec, &source)?,
+                None if sig == signal::Signal::SIGINT => interrupted = true,
                 _ => (),
             }
         }
+        if interrupted {
+            Err(ErrorKind::Interrupted)?;
+        }
+        Ok(())
+    }
 
-        Ok(status.unwrap())
+    /// Run the EXIT trap, if there is one, it's removed first so it can only run once
+    pub fn run_exit_trap(&mut self, ec: &mut ExecutionContext) -> Result<()> {
+        if let Some(traps::Action::Eval(source)) = traps::action(traps::Condition::Exit) {
+            traps::release(traps::Condition::Exit).ok();
+            self.run_trap(ec, &source)?;
+        }
+        Ok(())
     }
 
-    fn add_job(&mut self, pid: unistd::Pid) -> Jid {
-        let jid = Jid(self.next_jid);
-        self.running_jobs.insert(pid.into(), jid);
-        self.next_jid += 1;
-        jid
+    /// Run the DEBUG trap for a simple command about to run, with RUSH_COMMAND set to the command's expanded text
+    fn run_debug_trap(&mut self, ec: &mut ExecutionContext, text: OsString) -> Result<()> {
+        if self.in_debug_trap {
+            return Ok(());
+        }
+        let source = match traps::action(traps::Condition::Debug) {
+            Some(traps::Action::Eval(source)) => source,
+            _ => return Ok(()),
+        };
+
+        // a readonly RUSH_COMMAND just keeps the value it has
+        ec.vars.define("RUSH_COMMAND", text).ok();
+        self.in_debug_trap = true;
+        let result = self.run_trap(ec, &source);
+        self.in_debug_trap = false;
+        result
     }
 
-    /// Low level function to smooth over fork + execv[e]
-    fn spawn_proc<'a>(
-        &mut self,
-        exe: &CString,
-        args: &[CString],
-        path: &PathBuf,
-        opts: &'a ProcOptions<'a>,
-    ) -> Result<Jid> {
-        match unistd::fork().context(ErrorKind::ExecFailed)? {
-            unistd::ForkResult::Child => {
-                for fd in opts.close_fds {
-                    unistd::close(*fd);
-                }
+    /// Run a trap's commands without disturbing `$?` or whatever control flow was already in progress,
+    /// unless the trap asks for its own
+    fn run_trap(&mut self, ec: &mut ExecutionContext, source: &str) -> Result<()> {
+        let command = match Command::parse(source) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("rush: trap: {}", e);
+                return Ok(());
+            }
+        };
 
-                if let Some(stdin) = opts.stdin {
-                    unistd::dup2(stdin, 0);
-                }
+        let status = ec.vars.status();
+        let line = ec.vars.line();
+        let flow = self.flow.take();
 
-                if let Some(stdout) = opts.stdout {
-                    unistd::dup2(stdout, 1);
-                }
+        let result = self.run(ec, command);
 
-                unistd::chdir(path);
-                if opts.env.len() == 0 {
-                    unistd::execv(exe, args).unwrap();
-                } else {
-                    let mut exe_env: Vec<CString> = env::vars_os()
-                        .map(|(k, v)| {
-                            let mut kv = OsString::with_capacity(k.len() + 1 + v.len());
-                            kv.push(k.into_string().unwrap());
-                            kv.push("=");
-                            kv.push(v.into_string().unwrap());
-                            CString::new(kv.into_string().unwrap().as_bytes()).unwrap()
-                        }).collect();
-                    exe_env.extend(opts.env.iter().map(|e| e.clone()));
-                    unistd::execve(&exe, args, &exe_env).unwrap();
-                }
-                unreachable!();
-            }
-            unistd::ForkResult::Parent { child } => Ok(self.add_job(child)),
+        if self.flow.is_none() {
+            self.flow = flow;
         }
+        ec.vars.set_status(status);
+        ec.vars.set_line(line);
+        result.map(|_| ())
     }
 
-    // spawn 0 or more processes based on a shell-language abstract syntax tree in a given execution context
-    fn spawn_procs_from_ast<'a>(
+    /// Run the expanded words as a simple command with the given standard input, output, and error,
+    /// skipping function lookup unless `functions` is set
+    pub fn run_words(
         &mut self,
-        opts: &'a ProcOptions<'a>,
         ec: &mut ExecutionContext,
-        command: &Command,
-    ) -> Result<Vec<Jid>> {
-        match command {
-            Command::SimpleCommand(cmd) => {
-                let mut args = Vec::with_capacity(cmd.arguments.len());
-                for w in &cmd.arguments {
-                    let arg = w.compile(&mut ec.vars).context(ErrorKind::ExecFailed)?;
-                    args.push(CString::new(arg.as_bytes()).context(ErrorKind::ExecFailed)?);
-                }
+        words: &[OsString],
+        standard_fds: [RawFd; 3],
+        functions: bool,
+    ) -> Result<ExitStatus> {
+        let (fds, copies) = standard_actions(standard_fds)?;
+        let env = Vec::new();
+        let opts = ProcOptions { fds, env: &env };
 
-                // TODO check args count
-                let argv0 = args[0].to_string_lossy().to_string();
+        let execution = self.spawn_command(&opts, ec, words, functions);
+        for fd in copies {
+            unistd::close(fd).ok();
+        }
+        let status = self.finish(execution?)?;
+        ec.vars.set_status(status.exit_code);
+        Ok(status)
+    }
 
-                if let Some(body) = ec.functions().value(&argv0) {
-                    self.spawn_procs_from_ast(opts, ec, &body)
-                } else {
-                    let exe = if !argv0.starts_with("./") {
-                        ec.find_executable(argv0)?
-                    } else {
-                        PathBuf::from(argv0)
-                    };
+    /// Run a command that `return` can leave early, like a sourced file
+    pub fn run_returnable(
+        &mut self,
+        ec: &mut ExecutionContext,
+        command: Command,
+        standard_fds: [RawFd; 3],
+    ) -> Result<ExitStatus> {
+        self.returnable_depth += 1;
+        let status = self.run_with_fds(ec, command, standard_fds);
+        self.returnable_depth -= 1;
+        if let Some(Flow::Return(_)) = self.flow {
+            self.flow = None;
+        }
+        status
+    }
 
-                    let c_exe = CString::new(exe.to_str().unwrap().as_bytes()).unwrap();
-                    Ok(vec![self.spawn_proc(&c_exe, &args, &ec.cwd, opts)?])
-                }
+    /// Whether `return` would leave a function or sourced file
+    pub fn can_return(&self) -> bool {
+        self.returnable_depth > 0
+    }
+
+    /// How many loops `break` and `continue` can leave
+    pub fn loop_depth(&self) -> usize {
+        self.loop_depth
+    }
+
+    /// Handle a pending `break` or `continue` after a loop's body runs, returning whether the loop should keep going
+    fn loop_continues(&mut self) -> bool {
+        match self.flow {
+            None => true,
+            Some(Flow::Continue(1)) => {
+                self.flow = None;
+                true
+            }
+            Some(Flow::Break(1)) => {
+                self.flow = None;
+                false
+            }
+            Some(Flow::Break(n)) => {
+                self.flow = Some(Flow::Break(n - 1));
+                false
+            }
+            Some(Flow::Continue(n)) => {
+                self.flow = Some(Flow::Continue(n - 1));
+                false
             }
-            Command::Pipeline(pipe) => {
-                let (stdin, stdout) = unistd::pipe().context(ErrorKind::PipelineCreationFailed)?;
-                let mut close_from = opts.close_fds.clone();
-                let mut to_from = opts.close_fds.clone();
+            Some(_) => false,
+        }
+    }
 
-                close_from.push(stdin);
-                if let Some(pipe_out) = opts.stdout {
-                    close_from.push(pipe_out)
+    /// Wait for whatever a command left running, giving the command's status
+    fn finish(&mut self, execution: Execution) -> Result<ExitStatus> {
+        match execution {
+            Execution::Started(jids) => {
+                self.wait_for_all(&jids)?;
+                let status = self.last_status(&jids);
+                self.forget_completed();
+                Ok(status)
+            }
+            Execution::Finished(mut status) => {
+                if let Some(Flow::Exit(code)) | Some(Flow::Return(code)) = self.flow {
+                    status.exit_code = code;
                 }
-                to_from.push(stdout);
-                if let Some(pipe_in) = opts.stdin {
-                    to_from.push(pipe_in)
+                Ok(status)
+            }
+        }
+    }
+
+    /// The status of the last of some completed jobs, with any pending exit's status taking priority
+    fn last_status(&self, jids: &[Jid]) -> ExitStatus {
+        let mut status = jids
+            .last()
+            .map(|id| match self.completed_jobs.get(id) {
+                Some(status) => *status,
+                // stopped processes haven't finished, they get the status of being killed by SIGTSTP
+                None => ExitStatus {
+                    exit_code: 128 + signal::Signal::SIGTSTP as i32,
+                    core_dumped: false,
+                    pid: unistd::getpid(),
+                    signal: Some(signal::Signal::SIGTSTP),
+                },
+            }).unwrap_or(ExitStatus {
+                exit_code: 0,
+                core_dumped: false,
+                pid: unistd::getpid(),
+                signal: None,
+            });
+
+        match self.flow {
+            Some(Flow::Exit(code)) | Some(Flow::Return(code)) => status.exit_code = code,
+            _ => (),
+        }
+        status
+    }
+
+    /// All the background jobs, ordered by job number
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    pub fn job(&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|job| job.id == id)
+    }
+
+    pub fn job_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    /// The job that job specs like `%+` refer to, the most recently started one
+    pub fn current_job(&self) -> Option<usize> {
+        self.job_order.last().cloned()
+    }
+
+    /// The job before the current one, `%-`
+    pub fn previous_job(&self) -> Option<usize> {
+        self.job_order.iter().rev().nth(1).cloned()
+    }
+
+    /// A job is complete once every process in it has completed, and then it has the status of the last one.
+    /// Until then it's stopped if any of its processes are.
+    pub fn job_status(&self, job: &Job) -> JobStatus {
+        if job.jids.iter().all(|jid| self.completed_jobs.contains_key(jid)) {
+            JobStatus::Complete(self.last_status(&job.jids))
+        } else if job.jids.iter().any(|jid| self.stopped_jobs.contains(jid)) {
+            JobStatus::Stopped
+        } else {
+            JobStatus::Running
+        }
+    }
+
+    /// Continue a stopped job without waiting for it, it becomes the current job
+    pub fn resume_background(&mut self, id: usize) -> Result<()> {
+        let jids = match self.job(id) {
+            Some(job) => {
+                for pid in job.signal_targets() {
+                    signal::kill(pid, signal::Signal::SIGCONT).context(ErrorKind::SysError)?;
                 }
+                job.jids.clone()
+            }
+            None => return Err(ErrorKind::NoSuchJob(id).into()),
+        };
 
-                let from_opts = ProcOptions {
-                    close_fds: &close_from,
-                    env: opts.env,
-                    stdin: opts.stdin,
-                    stdout: Some(stdout),
-                };
+        for jid in &jids {
+            self.stopped_jobs.remove(jid);
+        }
+        self.job_order.retain(|j| *j != id);
+        self.job_order.push(id);
+        Ok(())
+    }
 
-                let to_opts = ProcOptions {
-                    close_fds: &to_from,
-                    env: opts.env,
-                    stdin: Some(stdin),
-                    stdout: opts.stdout,
-                };
+    /// Whether any job is stopped, after collecting any changes that haven't been noticed yet
+    pub fn has_stopped_jobs(&mut self) -> Result<bool> {
+        self.reap()?;
+        Ok(self.jobs.iter().any(|job| self.job_status(job) == JobStatus::Stopped))
+    }
 
-                let mut jids = self.spawn_procs_from_ast(&from_opts, ec, &pipe.from)?;
-                jids.extend(self.spawn_procs_from_ast(&to_opts, ec, &pipe.to)?);
+    /// Stop tracking a job, the statuses of its processes are dropped once they've finished
+    pub fn remove_job(&mut self, id: usize) -> Option<Job> {
+        self.job_order.retain(|j| *j != id);
+        let index = self.jobs.iter().position(|job| job.id == id)?;
+        let job = self.jobs.remove(index);
+        self.forget_completed();
+        Some(job)
+    }
+
+    /// Drop the status of every finished process that isn't part of a job, nothing can ask for them anymore.
+    /// This happens whenever a status has been used, so the tables only grow with the jobs that are still around.
+    fn forget_completed(&mut self) {
+        let owned: BTreeSet<Jid> = self.jobs.iter().flat_map(|job| job.jids.iter().cloned()).collect();
+        let forgotten: BTreeSet<Jid> = self
+            .completed_jobs
+            .keys()
+            .filter(|jid| !owned.contains(jid))
+            .cloned()
+            .collect();
+        if forgotten.is_empty() {
+            return;
+        }
 
-                unistd::close(stdin);
-                unistd::close(stdout);
+        for jid in &forgotten {
+            self.completed_jobs.remove(jid);
+            self.stopped_jobs.remove(jid);
+            self.process_groups.remove(jid);
+        }
+        self.running_jobs.retain(|_, jid| !forgotten.contains(jid));
+    }
 
-                Ok(jids)
+    /// Send SIGHUP to every unfinished job that hasn't been exempted with `disown -h`, for when the shell exits.
+    /// Stopped jobs are continued afterwards so they can act on it.
+    pub fn hangup_jobs(&mut self) -> Result<()> {
+        self.reap()?;
+        for job in &self.jobs {
+            let stopped = match self.job_status(job) {
+                JobStatus::Complete(_) => continue,
+                status => status == JobStatus::Stopped,
+            };
+            if !job.hangup {
+                continue;
             }
-            Command::BraceGroup(group) => {
-                let mut exit_code = 0;
-                let mut subenv = ec.clone();
-                for cmd in &group.commands {
-                    let jids = self.spawn_procs_from_ast(opts, &mut subenv, &cmd)?;
-                    self.await_all(&jids);
+            for pid in job.signal_targets() {
+                signal::kill(pid, signal::Signal::SIGHUP).ok();
+                if stopped {
+                    signal::kill(pid, signal::Signal::SIGCONT).ok();
                 }
-                Ok(Vec::new())
             }
-            Command::Group(group) => {
-                let mut exit_code = 0;
-                for cmd in &group.commands {
-                    let jids = self.spawn_procs_from_ast(opts, ec, &cmd)?;
-                    self.await_all(&jids);
+        }
+        Ok(())
+    }
+
+    /// Bring a job into the foreground, continuing it if needed, and wait for it to finish or stop again
+    pub fn foreground(&mut self, id: usize) -> Result<ExitStatus> {
+        let jids = match self.job(id) {
+            Some(job) => {
+                if let Some(pgid) = job.pgid {
+                    unistd::tcsetpgrp(TERMINAL, pgid).ok();
                 }
-                Ok(Vec::new())
-            }
-            Command::ConditionalPair(cond) => {
-                let jobs_left = self.spawn_procs_from_ast(opts, ec, &cond.left)?;
-                self.await_all(&jobs_left);
-                let exit_code = jobs_left
-                    .last()
-                    .map(|r| self.completed_jobs.get(r).unwrap().exit_code)
-                    .unwrap_or(0);
-                if (exit_code == 0 && cond.operator == ConditionOperator::AndIf)
-                    || (exit_code != 0 && cond.operator == ConditionOperator::OrIf)
-                {
-                    let jobs_right = self.spawn_procs_from_ast(opts, ec, &cond.right)?;
-                    self.await_all(&jobs_right);
-                    Ok(jobs_right)
-                } else {
-                    Ok(jobs_left)
+                for pid in job.signal_targets() {
+                    signal::kill(pid, signal::Signal::SIGCONT).ok();
                 }
+                job.jids.clone()
             }
-            Command::Function(func) => {
-                let str_name = func.name.compile(ec.variables_mut())?;
-                ec.functions_mut().insert(str_name, func.body.clone());
-                Ok(vec![])
-            }
-            Command::Comment(_s) => Ok(vec![]),
-            _ => unimplemented!(),
+            None => return Err(ErrorKind::NoSuchJob(id).into()),
+        };
+
+        for jid in &jids {
+            self.stopped_jobs.remove(jid);
         }
+        self.wait_job(id)
     }
 
-    pub fn stat(&mut self, jid: Jid) -> Result<JobStatus> {
-        if let Some(status) = self.completed_jobs.get(&jid) {
-            Ok(JobStatus::Complete(status.clone()))
-        } else {
-            self.running_jobs
-                .iter()
-                .find(|(_, v)| **v == jid)
-                .map_or(Err(ErrorKind::InvalidJobId(jid).into()), |v| {
-                    Ok(JobStatus::Running)
-                })
+    /// Wait for every process in a job to finish, then stop tracking it.
+    /// If the job is stopped instead it's still tracked.
+    pub fn wait_job(&mut self, id: usize) -> Result<ExitStatus> {
+        let jids = match self.job(id) {
+            Some(job) => job.jids.clone(),
+            None => return Err(ErrorKind::NoSuchJob(id).into()),
+        };
+
+        // an interrupted job is still finished with
+        let waited = self.wait_for_all(&jids);
+        let status = self.last_status(&jids);
+        if jids.iter().all(|jid| self.completed_jobs.contains_key(jid)) {
+            self.remove_job(id);
         }
+        waited.map(|_| status)
     }
 
-    /// Wait for a specific job to complete
-    pub fn await(&mut self, jid: Jid) -> Result<ExitStatus> {
-        if let Some(exit_status) = self.completed_jobs.get(&jid) {
-            return Ok(exit_status.clone());
-        }
+    /// Wait for whichever job finishes first and stop tracking it, giving its id and status, or `None` if there are
+    /// no jobs. One that finished before this was called, without being noticed yet, is taken straight away.
+    pub fn wait_any_job(&mut self) -> Result<Option<(usize, ExitStatus)>> {
+        loop {
+            let mut finished = None;
+            for job in &self.jobs {
+                if let JobStatus::Complete(status) = self.job_status(job) {
+                    finished = Some((job.id, status));
+                    break;
+                }
+            }
+            if let Some((id, _)) = finished {
+                self.remove_job(id);
+                return Ok(finished);
+            }
+            if self.jobs.is_empty() {
+                return Ok(None);
+            }
 
-        let mut completed = self.next()?;
-        while completed.0 != jid {
-            self.completed_jobs.insert(completed.0, completed.1);
-            completed = self.next()?;
+            match self.next_change()? {
+                Some((jid, Some(status))) => {
+                    self.completed_jobs.insert(jid, status);
+                }
+                Some((_, None)) => (),
+                None => {
+                    // no children are left to wait for, so the jobs can't be running anymore
+                    let lost: Vec<Jid> = self
+                        .jobs
+                        .iter()
+                        .flat_map(|job| job.jids.iter().cloned())
+                        .filter(|jid| !self.completed_jobs.contains_key(jid))
+                        .collect();
+                    for jid in lost {
+                        self.lose_status(jid);
+                    }
+                }
+            }
         }
-        self.completed_jobs.insert(completed.0, completed.1);
-        Ok(completed.1)
     }
 
-    /// Wait for several jobs to complete
-    pub fn await_all(&mut self, jids: &[Jid]) -> Result<()> {
-        let mut incomplete: BTreeSet<Jid> = jids
+    /// Wait for a single child process, `None` if it isn't a child of this shell.
+    /// If it was the last running process of a job, the job is no longer tracked.
+    pub fn wait_pid(&mut self, pid: unistd::Pid) -> Option<Result<ExitStatus>> {
+        let raw: libc::pid_t = pid.into();
+        let jid = *self.running_jobs.get(&raw)?;
+        let status = self.wait_for(jid);
+
+        let finished: Vec<usize> = self
+            .jobs
             .iter()
-            .map(|jid| *jid)
-            .filter(|jid| self.completed_jobs.get(jid).is_none())
+            .filter(|job| job.jids.contains(&jid))
+            .filter(|job| job.jids.iter().all(|j| self.completed_jobs.contains_key(j)))
+            .map(|job| job.id)
             .collect();
-
-        let mut completed = self.next()?;
-        while incomplete.len() > 0 {
-            self.completed_jobs.insert(completed.0, completed.1);
-            completed = self.next()?;
-            incomplete.remove(&completed.0);
+        for id in finished {
+            self.remove_job(id);
         }
-        self.completed_jobs.insert(completed.0, completed.1);
-        Ok(())
+        Some(status)
     }
-}
 
-impl ExecutionContext {
-    pub fn new() -> ExecutionContext {
-        ExecutionContext {
-            vars: Variables::from_env(),
-            funcs: Functions::new(),
-            cwd: env::current_dir().unwrap(),
-        }
-    }
+    fn add_background_job(&mut self, text: String, jids: Vec<Jid>) -> usize {
+        let id = self.jobs.last().map(|job| job.id + 1).unwrap_or(1);
+        let pids = jids
+            .iter()
+            .filter_map(|jid| {
+                self.running_jobs
+                    .iter()
+                    .find(|(_, j)| *j == jid)
+                    .map(|(pid, _)| unistd::Pid::from_raw(*pid))
+            }).collect();
 
-    pub fn variables<'a>(&'a self) -> &'a Variables {
-        &self.vars
+        let pgid = jids.first().and_then(|jid| self.process_groups.get(jid)).cloned();
+        self.jobs.push(Job {
+            id,
+            text,
+            pids,
+            pgid,
+            hangup: true,
+            jids,
+        });
+        self.job_order.push(id);
+        id
     }
 
-    pub fn variables_mut<'a>(&'a mut self) -> &'a mut Variables {
-        &mut self.vars
+    /// Collect the status of any children that have already finished or stopped, without blocking
+    pub fn reap(&mut self) -> Result<()> {
+        let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
+        loop {
+            match waitpid(unistd::Pid::from_raw(-1), Some(flags)) {
+                Ok(WaitStatus::StillAlive) | Err(nix::Error::Sys(Errno::ECHILD)) => return Ok(()),
+                Ok(WaitStatus::Stopped(pid, _)) => if let Some(jid) = self.running_jobs.get(&pid.into()) {
+                    self.stopped_jobs.insert(*jid);
+                },
+                Ok(WaitStatus::Continued(pid)) => if let Some(jid) = self.running_jobs.get(&pid.into()) {
+                    self.stopped_jobs.remove(jid);
+                },
+                Ok(ws) => if let Some((jid, status)) = self.completion(ws) {
+                    // a continued process can die before its continuation is reported
+                    self.stopped_jobs.remove(&jid);
+                    self.completed_jobs.insert(jid, status);
+                },
+                Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(e) => Err(e).context(ErrorKind::WaitFailed)?,
+            }
+        }
     }
 
-    pub fn functions<'a>(&'a self) -> &'a Functions {
-        &self.funcs
+    /// Wait for one of the shell's processes to finish, `None` if the shell has no children left
+    fn next(&mut self) -> Result<Option<(Jid, ExitStatus)>> {
+        loop {
+            match self.next_change()? {
+                Some((jid, Some(status))) => return Ok(Some((jid, status))),
+                Some(_) => (),
+                None => return Ok(None),
+            }
+        }
     }
 
-    pub fn functions_mut<'a>(&'a mut self) -> &'a mut Functions {
-        &mut self.funcs
-    }
+    /// Wait for one of the shell's processes to finish, or with job control to stop, which gives no status.
+    /// `None` if the shell has no children left to wait for.
+    fn next_change(&mut self) -> Result<Option<(Jid, Option<ExitStatus>)>> {
+        loop {
+            if let Some(sig) = traps::pending_trap().filter(|_| traps::interrupting_waits()) {
+                Err(ErrorKind::WaitInterrupted(sig))?;
+            }
+            let ws = match if self.job_control {
+                waitpid(
+                    unistd::Pid::from_raw(-1),
+                    Some(WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED),
+                )
+            } else {
+                wait()
+            } {
+                Ok(ws) => ws,
+                Err(nix::Error::Sys(Errno::ECHILD)) => return Ok(None),
+                // the `wait` builtin is cut short by a trapped signal at the top of the loop
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(e) => Err(e).context(ErrorKind::WaitFailed)?,
+            };
 
-    pub fn find_executable<S: AsRef<OsStr>>(&self, prog: S) -> Result<PathBuf> {
-        let prog_ref = prog.as_ref();
-        for path in env::split_paths(&self.vars.value(&OsString::from("PATH"))) {
-            let p = path.with_file_name(prog_ref);
-            if p.exists() {
-                return Ok(p);
+            match ws {
+                WaitStatus::Stopped(pid, _) => if let Some(jid) = self.running_jobs.get(&pid.into()).cloned() {
+                    self.stopped_jobs.insert(jid);
+                    return Ok(Some((jid, None)));
+                },
+                // a job continued by something other than the shell, like `kill -CONT`
+                WaitStatus::Continued(pid) => if let Some(jid) = self.running_jobs.get(&pid.into()) {
+                    self.stopped_jobs.remove(jid);
+                },
+                ws => if let Some((jid, status)) = self.completion(ws) {
+                    self.stopped_jobs.remove(&jid);
+                    return Ok(Some((jid, Some(status))));
+                },
             }
         }
+    }
+
+    /// Give a process that can't be waited for anymore, because the shell has no children left, a status
+    /// so nothing waits for it again. Its real status is unknown, so it gets 127 like `wait` gives for a
+    /// process that isn't the shell's child.
+    fn lose_status(&mut self, jid: Jid) -> ExitStatus {
+        let status = ExitStatus {
+            pid: unistd::Pid::from_raw(0),
+            exit_code: 127,
+            core_dumped: false,
+            signal: None,
+        };
+        self.stopped_jobs.remove(&jid);
+        self.completed_jobs.insert(jid, status);
+        status
+    }
+
+    /// Translate a wait status into the completed job it belongs to, if it's one of ours
+    fn completion(&self, ws: WaitStatus) -> Option<(Jid, ExitStatus)> {
+        match ws {
+            WaitStatus::Exited(pid, code) => self.running_jobs.get(&pid.into()).map(|jid| {
+                (
+                    *jid,
+                    ExitStatus {
+                        pid,
+                        exit_code: code,
+                        core_dumped: false,
+                        signal: None,
+                    },
+                )
+            }),
+            WaitStatus::Signaled(pid, sig, core_dump) => self.running_jobs.get(&pid.into()).map(|jid| {
+                (
+                    *jid,
+                    ExitStatus {
+                        pid,
+                        exit_code: 128 + sig as i32,
+                        core_dumped: core_dump,
+                        signal: Some(sig),
+                    },
+                )
+            }),
+            _ => None,
+        }
+    }
+
+    fn add_job(&mut self, pid: unistd::Pid) -> Jid {
+        let jid = Jid(self.next_jid);
+        self.running_jobs.insert(pid.into(), jid);
+        self.next_jid += 1;
+        jid
+    }
+
+
+    /// Low level function to smooth over fork + execv[e]
+    fn spawn_proc<'a>(
+        &mut self,
+        exe: &CString,
+        args: &[CString],
+        path: &PathBuf,
+        environ: &[CString],
+        opts: &'a ProcOptions<'a>,
+    ) -> Result<Jid> {
+        // the child writes what went wrong here if it can't get as far as running the program,
+        // the pipe closes by itself when exec works
+        let (status_read, status_write) = shell_pipe()?;
+        let ignored = traps::ignored();
+        let forked = unistd::fork().context(ErrorKind::ExecFailed);
+        if forked.is_err() {
+            unistd::close(status_read).ok();
+            unistd::close(status_write).ok();
+        }
+        match forked? {
+            unistd::ForkResult::Child => {
+                unistd::close(status_read).ok();
+                self.join_job_group();
+                traps::restore_interrupts();
+                reset_signals(&ignored);
+
+                // exec only returns if it failed.
+                // _exit skips everything Rust would do on the way out of the shell's copy of itself.
+                let failure = match opts.apply() {
+                    Err(failure) => failure,
+                    Ok(()) => match unistd::chdir(path) {
+                        Err(e) => SetupFailure::Chdir(errno_of(e)),
+                        Ok(()) => SetupFailure::Exec(errno_of(unistd::execve(exe, args, environ).unwrap_err())),
+                    },
+                };
+                unistd::write(status_write, &failure.to_bytes()).ok();
+                unsafe { libc::_exit(failure.exit_code()) }
+            }
+            unistd::ForkResult::Parent { child } => {
+                unistd::close(status_write).ok();
+                let text: Vec<String> = args.iter().map(|a| a.to_string_lossy().to_string()).collect();
+                let jid = self.add_job_member(child, text.join(" "));
+
+                let mut buf = [0; 12];
+                let read = loop {
+                    match unistd::read(status_read, &mut buf) {
+                        Err(nix::Error::Sys(Errno::EINTR)) => (),
+                        read => break read,
+                    }
+                };
+                unistd::close(status_read).ok();
+                if let (Ok(12), Some(failure)) = (read, SetupFailure::from_bytes(&buf)) {
+                    let message = failure.message(&exe.to_string_lossy(), path);
+                    unistd::write(opts.fd(2), message.as_bytes()).ok();
+                }
+                Ok(jid)
+            }
+        }
+    }
+
+    /// Fork a copy of the shell to run a command, the child exits with the command's status.
+    /// Nothing the command changes gets back to the shell, the process-wide state like the working directory,
+    /// umask and resource limits included. So `( )`, the pipeline stages that would run in the shell, and command
+    /// substitution once it's supported all run here, instead of in the shell with their state saved and restored.
+    fn spawn_subshell<'a>(
+        &mut self,
+        opts: &'a ProcOptions<'a>,
+        ec: &mut ExecutionContext,
+        command: &Command,
+    ) -> Result<Jid> {
+        match unistd::fork().context(ErrorKind::ForkFailed)? {
+            unistd::ForkResult::Child => {
+                // none of the parent's children or jobs belong to the subshell, and its commands stay in its group
+                self.join_job_group();
+                traps::restore_interrupts();
+                if let Err(failure) = opts.apply() {
+                    eprint!("{}", failure.message("(subshell)", &ec.cwd));
+                    process::exit(failure.exit_code());
+                }
+                close_shell_fds();
+                self.job_control = false;
+                self.running_jobs.clear();
+                self.stopped_jobs.clear();
+                self.jobs.clear();
+                self.job_order.clear();
+                ec.set_interactive(false);
+
+                // the fds are already in place, so the commands inside start from the subshell's own
+                let inside = ProcOptions {
+                    fds: Vec::new(),
+                    env: opts.env,
+                };
+                let code = match self
+                    .spawn_procs_from_ast(&inside, ec, command)
+                    .and_then(|execution| self.finish(execution))
+                {
+                    Ok(status) => status.exit_code,
+                    Err(e) => {
+                        eprintln!("rush: {}", e);
+                        1
+                    }
+                };
+                process::exit(code)
+            }
+            unistd::ForkResult::Parent { child } => Ok(self.add_job_member(child, "(subshell)".to_string())),
+        }
+    }
+
+    /// Run a function's body with the rest of the words as its positional parameters,
+    /// the status is the body's unless it's cut short by `return`
+    fn call_function(
+        &mut self,
+        opts: &ProcOptions,
+        ec: &mut ExecutionContext,
+        body: &Command,
+        words: &[OsString],
+    ) -> Result<Execution> {
+        // too deep a call is just a failed command, so the calls around it can go on
+        let limit = function_nesting_limit(ec);
+        if self.function_depth >= limit || stack_left().map(|left| left < STACK_RESERVE).unwrap_or(false) {
+            let name = words[0].to_string_lossy().to_string();
+            opts.error(ErrorKind::FunctionNesting(name, self.function_depth));
+            return Ok(Execution::finished(1));
+        }
+
+        // loops around the call can't be left from inside the function,
+        // and aliases being expanded around it can be used again
+        let loop_depth = mem::replace(&mut self.loop_depth, 0);
+        let expanding_aliases = mem::take(&mut self.expanding_aliases);
+        self.returnable_depth += 1;
+        self.function_depth += 1;
+        let params = words[1..].to_vec();
+        let saved = ec.variables_mut().set_positional(params);
+        let scope = ec.variables_mut().push_scope();
+        let execution = self.spawn_procs_from_ast(opts, ec, body);
+        ec.variables_mut().pop_scope(scope);
+        ec.variables_mut().set_positional(saved);
+        self.function_depth -= 1;
+        self.returnable_depth -= 1;
+        self.loop_depth = loop_depth;
+        self.expanding_aliases = expanding_aliases;
+        match self.flow {
+            Some(Flow::Return(code)) => {
+                self.flow = None;
+                Ok(Execution::finished(code))
+            }
+            _ => execution,
+        }
+    }
+
+    /// Start one side of a pipeline, anything that would run inside the shell gets a subshell,
+    /// so both sides run at the same time and a builtin writing to a full pipe can't block the shell
+    fn spawn_pipeline_member<'a>(
+        &mut self,
+        opts: &'a ProcOptions<'a>,
+        ec: &mut ExecutionContext,
+        command: &Command,
+    ) -> Result<Execution> {
+        if runs_in_process(ec, command) {
+            self.spawn_subshell(opts, ec, command).map(|jid| Execution::Started(vec![jid]))
+        } else {
+            self.spawn_procs_from_ast(opts, ec, command)
+        }
+    }
+
+    /// Start both sides of a pipe, `last_stage` are more fd actions for only the command at the end of it.
+    /// With `|&` only the command right before it has its standard error put into the pipe,
+    /// since a pipeline is nested to the left that's the end of the pipeline before it.
+    fn spawn_pipeline<'a>(
+        &mut self,
+        opts: &'a ProcOptions<'a>,
+        ec: &mut ExecutionContext,
+        pipe: &Pipeline,
+        last_stage: &[FdAction],
+    ) -> Result<Execution> {
+        let (read_end, write_end) = shell_pipe()?;
+        let from_opts = opts.with(&[FdAction::Dup(write_end, 1)]);
+        let merge_stderr = if pipe.stderr { vec![FdAction::Dup(write_end, 2)] } else { Vec::new() };
+        let mut to_actions = vec![FdAction::Dup(read_end, 0)];
+        to_actions.extend_from_slice(last_stage);
+        let to_opts = opts.with(&to_actions);
+
+        // when a side can't be started the pipe is closed straight away, so the other side sees EOF
+        // or SIGPIPE instead of waiting forever, and whatever already started is waited for
+        let mut jids = Vec::new();
+        let started_from = match &pipe.from {
+            Command::Pipeline(before) => self.spawn_pipeline(&from_opts, ec, before, &merge_stderr),
+            from => self.spawn_pipeline_member(&from_opts.with(&merge_stderr), ec, from),
+        };
+        let spawned = started_from.and_then(|from| {
+            jids = from.started();
+            self.spawn_pipeline_member(&to_opts, ec, &pipe.to)
+        });
+
+        // both ends are closed even if the first fails, a failure to close counts the same as one to start
+        let closed_read = unistd::close(read_end).context(ErrorKind::FailedToClosePipeFile(read_end));
+        let closed_write = unistd::close(write_end).context(ErrorKind::FailedToClosePipeFile(write_end));
+        let spawned = spawned.and_then(|to| {
+            jids.extend(to.started());
+            closed_read.and(closed_write)?;
+            Ok(())
+        });
+
+        match spawned {
+            Ok(()) => Ok(Execution::Started(jids)),
+            Err(e) => {
+                // the error that stopped the pipeline is the one reported, but one from waiting isn't lost
+                if let Err(waiting) = self.wait_for_all(&jids) {
+                    opts.error(waiting);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Start an expanded simple command, looking the name up as a special builtin, a function, a builtin,
+    /// and then a program. Functions are skipped when `functions` is false, as the `command` builtin does.
+    /// A program gets the words' bytes as they are, builtins get them as text, with anything that isn't UTF-8 replaced.
+    fn spawn_command(
+        &mut self,
+        opts: &ProcOptions,
+        ec: &mut ExecutionContext,
+        words: &[OsString],
+        functions: bool,
+    ) -> Result<Execution> {
+        let last = mem::replace(&mut self.exec_last, false);
+        let argv0 = match words.first() {
+            Some(argv0) => argv0.to_string_lossy().to_string(),
+            None => return Ok(Execution::finished(0)),
+        };
+
+        // special builtins like `exit` can't be replaced by functions
+        let function = ec
+            .functions()
+            .value(&argv0)
+            .filter(|_| functions && !builtins::is_special(&argv0));
+        if let Some(body) = function {
+            self.call_function(opts, ec, &body, words)
+        } else if let Some(builtin) = builtins::lookup(&argv0) {
+            let exit_code = builtin(&mut builtins::Invocation {
+                jobs: self,
+                ec,
+                args: words,
+                stdin: opts.fd(0),
+                stdout: opts.fd(1),
+                stderr: opts.fd(2),
+                redirections: &opts.redirections(),
+            })?;
+            Ok(Execution::finished(exit_code))
+        } else {
+            let mut args = Vec::with_capacity(words.len());
+            for w in words {
+                args.push(CString::new(w.as_bytes()).context(ErrorKind::IllegalNullByte)?);
+            }
+
+            let exe = match ec.command_path(&words[0]) {
+                Ok(exe) => exe,
+                // a missing program is just a failed command, the commands after it still run
+                Err(_) => {
+                    let message = format!("rush: {}: command not found\n", argv0);
+                    unistd::write(opts.fd(2), message.as_bytes()).ok();
+                    return Ok(Execution::finished(127));
+                }
+            };
+            let c_exe = CString::new(exe.as_os_str().as_bytes()).context(ErrorKind::IllegalNullByte)?;
+            let environ = ec.environment(opts.env);
+            if last && self.can_exec_last(opts, ec) {
+                let failure = exec_in_place(&c_exe, &args, &ec.cwd, &environ);
+                let message = failure.message(&c_exe.to_string_lossy(), &ec.cwd);
+                unistd::write(opts.fd(2), message.as_bytes()).ok();
+                return Ok(Execution::finished(failure.exit_code()));
+            }
+            let jid = self.spawn_proc(&c_exe, &args, &ec.cwd, &environ, opts)?;
+            Ok(Execution::Started(vec![jid]))
+        }
+    }
+
+    /// Whether a program can replace the shell without anything noticing: the shell wouldn't wait for
+    /// other children or run a trap, and the program doesn't need fds moved that the shell would have to keep
+    fn can_exec_last(&self, opts: &ProcOptions, ec: &ExecutionContext) -> bool {
+        opts.fds.is_empty()
+            && !ec.is_interactive()
+            && self.running_jobs.is_empty()
+            && self.jobs.is_empty()
+            && traps::traps().is_empty()
+    }
+
+    fn run_arithmetic_for(
+        &mut self,
+        opts: &ProcOptions,
+        ec: &mut ExecutionContext,
+        lp: &ArithmeticFor,
+    ) -> Result<Execution> {
+        evaluate_arithmetic(ec, &lp.init)?;
+        let mut status = Execution::finished(0);
+        while evaluate_arithmetic(ec, &lp.condition)?.unwrap_or(true) {
+            let execution = self.spawn_procs_from_ast(opts, ec, &lp.body)?;
+            status = Execution::Finished(self.finish(execution)?);
+            if !self.loop_continues() {
+                break;
+            }
+            evaluate_arithmetic(ec, &lp.step)?;
+        }
+        Ok(status)
+    }
+
+    /// Replace a command's first word with its alias, giving the alias' name and the command to run instead.
+    /// Keywords are recognized while parsing, before aliases are expanded, so aliasing them has no effect.
+    fn expand_alias(&self, ec: &ExecutionContext, cmd: &SimpleCommand) -> Result<Option<(String, Command)>> {
+        let name = match cmd.arguments.first().and_then(|w| w.literal()) {
+            Some(name) if !self.expanding_aliases.iter().any(|a| a == name) => name,
+            _ => return Ok(None),
+        };
+        let mut command = match ec.aliases().value(name) {
+            Some(value) => Command::parse(value)?,
+            None => return Ok(None),
+        };
+        command.append_arguments(&cmd.arguments[1..]);
+        // the commands an alias expands to are on the line it was used on
+        command.renumber(&|_| cmd.line);
+        Ok(Some((name.to_string(), command)))
+    }
+
+    // spawn 0 or more processes based on a shell-language abstract syntax tree in a given execution context
+    fn spawn_procs_from_ast<'a>(
+        &mut self,
+        opts: &'a ProcOptions<'a>,
+        ec: &mut ExecutionContext,
+        command: &Command,
+    ) -> Result<Execution> {
+        // only the commands at the end of what's being run are the last ones too
+        let last = mem::replace(&mut self.exec_last, false);
+
+        // with `set -n` commands are only read, and nothing can turn it off again
+        if ec.options.noexec {
+            return Ok(Execution::finished(0));
+        }
+
+        match command {
+            Command::SimpleCommand(cmd) => {
+                if cmd.line > 0 {
+                    ec.vars.set_line(cmd.line);
+                }
+                if let Some((name, expanded)) = self.expand_alias(ec, cmd)? {
+                    self.expanding_aliases.push(name);
+                    let execution = self.spawn_procs_from_ast(opts, ec, &expanded);
+                    self.expanding_aliases.pop();
+                    return execution;
+                }
+
+                let prefix = cmd
+                    .arguments
+                    .iter()
+                    .take_while(|w| w.assignment_name().is_some())
+                    .count();
+                let (assignments, arguments) = cmd.arguments.split_at(prefix);
+                if arguments.is_empty() {
+                    // each assignment can use the ones before it, so the DEBUG trap only gets the text once
+                    // they're all expanded, and then runs after them
+                    let mut text = Vec::with_capacity(assignments.len());
+                    for w in assignments {
+                        let assignment = match w.compile(&mut ec.vars) {
+                            Err(ref e) if is_readonly(e) => {
+                                opts.error(e);
+                                return Ok(Execution::finished(1));
+                            }
+                            assignment => assignment?,
+                        };
+                        let assigned = match split_assignment(&assignment) {
+                            (name, None, _, append) if w.is_compound_assignment() => w
+                                .compound_elements(&mut ec.vars)
+                                .and_then(|elements| ec.vars.define_array(&OsString::from(name), elements, append)),
+                            (name, Some(key), value, false) => ec.vars.define_element(&OsString::from(name), &key, value),
+                            (name, Some(key), value, true) => ec.vars.append_element(&OsString::from(name), &key, value),
+                            (name, None, value, false) => ec.vars.define(name, value),
+                            (name, None, value, true) => ec.vars.append(name, value),
+                        };
+                        if let Err(e) = assigned {
+                            opts.error(e);
+                            return Ok(Execution::finished(1));
+                        }
+                        text.push(assignment);
+                    }
+                    if traps::debugging() {
+                        self.run_debug_trap(ec, word::join_fields(text))?;
+                    }
+                    return Ok(Execution::finished(0));
+                }
+
+                // an unquoted word that expands to nothing, like `$EMPTY`, goes away entirely.
+                // The words are expanded before the assignments, so `X=1 echo $X` prints the old X.
+                let mut words = Vec::with_capacity(arguments.len());
+                for w in arguments {
+                    let fields = match w.fields(&mut ec.vars) {
+                        Err(ref e) if is_readonly(e) => {
+                            opts.error(e);
+                            return Ok(Execution::finished(1));
+                        }
+                        fields => fields?,
+                    };
+                    for field in fields {
+                        if !field.is_empty() || w.has_quotes() {
+                            words.push(field);
+                        }
+                    }
+                }
+
+                if traps::debugging() {
+                    self.run_debug_trap(ec, word::join_fields(words.clone()))?;
+                }
+                self.exec_last = last;
+                let opts = opts.with_script_fds(ec);
+                match ec.with_assignments(assignments, |ec| self.spawn_command(&opts, ec, &words, true)) {
+                    Err(ref e) if is_readonly(e) => {
+                        opts.error(e);
+                        Ok(Execution::finished(1))
+                    }
+                    execution => execution,
+                }
+            }
+            Command::Pipeline(pipe) => self.spawn_pipeline(opts, ec, pipe, &[]),
+            Command::Group(group) | Command::BraceGroup(group) => {
+                let commands: Vec<&Command> = group.commands.iter().filter(|c| !c.is_comment()).collect();
+                let mut result = Execution::finished(0);
+                for (i, cmd) in commands.iter().enumerate() {
+                    self.exec_last = last && i + 1 == commands.len();
+                    let execution = self.spawn_procs_from_ast(opts, ec, cmd)?;
+                    let status = self.finish(execution)?;
+                    ec.vars.set_status(status.exit_code);
+                    result = Execution::Finished(status);
+                    self.run_traps(ec)?;
+                    if self.flow.is_some() {
+                        break;
+                    }
+                }
+                Ok(result)
+            }
+            Command::SubShell(group) => {
+                let body = Command::Group(group.clone());
+                Ok(Execution::Started(vec![self.spawn_subshell(opts, ec, &body)?]))
+            }
+            Command::ConditionalPair(cond) => {
+                let left = self.spawn_procs_from_ast(opts, ec, &cond.left)?;
+                let status = self.finish(left)?;
+                ec.vars.set_status(status.exit_code);
+                if self.flow.is_none()
+                    && ((status.exit_code == 0 && cond.operator == ConditionOperator::AndIf)
+                        || (status.exit_code != 0 && cond.operator == ConditionOperator::OrIf))
+                {
+                    self.exec_last = last;
+                    let right = self.spawn_procs_from_ast(opts, ec, &cond.right)?;
+                    Ok(Execution::Finished(self.finish(right)?))
+                } else {
+                    Ok(Execution::Finished(status))
+                }
+            }
+            Command::ArithmeticFor(lp) => {
+                self.loop_depth += 1;
+                let result = self.run_arithmetic_for(opts, ec, lp);
+                self.loop_depth -= 1;
+                result
+            }
+            Command::Function(func) => {
+                let str_name = func.name.compile(ec.variables_mut())?.to_string_lossy().to_string();
+                if !Variables::is_valid_name(&str_name) {
+                    opts.error(ErrorKind::InvalidIdentifier(str_name));
+                    return Ok(Execution::finished(1));
+                }
+                ec.functions_mut().insert(str_name, Rc::new(func.body.clone()));
+                Ok(Execution::finished(0))
+            }
+            Command::Time(timed) => {
+                let started = Instant::now();
+                let (user_before, sys_before) = children_cpu_time();
+                let execution = self.spawn_procs_from_ast(opts, ec, timed)?;
+                let status = self.finish(execution)?;
+                let (user_after, sys_after) = children_cpu_time();
+
+                eprintln!(
+                    "real {:.2}\nuser {:.2}\nsys {:.2}",
+                    started.elapsed().as_secs_f64(),
+                    (user_after - user_before).as_secs_f64(),
+                    (sys_after - sys_before).as_secs_f64()
+                );
+                Ok(Execution::Finished(status))
+            }
+            Command::FileRedirect(redirect) => {
+                let mut actions = Vec::new();
+                let mut opened = Vec::new();
+                let mut failure = None;
+                for r in &redirect.redirects {
+                    match open_redirect(ec, r, &mut opened) {
+                        Ok(redirected) => actions.extend(redirected),
+                        Err(e) => {
+                            failure = Some(e);
+                            break;
+                        }
+                    }
+                }
+
+                let result = match failure {
+                    Some(e) => Err(e),
+                    None => self.spawn_procs_from_ast(&opts.with(&actions), ec, &redirect.left),
+                };
+
+                // the children have their own copies by now
+                for fd in opened {
+                    unistd::close(fd).ok();
+                }
+                result
+            }
+            Command::Background(bg) => {
+                self.starting_background = true;
+                let jids = if runs_in_process(ec, &bg.command) {
+                    self.spawn_subshell(opts, ec, &bg.command).map(|jid| vec![jid])
+                } else {
+                    self.spawn_procs_from_ast(opts, ec, &bg.command).map(Execution::started)
+                };
+                self.starting_background = false;
+                self.job_pgid = None;
+                self.job_text.clear();
+
+                let id = self.add_background_job(bg.text.clone(), jids?);
+                if ec.is_interactive() {
+                    if let Some(pid) = self.job(id).and_then(|job| job.pids.last()) {
+                        eprintln!("[{}] {}", id, pid);
+                    }
+                }
+                Ok(Execution::finished(0))
+            }
+            Command::Comment(_s) => Ok(Execution::finished(0)),
+            _ => unimplemented!(),
+        }
+    }
+
+    pub fn stat(&mut self, jid: Jid) -> Result<JobStatus> {
+        if let Some(status) = self.completed_jobs.get(&jid) {
+            Ok(JobStatus::Complete(status.clone()))
+        } else {
+            self.running_jobs
+                .iter()
+                .find(|(_, v)| **v == jid)
+                .map_or(Err(ErrorKind::InvalidJobId(jid).into()), |v| {
+                    Ok(JobStatus::Running)
+                })
+        }
+    }
+
+    /// Wait for a specific job to complete
+    pub fn wait_for(&mut self, jid: Jid) -> Result<ExitStatus> {
+        if let Some(exit_status) = self.completed_jobs.get(&jid) {
+            return Ok(exit_status.clone());
+        }
+
+        loop {
+            match self.next()? {
+                Some((completed, status)) => {
+                    self.completed_jobs.insert(completed, status);
+                    if completed == jid {
+                        return Ok(status);
+                    }
+                }
+                None => return Ok(self.lose_status(jid)),
+            }
+        }
+    }
+
+    /// Wait for several jobs to complete, it's an error if one was interrupted while the shell is catching interrupts
+    pub fn wait_for_all(&mut self, jids: &[Jid]) -> Result<()> {
+        let mut incomplete: BTreeSet<Jid> = jids
+            .iter()
+            .map(|jid| *jid)
+            .filter(|jid| self.completed_jobs.get(jid).is_none())
+            .collect();
+
+        // other children (like background jobs) may finish first, their statuses are kept for later
+        let mut interrupted = false;
+        while !incomplete.is_empty() {
+            match self.next_change()? {
+                Some((jid, Some(status))) => {
+                    self.completed_jobs.insert(jid, status);
+                    if incomplete.remove(&jid) {
+                        report_signal(&status);
+                        interrupted |= status.signal == Some(signal::Signal::SIGINT);
+                    }
+                }
+                Some((jid, None)) => if incomplete.contains(&jid) {
+                    self.stop_foreground(jids);
+                    break;
+                },
+                None => {
+                    for jid in incomplete {
+                        self.lose_status(jid);
+                    }
+                    break;
+                }
+            }
+        }
+
+        // whatever is waited for next belongs to a new job
+        self.job_pgid = None;
+        self.job_text.clear();
+        if self.job_control {
+            unistd::tcsetpgrp(TERMINAL, self.shell_pgid).ok();
+        }
+        if interrupted && traps::catching_interrupts() {
+            Err(ErrorKind::Interrupted)?;
+        }
+        Ok(())
+    }
+
+    /// Turn a foreground command that was stopped into a job, unless it already was one, and announce it
+    fn stop_foreground(&mut self, jids: &[Jid]) {
+        let existing = self
+            .jobs
+            .iter()
+            .find(|job| job.jids.iter().any(|jid| jids.contains(jid)))
+            .map(|job| job.id);
+        let id = match existing {
+            Some(id) => {
+                self.job_order.retain(|j| *j != id);
+                self.job_order.push(id);
+                id
+            }
+            None => {
+                let text = self.job_text.join(" | ");
+                self.add_background_job(text, jids.to_vec())
+            }
+        };
+        if let Some(job) = self.job(id) {
+            eprintln!("\n[{}]+  {:<24}{}", id, "Stopped", job.text);
+        }
+    }
+
+    /// Move a newly forked child into the process group of the job being started, in the child.
+    /// A foreground job's first process takes the terminal, and the signals the shell ignores are restored.
+    fn join_job_group(&self) {
+        if !self.job_control {
+            return;
+        }
+        unistd::setpgid(unistd::Pid::from_raw(0), self.job_pgid.unwrap_or(unistd::Pid::from_raw(0))).ok();
+        if !self.starting_background {
+            unistd::tcsetpgrp(TERMINAL, unistd::getpgrp()).ok();
+        }
+        for sig in JOB_CONTROL_SIGNALS {
+            if traps::action(traps::Condition::Signal(*sig)) != Some(traps::Action::Ignore) {
+                set_disposition(*sig, signal::SigHandler::SigDfl);
+            }
+        }
+    }
+
+    /// Record a forked child as part of the job being started, in the parent.
+    /// The process group is set here too so it's in place whichever of the two runs first.
+    fn add_job_member(&mut self, child: unistd::Pid, text: String) -> Jid {
+        let jid = self.add_job(child);
+        if !self.job_control {
+            return jid;
+        }
+
+        let pgid = *self.job_pgid.get_or_insert(child);
+        unistd::setpgid(child, pgid).ok();
+        if pgid == child && !self.starting_background {
+            unistd::tcsetpgrp(TERMINAL, pgid).ok();
+        }
+        self.process_groups.insert(jid, pgid);
+        self.job_text.push(text);
+        jid
+    }
+}
+
+/// The directory the shell starts in, an inherited $PWD is kept when it's still the same directory as the real one,
+/// so the symlinks it went through are kept too
+fn initial_cwd(vars: &Variables) -> PathBuf {
+    let physical = env::current_dir().unwrap();
+    let inherited = PathBuf::from(vars.value(&OsString::from("PWD")));
+    let same_dir = match (fs::metadata(&inherited), fs::metadata(&physical)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    };
+    if inherited.is_absolute() && same_dir {
+        inherited
+    } else {
+        physical
+    }
+}
+
+/// The search path used when the environment doesn't give one
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Set the variables every shell starts with: SHLVL one more than the shell that started this one,
+/// HOSTNAME, the readonly UID, EUID and PPID, and SHELL and PATH when the environment doesn't have them
+fn standard_variables(vars: &mut Variables) {
+    let level = vars
+        .value(&OsString::from("SHLVL"))
+        .to_str()
+        .and_then(|v| v.trim().parse::<i64>().ok())
+        .filter(|&n| n >= 0)
+        .unwrap_or(0);
+    vars.define("SHLVL", (level + 1).to_string()).ok();
+    vars.export(&OsString::from("SHLVL"));
+
+    let mut buf = [0u8; 256];
+    if let Ok(name) = unistd::gethostname(&mut buf) {
+        vars.define("HOSTNAME", name.to_string_lossy().to_string()).ok();
+    }
+
+    let ids = [
+        ("UID", unistd::getuid().to_string()),
+        ("EUID", unistd::geteuid().to_string()),
+        ("PPID", unistd::getppid().to_string()),
+    ];
+    for (name, id) in ids.iter() {
+        vars.define(*name, id.as_str()).ok();
+        vars.set_readonly(&OsString::from(name));
+    }
+
+    if !vars.exists(&OsString::from("SHELL")) {
+        if let Ok(exe) = env::current_exe() {
+            vars.define("SHELL", exe).ok();
+        }
+    }
+    if !vars.exists(&OsString::from("PATH")) {
+        vars.define("PATH", DEFAULT_PATH).ok();
+    }
+}
+
+impl ExecutionContext {
+    pub fn new() -> ExecutionContext {
+        let mut vars = Variables::from_env();
+        let cwd = initial_cwd(&vars);
+        // nothing is readonly yet, so these can't fail
+        vars.define("PWD", cwd.as_os_str()).ok();
+        standard_variables(&mut vars);
+        ExecutionContext {
+            vars,
+            funcs: Functions::new(),
+            aliases: Aliases::new(),
+            hashed: PathCache::new(),
+            options: Options::new(),
+            cwd,
+            dir_stack: Vec::new(),
+            interactive: false,
+            option_cursor: (1, 1),
+            script_fds: BTreeMap::new(),
+        }
+    }
+
+    /// Move to a new logical working directory, keeping $PWD and $OLDPWD up to date.
+    /// The directory changes even if one of them is readonly, the error is still returned.
+    pub fn set_cwd(&mut self, dir: PathBuf) -> Result<()> {
+        let old = mem::replace(&mut self.cwd, dir);
+        let set_old = self.vars.define("OLDPWD", old);
+        let set_pwd = self.vars.define("PWD", self.cwd.as_os_str());
+        set_old.and(set_pwd)
+    }
+
+    pub fn variables(&self) -> &Variables {
+        &self.vars
+    }
+
+    pub fn variables_mut(&mut self) -> &mut Variables {
+        &mut self.vars
+    }
+
+    pub fn functions(&self) -> &Functions {
+        &self.funcs
+    }
+
+    pub fn functions_mut(&mut self) -> &mut Functions {
+        &mut self.funcs
+    }
+
+    pub fn aliases(&self) -> &Aliases {
+        &self.aliases
+    }
+
+    pub fn aliases_mut(&mut self) -> &mut Aliases {
+        &mut self.aliases
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    /// Whether the shell is reading commands from a user at a terminal
+    pub fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// The (OPTIND, character index) `getopts` stopped at
+    pub fn option_cursor(&self) -> (usize, usize) {
+        self.option_cursor
+    }
+
+    pub fn set_option_cursor(&mut self, cursor: (usize, usize)) {
+        self.option_cursor = cursor;
+    }
+
+    /// The shell's own fd that a script's fd `n` is, if `exec` opened one for it
+    pub fn script_fd(&self, n: RawFd) -> Option<RawFd> {
+        self.script_fds.get(&n).cloned()
+    }
+
+    /// Make `fd`, one of the shell's own fds, the script's fd `n` for every command from now on.
+    /// The fd `n` was before is closed.
+    pub fn keep_script_fd(&mut self, n: RawFd, fd: RawFd) {
+        if let Some(old) = self.script_fds.insert(n, fd) {
+            unistd::close(old).ok();
+        }
+    }
+
+    /// Close the script's fd `n`, for `exec n>&-`
+    pub fn close_script_fd(&mut self, n: RawFd) {
+        if let Some(old) = self.script_fds.remove(&n) {
+            unistd::close(old).ok();
+        }
+    }
+
+    /// Run `f` with the variables in some `NAME=value` words set only until it returns, like the `FOO=bar` in `FOO=bar cmd`.
+    /// They're exported so the programs `f` starts see them. Afterwards, whether `f` succeeded or not,
+    /// each one gets back the value and export it had, or is unset again if it had no value.
+    pub fn with_assignments<T, F>(&mut self, assignments: &[Word], f: F) -> Result<T>
+    where
+        F: FnOnce(&mut ExecutionContext) -> Result<T>,
+    {
+        let mut hidden = Vec::with_capacity(assignments.len());
+        let result = self.assign_temporarily(assignments, &mut hidden).and_then(|_| f(self));
+        // undone last to first, so a name assigned twice ends up with the value from before either
+        for (name, h) in hidden.into_iter().rev() {
+            self.vars.unhide(&name, h);
+        }
+        result
+    }
+
+    /// Set each assignment in turn, so later ones can use earlier ones, saving what they hide.
+    /// A program can't be given an array, so an element's assignment is kept like one without a command.
+    fn assign_temporarily(&mut self, assignments: &[Word], hidden: &mut Vec<(OsString, Hidden)>) -> Result<()> {
+        for w in assignments {
+            let assignment = w.compile(&mut self.vars)?;
+            let (name, key, value, append) = split_assignment(&assignment);
+            let name = OsString::from(name);
+            match key {
+                Some(key) if append => self.vars.append_element(&name, &key, value)?,
+                Some(key) => self.vars.define_element(&name, &key, value)?,
+                None => {
+                    hidden.push((name.clone(), self.vars.hide(&name)));
+                    if append {
+                        self.vars.append(name.clone(), value)?;
+                    } else {
+                        self.vars.define(name.clone(), value)?;
+                    }
+                    self.vars.export(&name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The environment a program is started with, the exported variables along with the command's own
+    /// `NAME=value` assignments, which take the place of any exported variable with the same name
+    pub fn environment(&self, assignments: &[CString]) -> Vec<CString> {
+        let assigned: Vec<&[u8]> = assignments
+            .iter()
+            .map(|a| a.as_bytes().split(|&b| b == b'=').next().unwrap_or(&[]))
+            .collect();
+        let mut environ: Vec<CString> = self
+            .vars
+            .exported()
+            .filter(|&(k, _)| !assigned.contains(&k.as_bytes()))
+            .filter_map(|(k, v)| {
+                let mut entry = k.as_bytes().to_vec();
+                entry.push(b'=');
+                entry.extend(v?.as_bytes());
+                CString::new(entry).ok()
+            }).collect();
+        environ.extend(assignments.iter().cloned());
+        environ
+    }
+
+    /// Find the program a command name refers to, names with a slash are paths used as-is, relative to the working directory.
+    /// Only names that are UTF-8 are remembered in the path cache.
+    pub fn command_path<S: AsRef<OsStr>>(&mut self, name: S) -> Result<PathBuf> {
+        let name = name.as_ref();
+        if name.as_bytes().contains(&b'/') {
+            return Ok(PathBuf::from(name));
+        }
+
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return self.find_executable(name),
+        };
+        if let Some(path) = self.path_cache_mut().hit(name) {
+            return Ok(path);
+        }
+        let path = self.find_executable(name)?;
+        self.hashed.insert(name, path.clone(), 1);
+        Ok(path)
+    }
+
+    /// The programs that have been found in $PATH, cleared whenever $PATH changes
+    pub fn path_cache_mut(&mut self) -> &mut PathCache {
+        let search_path = self.vars.value(&OsString::from("PATH"));
+        self.hashed.sync(&search_path);
+        &mut self.hashed
+    }
+
+    pub fn find_executable<S: AsRef<OsStr>>(&self, prog: S) -> Result<PathBuf> {
+        let prog_ref = prog.as_ref();
+        match self.find_executables(prog_ref).into_iter().next() {
+            Some(p) => Ok(p),
+            None => {
+                let owned_prog = prog_ref.to_os_string().to_string_lossy().to_string();
+                Err(Error::from(ErrorKind::MissingExecutable(owned_prog)))
+            }
+        }
+    }
+
+    /// Every executable match for a program in $PATH, in the order they would be tried.
+    /// Empty entries in $PATH, like relative ones, are relative to the working directory.
+    pub fn find_executables<S: AsRef<OsStr>>(&self, prog: S) -> Vec<PathBuf> {
+        let prog_ref = prog.as_ref();
+        env::split_paths(&self.vars.value(&OsString::from("PATH")))
+            .map(|path| self.cwd.join(path).join(prog_ref))
+            .filter(|p| is_executable(p))
+            .collect()
+    }
+}
+
+/// Whether a path is a regular file the shell has permission to execute
+fn is_executable(path: &Path) -> bool {
+    let is_file = path.metadata().map(|m| m.is_file()).unwrap_or(false);
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => is_file && unsafe { libc::access(c_path.as_ptr(), libc::X_OK) } == 0,
+        Err(_) => false,
+    }
+}
+
+/// The terminal a job control shell hands to its foreground jobs
+const TERMINAL: RawFd = 0;
+
+/// The lowest fd the shell uses for the files and pipes it opens for itself, like other shells 0 to 9 are left to scripts
+const FIRST_SHELL_FD: RawFd = 10;
+
+/// The function nesting limit with no $FUNCNEST
+const DEFAULT_FUNCTION_NESTING: usize = 1000;
+
+/// How much of the stack a function call has to leave for whatever it runs, the calls inside it check again
+const STACK_RESERVE: usize = 512 << 10;
+
+/// The signals an interactive shell ignores so that it can't be stopped by them, its children get them back
+const JOB_CONTROL_SIGNALS: &[signal::Signal] = &[
+    signal::Signal::SIGTSTP,
+    signal::Signal::SIGTTIN,
+    signal::Signal::SIGTTOU,
+];
+
+/// The signals the shell might catch or ignore for itself, the programs it starts get their default behavior back.
+/// Rust ignores SIGPIPE in everything it builds, so that's one of them even though the shell never touches it.
+const RESET_SIGNALS: &[signal::Signal] = &[
+    signal::Signal::SIGINT,
+    signal::Signal::SIGQUIT,
+    signal::Signal::SIGPIPE,
+    signal::Signal::SIGTSTP,
+    signal::Signal::SIGTTIN,
+    signal::Signal::SIGTTOU,
+];
+
+/// Replace the shell with a program the way a forked child does, only returning if it couldn't be run
+fn exec_in_place(exe: &CString, args: &[CString], path: &Path, environ: &[CString]) -> SetupFailure {
+    traps::restore_interrupts();
+    reset_signals(&traps::ignored());
+    match unistd::chdir(path) {
+        Err(e) => SetupFailure::Chdir(errno_of(e)),
+        Ok(()) => SetupFailure::Exec(errno_of(unistd::execve(exe, args, environ).unwrap_err())),
+    }
+}
+
+/// Put a child's signals the way a program expects them before it's exec'd: nothing blocked, and the defaults for
+/// everything the shell changed except the `ignored` signals a trap ignores. Signals trapped with a command need
+/// nothing here, exec resets caught signals by itself. `ignored` is worked out before forking, so the child
+/// only makes async-signal-safe calls. It has to be done after the child takes the terminal, which raises SIGTTOU.
+fn reset_signals(ignored: &[signal::Signal]) {
+    for sig in RESET_SIGNALS.iter().filter(|sig| !ignored.contains(sig)) {
+        set_disposition(*sig, signal::SigHandler::SigDfl);
+    }
+    signal::sigprocmask(signal::SigmaskHow::SIG_SETMASK, Some(&signal::SigSet::empty()), None).ok();
+}
+
+fn set_disposition(sig: signal::Signal, handler: signal::SigHandler) {
+    let action = signal::SigAction::new(handler, signal::SaFlags::empty(), signal::SigSet::empty());
+    unsafe { signal::sigaction(sig, &action) }.ok();
+}
+
+/// Assigning to a readonly variable, while expanding a command or in the assignments before it,
+/// only makes that command fail, instead of stopping the script like other errors
+fn is_readonly(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::ReadonlyVariable(_))
+}
+
+/// How deeply functions can call each other, from $FUNCNEST if it's set to a positive number.
+/// Runaway recursion stops there, or sooner when the stack is running out, instead of overflowing it.
+fn function_nesting_limit(ec: &ExecutionContext) -> usize {
+    ec.vars
+        .value(&OsString::from("FUNCNEST"))
+        .to_str()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_FUNCTION_NESTING)
+}
+
+thread_local! {
+    /// The lowest address of the running thread's stack, `None` if it can't be found
+    static STACK_END: Option<usize> = unsafe {
+        let mut attr: libc::pthread_attr_t = mem::zeroed();
+        let mut addr: *mut libc::c_void = std::ptr::null_mut();
+        let mut size: libc::size_t = 0;
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            None
+        } else {
+            let found = libc::pthread_attr_getstack(&attr, &mut addr, &mut size) == 0;
+            libc::pthread_attr_destroy(&mut attr);
+            if found { Some(addr as usize) } else { None }
+        }
+    };
+}
+
+/// Roughly how many bytes are left on the stack, which grows down towards its end
+fn stack_left() -> Option<usize> {
+    let here = 0u8;
+    let here = &here as *const u8 as usize;
+    STACK_END.with(|end| end.map(|end| here.saturating_sub(end)))
+}
+
+/// Tell the user a process was killed by a signal, other than the ones that are expected to end things quietly
+fn report_signal(status: &ExitStatus) {
+    if let Some(report) = signal_report(status) {
+        eprintln!("{}", report);
+    }
+}
+
+/// What `report_signal` says about a status, if anything
+fn signal_report(status: &ExitStatus) -> Option<String> {
+    match status.signal {
+        Some(signal::Signal::SIGINT) | Some(signal::Signal::SIGPIPE) | None => None,
+        Some(sig) if status.core_dumped => Some(format!("{} (core dumped)", traps::signal_description(sig))),
+        Some(sig) => Some(traps::signal_description(sig).to_string()),
+    }
+}
+
+/// Split an expanded assignment, `NAME=value` or `NAME[KEY]=value`, into the name, the key, and the value,
+/// which keeps whatever bytes it was given. The last part is whether it appends with `+=`.
+fn split_assignment(assignment: &OsStr) -> (String, Option<String>, &OsStr, bool) {
+    let bytes = assignment.as_bytes();
+    let text = |b: &[u8]| String::from_utf8_lossy(b).to_string();
+    if let Some(open) = bytes.iter().position(|&b| b == b'=' || b == b'[').filter(|&i| bytes[i] == b'[') {
+        if let Some(close) = (open..bytes.len()).find(|&i| bytes[i..].starts_with(b"]=") || bytes[i..].starts_with(b"]+=")) {
+            let append = bytes[close + 1] == b'+';
+            return (
+                text(&bytes[..open]),
+                Some(text(&bytes[open + 1..close])),
+                OsStr::from_bytes(&bytes[close + if append { 3 } else { 2 }..]),
+                append,
+            );
+        }
+    }
+    let split = bytes.iter().position(|&b| b == b'=').unwrap();
+    let append = split > 0 && bytes[split - 1] == b'+';
+    let name = if append { &bytes[..split - 1] } else { &bytes[..split] };
+    (text(name), None, OsStr::from_bytes(&bytes[split + 1..]), append)
+}
+
+/// Whether a command runs at least partly inside the shell process, running those asynchronously needs a subshell
+fn runs_in_process(ec: &mut ExecutionContext, command: &Command) -> bool {
+    match command {
+        Command::SimpleCommand(cmd) => {
+            let first = match cmd.arguments.iter().find(|w| w.assignment_name().is_none()) {
+                Some(first) => first,
+                None => return true,
+            };
+            if let Some(name) = first.literal() {
+                if ec.aliases().value(name).is_some() {
+                    return true;
+                }
+            }
+            match first.compile(&mut ec.vars) {
+                Ok(name) => {
+                    let name = name.to_string_lossy();
+                    ec.functions().exists(&name) || builtins::is_builtin(&name)
+                }
+                Err(_) => true,
+            }
+        }
+        Command::FileRedirect(redirect) => runs_in_process(ec, &redirect.left),
+        Command::Pipeline(_) | Command::SubShell(_) => false,
+        _ => true,
+    }
+}
+
+/// Open the file for a redirection, returning the fd it targets, the fd to put there,
+/// and whether the fd was opened here and needs to be closed once the command has started
+fn open_redirect(
+    ec: &mut ExecutionContext,
+    r: &RedirectDestination,
+    opened: &mut Vec<RawFd>,
+) -> Result<Vec<FdAction>> {
+    let target = r.fd.unwrap_or(match r.operation {
+        IoOperation::Input
+        | IoOperation::InputDupFd
+        | IoOperation::ReadWrite
+        | IoOperation::HereDocument
+        | IoOperation::HereDocumentStrip => 0,
+        _ => 1,
+    });
+    let file = r.file.compile(&mut ec.vars)?;
+
+    let flags = match r.operation {
+        IoOperation::Input => OFlag::O_RDONLY,
+        IoOperation::OutputCreate | IoOperation::Output => {
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC
+        }
+        IoOperation::OutputAppend => OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_APPEND,
+        IoOperation::ReadWrite => OFlag::O_RDWR | OFlag::O_CREAT,
+        // duplicating copies wherever the fd has been redirected so far,
+        // which is why `>file 2>&1` and `2>&1 >file` are different
+        IoOperation::InputDupFd | IoOperation::OutputDupFd => {
+            if file == "-" {
+                return Ok(vec![FdAction::Close(target)]);
+            }
+            return match file.to_str().and_then(|f| f.parse::<RawFd>().ok()) {
+                Some(fd) => Ok(vec![FdAction::Dup(fd, target)]),
+                None => Err(ErrorKind::RedirectFailed(
+                    file.to_string_lossy().to_string(),
+                    "ambiguous redirect".to_string(),
+                ).into()),
+            }
+        }
+        IoOperation::HereDocument | IoOperation::HereDocumentStrip => {
+            return Err(ErrorKind::UnsupportedRedirect(target).into())
+        }
+    };
+
+    // CLOEXEC so only the dup'd copy makes it through exec
+    let mode = Mode::from_bits_truncate(0o666);
+    match fcntl::open(&ec.cwd.join(&file), flags | OFlag::O_CLOEXEC, mode) {
+        Ok(fd) => {
+            let fd = shell_fd(fd)?;
+            opened.push(fd);
+            Ok(vec![FdAction::Dup(fd, target)])
+        }
+        Err(e) => {
+            let reason = match e {
+                nix::Error::Sys(errno) => errno.desc().to_string(),
+                e => e.to_string(),
+            };
+            Err(ErrorKind::RedirectFailed(file.to_string_lossy().to_string(), reason).into())
+        }
+    }
+}
+
+/// The fd actions that make the shell's `standard_fds` a command's standard input, output, and error.
+/// The fds are the shell's, so any that would be overwritten by the ones before them are copied first,
+/// the copies are returned so they can be closed once the command has started.
+fn standard_actions(standard_fds: [RawFd; 3]) -> Result<(Vec<FdAction>, Vec<RawFd>)> {
+    let mut copies = Vec::new();
+    let mut fds = Vec::new();
+    for (target, &fd) in standard_fds.iter().enumerate() {
+        let target = target as RawFd;
+        if fd == target {
+            continue;
+        }
+        let fd = if fd < target {
+            let copy = shell_fd(unistd::dup(fd).context(ErrorKind::SysError)?)?;
+            copies.push(copy);
+            copy
+        } else {
+            fd
+        };
+        fds.push(FdAction::Dup(fd, target));
+    }
+    Ok((fds, copies))
+}
+
+/// Move an fd the shell opened for itself above the ones scripts use, so redirecting those can't clobber it.
+/// The fd it ends up as is close-on-exec.
+pub fn shell_fd(fd: RawFd) -> Result<RawFd> {
+    if fd >= FIRST_SHELL_FD {
+        if let Err(e) = fcntl::fcntl(fd, fcntl::FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            unistd::close(fd).ok();
+            Err(e).context(ErrorKind::SysError)?;
+        }
+        return Ok(fd);
+    }
+    let moved = fcntl::fcntl(fd, fcntl::FcntlArg::F_DUPFD_CLOEXEC(FIRST_SHELL_FD));
+    unistd::close(fd).ok();
+    Ok(moved.context(ErrorKind::SysError)?)
+}
+
+/// Close the fds the shell opened for itself, in a forked subshell once its own fds are in place.
+/// Those are the close-on-exec fds from FIRST_SHELL_FD up, anything else was inherited on purpose.
+/// Otherwise a subshell on one side of a pipe would hold the other end open, and never see EOF or SIGPIPE.
+fn close_shell_fds() {
+    let fds: Vec<RawFd> = match fs::read_dir("/dev/fd") {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(_) => return,
+    };
+    for fd in fds.into_iter().filter(|&fd| fd >= FIRST_SHELL_FD) {
+        let cloexec = fcntl::fcntl(fd, fcntl::FcntlArg::F_GETFD)
+            .map(|flags| FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC))
+            .unwrap_or(false);
+        if cloexec {
+            unistd::close(fd).ok();
+        }
+    }
+}
+
+/// A pipe, as its read and write ends, kept out of the way of the fds scripts use, both close-on-exec
+fn shell_pipe() -> Result<(RawFd, RawFd)> {
+    let (read_end, write_end) = unistd::pipe().context(ErrorKind::PipelineCreationFailed)?;
+    match (shell_fd(read_end), shell_fd(write_end)) {
+        (Ok(read_end), Ok(write_end)) => Ok((read_end, write_end)),
+        (Ok(fd), Err(e)) | (Err(e), Ok(fd)) => {
+            unistd::close(fd).ok();
+            Err(e)
+        }
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+/// Evaluate an arithmetic word for its truth value, `None` if the expression was empty
+fn evaluate_arithmetic(ec: &mut ExecutionContext, w: &Word) -> Result<Option<bool>> {
+    let text = w.compile(&mut ec.vars)?.to_string_lossy().to_string();
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let value = expr::parse(&text)
+        .context(ErrorKind::ExpressionError)?
+        .evaluate(&mut ec.vars)?;
+    Ok(Some(value.as_boolean()))
+}
+
+/// Get the (user, system) CPU time used by all of this process's children that have been waited for
+fn children_cpu_time() -> (Duration, Duration) {
+    cpu_time(libc::RUSAGE_CHILDREN)
+}
+
+/// Get the (user, system) CPU time used by this process, with `libc::RUSAGE_SELF`,
+/// or its children that have been waited for, with `libc::RUSAGE_CHILDREN`
+pub fn cpu_time(who: libc::c_int) -> (Duration, Duration) {
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    unsafe {
+        libc::getrusage(who, &mut usage);
+    }
+    (
+        timeval_duration(usage.ru_utime),
+        timeval_duration(usage.ru_stime),
+    )
+}
+
+fn timeval_duration(tv: libc::timeval) -> Duration {
+    Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{initial_cwd, set_disposition, signal_report, ExecutionContext, ExitStatus, Jid, DEFAULT_PATH};
+    use crate::env::variables::Variables;
+    use crate::env::traps::Signal;
+    use crate::lang::ast::Command;
+    use crate::lang::testing::{run, run_in, scratch_dir, with_shell, ScratchDir};
+    use nix::fcntl;
+    use nix::sys::wait::{waitpid, WaitPidFlag};
+    use nix::libc;
+    use nix::sys::signal;
+    use nix::unistd;
+    use std::env;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn true_false_and_colon_run_without_forking() {
+        let children = with_shell(|ec, jobs| {
+            let script = "for ((i = 0; i < 100; i++)); do true; false; : $i; done";
+            assert_eq!(jobs.run(ec, Command::from(script)).unwrap().exit_code, 0);
+            jobs.next_jid
+        });
+        assert_eq!(children, 0);
+    }
+
+    #[test]
+    fn arithmetic_for_counts() {
+        let out = run("for ((i = 0; i < 3; i++)); do echo $i; done");
+        assert_eq!(out.stdout, "0\n1\n2\n");
+        assert_eq!(out.status, 0);
+        assert_eq!(run("for ((i = 0; i < 3; i++)) do true; done; echo $i").stdout, "3\n");
+    }
+
+    #[test]
+    fn arithmetic_for_empty_condition_is_true() {
+        let out = run("for ((i = 0; ; i++)); do\n  echo $i\n  [ $i -eq 2 ] && break\ndone");
+        assert_eq!(out.stdout, "0\n1\n2\n");
+    }
+
+    #[test]
+    fn arithmetic_for_status_is_the_last_body_status() {
+        assert_eq!(run("for ((i = 0; i < 2; i++)); do false; done").status, 1);
+        assert_eq!(run("false; for ((; 0; )); do false; done").status, 0);
+    }
+
+    /// Make a directory of PATH entries: `noexec/prog` can't be run, `dir/prog` is a directory and `bin/prog` a program
+    fn path_entries() -> ScratchDir {
+        let dir = scratch_dir();
+        for entry in &["noexec", "dir/prog", "bin"] {
+            fs::create_dir_all(dir.join(entry)).unwrap();
+        }
+        fs::write(dir.join("noexec/prog"), "#!/bin/sh\n").unwrap();
+        fs::write(dir.join("bin/prog"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(dir.join("bin/prog"), fs::Permissions::from_mode(0o755)).unwrap();
+        dir
+    }
+
+    #[test]
+    fn find_executable_joins_the_program_onto_each_entry() {
+        let dir = path_entries();
+        let found = with_shell(|ec, _| {
+            ec.vars.define("PATH", dir.join("bin")).unwrap();
+            ec.find_executable("prog").unwrap()
+        });
+        assert_eq!(found, dir.join("bin/prog"));
+    }
+
+    #[test]
+    fn find_executable_skips_what_cannot_be_run() {
+        let dir = path_entries();
+        let path = env::join_paths(&[dir.join("noexec"), dir.join("dir"), dir.join("bin")]).unwrap();
+        let (first, all) = with_shell(|ec, _| {
+            ec.vars.define("PATH", path).unwrap();
+            (ec.find_executable("prog").unwrap(), ec.find_executables("prog"))
+        });
+        assert_eq!(first, dir.join("bin/prog"));
+        assert_eq!(all, vec![dir.join("bin/prog")]);
+        let missing = with_shell(|ec, _| {
+            ec.vars.define("PATH", dir.join("noexec")).unwrap();
+            ec.find_executable("prog").is_err()
+        });
+        assert!(missing);
+    }
+
+    #[test]
+    fn empty_path_entries_are_the_working_directory() {
+        let dir = path_entries();
+        let found = with_shell(|ec, _| {
+            ec.set_cwd(dir.join("bin")).unwrap();
+            ec.vars.define("PATH", "/nowhere::/nowhere/else").unwrap();
+            ec.find_executable("prog").unwrap()
+        });
+        assert_eq!(found, dir.join("bin/prog"));
+    }
+
+    #[test]
+    fn names_with_a_slash_are_paths_relative_to_the_working_directory() {
+        let dir = scratch_dir();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("x"), "#!/bin/sh\necho \"ran $0\"\n").unwrap();
+        fs::set_permissions(dir.join("x"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::copy(dir.join("x"), dir.join("sub/x")).unwrap();
+        let abs = dir.join("x");
+        let out = run_in(&dir, &format!("./x; sub/x; cd sub; ../x; {}", abs.display()));
+        assert_eq!(out.stdout, format!("ran ./x\nran sub/x\nran ../x\nran {}\n", abs.display()));
+        // PATH isn't searched for them at all
+        let out = run_in(&dir, "PATH=$PWD/sub; cd sub; ./x; echo $?; ../sub/x");
+        assert_eq!(out.stdout, "ran ./x\n0\nran ../sub/x\n");
+    }
+
+    #[test]
+    fn paths_that_cannot_be_run_are_126_and_missing_ones_127() {
+        let dir = scratch_dir();
+        fs::write(dir.join("plain"), "#!/bin/sh\n").unwrap();
+        let out = run_in(&dir, "./plain; echo $?; sub/missing; echo $?; /nowhere/x; echo $?");
+        assert_eq!(out.stdout, "126\n127\n127\n");
+    }
+
+    #[test]
+    fn programs_are_not_run_in_a_directory_that_is_gone() {
+        let dir = scratch_dir();
+        let gone = dir.join("gone");
+        let out = run_in(&dir, "mkdir gone; cd gone; rmdir ../gone; sh -c 'echo ran'; echo $?");
+        assert_eq!(out.stdout, "126\n");
+        assert_eq!(out.stderr, format!("rush: {}: No such file or directory\n", gone.display()));
+    }
+
+    #[test]
+    fn programs_are_not_run_with_a_redirection_that_failed() {
+        let out = run("sh -c 'echo ran' <&7; echo $?; sh -c 'echo ran' 2>/dev/null >&8; echo $?");
+        assert_eq!(out.stdout, "126\n126\n");
+        assert_eq!(out.stderr, "rush: 7: Bad file number\n");
+    }
+
+    /// Run a script as the last thing a forked copy of the test does, giving the copy's pid and what `$$ $PPID`
+    /// was for the program at the end of it
+    fn last_program_pids(script: &str) -> (i32, Vec<i32>) {
+        let dir = scratch_dir();
+        let out = dir.join("pids");
+        let file = fs::File::create(&out).unwrap();
+        let child = with_shell(|ec, jobs| match unistd::fork().unwrap() {
+            unistd::ForkResult::Child => {
+                unistd::dup2(file.as_raw_fd(), 1).unwrap();
+                let status = jobs.run_last(ec, Command::from(script)).map(|s| s.exit_code).unwrap_or(1);
+                unsafe { libc::_exit(status) }
+            }
+            unistd::ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+                child
+            }
+        });
+        let pids = fs::read_to_string(&out).unwrap();
+        (child.into(), pids.split_whitespace().map(|pid| pid.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn a_last_program_replaces_the_shell() {
+        let (shell, pids) = last_program_pids("true; sh -c 'echo $$ $PPID'");
+        assert_eq!(pids[0], shell);
+    }
+
+    #[test]
+    fn a_last_program_is_forked_when_the_shell_has_more_to_do() {
+        let list = "sh -c 'echo $$ $PPID'";
+        for script in &[
+            format!("{}; true", list),
+            format!("trap 'true' EXIT\n{}", list),
+            format!("trap 'true' USR1\n{}", list),
+            format!("sleep 0 &\n{}", list),
+            format!("{} 2>/dev/null", list),
+        ] {
+            let (shell, pids) = last_program_pids(script);
+            assert_eq!(pids[1], shell, "{}", script);
+        }
+    }
+
+    #[test]
+    fn pwd_keeps_the_symlinks_cd_went_through() {
+        let dir = scratch_dir();
+        fs::create_dir_all(dir.join("real")).unwrap();
+        std::os::unix::fs::symlink(dir.join("real"), dir.join("link")).unwrap();
+        let out = run_in(&dir, "cd link; echo $PWD; pwd; pwd -P; echo $OLDPWD; cd ..; echo $PWD");
+        let (dir, real, link) = (dir.display(), dir.join("real"), dir.join("link"));
+        assert_eq!(out.stdout, format!("{1}\n{1}\n{2}\n{0}\n{0}\n", dir, link.display(), real.display()));
+    }
+
+    #[test]
+    fn every_directory_change_updates_pwd_and_oldpwd() {
+        let dir = scratch_dir();
+        fs::create_dir_all(dir.join("a")).unwrap();
+        let out = run_in(&dir, "cd a; cd - >/dev/null; echo $PWD $OLDPWD; pushd a >/dev/null; echo $PWD; popd >/dev/null; echo $PWD $OLDPWD");
+        let (dir, a) = (dir.display().to_string(), dir.join("a").display().to_string());
+        assert_eq!(out.stdout, format!("{0} {1}\n{1}\n{0} {1}\n", dir, a));
+    }
+
+    #[test]
+    fn an_inherited_pwd_is_kept_while_it_is_the_same_directory() {
+        let dir = scratch_dir();
+        let link = dir.join("here");
+        std::os::unix::fs::symlink(env::current_dir().unwrap(), &link).unwrap();
+        let mut vars = Variables::new();
+        vars.define("PWD", link.as_os_str()).unwrap();
+        assert_eq!(initial_cwd(&vars), link);
+        let other = scratch_dir();
+        vars.define("PWD", other.as_os_str()).unwrap();
+        assert_eq!(initial_cwd(&vars), env::current_dir().unwrap());
+    }
+
+    #[test]
+    fn pipeline_members_are_reaped_when_a_later_one_fails_to_start() {
+        let (failed, started, unreaped) = with_shell(|ec, jobs| {
+            let failed = jobs.run(ec, Command::from("yes | cat </nonexistent/file")).is_err();
+            let unreaped = jobs
+                .running_jobs
+                .keys()
+                .filter(|&&pid| waitpid(unistd::Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)).is_ok())
+                .count();
+            (failed, jobs.running_jobs.len(), unreaped)
+        });
+        assert!(failed);
+        assert_eq!(started, 1);
+        assert_eq!(unreaped, 0);
+    }
+
+    #[test]
+    fn pipeline_stops_when_its_first_member_fails_to_start() {
+        let (failed, started) = with_shell(|ec, jobs| {
+            let failed = jobs.run(ec, Command::from("cat </nonexistent/file | sort")).is_err();
+            (failed, jobs.next_jid)
+        });
+        assert!(failed);
+        assert_eq!(started, 0);
+    }
+
+    #[test]
+    fn waiting_for_no_jobs_returns_straight_away() {
+        let waited = with_shell(|_, jobs| jobs.wait_for_all(&[]).is_ok());
+        assert!(waited);
+    }
+
+    #[test]
+    fn waiting_for_completed_jobs_leaves_other_children_alone() {
+        let (first, again, other) = with_shell(|ec, jobs| {
+            jobs.run(ec, Command::from("sh -c 'exit 3' &")).unwrap();
+            jobs.wait_for_all(&[Jid(0)]).unwrap();
+            let first = jobs.completed_jobs[&Jid(0)].exit_code;
+            jobs.run(ec, Command::from("sleep 0.2 &")).unwrap();
+            jobs.wait_for_all(&[Jid(0)]).unwrap();
+            let again = jobs.completed_jobs[&Jid(0)].exit_code;
+            let other = jobs.completed_jobs.contains_key(&Jid(1));
+            jobs.wait_for_all(&[Jid(1)]).unwrap();
+            (first, again, other)
+        });
+        assert_eq!((first, again), (3, 3));
+        assert!(!other);
+    }
+
+    #[test]
+    fn status_is_kept_after_every_command() {
+        let dir = scratch_dir();
+        let out = run_in(&dir, "true; echo $?; false; echo $?; no_such_command; echo $?; echo x >f; ./f; echo $?");
+        assert_eq!(out.stdout, "0\n1\n127\n126\n");
+        assert!(dir.join("f").is_file());
+        let out = run("sh -c 'kill -TERM $$'; echo $?");
+        assert_eq!(out.stdout, "143\n");
+    }
+
+    #[test]
+    fn status_is_left_alone_by_comments_and_blank_lines() {
+        let out = run("false\n# a comment\n\necho $?");
+        assert_eq!(out.stdout, "1\n");
+    }
+
+    #[test]
+    fn status_comes_from_inside_loops_and_conditionals() {
+        let out = run("for ((i = 0; i < 2; i++)); do false; done; echo $?; false && true; echo $?; false || true; echo $?");
+        assert_eq!(out.stdout, "1\n1\n0\n");
+        let out = run("for ((i = 0; i < 1; i++)); do false; echo \"inside $?\"; done");
+        assert_eq!(out.stdout, "inside 1\n");
+    }
+
+    #[test]
+    fn signaled_children_exit_with_128_plus_the_signal() {
+        let out = run("sh -c 'kill -KILL $$' && echo ran; echo $?");
+        assert_eq!(out.stdout, "137\n");
+        assert_eq!(run("sh -c 'kill -TERM $$'").status, 143);
+    }
+
+    #[test]
+    fn programs_get_the_signals_the_shell_ignores_or_blocks_back() {
+        set_disposition(signal::Signal::SIGINT, signal::SigHandler::SigIgn);
+        let mut blocked = signal::SigSet::empty();
+        blocked.add(signal::Signal::SIGUSR1);
+        signal::pthread_sigmask(signal::SigmaskHow::SIG_BLOCK, Some(&blocked), None).unwrap();
+        let out = run("sh -c 'kill -INT $$'; echo $?; sh -c 'kill -USR1 $$'; echo $?; yes | head -1");
+        signal::pthread_sigmask(signal::SigmaskHow::SIG_UNBLOCK, Some(&blocked), None).unwrap();
+        set_disposition(signal::Signal::SIGINT, signal::SigHandler::SigDfl);
+        assert_eq!(out.stdout, "130\n138\ny\n");
+        assert_eq!(out.stderr, "");
+    }
+
+    #[test]
+    fn signals_a_trap_ignores_stay_ignored() {
+        let out = run("trap '' INT; sh -c 'kill -INT $$; echo survived'; trap - INT; sh -c 'kill -INT $$'; echo $?");
+        assert_eq!(out.stdout, "survived\n130\n");
+    }
+
+    #[test]
+    fn lost_statuses_are_127() {
+        let status = with_shell(|_, jobs| jobs.lose_status(Jid(0)));
+        assert_eq!(status.exit_code, 127);
+        assert_eq!(status.signal, None);
+    }
+
+    #[test]
+    fn signal_reports_skip_quiet_signals() {
+        let status = |signal, core_dumped| ExitStatus {
+            pid: unistd::Pid::from_raw(0),
+            exit_code: 0,
+            core_dumped,
+            signal,
+        };
+        assert_eq!(signal_report(&status(Some(Signal::SIGTERM), false)).unwrap(), "Terminated");
+        assert_eq!(signal_report(&status(Some(Signal::SIGSEGV), true)).unwrap(), "Segmentation fault (core dumped)");
+        assert_eq!(signal_report(&status(Some(Signal::SIGINT), false)), None);
+        assert_eq!(signal_report(&status(Some(Signal::SIGPIPE), false)), None);
+        assert_eq!(signal_report(&status(None, false)), None);
+    }
+
+    #[test]
+    fn standard_error_goes_to_the_fd_it_was_given() {
+        let dir = scratch_dir();
+        let path = dir.join("err");
+        let file = fs::File::create(&path).unwrap();
+        with_shell(|ec, jobs| {
+            let command = Command::from("sh -c 'echo oops >&2'; echo builtin >&2");
+            jobs.run_with_fds(ec, command, [0, 1, file.as_raw_fd()]).unwrap();
+        });
+        assert_eq!(fs::read_to_string(&path).unwrap(), "oops\nbuiltin\n");
+    }
+
+    #[test]
+    fn standard_error_redirections_apply_in_order() {
+        let out = run("sh -c 'echo err >&2' 2>&1 >/dev/null >out; sh -c 'echo err2 >&2' >out2 2>&1; cat out out2");
+        assert_eq!(out.stdout, "err\nerr2\n");
+        assert_eq!(out.stderr, "");
+    }
+
+    #[test]
+    fn pipe_and_stderr_only_takes_the_command_right_before_it() {
+        let out = run("sh -c 'echo e >&2' |& cat");
+        assert_eq!((out.stdout.as_str(), out.stderr.as_str()), ("e\n", ""));
+        let out = run("sh -c 'echo a; echo a-err >&2' | sh -c 'cat; echo b-err >&2' |& cat");
+        assert_eq!((out.stdout.as_str(), out.stderr.as_str()), ("a\nb-err\n", "a-err\n"));
+        let out = run("sh -c 'echo a-err >&2' |& sh -c 'cat; echo b-err >&2' | cat");
+        assert_eq!((out.stdout.as_str(), out.stderr.as_str()), ("a-err\n", "b-err\n"));
+    }
+
+    #[test]
+    fn assignments_before_a_command_are_only_for_it() {
+        let out = run("Z=1 sh -c 'echo $Z'; echo \"[$Z]\"; X=1; X=2 sh -c 'echo $X'; echo $X; sh -c 'echo \"[$X]\"'");
+        assert_eq!(out.stdout, "1\n[]\n2\n1\n[]\n");
+    }
+
+    #[test]
+    fn assignments_are_undone_when_the_command_fails_to_start() {
+        let out = run("X=1; X=2 /nonexistent/program; echo $X; unset Y; Y=2 no-such-command; echo ${Y-unset}");
+        assert_eq!(out.stdout, "1\nunset\n");
+        assert_eq!(out.status, 0);
+    }
+
+    #[test]
+    fn nested_assignments_unwind_in_order() {
+        let script = "function inner { sh -c 'echo $A $B'; }\nfunction outer { B=2 inner; echo \"$A[$B]\"; }\nA=1 outer; echo \"[$A$B]\"";
+        assert_eq!(run(script).stdout, "1 2\n1[]\n[]\n");
+    }
+
+    #[test]
+    fn programs_only_get_the_standard_fds_and_what_the_shell_inherited() {
+        // whatever the test process itself would pass on to a program is passed on by the shell too
+        let mut expected: Vec<i32> = fs::read_dir("/proc/self/fd")
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().to_str().unwrap().parse().ok())
+            .filter(|&fd| fd > 2)
+            .filter(|&fd| fcntl::fcntl(fd, fcntl::FcntlArg::F_GETFD).map(|flags| flags & libc::FD_CLOEXEC == 0) == Ok(true))
+            .collect();
+        expected.extend(&[0, 1, 2]);
+        // and the directory ls reads goes in the lowest fd left
+        let lowest = (0..).find(|fd| !expected.contains(fd)).unwrap();
+        expected.push(lowest);
+        expected.sort();
+
+        let list = "ls -m /proc/self/fd";
+        let script = format!("{0}; echo x | {0}; {{ {0}; }} | cat; {0} 2>/dev/null </dev/null; {0} | cat >/dev/null", list);
+        let out = run(&script);
+        let listings: Vec<Vec<i32>> = out
+            .stdout
+            .lines()
+            .map(|line| {
+                let mut fds: Vec<i32> = line.split(", ").map(|fd| fd.parse().unwrap()).collect();
+                fds.sort();
+                fds
+            }).collect();
+        assert_eq!(listings, vec![expected; 4]);
+    }
+
+    #[test]
+    fn readonly_assignments_only_fail_their_command() {
+        let out = run("readonly RO=1 EMPTY\nRO=2 sh -c 'echo ran'; echo \"a $?\"; echo ${EMPTY:=x}; echo \"b $?\"; X=${EMPTY:=x}; echo \"c $? $RO\"");
+        assert_eq!(out.stdout, "a 1\nb 1\nc 1 1\n");
+        assert_eq!(out.stderr, "rush: RO: readonly variable\nrush: EMPTY: readonly variable\nrush: EMPTY: readonly variable\n");
+        assert_eq!(out.status, 0);
+    }
+
+    #[test]
+    fn readonly_assignments_before_a_builtin_or_function_fail_it_too() {
+        let out = run("function f { echo ran; }\nreadonly RO=1\nRO=2 f; echo $?; RO=2 echo ran; echo $?; echo $RO");
+        assert_eq!(out.stdout, "1\n1\n1\n");
+    }
+
+    #[test]
+    fn commands_that_expand_to_no_words_do_nothing() {
+        let out = run("$UNSET_VAR; echo $?; false; $UNSET_VAR $ALSO_UNSET; echo $?");
+        assert_eq!(out.stdout, "0\n0\n");
+        let out = run("\"$EMPTY\"; echo $?");
+        assert_eq!(out.stdout, "127\n");
+    }
+
+    #[test]
+    fn commands_with_only_assignments_or_redirections() {
+        let out = run("X=1 Y=$X; echo $X $Y");
+        assert_eq!(out.stdout, "1 1\n");
+        let out = run("echo old >made; >made; echo $?; cat made; >new; ls new");
+        assert_eq!(out.stdout, "0\nnew\n");
+        let out = run("X=1 >made; echo $X; ls made");
+        assert_eq!(out.stdout, "1\nmade\n");
+    }
+
+    #[test]
+    fn brace_groups_run_in_the_shell() {
+        let out = run("mkdir sub\nx=0; { x=1; cd sub; function f { echo defined; }\n}; echo $x; f; pwd");
+        assert!(out.stdout.starts_with("1\ndefined\n") && out.stdout.ends_with("/sub\n"), "{}", out.stdout);
+    }
+
+    #[test]
+    fn subshells_keep_their_changes_to_themselves() {
+        let out = run("mkdir sub\nx=0; ( x=1; cd sub; function f { echo defined; }\necho \"inside $x\" ); echo $x; f; pwd");
+        assert!(out.stdout.starts_with("inside 1\n0\n") && !out.stdout.contains("defined"), "{}", out.stdout);
+        assert!(!out.stdout.ends_with("/sub\n"), "{}", out.stdout);
+        assert_eq!(out.status, 0);
+    }
+
+    #[test]
+    fn subshells_keep_the_working_directory_and_umask_to_themselves() {
+        let dir = scratch_dir();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let out = run_in(&dir, "umask 022; ( cd sub; umask 077; touch f; pwd ); pwd; umask; { cd sub; umask 077; } | cat; pwd; umask");
+        let (dir, sub) = (dir.display(), dir.join("sub").display().to_string());
+        assert_eq!(out.stdout, format!("{}\n{}\n0022\n{}\n0022\n", sub, dir, dir));
+        let mode = fs::metadata(sub + "/f").unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn groups_have_the_status_of_their_last_command() {
+        let out = run("{ false; } && echo yes; { true; false; }; echo $?; ( false ) || echo no; ( true; exit 3 ); echo $?");
+        assert_eq!(out.stdout, "1\nno\n3\n");
+        assert_eq!(run("{ true; false; }").status, 1);
+        assert_eq!(run("( false; true )").status, 0);
+        assert_eq!(run("{ false\n# a trailing comment\n}").status, 1);
+    }
+
+    #[test]
+    fn and_or_chains_look_at_everything_before_them() {
+        let out = run("true && echo a || echo b; false && echo c || echo d; false || false && echo e; echo $?");
+        assert_eq!(out.stdout, "a\nd\n1\n");
+        let out = run("true || echo skipped && echo ran; false && echo skipped || echo ran");
+        assert_eq!(out.stdout, "ran\nran\n");
+    }
+
+    #[test]
+    fn and_or_chains_of_pipelines_and_groups() {
+        let out = run("true | false && echo a || { echo b; false; } && echo c || echo d | cat");
+        assert_eq!(out.stdout, "b\nd\n");
+        let out = run("{ true; } && false | true && echo a; ( exit 2 ) || echo $? && echo b");
+        assert_eq!(out.stdout, "a\n2\nb\n");
+    }
+
+    #[test]
+    fn builtins_and_compound_commands_are_pipeline_stages() {
+        let out = run("echo foo | grep f; { echo a; echo b >&2; } 2>&1 | sort -r");
+        assert_eq!(out.stdout, "foo\nb\na\n");
+        let out = run("printf '2\\n1\\n' | { read x; read y; echo $y $x; }; echo y | read z; echo \"[$z]\"");
+        assert_eq!(out.stdout, "1 2\n[]\n");
+        let out = run("function up { tr a-z A-Z; }\nfor ((i = 0; i < 2; i++)); do echo $i; done | up; echo abc | up | cat");
+        assert_eq!(out.stdout, "0\n1\nABC\n");
+    }
+
+    #[test]
+    fn pipelines_with_sides_that_do_nothing_finish() {
+        let script = "function nothing { :; }\n: | cat; x=1 | cat; >/dev/null | cat; nothing | cat; echo a | : | cat; yes | :; yes | nothing; echo done";
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || sender.send(run(script)).unwrap());
+        let out = receiver.recv_timeout(Duration::from_secs(10)).expect("the pipelines hung");
+        assert_eq!(out.stdout, "done\n");
+    }
+
+    #[test]
+    fn pipelines_have_the_status_of_their_last_member() {
+        let out = run("false | true; echo $?; true | false; echo $?; echo x | sh -c 'exit 4'; echo $?");
+        assert_eq!(out.stdout, "0\n1\n4\n");
+        let out = run("echo x | false && echo no || echo yes");
+        assert_eq!(out.stdout, "yes\n");
+    }
+
+    #[test]
+    fn job_tables_only_hold_live_jobs() {
+        let sizes = with_shell(|ec, jobs| {
+            let script = "for ((i = 0; i < 50; i++)); do sh -c true; echo x | cat >/dev/null; done";
+            jobs.run(ec, Command::from(script)).unwrap();
+            let foreground = (jobs.completed_jobs.len(), jobs.running_jobs.len(), jobs.process_groups.len());
+            jobs.run(ec, Command::from("sh -c true &")).unwrap();
+            let background = jobs.jobs.len();
+            jobs.run(ec, Command::from("wait")).unwrap();
+            (foreground, background, jobs.jobs.len(), jobs.completed_jobs.len(), jobs.running_jobs.len())
+        });
+        assert_eq!(sizes, ((0, 0, 0), 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn wait_for_gives_a_jobs_status_every_time() {
+        let statuses = with_shell(|ec, jobs| {
+            jobs.run(ec, Command::from("sh -c 'exit 5' &")).unwrap();
+            let first = jobs.wait_for(Jid(0)).unwrap().exit_code;
+            (first, jobs.wait_for(Jid(0)).unwrap().exit_code)
+        });
+        assert_eq!(statuses, (5, 5));
+    }
+
+    #[test]
+    fn function_nesting_past_funcnest_is_a_failed_command() {
+        let out = run("function f { f; }\nFUNCNEST=20; f; echo \"after $?\"");
+        assert_eq!(out.stdout, "after 1\n");
+        assert_eq!(out.stderr, "rush: f: maximum function nesting level exceeded (20)\n");
+        let out = run("function a { b; }\nfunction b { a; }\nFUNCNEST=9 a; echo \"after $?\"");
+        assert_eq!(out.stdout, "after 1\n");
+        assert_eq!(out.stderr, "rush: b: maximum function nesting level exceeded (9)\n");
+    }
+
+    #[test]
+    fn runaway_recursion_stops_before_the_stack_runs_out() {
+        let out = run("function f { f; }\nFUNCNEST=1000000; f; echo \"after $?\"");
+        assert_eq!(out.stdout, "after 1\n");
+        assert!(out.stderr.starts_with("rush: f: maximum function nesting level exceeded"), "{}", out.stderr);
+    }
+
+    #[test]
+    fn pipelines_share_a_process_group_that_signals_reach() {
+        let statuses = with_shell(|ec, jobs| {
+            // the test process keeps its group and has no terminal, but every job still gets a group of its own
+            jobs.job_control = true;
+            jobs.run(ec, Command::from("sleep 10 | sleep 10 &")).unwrap();
+            let job = &jobs.jobs[0];
+            let (pgid, jids) = (job.pgid.unwrap(), job.jids.clone());
+            let groups: Vec<_> = jids.iter().map(|jid| jobs.process_groups[jid]).collect();
+            assert_eq!(groups, vec![pgid, pgid]);
+            signal::kill(unistd::Pid::from_raw(-libc::pid_t::from(pgid)), signal::Signal::SIGINT).unwrap();
+            let statuses: Vec<i32> = jids.iter().map(|jid| jobs.wait_for(*jid).unwrap().exit_code).collect();
+            statuses
+        });
+        assert_eq!(statuses, vec![130, 130]);
+    }
+
+    #[test]
+    fn functions_with_invalid_names_are_refused() {
+        let out = run("function f-g { echo x; }; echo $?; f-g");
+        assert_eq!(out.stdout, "1\n");
+        assert_eq!(
+            out.stderr,
+            "rush: 'f-g': not a valid identifier\nrush: f-g: command not found\n"
+        );
+    }
+
+    #[test]
+    fn shlvl_counts_the_shells_started_inside_each_other() {
+        let levels = with_shell(|_, _| {
+            // each shell is given the environment the one before it passes on to its children
+            env::set_var("SHLVL", "3");
+            let mut levels = Vec::new();
+            for _ in 0..2 {
+                let ec = ExecutionContext::new();
+                levels.push((ec.variables().value(&"SHLVL".into()), env::var_os("SHLVL").unwrap()));
+            }
+            for inherited in &["", "junk", "-2"] {
+                env::set_var("SHLVL", inherited);
+                levels.push((ExecutionContext::new().variables().value(&"SHLVL".into()), inherited.into()));
+            }
+            levels
+        });
+        let expected: Vec<(OsString, OsString)> = vec![
+            ("4".into(), "4".into()),
+            ("5".into(), "5".into()),
+            ("1".into(), "".into()),
+            ("1".into(), "junk".into()),
+            ("1".into(), "-2".into()),
+        ];
+        assert_eq!(levels, expected);
+    }
+
+    #[test]
+    fn ids_are_readonly_and_ppid_is_the_parent_of_the_shell() {
+        let out = run("echo $PPID $UID $EUID; PPID=1; UID=1; EUID=1; echo $? $PPID");
+        let ppid = unistd::getppid().to_string();
+        let uid = unistd::getuid().to_string();
+        let euid = unistd::geteuid().to_string();
+        assert_eq!(out.stdout, format!("{} {} {}\n1 {}\n", ppid, uid, euid, ppid));
+        assert_eq!(
+            out.stderr,
+            "rush: PPID: readonly variable\nrush: UID: readonly variable\nrush: EUID: readonly variable\n"
+        );
+    }
 
-        let owned_prog = prog_ref.to_os_string().to_string_lossy().to_string();
-        Err(Error::from(ErrorKind::MissingExecutable(owned_prog)))
+    #[test]
+    fn path_and_shell_have_defaults_when_the_environment_has_none() {
+        let (path, shell) = with_shell(|_, _| {
+            env::remove_var("PATH");
+            env::remove_var("SHELL");
+            let ec = ExecutionContext::new();
+            let vars = ec.variables();
+            (vars.value(&"PATH".into()), vars.value(&"SHELL".into()))
+        });
+        assert_eq!(path, DEFAULT_PATH);
+        assert_eq!(PathBuf::from(shell), env::current_exe().unwrap());
+        let shell = with_shell(|_, _| {
+            env::set_var("SHELL", "/bin/sh");
+            ExecutionContext::new().variables().value(&"SHELL".into())
+        });
+        assert_eq!(shell, "/bin/sh");
     }
 }
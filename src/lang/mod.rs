@@ -2,6 +2,8 @@ pub mod ast;
 mod errors;
 pub mod exec;
 pub mod parser;
+#[cfg(test)]
+pub mod testing;
 pub mod word;
 pub use self::errors::*;
-pub use self::exec::{ExecutionContext, JobManager};
+pub use self::exec::{ExecutionContext, ExitStatus, Flow, Job, JobManager, JobStatus};
@@ -1,9 +1,12 @@
-use lang::ast::*;
-use lang::word::word;
-use lang::word::Word;
-use nom;
+use crate::lang::ast::*;
+use crate::lang::word::Word;
+use crate::lang::word::{arithmetic_word, compound_assignment, word};
 ///! Nom combinations for parsing RUSH shell scripts
-use nom::types::CompleteStr;
+use nom::{
+    alt, call, char, delimited, do_parse, eat_separator, fold_many0, many0, many1, map, named,
+    none_of, not, one_of, opt, preceded, sep, separated_list, tag, take_while, take_while1,
+    terminated, types::CompleteStr,
+};
 use std::os::unix::io::RawFd;
 use std::str::FromStr;
 
@@ -19,6 +22,11 @@ named!(
     alt!( tag!("\n") | tag!("\n\r") )
 );
 
+/// Match a reserved word, but only when it isn't the prefix of some longer word
+pub fn keyword<'a>(i: CompleteStr<'a>, kw: &'static str) -> nom::IResult<CompleteStr<'a>, CompleteStr<'a>> {
+    terminated!(i, tag!(kw), not!(none_of!(" \t\n;&|(){}")))
+}
+
 /// skip anything chomp-able by `space`
 #[macro_export]
 macro_rules! sp (
@@ -71,10 +79,14 @@ named!(
     )
 );
 
-/// Pipes connect a command's standard out to another command's standard in
+/// Pipes connect a command's standard out to another command's standard in,
+/// `|&` connects its standard error as well
 named!(
-    pub pipe<CompleteStr, char>,
-    one_of!("|")
+    pub pipe<CompleteStr, bool>,
+    alt!(
+          tag!("|&") => { |_| true }
+        | tag!("|")  => { |_| false }
+    )
 );
 
 /// A separator splits WORDS into commands when they are on the same line
@@ -98,14 +110,46 @@ named!(
     terminated!(take_while1!(|c| c >= '0' && c <= '9'), one_of!("<>"))
 );
 
+/// Reserved words can't start a simple command, they start or close a compound command.
+/// The ones for compound commands that can't be parsed yet are still reserved, so using one is a syntax error.
+named!(
+    pub reserved_word<CompleteStr, CompleteStr>,
+    alt!(
+          call!(keyword, "done")
+        | call!(keyword, "do")
+        | call!(keyword, "if")
+        | call!(keyword, "then")
+        | call!(keyword, "elif")
+        | call!(keyword, "else")
+        | call!(keyword, "fi")
+        | call!(keyword, "while")
+        | call!(keyword, "until")
+        | call!(keyword, "case")
+        | call!(keyword, "esac")
+    )
+);
+
+/// Every word with a special meaning to the parser when it starts a command
+pub const KEYWORDS: &[&str] = &[
+    "!", "{", "}", "case", "do", "done", "elif", "else", "esac", "fi", "for", "function", "if", "then", "time",
+    "until", "while",
+];
+
 named!(
     pub simple_command<CompleteStr, Command>,
     do_parse!(
-        args: separated_list!(space, preceded!(not!(io_number), word)) >>
-        (Command::simple(args))
+        not!(reserved_word) >>
+        rest: remaining >>
+        args: separated_list!(space, preceded!(not!(alt!(io_number | tag!("#") | tag!("<") | tag!(">"))), alt!(compound_assignment | word))) >>
+        (Command::SimpleCommand(SimpleCommand { arguments: args, line: rest }))
     )
 );
 
+/// How much input is left, without taking any of it
+fn remaining(i: CompleteStr) -> nom::IResult<CompleteStr, usize> {
+    Ok((i, i.len()))
+}
+
 pub fn split_words<T: AsRef<str>>(s: T) -> Vec<Word> {
     let complete = CompleteStr(s.as_ref());
     separated_list!(complete, space, word)
@@ -118,20 +162,27 @@ named!(
     do_parse!(
         number : opt!(map!(take_while1!(|c| c >= '0' && c <= '9'), |nums| RawFd::from_str(nums.0).unwrap())) >>
         operation : call!(io_operator) >>
-        file: opt!(word) >>
+        file: opt!(preceded!(opt!(space), word)) >>
         (RedirectDestination::new(operation, number, file))
     )
 );
 
+/// Commands run in the shell's own environment, `{ ...; }`
 named!(
     pub group<CompleteStr, Command>,
-    sp!(delimited!(char!('{'), sp!(commandline), char!('}')))
+    map!(sp!(delimited!(char!('{'), sp!(commandline), char!('}'))), Command::brace_group)
+);
+
+/// Commands run in a copy of the shell, `( ... )`
+named!(
+    pub subshell<CompleteStr, Command>,
+    map!(sp!(delimited!(char!('('), sp!(commandline), char!(')'))), Command::subshell)
 );
 
 named!(
     pub redirect<CompleteStr, Command>,
     do_parse!(
-        command  : sp!(alt!(function | group | simple_command)) >>
+        command  : sp!(alt!(function | group | subshell | arithmetic_for | simple_command)) >>
         redirect : opt!(many1!(sp!(redirect_destination))) >>
         (match redirect {
             Some(v) => Command::redirect(command, v),
@@ -153,25 +204,63 @@ named!(
     )
 );
 
+/// A C-style loop, `for ((init; condition; step)); do ...; done`
+///
+/// Each of the three expressions may be left empty, an empty condition is always true.
+named!(
+    pub arithmetic_for<CompleteStr, Command>,
+    do_parse!(
+        _kw : sp!(call!(keyword, "for")) >>
+        _open : sp!(tag!("((")) >>
+        init : sp!(call!(arithmetic_word, ";")) >>
+        _sep1 : sp!(char!(';')) >>
+        condition : sp!(call!(arithmetic_word, ";")) >>
+        _sep2 : sp!(char!(';')) >>
+        step : sp!(call!(arithmetic_word, "")) >>
+        _close : sp!(tag!("))")) >>
+        _breaks : many0!(linebreak) >>
+        body : call!(do_group) >>
+        (Command::ArithmeticFor(Box::new(ArithmeticFor {
+            init,
+            condition,
+            step,
+            body,
+        })))
+    )
+);
+
+/// The body of a loop, `do ...; done`
+named!(
+    pub do_group<CompleteStr, Command>,
+    delimited!(sp!(call!(keyword, "do")), commandline, sp!(call!(keyword, "done")))
+);
+
+/// A pipeline may be prefixed by the `time` reserved word, which reports how long the whole pipeline took
 named!(
     pub pipeline<CompleteStr, Command>,
     do_parse! (
+        timed: opt!(sp!(call!(keyword, "time"))) >>
         bang: opt!(sp!(tag!("!"))) >>
         initial : sp!(redirect) >>
         sub: fold_many0!(
             do_parse!(
-                _op: sp!(pipe) >>
+                stderr: sp!(pipe) >>
                 expr: sp!(redirect) >>
-                (expr)
+                (stderr, expr)
             ),
             initial,
-            |start, expr| {
-                Command::pipeline(bang.is_some(), start, expr)
+            |start, (stderr, expr)| {
+                Command::pipeline(bang.is_some(), stderr, start, expr)
             }
-        ) >> (sub)
+        ) >> (match timed {
+            Some(_) => Command::Time(Box::new(sub)),
+            None => sub,
+        })
     )
 );
 
+/// Pipelines joined by `&&` and `||`, grouped from the left so `a && b || c` is `(a && b) || c`
+/// and each operator looks at the status of everything before it
 named!(
     pub list<CompleteStr, Command>,
     do_parse! (
@@ -192,10 +281,78 @@ named!(
 
 named!(
     pub comment<CompleteStr, Command>,
-    map!(preceded!(tag!("#"), take_until!("\n")), |s| Command::Comment(s.0.to_string()))
+    map!(preceded!(tag!("#"), take_while!(|c| c != '\n')), |s| Command::Comment(s.0.to_string()))
 );
 
+/// Anything that may come between two lists: a separator, the end of a line, or a comment
+named!(
+    pub linebreak<CompleteStr, Option<Separator>>,
+    sp!(alt!(
+          separator   => { Some }
+        | line_ending => { |_| Some(Separator::Eol) }
+        | comment     => { |_| None }
+    ))
+);
+
+/// A list and the separators after it, when the first separator is `&` the list runs in the background
+pub fn job<'a>(i: CompleteStr<'a>) -> nom::IResult<CompleteStr<'a>, Command> {
+    let (rest, command) = sp!(i, list)?;
+    let text = i.0[..i.0.len() - rest.0.len()].trim();
+    let (rest, separators) = many0!(rest, linebreak)?;
+    Ok((
+        rest,
+        match separators.first() {
+            Some(Some(Separator::Fork)) => Command::background(command, text),
+            _ => command,
+        },
+    ))
+}
+
+/// A sequence of lists, separators may trail the last list and blank lines are skipped
 named!(
     pub commandline<CompleteStr, Command>,
-    map!(sp!(separated_list!(separator, list)), |v| Command::group(v))
+    do_parse!(
+        _leading : many0!(linebreak) >>
+        commands : many0!(job) >>
+        _trailing : space >>
+        (Command::group(commands))
+    )
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A list with the way its conditionals nest made explicit by parentheses
+    fn nesting(command: &Command) -> String {
+        match command {
+            Command::ConditionalPair(pair) => {
+                let op = match pair.operator {
+                    ConditionOperator::AndIf => "&&",
+                    ConditionOperator::OrIf => "||",
+                };
+                format!("({} {} {})", nesting(&pair.left), op, nesting(&pair.right))
+            }
+            other => other.to_string().trim().to_string(),
+        }
+    }
+
+    fn parse_list(s: &str) -> String {
+        let (rest, command) = list(CompleteStr(s)).unwrap();
+        assert_eq!(rest.0, "", "{}", s);
+        nesting(&command)
+    }
+
+    #[test]
+    fn conditionals_group_from_the_left() {
+        assert_eq!(parse_list("a && b"), "(a && b)");
+        assert_eq!(parse_list("a && b && c || d"), "(((a && b) && c) || d)");
+        assert_eq!(parse_list("a || b && c"), "((a || b) && c)");
+    }
+
+    #[test]
+    fn conditionals_join_whole_pipelines_and_groups() {
+        assert_eq!(parse_list("a | b && c | d || e"), "((a | b && c | d) || e)");
+        assert_eq!(parse_list("{ a || b; } && c"), "({\n    a || b\n} && c)");
+    }
+}
@@ -0,0 +1,150 @@
+//! Running shell input inside the test process, so tests can check what the shell does with it
+use crate::lang::ast::Command;
+use crate::lang::{ExecutionContext, Flow, JobManager};
+use lazy_static::lazy_static;
+use nix::fcntl;
+use nix::unistd;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::ops::Deref;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+lazy_static! {
+    /// The shell changes process-wide state, like the environment and the standard fds, so only one runs at a time
+    static ref RUNNING: Mutex<()> = Mutex::new(());
+}
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// What a run of some shell input left behind
+#[derive(Debug)]
+pub struct Output {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// A directory made for one test, it's removed along with everything in it when it's dropped
+#[derive(Debug)]
+pub struct ScratchDir(PathBuf);
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// A directory of its own for each run, the shell starts in it so the input can make files with relative paths
+pub fn scratch_dir() -> ScratchDir {
+    let n = NEXT_DIR.fetch_add(1, Ordering::SeqCst);
+    let dir = env::temp_dir().join(format!("rush-test-{}-{}", process::id(), n));
+    fs::create_dir_all(&dir).unwrap();
+    ScratchDir(dir)
+}
+
+/// Run a script in a fresh shell, the way `rush -c` would
+pub fn run(script: &str) -> Output {
+    run_in(&scratch_dir(), script)
+}
+
+/// Run a script in a fresh shell that starts in `dir`
+pub fn run_in(dir: &Path, script: &str) -> Output {
+    let out = dir.join(".stdout");
+    let err = dir.join(".stderr");
+    let wrapped = format!(
+        "{{\n{}\n}} </dev/null >{} 2>{}",
+        script,
+        out.display(),
+        err.display()
+    );
+    let (status, error) = with_shell(|ec, jobs| {
+        ec.set_cwd(dir.to_path_buf()).unwrap();
+        // errors that stop the script are printed by main, after the redirections are gone
+        let (status, error) = match jobs.run(ec, Command::from(wrapped)) {
+            Ok(status) => (status.exit_code, String::new()),
+            Err(e) => (1, format!("rush: {}\n", e)),
+        };
+        match jobs.pending_flow() {
+            Some(Flow::Exit(code)) => (code, error),
+            _ => (status, error),
+        }
+    });
+    Output {
+        status,
+        stdout: fs::read_to_string(&out).unwrap_or_default(),
+        stderr: fs::read_to_string(&err).unwrap_or_default() + &error,
+    }
+}
+
+/// Run a script with `$BYTES` set to bytes that needn't be UTF-8, giving back the bytes it wrote to standard output
+pub fn run_with_bytes(bytes: &[u8], script: &str) -> Vec<u8> {
+    let dir = scratch_dir();
+    let out = dir.join(".stdout");
+    let wrapped = format!("{{\n{}\n}} </dev/null >{} 2>/dev/null", script, out.display());
+    with_shell(|ec, jobs| {
+        ec.set_cwd(dir.to_path_buf()).unwrap();
+        ec.variables_mut().define("BYTES", OsString::from_vec(bytes.to_vec())).unwrap();
+        jobs.run(ec, Command::from(wrapped)).ok();
+    });
+    fs::read(&out).unwrap_or_default()
+}
+
+/// Give a test a fresh shell to use, the process-wide state it changes, including the working directory,
+/// is put back afterwards
+pub fn with_shell<T, F>(f: F) -> T
+where
+    F: FnOnce(&mut ExecutionContext, &mut JobManager) -> T,
+{
+    let _running = RUNNING.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let environ: Vec<(OsString, OsString)> = env::vars_os().collect();
+    // a program run in place of the shell changes to its directory first, and a scratch one won't last
+    let cwd = env::current_dir().unwrap();
+    // the saved copies are close-on-exec so the programs the shell starts don't get them
+    let saved: Vec<RawFd> = (0..3)
+        .map(|fd| fcntl::fcntl(fd, fcntl::FcntlArg::F_DUPFD_CLOEXEC(0)).unwrap())
+        .collect();
+
+    let result = f(&mut ExecutionContext::new(), &mut JobManager::new());
+
+    for (fd, copy) in saved.into_iter().enumerate() {
+        unistd::dup2(copy, fd as RawFd).unwrap();
+        unistd::close(copy).unwrap();
+    }
+    for (k, _) in env::vars_os() {
+        env::remove_var(k);
+    }
+    for (k, v) in environ {
+        env::set_var(k, v);
+    }
+    env::set_current_dir(cwd).unwrap();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scratch_dirs_are_removed_with_what_is_in_them() {
+        let dir = scratch_dir();
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/f"), "x").unwrap();
+        let path = dir.to_path_buf();
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
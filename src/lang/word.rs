@@ -1,9 +1,13 @@
-use env;
-use expr;
-use failure::ResultExt;
-use lang::{ErrorKind, Result};
-use nom;
-use nom::types::CompleteStr;
+use crate::builtins::{double_quote, shell_quote};
+use crate::env;
+use crate::expr;
+use crate::lang::{ErrorKind, Result};
+use nom::{
+    alt, call, char, delimited, do_parse, many0, map, named, none_of, not, one_of, opt, pair,
+    error_position, preceded, recognize, tag, take_until_either1, take_while, take_while1, tuple, types::CompleteStr,
+};
+use std::ffi::{OsStr, OsString};
+use std::fmt::{self, Write};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Token {
@@ -15,7 +19,18 @@ pub enum Token {
     Multi(Vec<Word>),
     Regex,
     Escape(char),
-    Parameter(String, char, Word),
+    /// `${NAME}` with an operator like `:-` and the word it uses, or a case operator like `^^` and its pattern
+    Parameter(String, String, Word),
+    /// `${NAME[KEY]}`, one element of an array
+    Subscript(String, Word),
+    /// `${NAME[@]}`, every element of an array as its own field, or joined by spaces with `[*]`
+    Elements(String, bool),
+    /// `${!NAME[@]}`, the keys of an array as their own fields, or joined by spaces with `[*]`
+    Keys(String, bool),
+    /// The `(...)` of `NAME=(...)`, an array's elements and the `[KEY]=` any of them start with
+    Compound(Vec<(Option<Word>, Word)>),
+    /// `${!NAME}`, the name a nameref refers to, or for any other variable the value of the variable its value names
+    Indirect(String),
     Variable(String),
     Command(Word),
     Expr(Word),
@@ -31,14 +46,97 @@ pub struct Word {
 named!(
     pub sigiled_expression<CompleteStr, Token>,
     alt!(
-        delimited!(tag!("(("), expression_word, tag!("))")) => {|x| Token::Expr(x)}
-        | take_while!(|x| (x >= 'a' && x <= 'z') || (x >= 'A' && x <= 'Z') || x == '_') => {|x : CompleteStr| Token::Variable(x.0.to_string())}
+        delimited!(tag!("(("), expression_word, tag!("))")) => { Token::Expr }
+        | delimited!(char!('{'), parameter_expansion, char!('}')) => {|x| x}
+        | one_of!("0123456789#@*?") => {|c: char| Token::Variable(c.to_string())}
+        // a leading digit is a positional parameter, matched above
+        | take_while!(|x: char| x.is_ascii_alphanumeric() || x == '_') => {|x : CompleteStr| Token::Variable(x.0.to_string())}
+    )
+);
+
+named!(
+    pub parameter_expansion<CompleteStr, Token>,
+    alt!(
+        preceded!(char!('!'), pair!(parameter_name, all_subscript)) => {|(name, joined)| Token::Keys(name, joined)}
+        | pair!(parameter_name, all_subscript) => {|(name, joined)| Token::Elements(name, joined)}
+        | pair!(parameter_name, delimited!(char!('['), subscript_word, char!(']'))) => {|(name, key)| Token::Subscript(name, key)}
+        | preceded!(char!('!'), parameter_name) => { Token::Indirect }
+        | scalar_expansion
+    )
+);
+
+named!(
+    parameter_name<CompleteStr, String>,
+    map!(take_while1!(|c: char| c.is_ascii_alphanumeric() || c == '_'), |x: CompleteStr| x.0.to_string())
+);
+
+// `[@]` or `[*]`, whether the elements are joined into a single field
+named!(
+    all_subscript<CompleteStr, bool>,
+    delimited!(char!('['), map!(one_of!("@*"), |c| c == '*'), char!(']'))
+);
+
+named!(
+    scalar_expansion<CompleteStr, Token>,
+    do_parse!(
+        name: alt!(
+            take_while1!(|c: char| c.is_ascii_alphanumeric() || c == '_') => {|x: CompleteStr| x.0.to_string()}
+            | one_of!("#@*?") => {|c: char| c.to_string()}
+        ) >>
+        operation: opt!(pair!(
+            alt!(
+                recognize!(pair!(opt!(char!(':')), one_of!("-=+?")))
+                | tag!("^^") | tag!(",,") | tag!("^") | tag!(",")
+            ),
+            parameter_word
+        )) >>
+        (match operation {
+            Some((op, w)) => Token::Parameter(name, op.0.to_string(), w),
+            None => Token::Variable(name),
+        })
+    )
+);
+
+named!(
+    pub parameter_word<CompleteStr, Word>,
+    map!(
+        many0!(alt!(
+            preceded!(char!('\\'), none_of!("")) => { Token::Escape }
+            | delimited!(char!('"'), many0!(double_quoted_token), char!('"')) => {|x| Token::Quoted(Word::from(x))}
+            | delimited!(char!('\''), many0!(single_quoted_token), char!('\'')) => {|x| Token::Quoted(Word::from(x))}
+            | preceded!(char!('$'), sigiled_expression) => {|w| w}
+            | take_until_either1!("}\"'$\\") => {|x : CompleteStr| Token::Slice(x.0.to_string())}
+        )),
+        Word::from
+    )
+);
+
+// An array's key inside `[]`, it's expanded like a double quoted string
+named!(
+    pub subscript_word<CompleteStr, Word>,
+    map!(
+        many0!(alt!(
+            preceded!(char!('\\'), none_of!("")) => { Token::Escape }
+            | delimited!(char!('"'), many0!(double_quoted_token), char!('"')) => {|x| Token::Quoted(Word::from(x))}
+            | delimited!(char!('\''), many0!(single_quoted_token), char!('\'')) => {|x| Token::Quoted(Word::from(x))}
+            | preceded!(char!('$'), sigiled_expression) => {|w| w}
+            | take_until_either1!("]\"'$\\") => {|x : CompleteStr| Token::Slice(x.0.to_string())}
+        )),
+        Word::from
     )
 );
 
 named!(
     pub expression_word<CompleteStr, Word>,
-    map!(many0!(preceded!(
+    call!(arithmetic_word, "")
+);
+
+/// Parse the inside of an arithmetic expression up to the closing "))", or any of the characters in `stop`
+pub fn arithmetic_word<'a>(i: CompleteStr<'a>, stop: &'static str) -> nom::IResult<CompleteStr<'a>, Word> {
+    let slice_end = format!(")\"{}", stop);
+    map!(
+        i,
+        many0!(preceded!(
             not!(tag!("))")),
             alt!(
                 preceded!(char!('\\'),
@@ -50,19 +148,19 @@ named!(
                         | char!('$')
                         | char!('`')
                     )
-                ) => {|c| Token::Escape(c)}
+                ) => { Token::Escape }
                 | delimited!(
                     char!('"'),
                         many0!(double_quoted_token),
                     char!('"')
                 ) => { |c| Token::Quoted(Word::from(c)) }
                 | preceded!(char!('$'), sigiled_expression) => {|w| w}
-                | take_until_either1!(")\"") => {|x : CompleteStr| Token::Slice(x.0.to_string())}
+                | take_until_either1!(slice_end.as_str()) => {|x : CompleteStr| Token::Slice(x.0.to_string())}
             )
         )),
-        |x| Word::from(x)
+        Word::from
     )
-);
+}
 
 named!{
     pub double_quoted_token<CompleteStr, Token>,
@@ -76,7 +174,8 @@ named!{
                 | char!('$')
                 | char!('`')
             )
-        ) => {|c| Token::Escape(c)}
+        ) => { Token::Escape }
+        | preceded!(char!('\\'), none_of!("")) => {|c| Token::Slice(format!("\\{}", c))}
         | preceded!(char!('$'), sigiled_expression) => {|w| w}
         | take_until_either1!("\\$\"") => {|x : CompleteStr| Token::Slice(x.0.to_string())}
     )
@@ -88,7 +187,7 @@ named!(
             alt!(
                 char!('\'')
                 | char!('\\')
-            )) => {|c| Token::Escape(c)}
+            )) => { Token::Escape }
         | take_until_either1!("'") => {|x : CompleteStr| Token::Slice(x.0.to_string())}
     )
 );
@@ -109,8 +208,10 @@ named!(
                 | char!('&')
                 | char!('{')
                 | char!('}')
+                | char!('(')
+                | char!(')')
             )
-        ) => {|c| Token::Escape(c)}
+        ) => { Token::Escape }
         | preceded!(char!('$'), sigiled_expression) => {|w| w}
         | delimited!(
             char!('"'),
@@ -122,7 +223,7 @@ named!(
                 many0!(single_quoted_token),
             char!('\'')
         ) => { |c| Token::Quoted(Word::from(c)) }
-        | take_while1!(|c : char| c != '&'  && c != '"' && c != '{' && c != '}' && c != '\'' &&  c != '|' && c != ';' && c != '\n' && c != '\\' && c != '$' && !nom::is_space(c as u8)) => {|x : CompleteStr| Token::Slice(x.0.to_string())}
+        | take_while1!(|c : char| c != '&'  && c != '<' && c != '>' && c != '"' && c != '{' && c != '}' && c != '(' && c != ')' && c != '\'' &&  c != '|' && c != ';' && c != '\n' && c != '\\' && c != '$' && !nom::is_space(c as u8)) => {|x : CompleteStr| Token::Slice(x.0.to_string())}
     )
 );
 
@@ -136,6 +237,63 @@ named!(pub word<CompleteStr, Word>,
     )
 );
 
+/// `NAME=(...)` or `NAME+=(...)`, the assignment of a whole array
+pub fn compound_assignment(i: CompleteStr) -> nom::IResult<CompleteStr, Word> {
+    let (rest, name) = recognize!(i, tuple!(parameter_name, opt!(char!('+')), char!('=')))?;
+    if !env::Variables::is_valid_name(name.0.trim_end_matches(['+', '='])) {
+        return Err(nom::Err::Error(error_position!(i, nom::ErrorKind::Alpha)));
+    }
+    let (rest, elements) = compound_value(rest)?;
+    let parts = vec![Token::Slice(name.0.to_string()), elements];
+    Ok((rest, Word { parts }))
+}
+
+/// The `(...)` of an array's assignment, words separated by blanks or newlines that may each start with `[KEY]=`
+fn compound_value(i: CompleteStr) -> nom::IResult<CompleteStr, Token> {
+    let (mut rest, _) = char!(i, '(')?;
+    let mut elements = Vec::new();
+    loop {
+        rest = nom::multispace0(rest)?.0;
+        if let Ok((after, _)) = char!(rest, ')') {
+            return Ok((after, Token::Compound(elements)));
+        }
+        let (after, key) = opt!(rest, delimited!(char!('['), subscript_word, tag!("]=")))?;
+        let (after, value) = word(after)?;
+        // anything that can't start a word, like a `;`, doesn't belong in the list
+        if after == rest {
+            return Err(nom::Err::Error(error_position!(rest, nom::ErrorKind::Char)));
+        }
+        elements.push((key, value));
+        rest = after;
+    }
+}
+
+/// Parse the `(...)` of an array's assignment given as text, like the value in `declare -a NAME='(a b)'`
+pub fn parse_compound(text: &str) -> Option<Word> {
+    match compound_value(CompleteStr(text)) {
+        Ok((rest, elements)) if rest.trim().is_empty() => Some(Word { parts: vec![elements] }),
+        _ => None,
+    }
+}
+
+/// Write an array's elements as the `(...)` of an assignment that recreates them, the way `declare -p` shows them
+pub fn compound_text<'a, I>(elements: I) -> String
+where
+    I: IntoIterator<Item = (Option<&'a str>, &'a OsStr)>,
+{
+    let mut text = String::from("(");
+    for (key, v) in elements {
+        if let Some(key) = key {
+            let key = if shell_quote(key) == key { key.to_string() } else { double_quote(key) };
+            text.push_str(&format!("[{}]=", key));
+        }
+        text.push_str(&double_quote(v.to_string_lossy()));
+        text.push(' ');
+    }
+    text.push(')');
+    text
+}
+
 impl<T> From<T> for Word
 where
     T: IntoIterator<Item = Token>,
@@ -147,6 +305,95 @@ where
     }
 }
 
+/// A word is written back out as shell text that parses to the same word, quotes and escapes included
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_parts(f, &self.parts, false)
+    }
+}
+
+/// Write tokens as they'd appear in the source, `quoted` is whether they're inside double quotes.
+/// Quoted text that's only plain characters comes back single quoted, unless it has a `'` in it,
+/// then it's double quoted with `\`, `$`, `"` and `` ` `` escaped.
+fn write_parts(f: &mut fmt::Formatter, parts: &[Token], quoted: bool) -> fmt::Result {
+    for (i, x) in parts.iter().enumerate() {
+        match x {
+            Token::Tilde => f.write_str("~")?,
+            Token::WildcardString => f.write_str("*")?,
+            Token::WildcardChar => f.write_str("?")?,
+            Token::Regex => (),
+            Token::Slice(s) if quoted => for c in s.chars() {
+                if "\\$\"`".contains(c) {
+                    f.write_char('\\')?;
+                }
+                f.write_char(c)?;
+            },
+            Token::Slice(s) => f.write_str(s)?,
+            // inside double quotes only these are escapes, anything else means itself
+            Token::Escape(c) if quoted && !"\"\\nt$`".contains(*c) => f.write_char(*c)?,
+            Token::Escape(c) => write!(f, "\\{}", c)?,
+            Token::Unquoted(w) => write_parts(f, &w.parts, quoted)?,
+            Token::Quoted(w) if !quoted && w.parts.iter().all(|t| match t {
+                Token::Slice(s) => !s.contains('\''),
+                _ => false,
+            }) => {
+                f.write_char('\'')?;
+                write_parts(f, &w.parts, false)?;
+                f.write_char('\'')?;
+            }
+            Token::Quoted(w) => {
+                f.write_char('"')?;
+                write_parts(f, &w.parts, true)?;
+                f.write_char('"')?;
+            }
+            Token::Multi(words) => {
+                f.write_char('{')?;
+                for (n, w) in words.iter().enumerate() {
+                    if n > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", w)?;
+                }
+                f.write_char('}')?;
+            }
+            Token::Parameter(name, op, w) => write!(f, "${{{}{}{}}}", name, op, w)?,
+            Token::Subscript(name, key) => write!(f, "${{{}[{}]}}", name, key)?,
+            Token::Elements(name, joined) => write!(f, "${{{}[{}]}}", name, if *joined { '*' } else { '@' })?,
+            Token::Keys(name, joined) => write!(f, "${{!{}[{}]}}", name, if *joined { '*' } else { '@' })?,
+            Token::Indirect(name) => write!(f, "${{!{}}}", name)?,
+            Token::Compound(elements) => {
+                f.write_char('(')?;
+                for (n, (key, value)) in elements.iter().enumerate() {
+                    if n > 0 {
+                        f.write_char(' ')?;
+                    }
+                    if let Some(key) = key {
+                        write!(f, "[{}]=", key)?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_char(')')?;
+            }
+            Token::Variable(name) => {
+                // braces keep the name from running into the text after it, and `${10}` from being `$1` and a 0
+                let runs_on = match parts.get(i + 1) {
+                    Some(Token::Slice(s)) => s.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+                    _ => false,
+                };
+                if runs_on || (name.len() > 1 && name.starts_with(|c: char| c.is_ascii_digit())) {
+                    write!(f, "${{{}}}", name)?
+                } else {
+                    write!(f, "${}", name)?
+                }
+            }
+            Token::Command(w) => write!(f, "$({})", w)?,
+            Token::Expr(w) => write!(f, "$(({}))", w)?,
+            Token::QuotedCommand(s) => write!(f, "`{}`", s)?,
+        }
+    }
+    Ok(())
+}
+
 impl Word {
     pub fn new() -> Word {
         Word { parts: Vec::new() }
@@ -155,24 +402,145 @@ impl Word {
         word(CompleteStr(s.as_ref())).unwrap().1
     }
 
-    pub fn compile(&self, vars: &mut env::Variables) -> Result<String> {
-        use std::ffi::OsString;
+    /// The word's text if it's made of only plain, unquoted characters
+    pub fn literal(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [Token::Slice(s)] => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Whether the word assigns a whole array, like `NAME=(a b)` or `NAME+=(c)`
+    pub fn is_compound_assignment(&self) -> bool {
+        matches!(self.parts.as_slice(), [Token::Slice(_), Token::Compound(_)])
+    }
+
+    /// Expand the elements of a whole array's assignment, each with the key its `[KEY]=` gives it.
+    /// An element without a key can give more than one, like `${NAME[@]}` does.
+    pub fn compound_elements(&self, vars: &mut env::Variables) -> Result<Vec<(Option<String>, OsString)>> {
+        let mut expanded = Vec::new();
+        if let Some(Token::Compound(elements)) = self.parts.last() {
+            for (key, value) in elements {
+                match key {
+                    Some(key) => {
+                        let key = key.compile(vars)?.to_string_lossy().to_string();
+                        expanded.push((Some(key), value.compile(vars)?));
+                    }
+                    None => expanded.extend(value.fields(vars)?.into_iter().map(|v| (None, v))),
+                }
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// Whether any part of the word is quoted, a quoted word is still a word when it expands to nothing
+    pub fn has_quotes(&self) -> bool {
+        self.parts.iter().any(|t| match t {
+            Token::Quoted(_) => true,
+            Token::Unquoted(w) => w.has_quotes(),
+            Token::Multi(words) => words.iter().any(|w| w.has_quotes()),
+            _ => false,
+        })
+    }
+
+    /// If the word begins with an unquoted `NAME=`, or `NAME[KEY]=` for an element of an array,
+    /// it's a variable assignment, get the NAME. Either one may use `+=` to append instead.
+    pub fn assignment_name(&self) -> Option<&str> {
+        match self.parts.first() {
+            Some(Token::Slice(s)) => {
+                let name = match s.find(['=', '[']) {
+                    Some(end) if s[end..].starts_with('[') && self.closes_subscript() => &s[..end],
+                    Some(end) if s[end..].starts_with('=') && s[..end].ends_with('+') => &s[..end - 1],
+                    Some(end) if s[end..].starts_with('=') => &s[..end],
+                    _ => return None,
+                };
+                if env::Variables::is_valid_name(name) {
+                    Some(name)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
 
-        let mut s = String::new(); // TODO set capacity to avoid reallocations
+    /// Whether an unquoted `]=` or `]+=` follows the `[` of an assignment's subscript
+    fn closes_subscript(&self) -> bool {
+        let mut opened = false;
         for x in &self.parts {
-            match x {
-                Token::Tilde => {
-                    s.push_str(vars.value(&OsString::from("HOME")).to_str().unwrap_or(""))
+            if let Token::Slice(s) = x {
+                let from = if opened { 0 } else { s.find('[').map(|i| i + 1).unwrap_or(s.len()) };
+                if s[from..].contains("]=") || s[from..].contains("]+=") {
+                    return true;
                 }
-                Token::Slice(v) => s.push_str(v),
-                Token::Expr(v) => {
-                    let evaluated: String = expr::eval(v.compile(vars)?.as_str(), vars)
-                        .context(ErrorKind::ExpressionError)?;
-                    s.push_str(&evaluated)
+                opened = true;
+            }
+        }
+        false
+    }
+
+    /// Expand the word into a single string. Variables can hold any bytes but a NUL, so the result isn't always UTF-8.
+    pub fn compile(&self, vars: &mut env::Variables) -> Result<OsString> {
+        Word::compile_parts(&self.parts, vars)
+    }
+
+    /// Expand the word into the fields a command's arguments are made of. `${NAME[@]}` and `${!NAME[@]}` give
+    /// a field for each element or key, the text around them joining the first and last, otherwise there's one.
+    /// There are none when the word is nothing but arrays with no elements.
+    pub fn fields(&self, vars: &mut env::Variables) -> Result<Vec<OsString>> {
+        let mut fields = Vec::new();
+        self.expand_fields(vars, &mut fields)?;
+        Ok(fields)
+    }
+
+    fn expand_fields(&self, vars: &mut env::Variables, fields: &mut Vec<OsString>) -> Result<()> {
+        if self.parts.is_empty() {
+            push_text(fields, OsString::new());
+        }
+
+        // runs of tokens between the arrays are expanded together, the same as `compile` would
+        let mut run_start = 0;
+        for (i, x) in self.parts.iter().enumerate() {
+            let array = match x {
+                Token::Elements(name, false) => Some(array_fields(vars, name, false)),
+                Token::Keys(name, false) => Some(array_fields(vars, name, true)),
+                Token::Quoted(_) | Token::Unquoted(_) => None,
+                _ => continue,
+            };
+            if run_start < i {
+                push_text(fields, Word::compile_parts(&self.parts[run_start..i], vars)?);
+            }
+            run_start = i + 1;
+
+            match (x, array) {
+                (_, Some(elements)) => {
+                    let mut elements = elements.into_iter();
+                    if let Some(first) = elements.next() {
+                        push_text(fields, first);
+                    }
+                    fields.extend(elements);
                 }
-                Token::Variable(v) => {
-                    s.push_str(vars.value(&OsString::from(v)).to_str().unwrap_or(""))
+                (Token::Quoted(w), None) | (Token::Unquoted(w), None) => w.expand_fields(vars, fields)?,
+                _ => (),
+            }
+        }
+        if run_start < self.parts.len() {
+            push_text(fields, Word::compile_parts(&self.parts[run_start..], vars)?);
+        }
+        Ok(())
+    }
+
+    fn compile_parts(parts: &[Token], vars: &mut env::Variables) -> Result<OsString> {
+        let mut s = OsString::new(); // TODO set capacity to avoid reallocations
+        for x in parts {
+            match x {
+                Token::Tilde => s.push(vars.value(&OsString::from("HOME"))),
+                Token::Slice(v) => s.push(v),
+                Token::Expr(v) => {
+                    let evaluated: String = expr::eval(v.compile(vars)?.to_string_lossy(), vars)?;
+                    s.push(&evaluated)
                 }
+                Token::Variable(v) => s.push(vars.value(&OsString::from(v))),
                 Token::Escape(v) => s.push(match *v {
                     'n' => '\n',
                     't' => '\t',
@@ -183,12 +551,218 @@ impl Word {
                     '|' => '|',
                     '\n' => '\n',
                     '`' => '`',
-                    _ => '\u{FFFD}',
-                }),
-                Token::Quoted(v) => s.extend(v.compile(vars)?.chars()),
+                    c => c,
+                }.encode_utf8(&mut [0; 4])),
+                Token::Quoted(v) => s.push(v.compile(vars)?),
+                Token::Parameter(name, op, w) => {
+                    let key = OsString::from(name);
+                    let value = vars.value(&key);
+                    // with a colon an empty variable is treated the same as an unset one
+                    let missing = !vars.exists(&key) || (op.starts_with(':') && value.is_empty());
+                    match (op.trim_start_matches(':'), missing) {
+                        ("-", true) => s.push(w.compile(vars)?),
+                        ("=", true) => {
+                            let value = w.compile(vars)?;
+                            vars.define(name.as_str(), value.clone())?;
+                            s.push(value);
+                        }
+                        ("+", true) => (),
+                        ("+", false) => s.push(w.compile(vars)?),
+                        ("?", true) => {
+                            let mut message = w.compile(vars)?.to_string_lossy().to_string();
+                            if message.is_empty() {
+                                message = "parameter null or not set".to_string();
+                            }
+                            return Err(ErrorKind::UnsetParameter(name.clone(), message).into());
+                        }
+                        ("^^", _) | ("^", _) | (",,", _) | (",", _) => {
+                            let pattern: Vec<char> = w.compile(vars)?.to_string_lossy().chars().collect();
+                            s.push(change_case(value, op, &pattern))
+                        }
+                        _ => s.push(value),
+                    }
+                }
+                Token::Subscript(name, key) => {
+                    let name = OsString::from(name);
+                    let key = key.compile(vars)?.to_string_lossy().to_string();
+                    let key = vars.subscript(&name, &key)?;
+                    if let Some(v) = vars.element(&name, &key) {
+                        s.push(v)
+                    }
+                }
+                Token::Indirect(name) => {
+                    let name = OsString::from(name);
+                    match vars.reference(&name) {
+                        Some(target) => s.push(target),
+                        None => s.push(vars.value(&vars.value(&name))),
+                    }
+                }
+                Token::Elements(name, _) => s.push(join_fields(array_fields(vars, name, false))),
+                Token::Keys(name, _) => s.push(join_fields(array_fields(vars, name, true))),
+                // written out the way `declare` reads it back, since that's the only command that takes one
+                Token::Compound(_) => {
+                    let elements = Word::from(vec![x.clone()]).compound_elements(vars)?;
+                    s.push(compound_text(elements.iter().map(|(key, v)| (key.as_ref().map(String::as_str), v.as_os_str()))))
+                }
                 _ => unimplemented!(),
             };
         }
         Ok(s)
     }
 }
+
+/// Apply a case operator to a value: `^^` uppercases every character matching the pattern and `,,` lowercases them,
+/// `^` and `,` only look at the first character. An empty pattern matches anything.
+/// A character only changes when it has a single character for the other case, and values that aren't UTF-8 are kept as they are.
+fn change_case(value: OsString, op: &str, pattern: &[char]) -> OsString {
+    let text = match value.into_string() {
+        Ok(text) => text,
+        Err(value) => return value,
+    };
+    let upper = op.starts_with('^');
+    let first_only = op.len() == 1;
+    let changed: String = text
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if (first_only && i > 0) || !(pattern.is_empty() || glob_matches(pattern, &[c])) {
+                return c;
+            }
+            let mut mapped: Vec<char> = if upper {
+                c.to_uppercase().collect()
+            } else {
+                c.to_lowercase().collect()
+            };
+            if mapped.len() == 1 {
+                mapped.remove(0)
+            } else {
+                c
+            }
+        }).collect();
+    OsString::from(changed)
+}
+
+/// Match text against a pattern made of `*`, `?`, bracket expressions like `[a-z]` or `[!0-9]`, and
+/// backslash escaped or ordinary characters
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => (0..=text.len()).any(|skip| glob_matches(&pattern[1..], &text[skip..])),
+        Some('?') => !text.is_empty() && glob_matches(&pattern[1..], &text[1..]),
+        Some('[') => match (bracket_matches(&pattern[1..], text.first()), text.is_empty()) {
+            (Some((true, rest)), false) => glob_matches(rest, &text[1..]),
+            (Some(_), _) => false,
+            // a `[` that isn't closed is an ordinary character
+            (None, _) => text.first() == Some(&'[') && glob_matches(&pattern[1..], &text[1..]),
+        },
+        Some('\\') if pattern.len() > 1 => text.first() == Some(&pattern[1]) && glob_matches(&pattern[2..], &text[1..]),
+        Some(c) => text.first() == Some(c) && glob_matches(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Whether a character is in the bracket expression the pattern starts with, just after its `[`,
+/// and the rest of the pattern after the `]`. `None` when there's no closing `]`.
+fn bracket_matches<'a>(pattern: &'a [char], c: Option<&char>) -> Option<(bool, &'a [char])> {
+    let negated = pattern.first() == Some(&'!') || pattern.first() == Some(&'^');
+    let mut i = if negated { 1 } else { 0 };
+    let mut found = false;
+    let mut first = true;
+    loop {
+        // a `]` straight after the `[` or `[!` is part of the set
+        let start = *pattern.get(i)?;
+        if start == ']' && !first {
+            break;
+        }
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                found |= c.map(|&c| start <= c && c <= end).unwrap_or(false);
+                i += 3;
+            }
+            _ => {
+                found |= c == Some(&start);
+                i += 1;
+            }
+        }
+    }
+    Some((found != negated, &pattern[i + 1..]))
+}
+
+/// Add text to the end of the last field, it starts the first field if there isn't one yet
+fn push_text(fields: &mut Vec<OsString>, text: OsString) {
+    match fields.last_mut() {
+        Some(last) => last.push(text),
+        None => fields.push(text),
+    }
+}
+
+/// Put fields back together with spaces, the way `${NAME[*]}` shows an array
+pub fn join_fields(fields: Vec<OsString>) -> OsString {
+    let mut joined = OsString::new();
+    for (i, field) in fields.into_iter().enumerate() {
+        if i > 0 {
+            joined.push(" ");
+        }
+        joined.push(field);
+    }
+    joined
+}
+
+/// The elements of an array, or its keys. Any other variable with a value is an array of one,
+/// with the key `0`.
+fn array_fields(vars: &env::Variables, name: &str, keys: bool) -> Vec<OsString> {
+    let name = OsString::from(name);
+    match vars.elements(&name) {
+        Some(elements) if keys => elements.into_iter().map(|(key, _)| OsString::from(key)).collect(),
+        Some(elements) => elements.into_iter().map(|(_, v)| v.clone()).collect(),
+        None if !vars.exists(&name) => Vec::new(),
+        None if keys => vec![OsString::from("0")],
+        None => vec![vars.value(&name)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::testing::{run, run_with_bytes};
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn case_operators_change_every_or_the_first_character() {
+        let out = run("x='hello World'; echo \"${x^^}|${x,,}|${x^}|${x,}\"; y=Abc; echo ${y,}");
+        assert_eq!(out.stdout, "HELLO WORLD|hello world|Hello World|hello World\nabc\n");
+    }
+
+    #[test]
+    fn case_patterns_pick_the_characters_that_change() {
+        let out = run("x='hello world'; echo \"${x^^[lo]}|${x^^[!lo]}|${x^h}|${x^w}|${x^^?}|${x^^*}\"");
+        assert_eq!(out.stdout, "heLLO wOrLd|HEllo WoRlD|Hello world|hello world|HELLO WORLD|HELLO WORLD\n");
+        assert_eq!(change_case(OsString::from("a]b"), "^^", &chars("[]a]")), "A]b");
+        assert_eq!(change_case(OsString::from("a-b"), "^^", &chars("\\-")), "a-b");
+    }
+
+    #[test]
+    fn case_operators_are_unicode_aware() {
+        assert_eq!(run("x='éclair ÅSA'; echo \"${x^^} ${x,,} ${x^}\"").stdout, "ÉCLAIR ÅSA éclair åsa Éclair ÅSA\n");
+        // ß has no single-character uppercase, so it's kept as it is
+        assert_eq!(change_case(OsString::from("straße"), "^^", &[]), "STRAßE");
+        assert_eq!(run_with_bytes(b"ab\xff", "echo \"${BYTES^^}\""), b"ab\xff\n");
+    }
+
+    #[test]
+    fn case_operators_work_with_other_expansions() {
+        let out = run("unset u; echo \"[${u,,}]\" \"[${u^^}]\"; a=ab b=CD; echo ${a^^}-${b,,}-${a^}$b; echo ${u:-Def} ${b,}");
+        assert_eq!(out.stdout, "[] []\nAB-cd-AbCD\nDef cD\n");
+    }
+
+    #[test]
+    fn assignments_split_at_the_first_equals_of_a_valid_name() {
+        assert_eq!(run("A=1=2; echo $A").stdout, "1=2\n");
+        let out = run("A-B=1; echo $?");
+        assert_eq!(out.stdout, "127\n");
+        assert_eq!(out.stderr, "rush: A-B=1: command not found\n");
+    }
+}
@@ -1,12 +1,4 @@
-#[macro_use]
-extern crate nom;
-#[macro_use]
-extern crate lazy_static;
-#[macro_use]
-extern crate failure;
-extern crate nix;
-extern crate nixterm;
-
+pub mod builtins;
 pub mod env;
 pub mod expr;
 pub mod lang;
@@ -22,25 +14,123 @@ fn main() {
     let mut environ = lang::ExecutionContext::new();
     let mut job_manager = lang::JobManager::new();
 
-    environ.variables_mut().define("RUSH_VERSION", "0.1.0");
+    // nothing is readonly yet, so these assignments can't fail
+    environ.variables_mut().define("RUSH_VERSION", "0.1.0").ok();
+
+    let argv: Vec<String> = args().collect();
+    let mut first_arg = 1;
+    while argv.get(first_arg).map(|arg| arg == "-n").unwrap_or(false) {
+        environ.options_mut().noexec = true;
+        first_arg += 1;
+    }
 
-    match args().nth(1) {
+    match argv.get(first_arg) {
+        Some(flag) if flag == "-c" => match argv.get(first_arg + 1) {
+            Some(command) => {
+                if let Some(name) = argv.get(first_arg + 2) {
+                    environ.variables_mut().define("0", name.as_str()).ok();
+                }
+                set_positional(&mut environ, argv.iter().skip(first_arg + 3));
+                exit(run_script(&mut job_manager, &mut environ, "-c", command.clone()))
+            }
+            None => {
+                eprintln!("rush: -c: option requires an argument");
+                exit(2)
+            }
+        },
         Some(v) => {
+            environ.variables_mut().define("0", v.as_str()).ok();
+            set_positional(&mut environ, argv.iter().skip(first_arg + 1));
             let mut data = String::new();
             {
                 let mut f = File::open(v).unwrap();
                 f.read_to_string(&mut data).unwrap();
             }
-            exit(
-                job_manager
-                    .run(&mut environ, lang::ast::Command::from(data))
-                    .map(|exit_status| exit_status.exit_code)
-                    .unwrap_or_else(|e| {
-                        println!("{}", e);
-                        1
-                    }),
-            )
+            exit(run_script(&mut job_manager, &mut environ, v, data))
+        }
+        None => {
+            if environ.options().noexec {
+                eprintln!("rush: -n: ignored by an interactive shell");
+                environ.options_mut().noexec = false;
+            }
+            exit(shell.unwrap().run(&mut environ, &mut job_manager))
+        }
+    }
+}
+
+fn set_positional<'a, I: Iterator<Item = &'a String>>(environ: &mut lang::ExecutionContext, params: I) {
+    environ
+        .variables_mut()
+        .set_positional(params.map(std::ffi::OsString::from).collect());
+}
+
+/// Run a whole script non-interactively, returning the shell's exit code.
+/// Nothing runs if the script has a syntax error, and with `-n` it's only checked for one.
+fn run_script(
+    job_manager: &mut lang::JobManager,
+    environ: &mut lang::ExecutionContext,
+    name: &str,
+    data: String,
+) -> i32 {
+    let command = match lang::ast::Command::parse_script(&data) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("rush: {}: {}", name, e);
+            return 2;
         }
-        None => shell.unwrap().run(&mut environ, &mut job_manager),
+    };
+    if environ.options().noexec {
+        return 0;
+    }
+
+    let code = job_manager
+        .run_last(environ, command)
+        .map(|exit_status| exit_status.exit_code)
+        .unwrap_or_else(|e| {
+            eprintln!("rush: {}", e);
+            1
+        });
+    if let Err(e) = job_manager.run_exit_trap(environ) {
+        eprintln!("rush: {}", e);
+    }
+    // the exit trap can call exit itself to change the status
+    match job_manager.pending_flow() {
+        Some(lang::Flow::Exit(code)) => code,
+        _ => code,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run_script;
+    use crate::lang::testing::{scratch_dir, with_shell};
+    use std::fs;
+    use std::path::Path;
+
+    /// Run a script the way `rush -c` does in `dir`, giving its exit code
+    fn exit_code(dir: &Path, noexec: bool, script: &str) -> i32 {
+        with_shell(|ec, jobs| {
+            ec.set_cwd(dir.to_path_buf()).unwrap();
+            ec.options_mut().noexec = noexec;
+            run_script(jobs, ec, "-c", script.to_string())
+        })
+    }
+
+    #[test]
+    fn scripts_with_a_syntax_error_run_nothing() {
+        let dir = scratch_dir();
+        for &noexec in &[false, true] {
+            assert_eq!(exit_code(&dir, noexec, "echo a >out\nif true; then echo b; fi; echo c >out"), 2);
+            assert!(!dir.join("out").exists());
+        }
+    }
+
+    #[test]
+    fn scripts_run_unless_they_are_only_checked() {
+        let dir = scratch_dir();
+        assert_eq!(exit_code(&dir, true, "echo a >out; false"), 0);
+        assert!(!dir.join("out").exists());
+        assert_eq!(exit_code(&dir, false, "echo a >out\nfalse"), 1);
+        assert_eq!(fs::read_to_string(dir.join("out")).unwrap(), "a\n");
     }
 }
@@ -1,29 +1,54 @@
-use failure;
-use lang;
-use lang::ast::Command;
-use lang::parser;
-use lang::word::Word;
-use nixterm;
+mod prompt;
+
+use crate::builtins;
+use crate::env::traps;
+use crate::env::Variables;
+use crate::lang;
+use crate::lang::ast::Command;
+use crate::lang::word::Word;
+use failure::ResultExt;
+use nix::errno::Errno;
+use nix::poll::{poll, EventFlags, PollFd};
+use nix::unistd;
 use nixterm::events::Key;
 use std::ffi::OsString;
+use std::fs;
 use std::io;
 use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+
+type Term = nixterm::Term<TerminalInput, io::Stdout>;
 
 pub struct Shell {
     command_buffer: String,
     old_settings: nixterm::term::Settings,
-    term: nixterm::Term<io::Stdin, io::Stdout>,
+    term: Term,
     history: Vec<String>,
     exit: bool,
+    /// Whether the last attempt to exit was refused because of stopped jobs, a second one in a row goes through
+    warned_stopped_jobs: bool,
+    /// How many lines of commands have been entered, so `$LINENO` keeps counting from one command to the next
+    lines_read: usize,
+    /// How many lines the command being read continued onto
+    continued_lines: usize,
+    /// Whether the line being read was given up on with ^C
+    abandoned: bool,
 }
 
 impl Shell {
     pub fn new() -> nixterm::Result<Shell> {
-        let t = nixterm::Term::new()?;
+        let info = nixterm::terminfo::from_env()
+            .context(nixterm::ErrorKind::FailedToCreateTermInstance)?;
+        let t = nixterm::Term::from_streams(info, TerminalInput, io::stdout());
         Ok(Shell {
             command_buffer: String::new(),
             history: Vec::new(),
             exit: false,
+            warned_stopped_jobs: false,
+            lines_read: 0,
+            continued_lines: 0,
+            abandoned: false,
             old_settings: t.settings(),
             term: t,
         })
@@ -36,32 +61,45 @@ impl Shell {
         }
     }
 
-    pub fn run(&mut self, ec: &mut lang::ExecutionContext, jm: &mut lang::JobManager) {
+    /// Run the commands in ~/.rushrc, if it exists, so what it defines is ready for the first prompt
+    fn load_rc(ec: &mut lang::ExecutionContext, jm: &mut lang::JobManager) {
+        let home = ec.variables().value(&OsString::from("HOME"));
+        if home.is_empty() {
+            return;
+        }
+        if let Ok(data) = fs::read_to_string(Path::new(&home).join(".rushrc")) {
+            if let Err(e) = jm.run(ec, Command::from(data)) {
+                Shell::print_error(e);
+            }
+        }
+    }
+
+    /// Read and run commands until the user leaves, returning the shell's exit code:
+    /// the one given to `exit`, or the last command's status
+    pub fn run(&mut self, ec: &mut lang::ExecutionContext, jm: &mut lang::JobManager) -> i32 {
+        ec.set_interactive(true);
+        if let Err(e) = traps::catch_interrupts() {
+            Shell::print_error(e);
+        }
+        if let Err(e) = jm.enable_job_control() {
+            Shell::print_error(e);
+        }
+        Shell::load_rc(ec, jm);
         while !self.exit_requested() {
-            let prefix_command = ec
-                .variables()
-                .value(&OsString::from("RUSH_PROMPT"))
-                .to_string_lossy()
-                .to_string();
-
-            match jm.run(
-                ec,
-                if prefix_command.is_empty() {
-                    Command::simple(
-                        ["printf", "'rush-%s$ '", "$RUSH_VERSION"]
-                            .iter()
-                            .map(|w| Word::parse(w))
-                            .collect(),
-                    )
-                } else {
-                    Command::from(prefix_command)
-                },
-            ) {
+            // signals that came while an empty line was typed haven't had their traps run by a command yet,
+            // an interrupt at the prompt only means the line was abandoned
+            match jm.run_traps(ec) {
+                Err(ref e) if *e.kind() == lang::ErrorKind::Interrupted => (),
+                Err(e) => Shell::print_error(e),
+                Ok(()) => (),
+            }
+            match builtins::notify_completed(jm) {
+                Ok(notices) => eprint!("{}", notices),
                 Err(e) => Shell::print_error(e),
-                _ => (),
             }
 
-            let buffer = match self.readline(ec) {
+            Shell::print_prompt(ec, jm);
+            let buffer = match self.read_command(ec, jm) {
                 Ok(v) => v,
                 Err(e) => {
                     println!();
@@ -69,38 +107,213 @@ impl Shell {
                     continue;
                 }
             };
+            let ran_command = !buffer.is_empty();
             if !self.exit_requested() {
                 println!();
 
                 if !buffer.is_empty() {
                     self.history.push(buffer.clone());
-                    match jm.run(ec, Command::from(buffer)) {
+                    // an interrupt that came while the line was being typed was for the line, not the command
+                    traps::discard_interrupt();
+                    let mut command = Command::from(&buffer);
+                    let lines_before = self.lines_read;
+                    command.renumber(&|line| line + lines_before);
+                    self.lines_read += buffer.matches('\n').count() + self.continued_lines + 1;
+                    match jm.run(ec, command) {
+                        Err(ref e) if *e.kind() == lang::ErrorKind::Interrupted => {
+                            println!();
+                            ec.variables_mut().set_status(130);
+                            continue;
+                        }
                         Err(e) => {
                             Shell::print_error(e);
+                            ec.variables_mut().set_status(1);
                             continue;
                         }
                         _ => (),
                     }
+                    if let Some(lang::Flow::Exit(_)) = jm.pending_flow() {
+                        self.exit = true;
+                    }
+                }
+            }
+
+            if self.exit_requested() {
+                if !self.warned_stopped_jobs && jm.has_stopped_jobs().unwrap_or(false) {
+                    eprintln!("There are stopped jobs.");
+                    self.warned_stopped_jobs = true;
+                    self.exit = false;
+                    jm.clear_flow();
+                }
+            } else if ran_command {
+                self.warned_stopped_jobs = false;
+            }
+        }
+
+        Shell::leave(ec, jm)
+    }
+
+    /// Run the exit trap and hang up the jobs as the session ends, giving the shell's exit code
+    fn leave(ec: &mut lang::ExecutionContext, jm: &mut lang::JobManager) -> i32 {
+        if let Err(e) = jm.run_exit_trap(ec) {
+            Shell::print_error(e);
+        }
+        if let Err(e) = jm.hangup_jobs() {
+            Shell::print_error(e);
+        }
+        // the exit trap keeps `$?` as it was, but can call exit itself to change the status
+        match jm.pending_flow() {
+            Some(lang::Flow::Exit(code)) => code,
+            _ => ec.variables().status(),
+        }
+    }
+
+    /// Show the prompt for a new command. `$RUSH_PROMPT` is run as a command when it isn't empty,
+    /// otherwise `$PS1` is printed with its escapes expanded if it's set, even to nothing.
+    /// Without either the default `rush-VERSION$ ` is printed.
+    fn print_prompt(ec: &mut lang::ExecutionContext, jm: &mut lang::JobManager) {
+        if let Some(prompt) = Shell::ps1(ec.variables()) {
+            print!("{}", prompt);
+            io::stdout().flush().ok();
+            return;
+        }
+        let prefix_command = ec
+            .variables()
+            .value(&OsString::from("RUSH_PROMPT"))
+            .to_string_lossy()
+            .to_string();
+
+        // the prompt is a command too, but it shouldn't change `$?`
+        let status = ec.variables().status();
+        let command = if prefix_command.is_empty() {
+            Command::simple(["printf", "'rush-%s$ '", "$RUSH_VERSION"].iter().map(Word::parse).collect())
+        } else {
+            Command::from(prefix_command)
+        };
+        if let Err(e) = jm.run(ec, command) {
+            Shell::print_error(e);
+        }
+        ec.variables_mut().set_status(status);
+    }
+
+    /// `$PS1` with its escapes expanded, when it's the prompt to show instead of running `$RUSH_PROMPT`
+    fn ps1(vars: &Variables) -> Option<String> {
+        let ps1 = OsString::from("PS1");
+        if vars.value(&OsString::from("RUSH_PROMPT")).is_empty() && vars.exists(&ps1) {
+            Some(Shell::prompt_string(vars, "PS1", ""))
+        } else {
+            None
+        }
+    }
+
+    /// A prompt variable with its escapes expanded, or the default when it isn't set
+    fn prompt_string(vars: &Variables, name: &str, default: &str) -> String {
+        let name = OsString::from(name);
+        if vars.exists(&name) {
+            prompt::expand(&vars.value(&name).to_string_lossy(), vars)
+        } else {
+            default.to_string()
+        }
+    }
+
+    /// Read a line and the ones it continues onto, showing `$PS2` (`> ` by default) before each of those.
+    /// A line continues when it ends in an unquoted backslash, which is dropped along with the line break.
+    fn read_command(
+        &mut self,
+        ec: &mut lang::ExecutionContext,
+        jm: &mut lang::JobManager,
+    ) -> nixterm::Result<String> {
+        self.continued_lines = 0;
+        let mut command = self.readline(ec, jm)?;
+        while !self.exit_requested() && !self.abandoned && continues(&command) {
+            command.pop();
+            println!();
+            print!("{}", Shell::prompt_string(ec.variables(), "PS2", "> "));
+            io::stdout().flush().ok();
+            command.push_str(&self.readline(ec, jm)?);
+            self.continued_lines += 1;
+        }
+        // ^C on a continuation line gives up on the whole command
+        if self.abandoned {
+            command.clear();
+        }
+        Ok(command)
+    }
+
+    /// Wait until there's a key to read, announcing background jobs that finish in the meantime for `set -b`.
+    /// The announcements go where the line being edited was, and the prompt and line are drawn again under them.
+    fn wait_for_key(
+        term: &Term,
+        ec: &mut lang::ExecutionContext,
+        jm: &mut lang::JobManager,
+        buffer: &str,
+        xoffset: isize,
+    ) {
+        loop {
+            match builtins::notify_completed(jm) {
+                Ok(ref notices) if notices.is_empty() => (),
+                Ok(notices) => {
+                    // the terminal is raw, so each line needs its carriage return
+                    term.writer()
+                        .print("\r")
+                        .print(term.info.string(nixterm::terminfo::ClrEol).unwrap_or(""))
+                        .print(notices.replace('\n', "\r\n"))
+                        .done()
+                        .ok();
+                    Shell::print_prompt(ec, jm);
+                    term.writer().print(buffer).shift_cursor(-xoffset, 0).done().ok();
                 }
+                // the next prompt reports it
+                Err(_) => return,
+            }
+
+            // SIGCHLD cuts the wait short, the timeout catches one that arrived just before it started
+            let mut fds = [PollFd::new(0, EventFlags::POLLIN)];
+            match poll(&mut fds, 1000) {
+                Ok(0) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                _ => return,
             }
         }
     }
 
-    pub fn readline(&mut self, environ: &mut lang::ExecutionContext) -> nixterm::Result<String> {
+    pub fn readline(
+        &mut self,
+        environ: &mut lang::ExecutionContext,
+        jm: &mut lang::JobManager,
+    ) -> nixterm::Result<String> {
         self.command_buffer.clear();
+        self.abandoned = false;
 
         let mut hist_index = self.history.len();
         let mut xoffset: isize = 0;
         self.term.update(self.old_settings.clone().raw()).unwrap();
 
-        for k in self.term.read_keys() {
+        let notify = environ.options().notify;
+        if notify {
+            traps::watch_children().ok();
+        }
+        let mut keys = self.term.read_keys();
+        let mut result = Ok(());
+        loop {
+            if notify {
+                Shell::wait_for_key(&self.term, environ, jm, &self.command_buffer, xoffset);
+            }
+            let k = match keys.next() {
+                Some(Ok(k)) => k,
+                Some(Err(e)) => {
+                    result = Err(e);
+                    break;
+                }
+                None => break,
+            };
+
             let backtrack = self.command_buffer.len() as isize;
             self.term
                 .writer()
                 .shift_cursor(xoffset - backtrack, 0)
                 .done();
 
-            match k? {
+            match k {
                 Key::Control(c) => {
                     if c == 'D' && self.command_buffer.len() == 0 {
                         self.term
@@ -118,6 +331,7 @@ impl Shell {
                             .print("^C")
                             .done();
                         self.command_buffer.clear();
+                        self.abandoned = true;
                         break;
                     }
                 }
@@ -163,11 +377,111 @@ impl Shell {
                 .unwrap();
         }
 
+        if notify {
+            traps::unwatch_children();
+        }
         self.term.update(self.old_settings.clone());
-        Ok(self.command_buffer.clone())
+        result.map(|_| self.command_buffer.clone())
     }
 
     pub fn exit_requested(&self) -> bool {
         self.exit
     }
 }
+
+/// Whether a line ends in a backslash that isn't escaped by another one, or inside single quotes
+fn continues(line: &str) -> bool {
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in line.chars() {
+        match c {
+            '\\' if !quoted => escaped = !escaped,
+            '\'' if !escaped => quoted = !quoted,
+            _ => escaped = false,
+        }
+    }
+    escaped
+}
+
+/// The terminal's input, read a byte at a time so nothing typed ahead is kept in a buffer,
+/// which lets `Shell::wait_for_key` see whether there's more to read by polling the fd
+struct TerminalInput;
+
+impl io::Read for TerminalInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(1);
+        loop {
+            match unistd::read(0, &mut buf[..len]) {
+                Ok(n) => return Ok(n),
+                Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(nix::Error::Sys(errno)) => return Err(io::Error::from_raw_os_error(errno as i32)),
+                // nix only gives other errors for paths and strings
+                Err(_) => return Err(io::Error::from_raw_os_error(Errno::EIO as i32)),
+            }
+        }
+    }
+}
+
+impl AsRawFd for TerminalInput {
+    fn as_raw_fd(&self) -> RawFd {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::testing::{scratch_dir, with_shell};
+
+    #[test]
+    fn rc_file_runs_before_the_first_prompt() {
+        let home = scratch_dir();
+        fs::write(home.join(".rushrc"), "alias ll='ls -la'\nRC_LOADED=1\n").unwrap();
+        with_shell(|ec, jm| {
+            ec.variables_mut().define("HOME", home.as_os_str()).unwrap();
+            Shell::load_rc(ec, jm);
+            assert_eq!(ec.aliases().value("ll").map(String::as_str), Some("ls -la"));
+            assert_eq!(ec.variables().value(&OsString::from("RC_LOADED")), "1");
+        });
+    }
+
+    /// The exit code of a session that ran `script` before the user left
+    fn exit_code(script: &str) -> i32 {
+        with_shell(|ec, jm| {
+            jm.run(ec, Command::from(script)).ok();
+            Shell::leave(ec, jm)
+        })
+    }
+
+    #[test]
+    fn leaving_gives_the_last_status_or_the_exit_code() {
+        assert_eq!(exit_code("true"), 0);
+        assert_eq!(exit_code("false"), 1);
+        assert_eq!(exit_code("exit 5"), 5);
+        assert_eq!(exit_code("sh -c 'kill -TERM $$'"), 143);
+    }
+
+    #[test]
+    fn the_exit_trap_can_change_the_exit_code() {
+        assert_eq!(exit_code("trap 'false' EXIT; sh -c 'exit 3'"), 3);
+        assert_eq!(exit_code("trap 'exit 7' EXIT; true"), 7);
+    }
+
+    #[test]
+    fn rush_prompt_comes_before_ps1_and_ps2_has_a_default() {
+        let mut vars = Variables::new();
+        assert_eq!(Shell::ps1(&vars), None);
+        assert_eq!(Shell::prompt_string(&vars, "PS2", "> "), "> ");
+
+        vars.define("PS1", "").unwrap();
+        assert_eq!(Shell::ps1(&vars), Some(String::new()));
+        vars.define("PS1", "\\s\\$ ").unwrap();
+        vars.define("PS2", "... ").unwrap();
+        assert_eq!(Shell::prompt_string(&vars, "PS2", "> "), "... ");
+
+        vars.define("RUSH_PROMPT", "echo custom").unwrap();
+        assert_eq!(Shell::ps1(&vars), None);
+        vars.define("RUSH_PROMPT", "").unwrap();
+        assert!(Shell::ps1(&vars).unwrap().starts_with("rush"));
+    }
+}
@@ -0,0 +1,140 @@
+//! Expanding the backslash escapes in `$PS1` and `$PS2`, like `\u@\h:\w\$ `
+
+use crate::env::Variables;
+use nix::libc;
+use nix::unistd;
+use std::ffi::{CStr, OsString};
+
+/// Replace the escapes in a prompt string with what they stand for:
+///
+/// - `\u` the user's name, `\h` the host name up to the first dot, `\H` the whole host name
+/// - `\w` the working directory with `$HOME` shortened to `~`, `\W` just its last component
+/// - `\s` the shell's name, `\v` its version
+/// - `\$` a `#` for root and a `$` for everyone else
+/// - `\n`, `\r`, `\a`, `\e` and `\NNN` for the characters they name in `printf`
+/// - `\[` and `\]`, which only mark where non-printing characters start and end, are dropped
+///
+/// Any other backslash is left as it is
+pub fn expand(template: &str, vars: &Variables) -> String {
+    // `\NNN` escapes are bytes, so the prompt is put together as bytes and only decoded once it's whole
+    let mut prompt = Vec::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            prompt.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('u') => prompt.extend_from_slice(user_name().as_bytes()),
+            Some('h') => prompt.extend_from_slice(host_name().split('.').next().unwrap_or("").as_bytes()),
+            Some('H') => prompt.extend_from_slice(host_name().as_bytes()),
+            Some('w') => prompt.extend_from_slice(working_dir(vars).as_bytes()),
+            Some('W') => {
+                let dir = working_dir(vars);
+                prompt.extend_from_slice(match dir.rfind('/') {
+                    Some(i) if dir.len() > 1 => &dir.as_bytes()[i + 1..],
+                    _ => dir.as_bytes(),
+                });
+            }
+            Some('s') => prompt.extend_from_slice(b"rush"),
+            Some('v') => prompt.extend_from_slice(value(vars, "RUSH_VERSION").as_bytes()),
+            Some('$') => prompt.push(if unistd::geteuid().is_root() { b'#' } else { b'$' }),
+            Some('n') => prompt.push(b'\n'),
+            Some('r') => prompt.push(b'\r'),
+            Some('a') => prompt.push(b'\x07'),
+            Some('e') => prompt.push(b'\x1b'),
+            Some('\\') => prompt.push(b'\\'),
+            Some('[') | Some(']') => (),
+            Some(d) if d.is_digit(8) => {
+                let mut code = d.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match chars.peek().and_then(|c| c.to_digit(8)) {
+                        Some(n) => {
+                            code = code * 8 + n;
+                            chars.next();
+                        }
+                        None => break,
+                    }
+                }
+                prompt.push(code as u8);
+            }
+            Some(other) => {
+                prompt.push(b'\\');
+                prompt.extend_from_slice(other.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            None => prompt.push(b'\\'),
+        }
+    }
+    String::from_utf8_lossy(&prompt).into_owned()
+}
+
+fn value(vars: &Variables, name: &str) -> String {
+    vars.value(&OsString::from(name)).to_string_lossy().to_string()
+}
+
+/// `$PWD`, with `$HOME` at the start of it written as `~`
+fn working_dir(vars: &Variables) -> String {
+    let pwd = value(vars, "PWD");
+    let home = value(vars, "HOME");
+    let home = home.trim_end_matches('/');
+    if !home.is_empty() && (pwd == home || pwd.starts_with(&format!("{}/", home))) {
+        format!("~{}", &pwd[home.len()..])
+    } else {
+        pwd
+    }
+}
+
+/// The name of the effective user from the password database, or their uid when they aren't in it
+fn user_name() -> String {
+    let uid = unistd::geteuid();
+    let entry = unsafe { libc::getpwuid(uid.into()) };
+    if entry.is_null() {
+        return uid.to_string();
+    }
+    unsafe { CStr::from_ptr((*entry).pw_name) }
+        .to_string_lossy()
+        .to_string()
+}
+
+fn host_name() -> String {
+    let mut buf = [0u8; 256];
+    match unistd::gethostname(&mut buf) {
+        Ok(name) => name.to_string_lossy().to_string(),
+        Err(_) => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_stand_for_characters() {
+        let vars = Variables::new();
+        assert_eq!(expand("a\\nb\\r\\a\\e\\\\", &vars), "a\nb\r\x07\x1b\\");
+        assert_eq!(expand("\\[\\e[1m\\]bold", &vars), "\x1b[1mbold");
+        assert_eq!(expand("\\s", &vars), "rush");
+        assert_eq!(expand("\\q and \\", &vars), "\\q and \\");
+    }
+
+    #[test]
+    fn octal_escapes_are_bytes_of_utf8() {
+        let vars = Variables::new();
+        assert_eq!(expand("\\101\\1022", &vars), "AB2");
+        assert_eq!(expand("caf\\303\\251", &vars), "caf\u{e9}");
+        assert_eq!(expand("\\303!", &vars), "\u{fffd}!");
+    }
+
+    #[test]
+    fn working_directory_is_shortened_under_home() {
+        let mut vars = Variables::new();
+        vars.define("HOME", "/home/u/").unwrap();
+        vars.define("PWD", "/home/u/src/rush").unwrap();
+        assert_eq!(expand("\\w \\W", &vars), "~/src/rush rush");
+        vars.define("PWD", "/home/user").unwrap();
+        assert_eq!(expand("\\w", &vars), "/home/user");
+        vars.define("PWD", "/").unwrap();
+        assert_eq!(expand("\\w \\W", &vars), "/ /");
+    }
+}